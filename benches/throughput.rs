@@ -0,0 +1,170 @@
+//! Transactions per second through the threaded engine, by handler count, by how skewed the
+//! accounts are, and by router. Each iteration submits one batch from a few threads and waits
+//! for all of it to be applied.
+
+use std::thread;
+
+use aptone::{
+    AccountId, Aptone, AptoneBuilder, BackpressurePolicy, ConsistentHash, LeastQueueDepth, Money,
+    Random, RoundRobin, TxType,
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const ACCOUNTS: u32 = 1000;
+const BATCH: usize = 4000;
+const SUBMITTERS: usize = 4;
+const SEED: u64 = 0x5eed;
+
+// Zipf's exponent; with 1000 accounts the busiest one sees about 13% of the transactions
+const ZIPF_EXPONENT: f64 = 1.0;
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    // uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Skew {
+    Uniform,
+    Zipfian,
+}
+
+impl Skew {
+    fn name(self) -> &'static str {
+        match self {
+            Skew::Uniform => "uniform",
+            Skew::Zipfian => "zipfian",
+        }
+    }
+    // The accounts of one batch, drawn up front so the sampling isn't measured.
+    fn accounts(self) -> Vec<AccountId> {
+        let mut rng = XorShift(SEED);
+        match self {
+            Skew::Uniform => (0..BATCH)
+                .map(|_| (rng.next() % ACCOUNTS as u64) as AccountId)
+                .collect(),
+            Skew::Zipfian => {
+                let mut cdf: Vec<f64> = (1..=ACCOUNTS)
+                    .map(|rank| 1.0 / (rank as f64).powf(ZIPF_EXPONENT))
+                    .collect();
+                let mut total = 0.0;
+                for weight in &mut cdf {
+                    total += *weight;
+                    *weight = total;
+                }
+                (0..BATCH)
+                    .map(|_| {
+                        let target = rng.next_f64() * total;
+                        cdf.partition_point(|&sum| sum < target) as AccountId
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+fn engine(builder: AptoneBuilder) -> Aptone {
+    let aptone = builder.backpressure(BackpressurePolicy::Block).build();
+    // funded so the withdrawals in a batch go through
+    for _ in 0..ACCOUNTS {
+        aptone.open_account(Money::from(u32::MAX)).unwrap();
+    }
+    aptone
+}
+
+// Every fourth transaction is a withdrawal, the rest deposits.
+fn run_batch(aptone: &Aptone, accounts: &[AccountId]) {
+    thread::scope(|scope| {
+        for chunk in accounts.chunks(accounts.len().div_ceil(SUBMITTERS)) {
+            scope.spawn(move || {
+                let receipts: Vec<_> = chunk
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &account)| {
+                        let tx_type = if i % 4 == 3 {
+                            TxType::WITHDRAW
+                        } else {
+                            TxType::DEPOSIT
+                        };
+                        aptone.handle_tx(account, Money::from(1), tx_type).unwrap()
+                    })
+                    .collect();
+                for receipt in receipts {
+                    receipt.wait().unwrap();
+                }
+            });
+        }
+    });
+}
+
+fn threads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("threads");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    let accounts = Skew::Uniform.accounts();
+    for threads in [1, 2, 4, 8] {
+        let aptone = engine(Aptone::builder().threads(threads));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &accounts,
+            |b, accounts| b.iter(|| run_batch(&aptone, accounts)),
+        );
+    }
+    group.finish();
+}
+
+fn skew(c: &mut Criterion) {
+    let mut group = c.benchmark_group("skew");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    for skew in [Skew::Uniform, Skew::Zipfian] {
+        let aptone = engine(Aptone::builder().threads(4));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(skew.name()),
+            &skew.accounts(),
+            |b, accounts| b.iter(|| run_batch(&aptone, accounts)),
+        );
+    }
+    group.finish();
+}
+
+fn routers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("routers");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    let builders = [
+        ("consistent_hash", Aptone::builder().router(ConsistentHash)),
+        (
+            "least_queue_depth",
+            Aptone::builder().router(LeastQueueDepth),
+        ),
+        ("round_robin", Aptone::builder().router(RoundRobin::new())),
+        ("random", Aptone::builder().router(Random::with_seed(SEED))),
+    ];
+    for skew in [Skew::Uniform, Skew::Zipfian] {
+        let accounts = skew.accounts();
+        for (name, builder) in &builders {
+            let aptone = engine(builder.clone().threads(4));
+            group.bench_with_input(
+                BenchmarkId::new(*name, skew.name()),
+                &accounts,
+                |b, accounts| b.iter(|| run_batch(&aptone, accounts)),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = threads, skew, routers
+}
+criterion_main!(benches);
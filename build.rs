@@ -0,0 +1,8 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        // no protoc is assumed on the build machine
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+        tonic_build::compile_protos("proto/aptone.proto").unwrap();
+    }
+}
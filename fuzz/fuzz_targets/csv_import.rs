@@ -0,0 +1,25 @@
+//! Any CSV imports without panicking, accounting for every row, and leaves no account overdrawn
+//! or with anything pending.
+
+#![no_main]
+
+use aptone::{import, Aptone, Money};
+use libfuzzer_sys::fuzz_target;
+
+const ACCOUNTS: usize = 4;
+
+fuzz_target!(|bytes: &[u8]| {
+    let aptone = Aptone::builder().threads(2).build();
+    for _ in 0..ACCOUNTS {
+        aptone.open_account(Money::from_minor(1_000_000)).unwrap();
+    }
+    if let Ok(report) = import::from_csv(&aptone, bytes, 8) {
+        assert_eq!(report.rows, report.applied + report.errors.len() as u64);
+    }
+    aptone.flush();
+    let snapshot = aptone.snapshot();
+    for balances in snapshot.balances.values() {
+        assert!(balances.values().all(|balance| !balance.is_negative()));
+    }
+    assert!(snapshot.pending.is_empty(), "{:?}", snapshot.pending);
+});
@@ -0,0 +1,127 @@
+//! Any sequence of operations, on the deterministic engine with the handlers interleaved as the
+//! input has them, finishes every transaction accepted, overdraws no account, and leaves the
+//! accounts holding what was opened with plus what was applied.
+
+#![no_main]
+
+use aptone::{AccountId, Aptone, Currency, HandleId, Money, TestHarness, Tx, TxReceipt, TxType};
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    seed: u64,
+    threads: u8,
+    ops: Vec<Op>,
+}
+
+// accounts are picked by position among those opened so far
+#[derive(Debug, Arbitrary)]
+enum Op {
+    Open(u32),
+    Close(u8),
+    Deposit(u8, u32),
+    Withdraw(u8, u32),
+    Transfer(u8, u8, u32),
+    // legs depositing if true, withdrawing otherwise
+    Batch(Vec<(u8, u32, bool)>),
+    Step,
+    StepHandler(u8),
+    RunUntilIdle,
+}
+
+fuzz_target!(|input: Input| {
+    let builder = Aptone::builder().threads(input.threads as usize % 4 + 1);
+    let harness = TestHarness::with_builder(input.seed, builder);
+    let aptone = harness.aptone();
+    let mut accounts: Vec<AccountId> = Vec::new();
+    // what the accounts hold together once what was accepted is through, but for the
+    // transactions in `submitted`, with what each adds if applied
+    let mut expected = 0i128;
+    let mut submitted: Vec<(TxReceipt, i128)> = Vec::new();
+
+    for op in input.ops {
+        let pick = |i: u8| accounts[i as usize % accounts.len()];
+        let mut submit = |tx: Tx, adds: i128| {
+            if let Ok(receipt) = aptone.submit_tx(tx) {
+                submitted.push((receipt, adds));
+            }
+        };
+        match op {
+            Op::Open(balance) => {
+                if let Ok(account) = aptone.open_account(Money::from_minor(balance as i128)) {
+                    accounts.push(account);
+                    expected += balance as i128;
+                }
+            }
+            _ if accounts.is_empty() => {}
+            Op::Close(i) => {
+                if let Ok(held) = aptone.close_account(pick(i)) {
+                    for (currency, balance) in held {
+                        assert_eq!(currency, Currency::default());
+                        expected -= balance.minor();
+                    }
+                }
+            }
+            Op::Deposit(i, amount) => {
+                let tx = Tx::new(pick(i), Money::from_minor(amount as i128), TxType::DEPOSIT);
+                submit(tx, amount as i128);
+            }
+            Op::Withdraw(i, amount) => {
+                let tx = Tx::new(pick(i), Money::from_minor(amount as i128), TxType::WITHDRAW);
+                submit(tx, -(amount as i128));
+            }
+            Op::Transfer(from, to, amount) => {
+                let tx_type = TxType::TRANSFER { to: pick(to) };
+                submit(
+                    Tx::new(pick(from), Money::from_minor(amount as i128), tx_type),
+                    0,
+                );
+            }
+            Op::Batch(legs) => {
+                let mut adds = 0;
+                let legs = legs
+                    .into_iter()
+                    .map(|(i, amount, deposit)| {
+                        let (tx_type, sign) = match deposit {
+                            true => (TxType::DEPOSIT, 1),
+                            false => (TxType::WITHDRAW, -1),
+                        };
+                        adds += sign * amount as i128;
+                        Tx::new(pick(i), Money::from_minor(amount as i128), tx_type)
+                    })
+                    .collect();
+                if let Ok(receipt) = aptone.submit_batch(legs) {
+                    submitted.push((receipt, adds));
+                }
+            }
+            Op::Step => {
+                harness.step();
+            }
+            Op::StepHandler(handler) => {
+                harness.step_handler(handler as HandleId);
+            }
+            Op::RunUntilIdle => harness.run_until_idle(),
+        }
+    }
+
+    harness.run_until_idle();
+    for (receipt, adds) in submitted {
+        let result = receipt
+            .try_result()
+            .expect("a transaction was left unfinished");
+        if result.is_ok() {
+            expected += adds;
+        }
+    }
+    let snapshot = aptone.snapshot();
+    let mut total = 0i128;
+    for balances in snapshot.balances.values() {
+        for balance in balances.values() {
+            assert!(!balance.is_negative(), "{:?}", snapshot.balances);
+            total += balance.minor();
+        }
+    }
+    assert_eq!(total, expected);
+    assert!(snapshot.pending.is_empty(), "{:?}", snapshot.pending);
+});
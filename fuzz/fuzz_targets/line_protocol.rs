@@ -0,0 +1,27 @@
+//! Any requests over the line protocol are answered with `OK` or `ERR` without panicking, and
+//! leave nothing pending.
+
+#![no_main]
+
+use aptone::{tcp, Aptone};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|bytes: &[u8]| {
+    let aptone = Aptone::builder().threads(2).build();
+    let mut answers = Vec::new();
+    // lines that aren't UTF-8 end the session
+    let _ = tcp::session(&aptone, bytes, &mut answers);
+    for answer in String::from_utf8(answers).unwrap().lines() {
+        assert!(
+            answer.starts_with("OK ") || answer.starts_with("ERR "),
+            "{:?}",
+            answer
+        );
+    }
+    aptone.flush();
+    let snapshot = aptone.snapshot();
+    for balances in snapshot.balances.values() {
+        assert!(balances.values().all(|balance| !balance.is_negative()));
+    }
+    assert!(snapshot.pending.is_empty(), "{:?}", snapshot.pending);
+});
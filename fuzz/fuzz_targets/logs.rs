@@ -0,0 +1,21 @@
+//! Transaction logs and recordings are read or refused, and a torn last line, as a crash in the
+//! middle of an append leaves, changes nothing about what is read before it.
+
+#![no_main]
+
+use aptone::{recording, wal};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|bytes: &[u8]| {
+    let log = wal::read_from(bytes);
+    let recorded = recording::read_from(bytes);
+    if bytes.is_empty() || bytes.ends_with(b"\n") {
+        let torn = [bytes, b"DEPOSIT 1"].concat();
+        if let Ok(entries) = log {
+            assert_eq!(wal::read_from(&torn[..]).unwrap(), entries);
+        }
+        if let Ok(entries) = recorded {
+            assert_eq!(recording::read_from(&torn[..]).unwrap(), entries);
+        }
+    }
+});
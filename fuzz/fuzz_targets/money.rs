@@ -0,0 +1,15 @@
+//! Amounts and currencies read back as they are written, and anything else is refused.
+
+#![no_main]
+
+use aptone::{Currency, Money};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: &str| {
+    if let Ok(money) = input.parse::<Money>() {
+        assert_eq!(money.to_string().parse::<Money>(), Ok(money));
+    }
+    if let Ok(currency) = input.parse::<Currency>() {
+        assert_eq!(currency.to_string().parse::<Currency>(), Ok(currency));
+    }
+});
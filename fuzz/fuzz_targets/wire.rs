@@ -0,0 +1,17 @@
+//! Wire messages decode to what they encode back to, framed or not, and anything else is
+//! refused.
+
+#![no_main]
+
+use aptone::WireMessage;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|bytes: &[u8]| {
+    if let Ok(message) = WireMessage::decode(bytes) {
+        assert_eq!(WireMessage::decode(&message.encode()), Ok(message.clone()));
+        let mut framed = Vec::new();
+        message.write(&mut framed).unwrap();
+        assert_eq!(WireMessage::read(&mut &framed[..]).unwrap(), message);
+    }
+    let _ = WireMessage::read(&mut &bytes[..]);
+});
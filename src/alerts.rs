@@ -0,0 +1,172 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{AccountId, Currency, EntryKind, HandleId, HistoryEntry, Money, TxCount, TxId};
+
+/// Something worth paging someone about, raised against `AlertThresholds`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Alert {
+    /// A transaction took the balance of `account` in `currency` from at or above `threshold` to
+    /// below it.
+    LowBalance {
+        tx_id: TxId,
+        account: AccountId,
+        currency: Currency,
+        threshold: Money,
+        balance: Money,
+    },
+    /// A withdrawal, outgoing transfer or capture took more than `threshold` out of `account`.
+    LargeWithdrawal {
+        tx_id: TxId,
+        account: AccountId,
+        currency: Currency,
+        threshold: Money,
+        amount: Money,
+    },
+    /// The queue of `handler` grew past `threshold` transactions. Raised again only once it has
+    /// drained back to the threshold.
+    QueueDepth {
+        handler: HandleId,
+        threshold: TxCount,
+        depth: TxCount,
+    },
+    /// A transaction has waited on the queue of `handler` for `waited`, longer than the
+    /// threshold. Raised once per transaction.
+    StuckTx {
+        tx_id: TxId,
+        handler: HandleId,
+        waited: Duration,
+    },
+}
+
+/// When to raise alerts; none by default. Thresholds hold for every currency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AlertThresholds {
+    pub low_balance: Option<Money>,
+    pub large_withdrawal: Option<Money>,
+    pub queue_depth: Option<TxCount>,
+    pub stuck_tx: Option<Duration>,
+}
+
+/// Where alerts go. Called on the thread that raised the alert, a handler's or a submitter's, so
+/// a sink should hand anything slow off elsewhere.
+pub trait AlertSink: fmt::Debug + Send + Sync {
+    fn alert(&self, alert: &Alert);
+}
+
+/// Sends each alert down the channel, for as long as the receiver is around.
+impl AlertSink for Sender<Alert> {
+    fn alert(&self, alert: &Alert) {
+        let _ = self.send(alert.clone());
+    }
+}
+
+#[cfg(feature = "crossbeam")]
+impl AlertSink for crossbeam_channel::Sender<Alert> {
+    fn alert(&self, alert: &Alert) {
+        let _ = self.send(alert.clone());
+    }
+}
+
+pub(crate) struct Alerts {
+    thresholds: AlertThresholds,
+    sinks: Vec<Arc<dyn AlertSink>>,
+    queue_raised: Vec<AtomicBool>, // handler id -> alerted on its depth, not drained since
+}
+
+impl Alerts {
+    pub(crate) fn new(
+        thresholds: AlertThresholds,
+        sinks: Vec<Arc<dyn AlertSink>>,
+        handlers: usize,
+    ) -> Alerts {
+        Alerts {
+            thresholds,
+            sinks,
+            queue_raised: (0..handlers).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+    /// Raises the alerts the history entries of an applied transaction call for.
+    pub(crate) fn applied<'a>(
+        &self,
+        tx_id: TxId,
+        entries: impl IntoIterator<Item = &'a (AccountId, HistoryEntry)>,
+    ) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        let AlertThresholds {
+            low_balance,
+            large_withdrawal,
+            ..
+        } = self.thresholds;
+        for (account, entry) in entries {
+            let (account, currency) = (*account, entry.currency);
+            if let Some(threshold) = low_balance {
+                if entry.balance < threshold && threshold <= entry.balance_before() {
+                    self.raise(Alert::LowBalance {
+                        tx_id,
+                        account,
+                        currency,
+                        threshold,
+                        balance: entry.balance,
+                    });
+                }
+            }
+            let outgoing = matches!(
+                entry.kind,
+                EntryKind::Withdraw
+                    | EntryKind::TransferOut { .. }
+                    | EntryKind::Capture { .. }
+                    | EntryKind::FeeOut { .. }
+            );
+            if let Some(threshold) = large_withdrawal.filter(|_| outgoing) {
+                if entry.amount > threshold {
+                    self.raise(Alert::LargeWithdrawal {
+                        tx_id,
+                        account,
+                        currency,
+                        threshold,
+                        amount: entry.amount,
+                    });
+                }
+            }
+        }
+    }
+    /// Notes the depth of `handler`'s queue, raising `Alert::QueueDepth` once it grows past the
+    /// threshold and arming it again once it's back.
+    pub(crate) fn queue_depth(&self, handler: HandleId, depth: TxCount) {
+        let Some(threshold) = self.thresholds.queue_depth else {
+            return;
+        };
+        if self.sinks.is_empty() {
+            return;
+        }
+        let raised = &self.queue_raised[handler as usize];
+        if depth <= threshold {
+            raised.store(false, Ordering::Relaxed);
+        } else if !raised.swap(true, Ordering::Relaxed) {
+            self.raise(Alert::QueueDepth {
+                handler,
+                threshold,
+                depth,
+            });
+        }
+    }
+    /// Raises `Alert::StuckTx`.
+    pub(crate) fn stuck(&self, tx_id: TxId, handler: HandleId, waited: Duration) {
+        self.raise(Alert::StuckTx {
+            tx_id,
+            handler,
+            waited,
+        });
+    }
+    fn raise(&self, alert: Alert) {
+        for sink in &self.sinks {
+            sink.alert(&alert);
+        }
+    }
+}
@@ -0,0 +1,497 @@
+//! Async flavour of the engine: handlers are tokio tasks fed through `tokio::sync::mpsc`, and
+//! every call resolves once its transaction has been applied. Must be created inside a tokio
+//! runtime.
+//!
+//! Each handler task runs in a `handler` span, and reports at debug level how long transactions
+//! sat on its queue, how long barriers held it, and any lock it waited on for a millisecond or
+//! more. With the `console` feature and `--cfg tokio_unstable`, the tasks are also named after
+//! their handler and tokio emits the task instrumentation `tokio-console` reads, once the
+//! application installs `console-subscriber`.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::{self, error::TrySendError, Permit};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{debug, info_span, Instrument};
+
+use crate::directory::{Directory, Shard, TxCounts};
+use crate::latency::LatencyInjector;
+use crate::server_data::ServerData;
+use crate::sync;
+use crate::tx;
+use crate::{
+    AccountId, BackpressurePolicy, Config, Currency, HandleId, HoldId, Money, Tx, TxError, TxId,
+    TxResult, TxType,
+};
+
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+// waits on a lock reported as contention
+const CONTENDED_LOCK: Duration = Duration::from_millis(1);
+
+struct Job {
+    tx: Tx,
+    reply: oneshot::Sender<TxResult>,
+    // hands the credit leg to the handler owning `to`, parked on a `Barrier` for this tx
+    credit: Option<(HandleId, oneshot::Sender<Credit>)>,
+    batch: Vec<Tx>, // the legs after `tx` of a batch it opens
+    queued: Instant,
+}
+
+struct Credit {
+    currency: Currency,
+    amount: Money,
+    ack: oneshot::Sender<TxResult>,
+}
+
+enum Message {
+    NewTx(Job),
+    // holds the account's queue on this handler until a transfer on another handler has been
+    // debited, then applies its credit leg
+    Barrier(AccountId, oneshot::Receiver<Credit>),
+}
+
+pub struct Aptone {
+    directory: Directory,
+    senders: Vec<mpsc::Sender<Message>>,
+    tasks: Vec<JoinHandle<()>>,
+    backpressure: BackpressurePolicy,
+    next_account: AtomicU32,
+    next_hold: AtomicU64,
+}
+
+impl Aptone {
+    pub fn new() -> Aptone {
+        Aptone::with_config(Config::default())
+    }
+    pub fn with_config(config: Config) -> Aptone {
+        assert!(config.threads > 0, "Aptone needs at least one handler task");
+        assert!(
+            config.channel_capacity > 0,
+            "handler queues need room for at least one message"
+        );
+        let directory = Directory::new(
+            config.threads,
+            config.lock_stripes,
+            Arc::clone(&config.router),
+            || config.shard(),
+        );
+        // the handlers sleep on tokio's timer rather than the clock
+        let latency = Arc::new(LatencyInjector::new(
+            config.latency,
+            None,
+            config.threads,
+            Arc::clone(&config.clock),
+        ));
+        let mut senders = Vec::with_capacity(config.threads);
+        let mut tasks = Vec::with_capacity(config.threads);
+
+        for id in 0..config.threads {
+            let (sender, receiver) = mpsc::channel::<Message>(config.channel_capacity);
+            senders.push(sender);
+            let handler = run_handler(
+                id as HandleId,
+                receiver,
+                Arc::clone(directory.shard(id as HandleId)),
+                directory.tx_counts(),
+                Arc::clone(&latency),
+            );
+            tasks.push(spawn_handler(
+                id as HandleId,
+                handler.instrument(info_span!("handler", handler = id)),
+            ));
+        }
+        Aptone {
+            directory,
+            senders,
+            tasks,
+            backpressure: config.backpressure,
+            next_account: AtomicU32::new(0),
+            next_hold: AtomicU64::new(0),
+        }
+    }
+    pub async fn handle_tx(&self, account: AccountId, amount: Money, tx_type: TxType) -> TxResult {
+        self.submit_tx(Tx::new(account, amount, tx_type)).await
+    }
+    /// Like `handle_tx`, for a transaction in any currency.
+    pub async fn submit_tx(&self, tx: Tx) -> TxResult {
+        self.submit(tx, Vec::new()).await
+    }
+    /// Applies `legs` as one transaction, all or none, see `crate::Aptone::submit_batch`.
+    pub async fn submit_batch(&self, mut legs: Vec<Tx>) -> TxResult {
+        if legs.is_empty() {
+            return Err(TxError::EmptyBatch);
+        }
+        let batch = legs.split_off(1);
+        self.submit(legs.pop().unwrap(), batch).await
+    }
+    async fn submit(&self, tx: Tx, batch: Vec<Tx>) -> TxResult {
+        if let Some(leg) = std::iter::once(&tx)
+            .chain(&batch)
+            .find(|leg| leg.tx_type.has_amount() && !leg.amount.is_positive())
+        {
+            return Err(TxError::InvalidAmount(leg.amount));
+        }
+        let (reply, receiver) = oneshot::channel();
+        let mut job = Job {
+            tx,
+            reply,
+            credit: None,
+            batch,
+            queued: Instant::now(),
+        };
+        let id = loop {
+            job = match self.try_dispatch(job)? {
+                Ok(id) => break id,
+                Err((_, Some(full_id))) if self.backpressure != BackpressurePolicy::Block => {
+                    return Err(TxError::QueueFull(full_id));
+                }
+                Err((job, _)) => job,
+            };
+            // the lock can't be held across an await, so poll until the handler has room, or the
+            // accounts of a batch have drained off other handlers
+            tokio::time::sleep(QUEUE_POLL_INTERVAL).await;
+        };
+        receiver
+            .await
+            .unwrap_or(Err(TxError::HandlerUnavailable(id)))
+    }
+    // Hands the job back, with the id of the full handler, if a target queue has no room, or
+    // without one if it is a batch whose accounts are pinned to different handlers.
+    fn try_dispatch(
+        &self,
+        mut job: Job,
+    ) -> Result<Result<HandleId, (Job, Option<HandleId>)>, TxError> {
+        if !job.batch.is_empty() {
+            return self.try_dispatch_batch(job);
+        }
+        let Tx {
+            account, tx_type, ..
+        } = job.tx;
+        let mut accounts = contended("stripe", || self.directory.lock(account, tx_type));
+        accounts.check_open(account, tx_type)?;
+
+        let (id, barrier) = accounts.route(account, tx_type);
+        let _crossing = barrier.map(|_| self.directory.lock_crossing());
+        // reserve every slot up front so the barrier and the transfer are queued together
+        let permit = match self.reserve(id)? {
+            Some(permit) => permit,
+            None => return Ok(Err((job, Some(id)))),
+        };
+        if let (Some(to), Some(to_id)) = (tx_type.payee(), barrier) {
+            let barrier_permit = match self.reserve(to_id)? {
+                Some(permit) => permit,
+                None => return Ok(Err((job, Some(to_id)))),
+            };
+            let (credit, credit_rx) = oneshot::channel();
+            accounts.track_barrier(to, to_id);
+            self.directory.reserve(to_id);
+            barrier_permit.send(Message::Barrier(to, credit_rx));
+            job.credit = Some((to_id, credit));
+        }
+        self.directory.claim_reversal(id, &mut job.tx)?;
+        // tracked first, since the handler may pick the job up right away
+        accounts.track_tx(account, tx_type, id, barrier.is_some());
+        self.directory.reserve(id);
+        permit.send(Message::NewTx(job));
+        Ok(Ok(id))
+    }
+    fn try_dispatch_batch(
+        &self,
+        mut job: Job,
+    ) -> Result<Result<HandleId, (Job, Option<HandleId>)>, TxError> {
+        let mut legs: Vec<Tx> = std::iter::once(&job.tx)
+            .chain(&job.batch)
+            .cloned()
+            .collect();
+        let touched = tx::accounts_of(&legs);
+        let mut accounts = contended("stripe", || self.directory.lock_accounts(&touched));
+        for leg in &legs {
+            accounts.check_open(leg.account, leg.tx_type)?;
+        }
+        let Some(id) = accounts.route_batch(&touched) else {
+            return Ok(Err((job, None)));
+        };
+        let permit = match self.reserve(id)? {
+            Some(permit) => permit,
+            None => return Ok(Err((job, Some(id)))),
+        };
+        self.directory.claim_reversals(id, &mut legs)?;
+        job.batch = legs.split_off(1);
+        job.tx = legs.pop().unwrap();
+        accounts.track_batch(&touched, id);
+        self.directory.reserve(id);
+        permit.send(Message::NewTx(job));
+        Ok(Ok(id))
+    }
+    fn reserve(&self, id: HandleId) -> Result<Option<Permit<'_, Message>>, TxError> {
+        match self.senders[id as usize].try_reserve() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(TrySendError::Full(())) => Ok(None),
+            Err(TrySendError::Closed(())) => Err(TxError::HandlerUnavailable(id)),
+        }
+    }
+    /// Opens an account holding `initial_balance` in the default currency and gives back its id.
+    pub async fn open_account(&self, initial_balance: Money) -> Result<AccountId, TxError> {
+        if initial_balance.is_negative() {
+            return Err(TxError::InvalidAmount(initial_balance));
+        }
+        let account = self.next_account.fetch_add(1, Ordering::SeqCst);
+        self.directory
+            .lock(account, TxType::DEPOSIT)
+            .open(account, initial_balance);
+        Ok(account)
+    }
+    /// Closes `account` and gives back what it held in each currency, unless it has
+    /// transactions pending.
+    pub async fn close_account(
+        &self,
+        account: AccountId,
+    ) -> Result<Vec<(Currency, Money)>, TxError> {
+        let mut accounts = self.directory.lock(account, TxType::DEPOSIT);
+        accounts.check_open(account, TxType::DEPOSIT)?;
+        let pending = accounts.get_pending_tx(account);
+        if pending > 0 {
+            return Err(TxError::AccountBusy { account, pending });
+        }
+        Ok(accounts.close(account))
+    }
+    pub async fn withdraw(&self, account: AccountId, amount: Money) -> TxResult {
+        self.handle_tx(account, amount, TxType::WITHDRAW).await
+    }
+    pub async fn deposit(&self, account: AccountId, amount: Money) -> TxResult {
+        self.handle_tx(account, amount, TxType::DEPOSIT).await
+    }
+    pub async fn transfer(&self, from: AccountId, to: AccountId, amount: Money) -> TxResult {
+        self.handle_tx(from, amount, TxType::TRANSFER { to }).await
+    }
+    /// Reserves `amount` of `account`'s available balance until the hold it gives back is
+    /// captured or released.
+    pub async fn authorize(&self, account: AccountId, amount: Money) -> Result<HoldId, TxError> {
+        let number = self.next_hold.fetch_add(1, Ordering::SeqCst);
+        let hold = HoldId { account, number };
+        self.handle_tx(account, amount, TxType::AUTHORIZE { hold })
+            .await?;
+        Ok(hold)
+    }
+    /// Takes what `hold` reserved off the account's balance.
+    pub async fn capture(&self, hold: HoldId) -> TxResult {
+        self.handle_tx(hold.account, Money::ZERO, TxType::CAPTURE { hold })
+            .await
+    }
+    /// Drops `hold`, making what it reserved available again.
+    pub async fn release(&self, hold: HoldId) -> TxResult {
+        self.handle_tx(hold.account, Money::ZERO, TxType::RELEASE { hold })
+            .await
+    }
+    /// Undoes `original`, see `TxType::REVERSAL`. Without a history to go by here, nothing is
+    /// ever found to reverse.
+    pub async fn reverse(&self, account: AccountId, original: TxId) -> TxResult {
+        self.handle_tx(account, Money::ZERO, TxType::REVERSAL { original })
+            .await
+    }
+    /// Converts `amount` of `from` in `account` into `to`, see `TxType::EXCHANGE`.
+    pub async fn exchange(
+        &self,
+        account: AccountId,
+        amount: Money,
+        from: Currency,
+        to: Currency,
+    ) -> TxResult {
+        self.submit_tx(Tx::new(account, amount, TxType::EXCHANGE { to }).in_currency(from))
+            .await
+    }
+    /// The balance in the default currency.
+    pub async fn get_balance(&self, account: AccountId) -> Money {
+        self.get_balance_in(account, Currency::default()).await
+    }
+    pub async fn get_balance_in(&self, account: AccountId, currency: Currency) -> Money {
+        self.directory.get_balance(account, currency)
+    }
+    /// The balance in the default currency less what holds reserve of it.
+    pub async fn get_available_balance(&self, account: AccountId) -> Money {
+        self.directory
+            .get_available_balance(account, Currency::default())
+    }
+    /// Every currency `account` holds, with its balance in it.
+    pub async fn balances(&self, account: AccountId) -> Vec<(Currency, Money)> {
+        self.directory.get_balances(account)
+    }
+    /// Resolves once every transaction submitted so far has been applied or rejected.
+    pub async fn flush(&self) {
+        while !self.directory.idle() {
+            tokio::time::sleep(QUEUE_POLL_INTERVAL).await;
+        }
+    }
+    /// Closes the handler queues and waits for the tasks to apply what is left in them.
+    pub async fn shutdown(mut self) {
+        self.senders.clear();
+        for task in self.tasks.drain(..) {
+            // a panicked handler has nothing left to drain
+            let _ = task.await;
+        }
+    }
+}
+
+impl Default for Aptone {
+    fn default() -> Aptone {
+        Aptone::new()
+    }
+}
+
+// Spawns the handler task, named after it where tokio can tell tokio-console.
+fn spawn_handler<F>(id: HandleId, handler: F) -> JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    #[cfg(all(tokio_unstable, feature = "console"))]
+    {
+        tokio::task::Builder::new()
+            .name(&format!("handler {}", id))
+            .spawn(handler)
+            .expect("failed to spawn a handler task")
+    }
+    #[cfg(not(all(tokio_unstable, feature = "console")))]
+    {
+        let _ = id;
+        tokio::spawn(handler)
+    }
+}
+
+// Takes a lock with `acquire`, reporting the wait if it was long.
+fn contended<T>(lock: &'static str, acquire: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let guard = acquire();
+    let waited = started.elapsed();
+    if waited >= CONTENDED_LOCK {
+        debug!(lock, ?waited, "waited on a lock");
+    }
+    guard
+}
+
+fn lock_shard(shard: &Shard) -> sync::MutexGuard<'_, ServerData> {
+    contended("shard", || sync::lock(shard))
+}
+
+async fn run_handler(
+    id: HandleId,
+    mut receiver: mpsc::Receiver<Message>,
+    shard: Shard,
+    tx_count: TxCounts,
+    latency: Arc<LatencyInjector>,
+) {
+    while let Some(message) = receiver.recv().await {
+        match message {
+            Message::NewTx(Job {
+                tx,
+                reply,
+                batch,
+                queued,
+                ..
+            }) if !batch.is_empty() => {
+                let (account, legs, waited) = (tx.account, batch.len() + 1, queued.elapsed());
+                debug!(account, legs, ?waited, "batch taken off the queue");
+                let legs: Vec<Tx> = std::iter::once(tx).chain(batch).collect();
+                let result = {
+                    let mut data = lock_shard(&shard);
+                    let result = data.apply_batch(&legs).map(|_| ());
+                    for account in tx::accounts_of(&legs) {
+                        data.decrease_pending_tx(account, 1);
+                    }
+                    result
+                };
+                tx_count[id as usize].fetch_sub(1, Ordering::SeqCst);
+                let _ = reply.send(result);
+                pause(&latency, id).await;
+            }
+            Message::NewTx(Job {
+                tx,
+                reply,
+                credit,
+                queued,
+                ..
+            }) => {
+                let waited = queued.elapsed();
+                let (account, tx_type) = (tx.account, tx.tx_type);
+                debug!(account, ?tx_type, ?waited, "tx taken off the queue");
+                let across = credit.is_some();
+                let result = match credit {
+                    Some((peer, credit)) => transfer_across(&shard, &tx, peer, credit).await,
+                    None => lock_shard(&shard).apply(&tx),
+                };
+                {
+                    let mut data = lock_shard(&shard);
+                    data.decrease_pending_tx(tx.account, 1);
+                    if let Some(to) = tx.tx_type.payee() {
+                        // with a barrier in place the peer handler owns `to` and releases it
+                        if to != tx.account && !across {
+                            data.decrease_pending_tx(to, 1);
+                        }
+                    }
+                }
+                tx_count[id as usize].fetch_sub(1, Ordering::SeqCst);
+                // the caller may have given up on the transaction
+                let _ = reply.send(result);
+                pause(&latency, id).await;
+            }
+            Message::Barrier(account, credit) => {
+                // an error means the debit failed and there is nothing to credit
+                let parked = Instant::now();
+                let credit = credit.await;
+                debug!(account, held = ?parked.elapsed(), "barrier released");
+
+                let mut data = lock_shard(&shard);
+                if let Ok(Credit {
+                    currency,
+                    amount,
+                    ack,
+                }) = credit
+                {
+                    let _ = ack.send(data.increase_balance(account, currency, amount));
+                }
+                data.decrease_pending_tx(account, 1);
+                drop(data);
+                tx_count[id as usize].fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+async fn pause(latency: &LatencyInjector, id: HandleId) {
+    let delay = latency.draw(id);
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// Debits the source locally, then has the handler owning `to` credit it, rolling the debit back
+// if that fails.
+async fn transfer_across(
+    shard: &Shard,
+    tx: &Tx,
+    peer: HandleId,
+    credit: oneshot::Sender<Credit>,
+) -> TxResult {
+    lock_shard(shard).pay_out(tx.account, tx.currency, tx.amount)?;
+
+    let (ack, ack_rx) = oneshot::channel();
+    let acked = match credit.send(Credit {
+        currency: tx.currency,
+        amount: tx.amount,
+        ack,
+    }) {
+        Ok(()) => ack_rx
+            .await
+            .unwrap_or(Err(TxError::HandlerUnavailable(peer))),
+        Err(_) => Err(TxError::HandlerUnavailable(peer)),
+    };
+    if let Err(err) = acked {
+        // the account is pinned to us, so nothing touched it since the debit
+        lock_shard(shard).refund(tx.account, tx.currency, tx.amount)?;
+        return Err(err);
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use tracing::error;
+
+use crate::ledger::EventLog;
+use crate::{AccountId, AuditAction, AuditPolicy, Currency, HandleId, Money, ServerData, TxCount};
+
+/// An invariant `Aptone::audit` found broken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// The balance of `account` in `currency` isn't what the events recorded for it add up to.
+    /// Only checked with `AptoneBuilder::record_events`.
+    Unrecorded {
+        account: AccountId,
+        currency: Currency,
+        balance: Money,
+        recorded: Money,
+    },
+    /// Balance reads of `account` in `currency` give `cached`, not the `balance` the handler
+    /// owning it holds.
+    StaleBalance {
+        account: AccountId,
+        currency: Currency,
+        balance: Money,
+        cached: Money,
+    },
+    /// The transaction count of `handler` went below zero and wrapped around.
+    NegativeCount { handler: HandleId, count: TxCount },
+    /// Accounts owned by `handler` have transactions pending, though none are counted on it.
+    Uncounted {
+        handler: HandleId,
+        account: AccountId,
+        pending: TxCount,
+    },
+    /// More messages are queued on `handler` than are counted on it.
+    Overqueued {
+        handler: HandleId,
+        queued: usize,
+        count: TxCount,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Unrecorded {
+                account,
+                currency,
+                balance,
+                recorded,
+            } => write!(
+                f,
+                "account {} holds {} {} but its events add up to {}",
+                account, balance, currency, recorded
+            ),
+            Violation::StaleBalance {
+                account,
+                currency,
+                balance,
+                cached,
+            } => write!(
+                f,
+                "account {} holds {} {} but reads give {}",
+                account, balance, currency, cached
+            ),
+            Violation::NegativeCount { handler, count } => {
+                write!(
+                    f,
+                    "handler {} counts {} transactions",
+                    handler, *count as i32
+                )
+            }
+            Violation::Uncounted {
+                handler,
+                account,
+                pending,
+            } => write!(
+                f,
+                "account {} has {} transactions pending on handler {}, which counts none",
+                account, pending, handler
+            ),
+            Violation::Overqueued {
+                handler,
+                queued,
+                count,
+            } => write!(
+                f,
+                "handler {} has {} messages queued but counts {} transactions",
+                handler, queued, count
+            ),
+        }
+    }
+}
+
+/// What the events recorded so far add up to, caught up with the log on every audit rather than
+/// replayed from the first event each time.
+#[derive(Default)]
+pub(crate) struct Replica {
+    data: ServerData,
+    seen: usize,
+}
+
+impl Replica {
+    pub(crate) fn catch_up(&mut self, log: &EventLog) -> &ServerData {
+        let events = log.read(self.seen);
+        self.seen += events.len();
+        for event in &events {
+            self.data.apply_event(event);
+        }
+        &self.data
+    }
+}
+
+/// Has `audit` check the invariants every `AuditPolicy::interval`, logging what it finds broken
+/// and, under `AuditAction::Abort`, aborting the process on the first violation. Stops when
+/// dropped.
+pub(crate) struct Auditor {
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Auditor {
+    pub(crate) fn start<A>(policy: AuditPolicy, audit: A) -> Auditor
+    where
+        A: Fn() -> Vec<Violation> + Send + 'static,
+    {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopped);
+        let thread = thread::spawn(move || loop {
+            thread::park_timeout(policy.interval);
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            let violations = audit();
+            for violation in &violations {
+                error!(%violation, "invariant violated");
+            }
+            if !violations.is_empty() && policy.on_violation == AuditAction::Abort {
+                error!("aborting on a violated invariant");
+                std::process::abort();
+            }
+        });
+        Auditor {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Auditor {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use tracing::debug;
+
+use crate::AutoscalePolicy;
+
+/// What the autoscaler goes by: the size of the pool, the transactions in flight on every
+/// handler, and running totals of the transactions finished and their latencies.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Load {
+    pub(crate) workers: usize,
+    pub(crate) in_flight: u64,
+    pub(crate) finished: u64,
+    pub(crate) latency_micros: u64,
+}
+
+/// Looks at the load `sample` gives every `AutoscalePolicy::interval` and has `resize` grow or
+/// shrink the pool a handler at a time, or bring it back within bounds. Stops when dropped.
+pub(crate) struct Autoscaler {
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Autoscaler {
+    pub(crate) fn start<S, R>(policy: AutoscalePolicy, sample: S, resize: R) -> Autoscaler
+    where
+        S: Fn() -> Load + Send + 'static,
+        R: Fn(usize) + Send + 'static,
+    {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopped);
+        let thread = thread::spawn(move || {
+            let mut last = sample();
+            let mut cooling_until = None;
+            // the first look only brings the pool within bounds, having no latencies to go by
+            let mut target = bounded(&policy, last.workers);
+            loop {
+                if cooling_until.is_none_or(|until| Instant::now() >= until)
+                    && target != last.workers
+                {
+                    debug!(?last, from = last.workers, to = target, "autoscaling");
+                    resize(target);
+                    cooling_until = Instant::now().checked_add(policy.cooldown);
+                }
+                thread::park_timeout(policy.interval);
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let load = sample();
+                target = wanted(&policy, &last, &load);
+                last = load;
+            }
+        });
+        Autoscaler {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Autoscaler {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+fn bounded(policy: &AutoscalePolicy, workers: usize) -> usize {
+    workers.clamp(policy.min, policy.max)
+}
+
+// The size of pool `load` calls for, given the one before it.
+fn wanted(policy: &AutoscalePolicy, last: &Load, load: &Load) -> usize {
+    let workers = load.workers;
+    if bounded(policy, workers) != workers {
+        return bounded(policy, workers);
+    }
+    let finished = load.finished.saturating_sub(last.finished);
+    let slow = policy.grow_latency.is_some_and(|limit| {
+        let micros = load.latency_micros.saturating_sub(last.latency_micros);
+        finished > 0 && micros / finished >= limit.as_micros() as u64
+    });
+    let per_worker = |depth| depth as u64 * workers as u64;
+    if slow || load.in_flight >= per_worker(policy.grow_depth) {
+        bounded(policy, workers + 1)
+    } else if load.in_flight <= per_worker(policy.shrink_depth) {
+        bounded(policy, workers - 1)
+    } else {
+        workers
+    }
+}
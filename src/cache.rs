@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::server_data::Balances;
+use crate::{AccountId, Currency, Money};
+
+/// A copy of every account's balances for reads, which the handlers bring up to date each time
+/// they settle a transaction, still holding their shard. Reading it takes no directory stripe
+/// nor shard, so balance reads never hold up a handler, and a handler only ever waits on the
+/// readers of one stripe. Split into stripes by account like the directory.
+pub(crate) struct BalanceCache {
+    stripes: Vec<RwLock<HashMap<AccountId, Balances>>>,
+}
+
+impl BalanceCache {
+    pub(crate) fn new(stripes: usize) -> BalanceCache {
+        BalanceCache {
+            stripes: (0..stripes).map(|_| RwLock::new(HashMap::new())).collect(),
+        }
+    }
+    fn stripe(&self, account: AccountId) -> &RwLock<HashMap<AccountId, Balances>> {
+        &self.stripes[account as usize % self.stripes.len()]
+    }
+    /// Records what `account` holds now, `None` once it is closed.
+    pub(crate) fn publish(&self, account: AccountId, balances: Option<Balances>) {
+        let mut stripe = self.stripe(account).write().unwrap();
+        match balances {
+            Some(balances) => stripe.insert(account, balances),
+            None => stripe.remove(&account),
+        };
+    }
+    pub(crate) fn get_balance(&self, account: AccountId, currency: Currency) -> Money {
+        let stripe = self.stripe(account).read().unwrap();
+        stripe
+            .get(&account)
+            .and_then(|balances| balances.get(&currency))
+            .copied()
+            .unwrap_or(Money::ZERO)
+    }
+    pub(crate) fn get_balances(&self, account: AccountId) -> Vec<(Currency, Money)> {
+        let stripe = self.stripe(account).read().unwrap();
+        stripe.get(&account).map_or(Vec::new(), |balances| {
+            balances.iter().map(|(&c, &b)| (c, b)).collect()
+        })
+    }
+}
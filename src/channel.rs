@@ -0,0 +1,55 @@
+//! The channels receipts, credits between handlers and subscriptions are carried over. The engine
+//! only needs unbounded channels it can send down and receive from, blocking or not, so the
+//! backend is picked when building: crossbeam's with the `crossbeam` feature, whose receivers can
+//! be waited on together with `crossbeam_channel::select!`, std's otherwise.
+
+/// Makes channels for the engine. Another backend implements it and becomes `Channels`.
+pub trait ChannelFactory {
+    type Sender<T>;
+    type Receiver<T>;
+    fn unbounded<T>() -> (Self::Sender<T>, Self::Receiver<T>);
+}
+
+/// `std::sync::mpsc`.
+#[derive(Debug, Clone, Copy)]
+pub struct StdChannels;
+
+impl ChannelFactory for StdChannels {
+    type Sender<T> = std::sync::mpsc::Sender<T>;
+    type Receiver<T> = std::sync::mpsc::Receiver<T>;
+    fn unbounded<T>() -> (Self::Sender<T>, Self::Receiver<T>) {
+        std::sync::mpsc::channel()
+    }
+}
+
+/// `crossbeam_channel`. Only built with the `crossbeam` feature.
+#[cfg(feature = "crossbeam")]
+#[derive(Debug, Clone, Copy)]
+pub struct CrossbeamChannels;
+
+#[cfg(feature = "crossbeam")]
+impl ChannelFactory for CrossbeamChannels {
+    type Sender<T> = crossbeam_channel::Sender<T>;
+    type Receiver<T> = crossbeam_channel::Receiver<T>;
+    fn unbounded<T>() -> (Self::Sender<T>, Self::Receiver<T>) {
+        crossbeam_channel::unbounded()
+    }
+}
+
+/// The backend this build uses.
+#[cfg(feature = "crossbeam")]
+pub type Channels = CrossbeamChannels;
+#[cfg(not(feature = "crossbeam"))]
+pub type Channels = StdChannels;
+
+pub type Sender<T> = <Channels as ChannelFactory>::Sender<T>;
+pub type Receiver<T> = <Channels as ChannelFactory>::Receiver<T>;
+
+#[cfg(feature = "crossbeam")]
+pub use crossbeam_channel::{RecvTimeoutError, TryRecvError};
+#[cfg(not(feature = "crossbeam"))]
+pub use std::sync::mpsc::{RecvTimeoutError, TryRecvError};
+
+pub(crate) fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    Channels::unbounded()
+}
@@ -0,0 +1,101 @@
+//! Seeded fault injection, to see how the engine and whatever drives it hold up when handlers
+//! stall, lose work or die. Only built with the `chaos` feature.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::directory::Shard;
+use crate::{Clock, HandleId};
+
+/// How likely each fault is, drawn for every transaction a handler picks up. At most one fault
+/// strikes a transaction.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    /// Every handler draws from its own generator seeded from this, so with the deterministic
+    /// executor the same seed strikes the same transactions.
+    pub seed: u64,
+    /// Chance of the handler sleeping for `delay` before applying the transaction.
+    pub delay_probability: f64,
+    pub delay: Duration,
+    /// Chance of the transaction getting lost before it is applied. Its receipt resolves to
+    /// `TxError::HandlerUnavailable`.
+    pub drop_probability: f64,
+    /// Chance of the handler panicking before it applies the transaction.
+    pub panic_probability: f64,
+    /// Chance of the handler panicking while holding the lock on its accounts, leaving it
+    /// poisoned.
+    pub poison_probability: f64,
+}
+
+enum Fault {
+    Delay,
+    Drop,
+    Panic,
+    Poison,
+}
+
+pub(crate) struct Injector {
+    faults: Faults,
+    rngs: Vec<Mutex<u64>>, // handler id -> xorshift state
+    clock: Arc<dyn Clock>,
+}
+
+impl Injector {
+    pub(crate) fn new(faults: Faults, handlers: usize, clock: Arc<dyn Clock>) -> Injector {
+        let rngs = (0..handlers as u64)
+            // xorshift never leaves zero
+            .map(|id| {
+                Mutex::new((faults.seed ^ (id + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15)).max(1))
+            })
+            .collect();
+        Injector {
+            faults,
+            rngs,
+            clock,
+        }
+    }
+    /// Lets the fault drawn for the transaction `handler` is about to apply strike, `shard`
+    /// being the accounts it applies it to. `true` if the transaction is lost.
+    pub(crate) fn strike(&self, handler: HandleId, shard: &Shard) -> bool {
+        match self.draw(handler) {
+            None => false,
+            Some(Fault::Delay) => {
+                warn!(handler, delay = ?self.faults.delay, "injected delay");
+                self.clock.sleep(self.faults.delay);
+                false
+            }
+            Some(Fault::Drop) => {
+                warn!(handler, "injected message loss");
+                true
+            }
+            Some(Fault::Panic) => panic!("injected panic in handler {}", handler),
+            Some(Fault::Poison) => {
+                let _data = shard.lock();
+                panic!("injected panic in handler {} holding its accounts", handler)
+            }
+        }
+    }
+    fn draw(&self, handler: HandleId) -> Option<Fault> {
+        let mut rng = self.rngs[handler as usize].lock().unwrap();
+        *rng ^= *rng << 13;
+        *rng ^= *rng >> 7;
+        *rng ^= *rng << 17;
+        let mut chance = (*rng >> 11) as f64 / (1u64 << 53) as f64;
+
+        let faults = &self.faults;
+        for (probability, fault) in [
+            (faults.delay_probability, Fault::Delay),
+            (faults.drop_probability, Fault::Drop),
+            (faults.panic_probability, Fault::Panic),
+            (faults.poison_probability, Fault::Poison),
+        ] {
+            if chance < probability {
+                return Some(fault);
+            }
+            chance -= probability;
+        }
+        None
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where the engine gets the time from: the artificial handler delay sleeps on it, latencies
+/// and dedup windows are measured with it, and history entries, recorded events and dead
+/// letters are stamped with its `wall_time`.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+    /// The date and time it is; the operating system's unless overridden.
+    fn wall_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// The operating system's clock. The default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// A clock that only moves when told to. Sleeping advances it by the duration right away, so
+/// delays cost no real time and every run sees the same timings. Clones share one time.
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    origin: Instant,
+    start: SystemTime, // the wall time at `origin`
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl VirtualClock {
+    /// Starts at the epoch, so that the same steps give the same wall times in every run.
+    pub fn new() -> VirtualClock {
+        VirtualClock::starting_at(UNIX_EPOCH)
+    }
+    pub fn starting_at(start: SystemTime) -> VirtualClock {
+        VirtualClock {
+            origin: Instant::now(),
+            start,
+            elapsed: Arc::new(Mutex::new(Duration::ZERO)),
+        }
+    }
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+    /// Time advanced since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> VirtualClock {
+        VirtualClock::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.origin + self.elapsed()
+    }
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+    fn wall_time(&self) -> SystemTime {
+        self.start + self.elapsed()
+    }
+}
@@ -0,0 +1,1120 @@
+//! A cluster of engines agreeing on one order of commands by Raft, so it keeps going as long as
+//! most of its nodes are up. Every node holds an `Aptone` of its own and applies the commands
+//! committed to the log to it one at a time, in log order, so they all come out with the same
+//! balances; only the leader takes commands, answering once they are applied on it. Reads go to
+//! the local engine and may trail the leader on the other nodes.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use aptone::cluster::{ClusterConfig, ClusterNode};
+//! use aptone::{Config, Money};
+//!
+//! let peers = vec![(2, "10.0.0.2:7272".parse()?), (3, "10.0.0.3:7272".parse()?)];
+//! let cluster = ClusterConfig::new(1, "10.0.0.1:7272".parse()?, peers);
+//! let node = ClusterNode::start(cluster, Config::default())?;
+//! let account = node.open_account(Money::from(100))?;
+//! println!("{}", node.aptone().get_balance(account));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The engines have to come out the same from the same commands, so every node needs the same
+//! `Config`, without rate limits, velocity limits or anything else going by the clock. Nodes
+//! talk over TCP in frames of a big-endian `u32` length and a postcard message. The cluster is
+//! fixed at start: nodes can't join or leave it. Without `ClusterConfig::dir` a node keeps its
+//! log in memory only, so one that restarts has to come back under another id.
+//!
+//! Every `ClusterConfig::snapshot_every` entries it applies, a node takes the `ExportedState` of
+//! its engine as a snapshot and cuts the log back to what follows. A follower missing entries the
+//! leader cut is sent its snapshot instead, and starts its engine over from it. Only built with
+//! the `cluster` feature.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::hash::BuildHasher;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, warn};
+
+use crate::channel::{channel, RecvTimeoutError, Sender};
+use crate::{AccountId, Aptone, Config, Currency, ExportedState, Money, Tx, TxError, TxResult};
+
+pub type NodeId = u64;
+
+// where an entry stands in the log, counting from 1; 0 is before the first
+type Index = u64;
+type Term = u64;
+
+// entries sent to a follower in one message at most
+const MAX_ENTRIES: usize = 512;
+
+// the longest message a node takes, so a garbled length can't exhaust memory
+const MAX_FRAME: usize = 64 << 20;
+
+// how often a node checks whether its election timeout ran out
+const TICK: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    pub id: NodeId,
+    /// Where this node listens for the others.
+    pub addr: SocketAddr,
+    /// Every other node, with where it listens.
+    pub peers: Vec<(NodeId, SocketAddr)>,
+    /// Where the node keeps its term, vote and log, to come back with them on restarting.
+    pub dir: Option<PathBuf>,
+    /// A follower hearing nothing from a leader for this long, or up to twice it picked at
+    /// random, stands for election.
+    pub election_timeout: Duration,
+    pub heartbeat_interval: Duration,
+    /// How long a leader waits for a command to be applied before giving up on answering, see
+    /// `ClusterError::Timeout`.
+    pub commit_timeout: Duration,
+    /// Entries applied between snapshots, above zero.
+    pub snapshot_every: u64,
+}
+
+impl ClusterConfig {
+    pub fn new(id: NodeId, addr: SocketAddr, peers: Vec<(NodeId, SocketAddr)>) -> ClusterConfig {
+        ClusterConfig {
+            id,
+            addr,
+            peers,
+            dir: None,
+            election_timeout: Duration::from_millis(300),
+            heartbeat_interval: Duration::from_millis(50),
+            commit_timeout: Duration::from_secs(5),
+            snapshot_every: 10_000,
+        }
+    }
+}
+
+/// What the log holds, applied to every node's engine in order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    Open { balance: Money },
+    Close { account: AccountId },
+    Tx(Tx),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClusterError {
+    /// Only the leader takes commands; this is the one this node last heard from, if any.
+    NotLeader(Option<NodeId>),
+    /// The command was applied and rejected.
+    Tx(TxError),
+    /// The leader lost its leadership before the command was committed, and the log it went to
+    /// was overwritten.
+    Lost,
+    /// Not applied within `ClusterConfig::commit_timeout`; it still may be.
+    Timeout,
+    /// The node failed to persist its log and stopped taking part.
+    Halted,
+}
+
+impl fmt::Display for ClusterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClusterError::NotLeader(Some(leader)) => {
+                write!(f, "not the leader, node {} is", leader)
+            }
+            ClusterError::NotLeader(None) => write!(f, "not the leader, and no leader is known"),
+            ClusterError::Tx(err) => err.fmt(f),
+            ClusterError::Lost => write!(f, "leadership lost before the command was committed"),
+            ClusterError::Timeout => write!(f, "command not applied in time"),
+            ClusterError::Halted => write!(f, "node halted after failing to persist its log"),
+        }
+    }
+}
+
+impl Error for ClusterError {}
+
+impl From<TxError> for ClusterError {
+    fn from(err: TxError) -> ClusterError {
+        ClusterError::Tx(err)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    term: Term,
+    command: Option<Command>, // none for the entry a leader opens its term with
+}
+
+// The engine's state once the entries up to `index` were applied, standing in for them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Snapshot {
+    index: Index,
+    term: Term, // of the entry at `index`
+    state: ExportedState,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Message {
+    Vote {
+        term: Term,
+        candidate: NodeId,
+        last_index: Index,
+        last_term: Term,
+    },
+    Voted {
+        term: Term,
+        granted: bool,
+    },
+    Append {
+        term: Term,
+        leader: NodeId,
+        prev_index: Index,
+        prev_term: Term,
+        entries: Vec<Entry>,
+        commit: Index,
+    },
+    Appended {
+        term: Term,
+        success: bool,
+        matched: Index, // the follower's log holds the leader's up to here, or is at most this long
+    },
+    // for a follower missing entries the leader cut from its log, answered with `Appended`
+    Install {
+        term: Term,
+        leader: NodeId,
+        snapshot: Snapshot,
+    },
+}
+
+// What applying a command came to, for the submitter waiting on it.
+enum Outcome {
+    Opened(Result<AccountId, TxError>),
+    Closed(Result<Vec<(Currency, Money)>, TxError>),
+    Applied(TxResult),
+}
+
+enum Role {
+    Follower,
+    Candidate {
+        votes: HashSet<NodeId>,
+        asked: HashSet<NodeId>,
+    },
+    Leader {
+        next: HashMap<NodeId, Index>,    // peer -> the next entry to send it
+        matched: HashMap<NodeId, Index>, // peer -> the last entry known to be in its log
+    },
+}
+
+// The term, vote, snapshot and log on disk: the state and the snapshot in a file each, rewritten
+// whole, the log in another, appended to in frames after a first one with the index of the entry
+// it follows on from.
+struct Disk {
+    dir: PathBuf,
+    log: File,
+    ends: Vec<u64>, // where the first frame, then each entry's, ends in the log file
+}
+
+// What a node had on disk when it stopped.
+#[derive(Default)]
+struct Kept {
+    term: Term,
+    voted_for: Option<NodeId>,
+    snapshot: Option<Snapshot>,
+    log: Vec<Entry>,
+}
+
+impl Disk {
+    fn open(dir: PathBuf) -> io::Result<(Disk, Kept)> {
+        fs::create_dir_all(&dir)?;
+        let (term, voted_for) = match read_file(&dir.join("state"))? {
+            Some(bytes) => postcard::from_bytes(&bytes).map_err(invalid)?,
+            None => (0, None),
+        };
+        let snapshot: Option<Snapshot> = match read_file(&dir.join("snapshot"))? {
+            Some(bytes) => Some(postcard::from_bytes(&bytes).map_err(invalid)?),
+            None => None,
+        };
+        let path = dir.join("log");
+        let bytes = read_file(&path)?.unwrap_or_default();
+        let mut rest = &bytes[..];
+        let (mut after, mut log, mut ends) = (None, Vec::new(), Vec::new());
+        if let Ok(index) = read_frame::<Index>(&mut rest) {
+            after = Some(index);
+            ends.push((bytes.len() - rest.len()) as u64);
+            // a torn last frame is dropped: it was never acknowledged
+            while let Ok(entry) = read_frame(&mut rest) {
+                log.push(entry);
+                ends.push((bytes.len() - rest.len()) as u64);
+            }
+        }
+        let mut disk = Disk {
+            log: OpenOptions::new().create(true).append(true).open(&path)?,
+            dir,
+            ends,
+        };
+        let base = snapshot.as_ref().map_or(0, |snapshot| snapshot.index);
+        match after {
+            Some(after) if after > base => {
+                return Err(invalid(format!(
+                    "the log follows on from entry {after}, past the snapshot at {base}"
+                )));
+            }
+            Some(after) if after == base => disk.truncate(log.len())?,
+            // the snapshot was taken but the log not cut back yet
+            Some(after) => {
+                log.drain(..log.len().min((base - after) as usize));
+                disk.rewrite(base, &log)?;
+            }
+            None => disk.rewrite(base, &log)?,
+        }
+        let kept = Kept {
+            term,
+            voted_for,
+            snapshot,
+            log,
+        };
+        Ok((disk, kept))
+    }
+    fn save(&self, term: Term, voted_for: Option<NodeId>) -> io::Result<()> {
+        let bytes = postcard::to_allocvec(&(term, voted_for)).map_err(invalid)?;
+        self.replace("state", &bytes)
+    }
+    // Puts `bytes` in the file `name` in one go, the old contents staying until they're in.
+    fn replace(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let staged = self.dir.join(format!("{name}.new"));
+        let mut file = File::create(&staged)?;
+        file.write_all(bytes)?;
+        file.sync_data()?;
+        fs::rename(staged, self.dir.join(name))
+    }
+    fn append(&mut self, entries: &[Entry]) -> io::Result<()> {
+        let at = self.ends.last().copied().unwrap_or(0);
+        let mut bytes = Vec::new();
+        let mut ends = Vec::with_capacity(entries.len());
+        for entry in entries {
+            write_frame(&mut bytes, entry)?;
+            ends.push(at + bytes.len() as u64);
+        }
+        self.log.write_all(&bytes)?;
+        self.log.sync_data()?;
+        self.ends.extend(ends);
+        Ok(())
+    }
+    // Cuts the log file back to its first `len` entries.
+    fn truncate(&mut self, len: usize) -> io::Result<()> {
+        self.log.set_len(self.ends[len])?;
+        self.log.sync_data()?;
+        self.ends.truncate(len + 1);
+        Ok(())
+    }
+    // Saves `snapshot`, then starts the log over with `log`, what follows it.
+    fn compact(&mut self, snapshot: &Snapshot, log: &[Entry]) -> io::Result<()> {
+        let bytes = postcard::to_allocvec(snapshot).map_err(invalid)?;
+        self.replace("snapshot", &bytes)?;
+        self.rewrite(snapshot.index, log)
+    }
+    fn rewrite(&mut self, after: Index, log: &[Entry]) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &after)?;
+        let mut ends = vec![bytes.len() as u64];
+        for entry in log {
+            write_frame(&mut bytes, entry)?;
+            ends.push(bytes.len() as u64);
+        }
+        self.replace("log", &bytes)?;
+        self.log = OpenOptions::new().append(true).open(self.dir.join("log"))?;
+        self.ends = ends;
+        Ok(())
+    }
+}
+
+// A node's share of the protocol, behind one lock.
+struct Raft {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    term: Term,
+    voted_for: Option<NodeId>,
+    snapshot: Option<Snapshot>, // standing in for the entries up to it, cut from the log
+    log: Vec<Entry>,            // what follows the snapshot
+    commit: Index,
+    applied: Index,
+    role: Role,
+    leader: Option<NodeId>,
+    heard: Instant,    // when the election timeout last started over
+    timeout: Duration, // this round's, picked at random
+    election_timeout: Duration,
+    elections: u64,                           // stood for, to vary the timeouts picked
+    waiting: HashMap<Index, Sender<Outcome>>, // entry -> who submitted it here
+    disk: Option<Disk>,
+    halted: bool,  // failed to persist, so it can no longer vouch for its log
+    restore: bool, // the engine has to start over from a snapshot sent by the leader
+}
+
+impl Raft {
+    // The last entry the snapshot stands in for, 0 without one.
+    fn base(&self) -> (Index, Term) {
+        let snapshot = self.snapshot.as_ref();
+        snapshot.map_or((0, 0), |snapshot| (snapshot.index, snapshot.term))
+    }
+    fn last(&self) -> (Index, Term) {
+        let (base, term) = self.base();
+        let index = base + self.log.len() as Index;
+        (index, self.log.last().map_or(term, |entry| entry.term))
+    }
+    // `None` past the end of the log, and before the snapshot.
+    fn term_at(&self, index: Index) -> Option<Term> {
+        let (base, term) = self.base();
+        match index.checked_sub(base)? {
+            0 => Some(term),
+            at => self.log.get(at as usize - 1).map(|entry| entry.term),
+        }
+    }
+    fn entry(&self, index: Index) -> &Entry {
+        &self.log[(index - self.base().0) as usize - 1]
+    }
+    fn quorum(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+    fn persisted(&mut self, result: io::Result<()>) {
+        if let Err(err) = result {
+            error!(%err, node = self.id, "failed to persist the raft log, halting");
+            self.halted = true;
+            self.role = Role::Follower;
+        }
+    }
+    fn save(&mut self) {
+        if let Some(disk) = &self.disk {
+            let saved = disk.save(self.term, self.voted_for);
+            self.persisted(saved);
+        }
+    }
+    fn restart_timeout(&mut self) {
+        self.heard = Instant::now();
+        // RandomState is the std source of per-process randomness
+        let spread = RandomState::new().hash_one((self.id, self.elections)) % 1000;
+        self.timeout = self.election_timeout + self.election_timeout * spread as u32 / 1000;
+    }
+    // Follows `term` from here on, if it's ahead. Whoever led before doesn't lead in it.
+    fn observe(&mut self, term: Term) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.role = Role::Follower;
+            self.leader = None;
+            self.save();
+        }
+    }
+    fn campaign(&mut self) {
+        self.term += 1;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+        self.elections += 1;
+        self.save();
+        self.restart_timeout();
+        self.role = Role::Candidate {
+            votes: HashSet::from([self.id]),
+            asked: HashSet::new(),
+        };
+        debug!(node = self.id, term = self.term, "standing for election");
+        self.count_votes();
+    }
+    fn count_votes(&mut self) {
+        let Role::Candidate { votes, .. } = &self.role else {
+            return;
+        };
+        if votes.len() < self.quorum() {
+            return;
+        }
+        let next = self.last().0 + 1;
+        let peers = self.peers.iter();
+        self.role = Role::Leader {
+            next: peers.clone().map(|&peer| (peer, next)).collect(),
+            matched: peers.map(|&peer| (peer, 0)).collect(),
+        };
+        self.leader = Some(self.id);
+        debug!(node = self.id, term = self.term, "elected leader");
+        // entries of earlier terms only commit along with one of this term
+        self.append(None);
+    }
+    fn append(&mut self, command: Option<Command>) -> Index {
+        let entry = Entry {
+            term: self.term,
+            command,
+        };
+        if let Some(disk) = &mut self.disk {
+            let appended = disk.append(std::slice::from_ref(&entry));
+            self.persisted(appended);
+        }
+        self.log.push(entry);
+        self.advance_commit();
+        self.last().0
+    }
+    fn advance_commit(&mut self) {
+        let Role::Leader { matched, .. } = &self.role else {
+            return;
+        };
+        let mut indexes: Vec<Index> = matched.values().copied().collect();
+        indexes.push(self.last().0);
+        indexes.sort_unstable_by(|a, b| b.cmp(a));
+        let agreed = indexes[self.quorum() - 1];
+        if agreed > self.commit && self.term_at(agreed) == Some(self.term) {
+            self.commit = agreed;
+        }
+    }
+    fn handle(&mut self, message: Message) -> Message {
+        match message {
+            Message::Vote {
+                term,
+                candidate,
+                last_index,
+                last_term,
+            } => {
+                self.observe(term);
+                let (index, log_term) = self.last();
+                let up_to_date = (last_term, last_index) >= (log_term, index);
+                let granted = term == self.term
+                    && self.voted_for.is_none_or(|voted| voted == candidate)
+                    && up_to_date;
+                if granted {
+                    self.voted_for = Some(candidate);
+                    self.save();
+                    self.restart_timeout();
+                }
+                Message::Voted {
+                    term: self.term,
+                    granted,
+                }
+            }
+            Message::Append {
+                term,
+                leader,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => {
+                let refused = |raft: &Raft, matched| Message::Appended {
+                    term: raft.term,
+                    success: false,
+                    matched,
+                };
+                if term < self.term {
+                    return refused(self, 0);
+                }
+                self.observe(term);
+                self.role = Role::Follower;
+                self.leader = Some(leader);
+                self.restart_timeout();
+                // what the snapshot stands in for was committed, so the leader's log agrees
+                let (base, base_term) = self.base();
+                let (prev_index, prev_term, entries) = match prev_index < base {
+                    true => {
+                        let skipped = (base - prev_index) as usize;
+                        (base, base_term, entries.into_iter().skip(skipped).collect())
+                    }
+                    false => (prev_index, prev_term, entries),
+                };
+                match self.term_at(prev_index) {
+                    None => return refused(self, self.last().0),
+                    Some(at) if at != prev_term => return refused(self, prev_index - 1),
+                    Some(_) => {}
+                }
+                let mut index = prev_index;
+                let mut appended = Vec::new();
+                for entry in entries {
+                    index += 1;
+                    match self.term_at(index) {
+                        Some(at) if at == entry.term => continue,
+                        Some(_) => self.truncate(index),
+                        None => {}
+                    }
+                    appended.push(entry.clone());
+                    self.log.push(entry);
+                }
+                if let Some(disk) = &mut self.disk {
+                    let written = disk.append(&appended);
+                    self.persisted(written);
+                }
+                self.commit = self.commit.max(commit.min(index));
+                Message::Appended {
+                    term: self.term,
+                    success: true,
+                    matched: index,
+                }
+            }
+            Message::Install {
+                term,
+                leader,
+                snapshot,
+            } => {
+                if term >= self.term {
+                    self.observe(term);
+                    self.role = Role::Follower;
+                    self.leader = Some(leader);
+                    self.restart_timeout();
+                }
+                let index = snapshot.index;
+                if term == self.term && index > self.commit {
+                    self.install(snapshot);
+                }
+                Message::Appended {
+                    term: self.term,
+                    success: term == self.term,
+                    matched: index,
+                }
+            }
+            reply => {
+                warn!(?reply, "a reply sent as a request");
+                refused_vote(self.term)
+            }
+        }
+    }
+    // Starts over from `snapshot` of the leader's, keeping what follows it in the log if the
+    // log has the entry it was taken at.
+    fn install(&mut self, snapshot: Snapshot) {
+        let index = snapshot.index;
+        let follows = self.term_at(index) == Some(snapshot.term);
+        self.log = match follows {
+            true => self.log.split_off((index - self.base().0) as usize),
+            false => Vec::new(),
+        };
+        // their submitters hear of it as their senders drop
+        self.waiting.retain(|&waiting, _| waiting > index);
+        self.commit = index;
+        self.restore = true;
+        if let Some(disk) = &mut self.disk {
+            let compacted = disk.compact(&snapshot, &self.log);
+            self.persisted(compacted);
+        }
+        self.snapshot = Some(snapshot);
+    }
+    // Takes `state`, the engine's once the entries up to `index` were applied, as the snapshot,
+    // cutting those from the log.
+    fn compact(&mut self, index: Index, state: ExportedState) {
+        let (base, _) = self.base();
+        if self.restore || index <= base {
+            return;
+        }
+        let Some(term) = self.term_at(index) else {
+            return;
+        };
+        self.log.drain(..(index - base) as usize);
+        let snapshot = Snapshot { index, term, state };
+        if let Some(disk) = &mut self.disk {
+            let compacted = disk.compact(&snapshot, &self.log);
+            self.persisted(compacted);
+        }
+        self.snapshot = Some(snapshot);
+        debug!(node = self.id, index, "took a snapshot");
+    }
+    // Drops the entries from `index` on, which a leader overwrote.
+    fn truncate(&mut self, index: Index) {
+        self.log.truncate((index - self.base().0) as usize - 1);
+        // their submitters hear of it as their senders drop
+        self.waiting.retain(|&waiting, _| waiting < index);
+        if let Some(disk) = &mut self.disk {
+            let truncated = disk.truncate(self.log.len());
+            self.persisted(truncated);
+        }
+    }
+    // What to send `peer` now, if anything.
+    fn request_for(&mut self, peer: NodeId) -> Option<Message> {
+        if self.halted {
+            return None;
+        }
+        let (last_index, last_term) = self.last();
+        let (base, _) = self.base();
+        match &mut self.role {
+            Role::Follower => None,
+            Role::Candidate { asked, .. } => asked.insert(peer).then_some(Message::Vote {
+                term: self.term,
+                candidate: self.id,
+                last_index,
+                last_term,
+            }),
+            Role::Leader { next, .. } => {
+                let next = next[&peer].max(1);
+                let prev_index = next - 1;
+                if prev_index < base {
+                    // what it needs next was cut from the log
+                    return Some(Message::Install {
+                        term: self.term,
+                        leader: self.id,
+                        snapshot: self.snapshot.clone()?,
+                    });
+                }
+                let from = (prev_index - base) as usize;
+                let to = self.log.len().min(from + MAX_ENTRIES);
+                Some(Message::Append {
+                    term: self.term,
+                    leader: self.id,
+                    prev_index,
+                    prev_term: self.term_at(prev_index).unwrap_or(0),
+                    entries: self.log[from..to].to_vec(),
+                    commit: self.commit,
+                })
+            }
+        }
+    }
+    fn handle_reply(&mut self, peer: NodeId, request_term: Term, reply: Message) {
+        match reply {
+            Message::Voted { term, granted } => {
+                self.observe(term);
+                if let Role::Candidate { votes, .. } = &mut self.role {
+                    if granted && term == self.term && request_term == self.term {
+                        votes.insert(peer);
+                        self.count_votes();
+                    }
+                }
+            }
+            Message::Appended {
+                term,
+                success,
+                matched: at,
+            } => {
+                self.observe(term);
+                if request_term != self.term {
+                    return;
+                }
+                if let Role::Leader { next, matched } = &mut self.role {
+                    if success {
+                        matched.insert(peer, at);
+                        next.insert(peer, at + 1);
+                        self.advance_commit();
+                    } else {
+                        let behind = next[&peer].saturating_sub(1).min(at + 1).max(1);
+                        next.insert(peer, behind);
+                    }
+                }
+            }
+            request => warn!(?request, "a request sent as a reply"),
+        }
+    }
+    // `peer` couldn't be reached with a request of `request_term`: a vote asked for goes again
+    // while the election lasts.
+    fn unreachable(&mut self, peer: NodeId, request_term: Term) {
+        if let Role::Candidate { asked, .. } = &mut self.role {
+            if request_term == self.term {
+                asked.remove(&peer);
+            }
+        }
+    }
+    // Whether `peer` has entries waiting to go, so its sender shouldn't wait for the heartbeat.
+    fn behind(&self, peer: NodeId) -> bool {
+        match &self.role {
+            Role::Leader { next, .. } => next[&peer] <= self.last().0,
+            Role::Candidate { asked, .. } => !asked.contains(&peer),
+            Role::Follower => false,
+        }
+    }
+}
+
+fn refused_vote(term: Term) -> Message {
+    Message::Voted {
+        term,
+        granted: false,
+    }
+}
+
+// What a node shares with its threads.
+struct Shared {
+    raft: Mutex<Raft>,
+    changed: Condvar, // the log, the commit index or the role moved
+    stopped: AtomicBool,
+    aptone: RwLock<Aptone>, // replaced when starting over from a snapshot
+    engine: Config,         // what the engine is started on
+    config: ClusterConfig,
+}
+
+impl Shared {
+    fn raft(&self) -> MutexGuard<'_, Raft> {
+        self.raft.lock().unwrap()
+    }
+    fn aptone(&self) -> RwLockReadGuard<'_, Aptone> {
+        self.aptone.read().unwrap()
+    }
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+/// One node of a cluster, see the module docs. Stops taking part when dropped.
+pub struct ClusterNode {
+    shared: Arc<Shared>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl ClusterNode {
+    /// Starts the node on an engine with `config`, listening on `ClusterConfig::addr`. With
+    /// `ClusterConfig::dir`, the engine starts from the snapshot kept there, if any.
+    pub fn start(cluster: ClusterConfig, config: Config) -> io::Result<ClusterNode> {
+        if cluster.snapshot_every == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "snapshots have to be taken every entry or more",
+            ));
+        }
+        let (disk, kept) = match cluster.dir.clone() {
+            Some(dir) => {
+                let (disk, kept) = Disk::open(dir)?;
+                (Some(disk), kept)
+            }
+            None => (None, Kept::default()),
+        };
+        let Kept {
+            term,
+            voted_for,
+            snapshot,
+            log,
+        } = kept;
+        let aptone = match &snapshot {
+            Some(snapshot) => Aptone::from_state(config.clone(), snapshot.state.clone())?,
+            None => Aptone::try_with_config(config.clone())?,
+        };
+        let listener = TcpListener::bind(cluster.addr)?;
+        let base = snapshot.as_ref().map_or(0, |snapshot| snapshot.index);
+        let mut raft = Raft {
+            id: cluster.id,
+            peers: cluster.peers.iter().map(|&(peer, _)| peer).collect(),
+            term,
+            voted_for,
+            snapshot,
+            log,
+            commit: base,
+            applied: base,
+            role: Role::Follower,
+            leader: None,
+            heard: Instant::now(),
+            timeout: cluster.election_timeout,
+            election_timeout: cluster.election_timeout,
+            elections: 0,
+            waiting: HashMap::new(),
+            disk,
+            halted: false,
+            restore: false,
+        };
+        raft.restart_timeout();
+        let shared = Arc::new(Shared {
+            raft: Mutex::new(raft),
+            changed: Condvar::new(),
+            stopped: AtomicBool::new(false),
+            aptone: RwLock::new(aptone),
+            engine: config,
+            config: cluster,
+        });
+        let mut threads = Vec::new();
+        let node = Arc::clone(&shared);
+        threads.push(thread::spawn(move || listen(&node, listener)));
+        let node = Arc::clone(&shared);
+        threads.push(thread::spawn(move || tick(&node)));
+        let node = Arc::clone(&shared);
+        threads.push(thread::spawn(move || apply(&node)));
+        for &(peer, addr) in &shared.config.peers {
+            let node = Arc::clone(&shared);
+            threads.push(thread::spawn(move || send_to(&node, peer, addr)));
+        }
+        Ok(ClusterNode { shared, threads })
+    }
+    pub fn id(&self) -> NodeId {
+        self.shared.config.id
+    }
+    /// The leader this node last heard from, itself included.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.shared.raft().leader
+    }
+    pub fn is_leader(&self) -> bool {
+        matches!(self.shared.raft().role, Role::Leader { .. })
+    }
+    /// Entries of the log applied to the engine here.
+    pub fn applied(&self) -> u64 {
+        self.shared.raft().applied
+    }
+    /// The engine the log is applied to, for reading. Commands submitted to it directly are
+    /// this node's alone. Starting over from a snapshot the leader sent waits for the guard to
+    /// go, as do the commands after it.
+    pub fn aptone(&self) -> RwLockReadGuard<'_, Aptone> {
+        self.shared.aptone()
+    }
+    pub fn open_account(&self, balance: Money) -> Result<AccountId, ClusterError> {
+        match self.submit_command(Command::Open { balance })? {
+            Outcome::Opened(opened) => Ok(opened?),
+            _ => unreachable!("an account opened"),
+        }
+    }
+    pub fn close_account(
+        &self,
+        account: AccountId,
+    ) -> Result<Vec<(Currency, Money)>, ClusterError> {
+        match self.submit_command(Command::Close { account })? {
+            Outcome::Closed(closed) => Ok(closed?),
+            _ => unreachable!("an account closed"),
+        }
+    }
+    /// Commits `tx` to the log and answers once it is applied here.
+    pub fn submit(&self, tx: Tx) -> Result<(), ClusterError> {
+        match self.submit_command(Command::Tx(tx))? {
+            Outcome::Applied(applied) => Ok(applied?),
+            _ => unreachable!("a transaction applied"),
+        }
+    }
+    fn submit_command(&self, command: Command) -> Result<Outcome, ClusterError> {
+        let receiver = {
+            let mut raft = self.shared.raft();
+            if raft.halted {
+                return Err(ClusterError::Halted);
+            }
+            if !matches!(raft.role, Role::Leader { .. }) {
+                return Err(ClusterError::NotLeader(raft.leader));
+            }
+            let index = raft.append(Some(command));
+            let (sender, receiver) = channel();
+            raft.waiting.insert(index, sender);
+            receiver
+        };
+        self.shared.changed.notify_all();
+        match receiver.recv_timeout(self.shared.config.commit_timeout) {
+            Ok(outcome) => Ok(outcome),
+            Err(RecvTimeoutError::Timeout) => Err(ClusterError::Timeout),
+            Err(_) => Err(ClusterError::Lost),
+        }
+    }
+}
+
+impl Drop for ClusterNode {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        self.shared.changed.notify_all();
+        // wakes the listener up so it sees the flag
+        let mut wake = self.shared.config.addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        if let Err(err) = TcpStream::connect(wake) {
+            warn!(%err, "failed to stop the cluster listener");
+        }
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn listen(node: &Arc<Shared>, listener: TcpListener) {
+    for stream in listener.incoming() {
+        if node.is_stopped() {
+            break;
+        }
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let node = Arc::clone(node);
+        // not joined: they stop at their next message or read timeout
+        thread::spawn(move || {
+            if let Err(err) = answer(&node, stream) {
+                debug!(%err, "cluster peer went away");
+            }
+        });
+    }
+}
+
+fn answer(node: &Shared, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(node.config.election_timeout * 2))?;
+    stream.set_nodelay(true)?;
+    loop {
+        let message: Message = read_frame(&mut stream)?;
+        if node.is_stopped() {
+            return Ok(());
+        }
+        let reply = {
+            let mut raft = node.raft();
+            if raft.halted {
+                return Ok(());
+            }
+            raft.handle(message)
+        };
+        node.changed.notify_all();
+        write_frame(&mut stream, &reply)?;
+    }
+}
+
+fn tick(node: &Shared) {
+    while !node.is_stopped() {
+        {
+            let mut raft = node.raft();
+            let leading = matches!(raft.role, Role::Leader { .. });
+            if !leading && !raft.halted && raft.heard.elapsed() >= raft.timeout {
+                raft.campaign();
+                node.changed.notify_all();
+            }
+        }
+        thread::sleep(TICK);
+    }
+}
+
+// Applies the committed entries to the engine, in order, answering whoever submitted them here,
+// and takes a snapshot every `ClusterConfig::snapshot_every` of them.
+fn apply(node: &Shared) {
+    let mut raft = node.raft();
+    while !node.is_stopped() {
+        if raft.restore {
+            raft = restore(node, raft);
+            continue;
+        }
+        if raft.applied >= raft.commit {
+            raft = node.changed.wait_timeout(raft, TICK).unwrap().0;
+            continue;
+        }
+        let index = raft.applied + 1;
+        let command = raft.entry(index).command.clone();
+        drop(raft);
+        let outcome = command.map(|command| execute(&node.aptone(), command));
+        raft = node.raft();
+        // the engine starts over from a snapshot past it
+        if raft.restore {
+            continue;
+        }
+        raft.applied = index;
+        if let (Some(sender), Some(outcome)) = (raft.waiting.remove(&index), outcome) {
+            let _ = sender.send(outcome);
+        }
+        if index - raft.base().0 >= node.config.snapshot_every {
+            drop(raft);
+            let state = node.aptone().state();
+            raft = node.raft();
+            raft.compact(index, state);
+        }
+    }
+}
+
+// Starts the engine over from the snapshot the leader sent.
+fn restore<'a>(node: &'a Shared, mut raft: MutexGuard<'a, Raft>) -> MutexGuard<'a, Raft> {
+    let Some(snapshot) = raft.snapshot.clone() else {
+        raft.restore = false;
+        return raft;
+    };
+    drop(raft);
+    let restored = Aptone::from_state(node.engine.clone(), snapshot.state);
+    let replaced = restored.map(|aptone| mem::replace(&mut *node.aptone.write().unwrap(), aptone));
+    let mut raft = node.raft();
+    match replaced {
+        Ok(_) => {
+            debug!(
+                node = raft.id,
+                index = snapshot.index,
+                "started over from a snapshot"
+            );
+            raft.applied = snapshot.index;
+            // unless another came in meanwhile
+            raft.restore = raft.base().0 != snapshot.index;
+        }
+        Err(err) => {
+            error!(%err, node = raft.id, "failed to start over from a snapshot, halting");
+            raft.halted = true;
+            raft.restore = false;
+            raft.role = Role::Follower;
+        }
+    }
+    raft
+}
+
+fn execute(aptone: &Aptone, command: Command) -> Outcome {
+    match command {
+        Command::Open { balance } => Outcome::Opened(aptone.open_account(balance)),
+        Command::Close { account } => Outcome::Closed(aptone.close_account(account)),
+        Command::Tx(tx) => {
+            Outcome::Applied(aptone.submit_tx(tx).and_then(|receipt| receipt.wait()))
+        }
+    }
+}
+
+// Carries what this node has for `peer`, a message at a time, reconnecting as needed.
+fn send_to(node: &Shared, peer: NodeId, addr: SocketAddr) {
+    let mut stream: Option<TcpStream> = None;
+    let heartbeat = node.config.heartbeat_interval;
+    let mut raft = node.raft();
+    while !node.is_stopped() {
+        let Some(request) = raft.request_for(peer) else {
+            raft = node.changed.wait_timeout(raft, heartbeat).unwrap().0;
+            continue;
+        };
+        let term = raft.term;
+        drop(raft);
+        let reply = exchange(&mut stream, addr, node.config.election_timeout, &request);
+        raft = node.raft();
+        match reply {
+            Ok(reply) => {
+                raft.handle_reply(peer, term, reply);
+                node.changed.notify_all();
+            }
+            Err(err) => {
+                debug!(%err, peer, "failed to reach cluster peer");
+                raft.unreachable(peer, term);
+                stream = None;
+            }
+        }
+        if !raft.behind(peer) || stream.is_none() {
+            raft = node.changed.wait_timeout(raft, heartbeat).unwrap().0;
+        }
+    }
+}
+
+fn exchange(
+    stream: &mut Option<TcpStream>,
+    addr: SocketAddr,
+    timeout: Duration,
+    request: &Message,
+) -> io::Result<Message> {
+    if stream.is_none() {
+        let connected = TcpStream::connect_timeout(&addr, timeout)?;
+        connected.set_read_timeout(Some(timeout))?;
+        connected.set_write_timeout(Some(timeout))?;
+        connected.set_nodelay(true)?;
+        *stream = Some(connected);
+    }
+    let connected = stream.as_mut().unwrap();
+    write_frame(connected, request)?;
+    read_frame(connected)
+}
+
+fn write_frame<T: Serialize>(writer: &mut impl Write, message: &T) -> io::Result<()> {
+    let body = postcard::to_allocvec(message).map_err(invalid)?;
+    // in one write, so the length doesn't go out alone
+    let mut bytes = (body.len() as u32).to_be_bytes().to_vec();
+    bytes.extend_from_slice(&body);
+    writer.write_all(&bytes)
+}
+
+fn read_frame<T: for<'de> Deserialize<'de>>(reader: &mut impl Read) -> io::Result<T> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(invalid(format!(
+            "frame of {} bytes is over {}",
+            len, MAX_FRAME
+        )));
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    postcard::from_bytes(&bytes).map_err(invalid)
+}
+
+// What `path` holds, `None` if there is no such file.
+fn read_file(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn invalid<E: Into<Box<dyn Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
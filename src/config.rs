@@ -0,0 +1,735 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::webhook::WebhookUrl;
+#[cfg(feature = "chaos")]
+use crate::Faults;
+use crate::{
+    AccountId, Accrual, AlertSink, AlertThresholds, Aptone, Clock, ExchangeRates, FeeSchedule,
+    InterestPolicy, Latency, LeastQueueDepth, Money, Router, Rules, ServerData, Storage,
+    SystemClock, TxCount, TxMiddleware, VirtualClock,
+};
+
+pub const DEFAULT_THREAD_COUNT: usize = 4;
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 1024;
+pub const DEFAULT_LOCK_STRIPES: usize = 16;
+pub const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_secs(60);
+pub const DEFAULT_DEAD_LETTER_CAPACITY: usize = 1024;
+
+/// What `handle_tx` does when the target handler queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Wait until the handler has room for the transaction.
+    Block,
+    /// Fail the submission with `TxError::QueueFull`.
+    Reject,
+    /// Discard the transaction; its receipt resolves to `TxError::QueueFull` and it is counted
+    /// in `Aptone::dropped_tx`.
+    Drop,
+}
+
+/// What a handler does when a credit would take a balance past `Money::MAX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Fail the transaction with `TxError::Overflow`, leaving the balances as they were.
+    #[default]
+    Reject,
+    /// Cap the balance at `Money::MAX`; whatever doesn't fit is lost.
+    Saturate,
+}
+
+/// What a submission over its account's rate limit gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitPolicy {
+    /// Fail the submission with `TxError::RateLimited`.
+    #[default]
+    Reject,
+    /// Wait for the account's next token.
+    Delay,
+}
+
+/// A token bucket per account: each submission takes a token, the bucket holds up to `burst` of
+/// them and gets `per_second` back every second. A batch is charged to its first leg's account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+/// How submissions failing for a passing reason, see `TxError::is_transient`, are tried again:
+/// up to `max_attempts` times in all, waiting `backoff` before the first retry and `multiplier`
+/// times as long before each one after, never more than `max_backoff`. The submitter waits
+/// through the retries; a transaction still failing after the last is dead-lettered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub backoff: Duration,
+    pub multiplier: u32,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// No retries. The default.
+    pub const NONE: RetryPolicy = RetryPolicy {
+        max_attempts: 1,
+        backoff: Duration::ZERO,
+        multiplier: 1,
+        max_backoff: Duration::ZERO,
+    };
+
+    /// Up to `max_attempts` tries, the wait doubling from `backoff` up to a minute.
+    pub fn exponential(max_attempts: u32, backoff: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            backoff,
+            multiplier: 2,
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+    /// How long to wait after the `attempt`th try, counting from one.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(attempt.saturating_sub(1));
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::NONE
+    }
+}
+
+/// How `Aptone` grows and shrinks its own pool, between `min` and `max` handlers. Every `interval`
+/// it adds a handler if those in the pool have `grow_depth` transactions in flight on average, or
+/// the transactions finished since the last look took `grow_latency` on average, and takes one
+/// out if they have at most `shrink_depth` in flight on average without being that slow. After
+/// resizing it waits out `cooldown` before looking again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AutoscalePolicy {
+    pub min: usize,
+    pub max: usize,
+    pub grow_depth: TxCount,
+    pub shrink_depth: TxCount,
+    pub grow_latency: Option<Duration>,
+    pub interval: Duration,
+    pub cooldown: Duration,
+}
+
+impl AutoscalePolicy {
+    /// Between `min` and `max` handlers, on queue depth alone: growing at 64 transactions a
+    /// handler, shrinking at 4, looking every 100ms and holding off for a second after resizing.
+    pub fn new(min: usize, max: usize) -> AutoscalePolicy {
+        AutoscalePolicy {
+            min,
+            max,
+            grow_depth: 64,
+            shrink_depth: 4,
+            grow_latency: None,
+            interval: Duration::from_millis(100),
+            cooldown: Duration::from_secs(1),
+        }
+    }
+}
+
+/// What the auditor does on finding an invariant broken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AuditAction {
+    /// Log each violation and carry on.
+    #[default]
+    Log,
+    /// Log each violation, then abort the process rather than keep going on a broken ledger.
+    Abort,
+}
+
+/// How often the auditor checks the invariants, see `Aptone::audit`, and what it does when one
+/// is broken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditPolicy {
+    pub interval: Duration,
+    pub on_violation: AuditAction,
+}
+
+impl AuditPolicy {
+    /// Every `interval`, logging violations.
+    pub fn new(interval: Duration) -> AuditPolicy {
+        AuditPolicy {
+            interval,
+            on_violation: AuditAction::Log,
+        }
+    }
+}
+
+/// When `Aptone` settles, see `Aptone::settle`: at `at` past midnight UTC and every `every`
+/// after, the first going by the wall clock when the engine starts and the rest by its clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SettlementPolicy {
+    pub at: Duration,
+    pub every: Duration,
+}
+
+impl SettlementPolicy {
+    /// Once a day, at `at` past midnight UTC.
+    pub fn daily(at: Duration) -> SettlementPolicy {
+        SettlementPolicy {
+            at,
+            every: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// How far below zero withdrawals may take a balance. Withdrawals past it fail with
+/// `TxError::InsufficientFunds`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Overdraft {
+    /// Applies to every account without a limit of its own; zero allows no overdraft.
+    pub limit: Money,
+    pub accounts: HashMap<AccountId, Money>,
+}
+
+impl Overdraft {
+    pub fn limit(&self, account: AccountId) -> Money {
+        self.accounts.get(&account).copied().unwrap_or(self.limit)
+    }
+}
+
+/// How much an account may take out within a rolling window: withdrawals and transfers out past
+/// `amount` in any one currency fail with `TxError::LimitExceeded`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VelocityLimit {
+    pub amount: Money,
+    pub window: Duration,
+}
+
+/// The velocity limits every account is held to, all at once, unless it has limits of its own.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VelocityLimits {
+    pub limits: Vec<VelocityLimit>,
+    pub accounts: HashMap<AccountId, Vec<VelocityLimit>>,
+}
+
+impl VelocityLimits {
+    pub fn limits(&self, account: AccountId) -> &[VelocityLimit] {
+        self.accounts.get(&account).unwrap_or(&self.limits)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty() && self.accounts.values().all(Vec::is_empty)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Number of `TxHandler` threads.
+    pub threads: usize,
+    /// Most handlers `Aptone::resize_workers` can grow the pool to, `threads` if unset. Each
+    /// gets its queue and shard up front.
+    pub max_threads: Option<usize>,
+    /// Resizes the pool on its own, starting from `threads`. `max_threads` is raised to the
+    /// policy's `max` if below it. Only the thread-based engine scales.
+    pub autoscale: Option<AutoscalePolicy>,
+    /// Time each handler spends on a transaction after applying it, slept on `clock`.
+    pub latency: Option<Latency>,
+    /// Capacity of each handler queue.
+    pub channel_capacity: usize,
+    pub backpressure: BackpressurePolicy,
+    pub overflow: OverflowPolicy,
+    pub overdraft: Overdraft,
+    /// Measured on `clock`, over the withdrawals this engine applied: neither the windows nor the
+    /// limits carry over a restart, and replaying a log, which doesn't say when its transactions
+    /// were made, ignores them.
+    pub velocity_limits: VelocityLimits,
+    /// Converts between currencies for `TxType::EXCHANGE`; exchanges are rejected without it.
+    /// Recovering a log takes the same rates, as its exchanges are replayed at them.
+    pub exchange_rates: Option<Arc<dyn ExchangeRates>>,
+    /// Snapshot the balances and truncate the transaction log every N logged transactions.
+    pub checkpoint_interval: Option<u64>,
+    /// The ids `Aptone::open_account` gives out, for a process owning one range of the accounts,
+    /// see `partition`.
+    pub account_ids: Range<AccountId>,
+    /// Number of locks the account directory is split into.
+    pub lock_stripes: usize,
+    /// Picks a handler for accounts with no transactions in flight.
+    pub router: Arc<dyn Router>,
+    /// Has every handler take transactions off one queue instead of a queue of its own, so none
+    /// sits idle while another backs up. The queue is the first handler's, which every account
+    /// then lives with, and holds `channel_capacity` transactions in all; transactions on one
+    /// account are still handed out one at a time and in order. Stealing, draining and
+    /// rebalancing do nothing then. Only the thread-based engine shares a queue.
+    pub shared_queue: bool,
+    /// Let idle handlers take transactions from peers with at least this many queued messages.
+    /// Only the thread-based engine steals work.
+    pub steal_threshold: Option<usize>,
+    /// Hand a transaction queued past `AlertThresholds::stuck_tx` to an idle handler, if it can
+    /// be applied out of turn as a stolen one would be. Only the thread-based engine reroutes.
+    pub reroute_stuck: bool,
+    /// Caps how fast each account's transactions are taken; unlimited by default. Only the
+    /// thread-based engine limits rates.
+    pub rate_limit: Option<RateLimit>,
+    pub rate_limit_policy: RateLimitPolicy,
+    pub retry: RetryPolicy,
+    /// How long idempotency keys and transaction statuses are remembered.
+    pub dedup_window: Duration,
+    /// How many rejected transactions `Aptone::dead_letters` keeps; zero keeps none. Only the
+    /// thread-based engine keeps dead letters.
+    pub dead_letter_capacity: usize,
+    /// Run by the handlers around every transaction, in order. Only the thread-based engine runs
+    /// middleware.
+    pub middleware: Vec<Arc<dyn TxMiddleware>>,
+    /// Checked on every transaction before it is queued. Only the thread-based engine checks
+    /// rules.
+    pub rules: Rules,
+    /// Balances whose crossing is reported to subscribers as `TxEvent::ThresholdCrossed`.
+    pub balance_thresholds: Vec<Money>,
+    /// Only the thread-based engine raises alerts.
+    pub alert_thresholds: AlertThresholds,
+    pub alert_sinks: Vec<Arc<dyn AlertSink>>,
+    /// Keep every `LedgerEvent` the handlers apply, for `Aptone::ledger_events`. Only the
+    /// thread-based engine records them.
+    pub record_events: bool,
+    /// Plain `http://` URLs every applied transaction is POSTed to, as JSON. Only the thread-based
+    /// engine calls webhooks.
+    pub webhooks: Vec<String>,
+    /// Keeps the events not delivered to the webhooks yet in files here, to deliver after a
+    /// restart; without it they are lost when the engine goes.
+    pub outbox_dir: Option<PathBuf>,
+    /// Settles on its own by this policy; only when `Aptone::settle` is called by default. Only
+    /// the thread-based engine settles on its own, in deterministic mode `run_until_idle` does.
+    pub settlement: Option<SettlementPolicy>,
+    /// Worked out for every balance on each settlement, in order.
+    pub accruals: Vec<Arc<dyn Accrual>>,
+    /// Pays interest on its own by this policy; none by default. Only the thread-based engine
+    /// pays it on its own, in deterministic mode `run_until_idle` does.
+    pub interest: Option<InterestPolicy>,
+    /// Charges fees on the transactions submitted; none by default. Scheduled transactions and
+    /// standing orders are charged too, what the engine makes itself, like interest, isn't.
+    /// Only the thread-based engine charges them.
+    pub fees: Option<FeeSchedule>,
+    /// Checks the invariants on a background thread, taking a `snapshot` each time; not at all
+    /// by default. Only the thread-based engine audits.
+    pub audit: Option<AuditPolicy>,
+    /// Serve Prometheus metrics over HTTP at `/metrics` on this address.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Write every submission down in a recording here, see `recording`. Only the thread-based
+    /// engine records them.
+    pub recording: Option<PathBuf>,
+    /// Time source for the handler delay, latencies and the dedup window.
+    pub clock: Arc<dyn Clock>,
+    /// Run no handler threads; `Aptone::run_until_idle` processes the queued transactions on the
+    /// caller's thread, interleaving the handlers in an order drawn from this seed.
+    pub deterministic_seed: Option<u64>,
+    /// Faults to inject into the handlers; none by default.
+    #[cfg(feature = "chaos")]
+    pub faults: Faults,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            threads: DEFAULT_THREAD_COUNT,
+            max_threads: None,
+            autoscale: None,
+            latency: None,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            backpressure: BackpressurePolicy::Block,
+            overflow: OverflowPolicy::Reject,
+            overdraft: Overdraft::default(),
+            velocity_limits: VelocityLimits::default(),
+            exchange_rates: None,
+            checkpoint_interval: None,
+            account_ids: 0..AccountId::MAX,
+            lock_stripes: DEFAULT_LOCK_STRIPES,
+            router: Arc::new(LeastQueueDepth),
+            shared_queue: false,
+            steal_threshold: None,
+            reroute_stuck: false,
+            rate_limit: None,
+            rate_limit_policy: RateLimitPolicy::Reject,
+            retry: RetryPolicy::NONE,
+            dedup_window: DEFAULT_DEDUP_WINDOW,
+            dead_letter_capacity: DEFAULT_DEAD_LETTER_CAPACITY,
+            middleware: Vec::new(),
+            rules: Rules::default(),
+            balance_thresholds: Vec::new(),
+            alert_thresholds: AlertThresholds::default(),
+            alert_sinks: Vec::new(),
+            record_events: false,
+            webhooks: Vec::new(),
+            outbox_dir: None,
+            settlement: None,
+            accruals: Vec::new(),
+            interest: None,
+            fees: None,
+            audit: None,
+            metrics_addr: None,
+            recording: None,
+            clock: Arc::new(SystemClock),
+            deterministic_seed: None,
+            #[cfg(feature = "chaos")]
+            faults: Faults::default(),
+        }
+    }
+}
+
+impl Config {
+    /// An empty shard of account state, under the configured balance rules.
+    pub(crate) fn shard(&self) -> ServerData {
+        let data = self.replay_shard();
+        if self.velocity_limits.is_empty() {
+            return data;
+        }
+        data.with_velocity_limits(self.velocity_limits.clone(), Arc::clone(&self.clock))
+    }
+    /// `shard` without the velocity limits, for replaying a log into.
+    pub(crate) fn replay_shard(&self) -> ServerData {
+        let data = ServerData::with_limits(self.overflow, self.overdraft.clone())
+            .with_clock(Arc::clone(&self.clock));
+        match &self.exchange_rates {
+            Some(rates) => data.with_exchange_rates(Arc::clone(rates)),
+            None => data,
+        }
+    }
+    /// Turns down what the builder would have panicked on, for a config put together by hand,
+    /// along with a pool smaller than the handlers it starts with.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        let invalid = |reason: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, reason));
+        if self.threads == 0 {
+            return invalid("Aptone needs at least one handler thread");
+        }
+        if self.max_threads.is_some_and(|max| max < self.threads) {
+            return invalid("max_threads can't be below the threads started with");
+        }
+        if let Some(policy) = &self.autoscale {
+            if !(1 <= policy.min && policy.min <= policy.max) {
+                return invalid("autoscaling needs 1 <= min <= max handlers");
+            }
+            if policy.shrink_depth >= policy.grow_depth {
+                return invalid("autoscaling needs shrink_depth below grow_depth");
+            }
+        }
+        match self.latency {
+            Some(Latency::Uniform { min, max }) if min > max => {
+                return invalid("latency range is empty");
+            }
+            Some(Latency::LogNormal { sigma, .. }) if !(sigma.is_finite() && sigma >= 0.0) => {
+                return invalid("log-normal latency needs a finite, nonnegative sigma");
+            }
+            _ => {}
+        }
+        if self
+            .rate_limit
+            .is_some_and(|limit| limit.per_second == 0 || limit.burst == 0)
+        {
+            return invalid("a rate limit needs a nonzero rate and burst");
+        }
+        if self
+            .webhooks
+            .iter()
+            .any(|url| WebhookUrl::parse(url).is_none())
+        {
+            return invalid("webhooks need a plain http:// URL");
+        }
+        if self.settlement.is_some_and(|policy| policy.every.is_zero()) {
+            return invalid("settling needs a nonzero interval");
+        }
+        if let Some(policy) = &self.interest {
+            if policy.compounding.is_zero() {
+                return invalid("interest needs a nonzero compounding period");
+            }
+            if policy.rate.is_negative() {
+                return invalid("interest needs a nonnegative rate");
+            }
+        }
+        if self.audit.is_some_and(|policy| policy.interval.is_zero()) {
+            return invalid("auditing needs a nonzero interval");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AptoneBuilder {
+    config: Config,
+}
+
+impl AptoneBuilder {
+    pub fn new() -> AptoneBuilder {
+        AptoneBuilder::default()
+    }
+    pub fn threads(mut self, threads: usize) -> AptoneBuilder {
+        self.config.threads = threads;
+        self
+    }
+    pub fn max_threads(mut self, threads: usize) -> AptoneBuilder {
+        self.config.max_threads = Some(threads);
+        self
+    }
+    pub fn autoscale(mut self, policy: AutoscalePolicy) -> AptoneBuilder {
+        assert!(
+            1 <= policy.min && policy.min <= policy.max,
+            "autoscaling needs 1 <= min <= max handlers"
+        );
+        assert!(
+            policy.shrink_depth < policy.grow_depth,
+            "autoscaling needs shrink_depth below grow_depth"
+        );
+        self.config.autoscale = Some(policy);
+        self
+    }
+    pub fn latency(mut self, latency: Latency) -> AptoneBuilder {
+        match latency {
+            Latency::Fixed(_) => {}
+            Latency::Uniform { min, max } => assert!(min <= max, "latency range is empty"),
+            Latency::LogNormal { sigma, .. } => assert!(
+                sigma.is_finite() && sigma >= 0.0,
+                "log-normal latency needs a finite, nonnegative sigma"
+            ),
+        }
+        self.config.latency = Some(latency);
+        self
+    }
+    pub fn channel_capacity(mut self, capacity: usize) -> AptoneBuilder {
+        self.config.channel_capacity = capacity;
+        self
+    }
+    pub fn backpressure(mut self, policy: BackpressurePolicy) -> AptoneBuilder {
+        self.config.backpressure = policy;
+        self
+    }
+    pub fn overflow(mut self, policy: OverflowPolicy) -> AptoneBuilder {
+        self.config.overflow = policy;
+        self
+    }
+    /// Lets every account without a limit of its own go `limit` below zero.
+    pub fn overdraft_limit(mut self, limit: Money) -> AptoneBuilder {
+        self.config.overdraft.limit = limit;
+        self
+    }
+    pub fn account_overdraft_limit(mut self, account: AccountId, limit: Money) -> AptoneBuilder {
+        self.config.overdraft.accounts.insert(account, limit);
+        self
+    }
+    /// Holds every account without limits of its own to taking out at most `amount` within any
+    /// `window`, on top of the limits set so far.
+    pub fn velocity_limit(mut self, amount: Money, window: Duration) -> AptoneBuilder {
+        let limit = VelocityLimit { amount, window };
+        self.config.velocity_limits.limits.push(limit);
+        self
+    }
+    pub fn account_velocity_limit(
+        mut self,
+        account: AccountId,
+        amount: Money,
+        window: Duration,
+    ) -> AptoneBuilder {
+        let limit = VelocityLimit { amount, window };
+        let limits = self.config.velocity_limits.accounts.entry(account);
+        limits.or_default().push(limit);
+        self
+    }
+    pub fn exchange_rates<R: ExchangeRates + 'static>(mut self, rates: R) -> AptoneBuilder {
+        self.config.exchange_rates = Some(Arc::new(rates));
+        self
+    }
+    pub fn checkpoint_every(mut self, transactions: u64) -> AptoneBuilder {
+        self.config.checkpoint_interval = Some(transactions);
+        self
+    }
+    pub fn lock_stripes(mut self, stripes: usize) -> AptoneBuilder {
+        self.config.lock_stripes = stripes;
+        self
+    }
+    pub fn router<R: Router + 'static>(mut self, router: R) -> AptoneBuilder {
+        self.config.router = Arc::new(router);
+        self
+    }
+    pub fn shared_queue(mut self) -> AptoneBuilder {
+        self.config.shared_queue = true;
+        self
+    }
+    pub fn record_events(mut self) -> AptoneBuilder {
+        self.config.record_events = true;
+        self
+    }
+    pub fn account_ids(mut self, ids: Range<AccountId>) -> AptoneBuilder {
+        self.config.account_ids = ids;
+        self
+    }
+    pub fn work_stealing(mut self, threshold: usize) -> AptoneBuilder {
+        self.config.steal_threshold = Some(threshold);
+        self
+    }
+    /// Lets each account submit `per_second` transactions a second, in bursts of up to `burst`.
+    /// Panics if either is zero.
+    pub fn rate_limit(mut self, per_second: u32, burst: u32) -> AptoneBuilder {
+        assert!(
+            per_second > 0 && burst > 0,
+            "a rate limit needs a nonzero rate and burst"
+        );
+        self.config.rate_limit = Some(RateLimit { per_second, burst });
+        self
+    }
+    pub fn rate_limit_policy(mut self, policy: RateLimitPolicy) -> AptoneBuilder {
+        self.config.rate_limit_policy = policy;
+        self
+    }
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> AptoneBuilder {
+        self.config.retry = policy;
+        self
+    }
+    pub fn dedup_window(mut self, window: Duration) -> AptoneBuilder {
+        self.config.dedup_window = window;
+        self
+    }
+    pub fn dead_letter_capacity(mut self, capacity: usize) -> AptoneBuilder {
+        self.config.dead_letter_capacity = capacity;
+        self
+    }
+    /// Adds `middleware` to the end of the chain.
+    pub fn middleware<M: TxMiddleware + 'static>(mut self, middleware: M) -> AptoneBuilder {
+        self.config.middleware.push(Arc::new(middleware));
+        self
+    }
+    pub fn rules(mut self, rules: Rules) -> AptoneBuilder {
+        self.config.rules = rules;
+        self
+    }
+    pub fn balance_threshold(mut self, threshold: Money) -> AptoneBuilder {
+        self.config.balance_thresholds.push(threshold);
+        self
+    }
+    /// Alerts when a transaction takes a balance below `threshold`.
+    pub fn low_balance_alert(mut self, threshold: Money) -> AptoneBuilder {
+        self.config.alert_thresholds.low_balance = Some(threshold);
+        self
+    }
+    /// Alerts when a single transaction takes more than `threshold` out of an account.
+    pub fn large_withdrawal_alert(mut self, threshold: Money) -> AptoneBuilder {
+        self.config.alert_thresholds.large_withdrawal = Some(threshold);
+        self
+    }
+    /// Alerts when a handler has more than `threshold` transactions queued.
+    pub fn queue_depth_alert(mut self, threshold: TxCount) -> AptoneBuilder {
+        self.config.alert_thresholds.queue_depth = Some(threshold);
+        self
+    }
+    /// Alerts when a transaction has been queued for longer than `deadline`.
+    pub fn stuck_tx_alert(mut self, deadline: Duration) -> AptoneBuilder {
+        self.config.alert_thresholds.stuck_tx = Some(deadline);
+        self
+    }
+    /// Hands transactions queued past the `stuck_tx_alert` deadline to idle handlers, see
+    /// `Config::reroute_stuck`.
+    pub fn reroute_stuck(mut self) -> AptoneBuilder {
+        self.config.reroute_stuck = true;
+        self
+    }
+    /// Sends every alert to `sink` too, such as the `Sender` of a channel.
+    pub fn alert_sink<S: AlertSink + 'static>(mut self, sink: S) -> AptoneBuilder {
+        self.config.alert_sinks.push(Arc::new(sink));
+        self
+    }
+    pub fn webhook(mut self, url: impl Into<String>) -> AptoneBuilder {
+        let url = url.into();
+        assert!(
+            WebhookUrl::parse(&url).is_some(),
+            "webhooks need a plain http:// URL"
+        );
+        self.config.webhooks.push(url);
+        self
+    }
+    pub fn outbox_dir(mut self, dir: impl Into<PathBuf>) -> AptoneBuilder {
+        self.config.outbox_dir = Some(dir.into());
+        self
+    }
+    /// Settles by `policy`, see `Aptone::settle`. Panics for a zero interval.
+    pub fn settlement(mut self, policy: SettlementPolicy) -> AptoneBuilder {
+        assert!(!policy.every.is_zero(), "settling needs a nonzero interval");
+        self.config.settlement = Some(policy);
+        self
+    }
+    /// Adds `accrual` to those worked out on each settlement.
+    pub fn accrual<A: Accrual + 'static>(mut self, accrual: A) -> AptoneBuilder {
+        self.config.accruals.push(Arc::new(accrual));
+        self
+    }
+    /// Pays interest by `policy`, see `InterestPolicy`. Panics for a zero compounding period or
+    /// a negative rate.
+    pub fn interest(mut self, policy: InterestPolicy) -> AptoneBuilder {
+        assert!(
+            !policy.compounding.is_zero(),
+            "interest needs a nonzero compounding period"
+        );
+        assert!(
+            !policy.rate.is_negative(),
+            "interest needs a nonnegative rate"
+        );
+        self.config.interest = Some(policy);
+        self
+    }
+    /// Charges fees by `schedule`, see `FeeSchedule`.
+    pub fn fees(mut self, schedule: FeeSchedule) -> AptoneBuilder {
+        self.config.fees = Some(schedule);
+        self
+    }
+    /// Checks the invariants by `policy` on a background thread, see `Aptone::audit`. Panics
+    /// for a zero interval.
+    pub fn audit(mut self, policy: AuditPolicy) -> AptoneBuilder {
+        assert!(
+            !policy.interval.is_zero(),
+            "auditing needs a nonzero interval"
+        );
+        self.config.audit = Some(policy);
+        self
+    }
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> AptoneBuilder {
+        self.config.metrics_addr = Some(addr);
+        self
+    }
+    /// Records every submission to `path`, replacing what is there, see `recording`.
+    pub fn record_to(mut self, path: impl Into<PathBuf>) -> AptoneBuilder {
+        self.config.recording = Some(path.into());
+        self
+    }
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> AptoneBuilder {
+        self.config.clock = Arc::new(clock);
+        self
+    }
+    /// Switches to the single-threaded executor, on a fresh `VirtualClock` unless a clock is set
+    /// afterwards. The same seed and the same submissions always give the same results.
+    pub fn deterministic(mut self, seed: u64) -> AptoneBuilder {
+        self.config.deterministic_seed = Some(seed);
+        self.config.clock = Arc::new(VirtualClock::new());
+        self
+    }
+    #[cfg(feature = "chaos")]
+    pub fn faults(mut self, faults: Faults) -> AptoneBuilder {
+        self.config.faults = faults;
+        self
+    }
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+    pub fn build(self) -> Aptone {
+        Aptone::with_config(self.config)
+    }
+    /// `build`, with an error instead of a panic for a config the engine can't run on.
+    pub fn try_build(self) -> io::Result<Aptone> {
+        Aptone::try_with_config(self.config)
+    }
+    pub fn recover<P: AsRef<Path>>(self, path: P) -> io::Result<Aptone> {
+        Aptone::recover_with_config(self.config, path)
+    }
+    /// Carries on from the state `Aptone::export_state` wrote to `reader`.
+    #[cfg(feature = "export")]
+    pub fn import_state(self, reader: impl io::Read) -> io::Result<Aptone> {
+        Aptone::import_state_with_config(self.config, reader)
+    }
+    /// Restores the balances persisted in `storage` and keeps persisting to it, see `Storage`.
+    pub fn persist_to<S: Storage + 'static>(self, storage: S) -> io::Result<Aptone> {
+        Aptone::persist_with_config(self.config, Arc::new(storage))
+    }
+}
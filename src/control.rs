@@ -0,0 +1,182 @@
+//! An admin socket for managing a running engine without an HTTP stack, say with
+//! `socat - UNIX-CONNECT:aptone.sock`. Each command is one line; the answer ends with a line of
+//! `OK [result]` or `ERR <reason>`:
+//!
+//! - `stats` lists each handler on a line of its own, then the totals, with latency percentiles
+//!   in microseconds
+//! - `health` lists each handler with how long ago it reported in and finished a message, and
+//!   fails if one in the pool isn't alive, see `Aptone::health`
+//! - `pause` and `resume`, see `Aptone::pause`
+//! - `drain <handler>` and `undrain <handler>`, see `Aptone::drain_handler`
+//! - `rebalance` and `resize <handlers>` answer with how many accounts were moved
+//! - `quit` closes the connection
+//!
+//! Only built on Unix.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use tracing::{debug, info};
+
+use crate::{Aptone, HandleId, LatencyPercentiles};
+
+/// Serves `aptone` on a socket at `path`, a thread per client, until accepting fails. A socket
+/// file left at `path` by an earlier run is replaced.
+pub fn serve(aptone: Arc<Aptone>, path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    loop {
+        let (stream, _) = listener.accept()?;
+        let aptone = Arc::clone(&aptone);
+        thread::spawn(move || {
+            if let Err(err) = client(&aptone, stream) {
+                debug!(%err, "control client went away");
+            }
+        });
+    }
+}
+
+fn client(aptone: &Aptone, stream: UnixStream) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some(command) = words.first() else {
+            continue;
+        };
+        let command = command.to_ascii_lowercase();
+        if command == "quit" {
+            break;
+        }
+        match request(aptone, &command, &words[1..], &mut writer)? {
+            Ok(result) if result.is_empty() => writeln!(writer, "OK")?,
+            Ok(result) => writeln!(writer, "OK {}", result)?,
+            Err(reason) => writeln!(writer, "ERR {}", reason)?,
+        }
+    }
+    Ok(())
+}
+
+fn request(
+    aptone: &Aptone,
+    command: &str,
+    args: &[&str],
+    out: &mut impl Write,
+) -> io::Result<Result<String, String>> {
+    let handler = || -> Result<HandleId, String> {
+        let arg = args.first().ok_or("missing handler")?;
+        match arg.parse::<HandleId>() {
+            Ok(id) if (id as usize) < aptone.stats().handlers.len() => Ok(id),
+            _ => Err(format!("no handler {}", arg)),
+        }
+    };
+    let result = match command {
+        "stats" => {
+            let stats = aptone.stats();
+            for (id, handler) in stats.handlers.iter().enumerate() {
+                writeln!(
+                    out,
+                    "handler {} queue_depth={} applied={} rejected={} accounts={} \
+                     active_accounts={} {}{}{}",
+                    id,
+                    handler.queue_depth,
+                    handler.applied,
+                    handler.rejected,
+                    handler.accounts,
+                    handler.active_accounts,
+                    percentiles(&handler.latency),
+                    if handler.drained { " drained" } else { "" },
+                    if handler.retired { " retired" } else { "" }
+                )?;
+            }
+            Ok(format!(
+                "applied={} rejected={} accounts={} active_accounts={} uptime_secs={} {}{}",
+                stats.applied,
+                stats.rejected,
+                stats.accounts,
+                stats.active_accounts,
+                stats.uptime.as_secs(),
+                percentiles(&stats.latency),
+                if aptone.is_paused() { " paused" } else { "" }
+            ))
+        }
+        "health" => {
+            let ago = |at: Option<Instant>| match at {
+                Some(at) => format!(
+                    "{}",
+                    Instant::now().saturating_duration_since(at).as_millis()
+                ),
+                None => "never".to_string(),
+            };
+            let mut hung = 0;
+            for (id, handler) in aptone.health().iter().enumerate() {
+                let state = match (handler.alive, handler.retired) {
+                    (true, _) => "alive",
+                    (false, true) => "retired",
+                    (false, false) => "hung",
+                };
+                hung += (state == "hung") as usize;
+                writeln!(
+                    out,
+                    "handler {} {} queue_depth={} heartbeat_ms_ago={} processed_ms_ago={}",
+                    id,
+                    state,
+                    handler.queue_depth,
+                    ago(handler.last_heartbeat),
+                    ago(handler.last_processed)
+                )?;
+            }
+            match hung {
+                0 => Ok(String::new()),
+                hung => Err(format!("{} handlers not alive", hung)),
+            }
+        }
+        "pause" => {
+            aptone.pause();
+            Ok(String::new())
+        }
+        "resume" => {
+            aptone.resume();
+            Ok(String::new())
+        }
+        "drain" => handler().map(|id| {
+            info!(handler = id, "draining handler");
+            aptone.drain_handler(id).to_string()
+        }),
+        "undrain" => handler().map(|id| {
+            info!(handler = id, "undraining handler");
+            aptone.undrain_handler(id);
+            String::new()
+        }),
+        "rebalance" => Ok(aptone.rebalance().to_string()),
+        "resize" => {
+            let slots = aptone.stats().handlers.len();
+            match args.first().map(|arg| arg.parse::<usize>()) {
+                Some(Ok(handlers)) if (1..=slots).contains(&handlers) => {
+                    Ok(aptone.resize_workers(handlers).to_string())
+                }
+                _ => Err(format!("the pool holds from 1 to {} handlers", slots)),
+            }
+        }
+        _ => Err(format!("unknown command: {}", command)),
+    };
+    Ok(result)
+}
+
+fn percentiles(latency: &LatencyPercentiles) -> String {
+    format!(
+        "p50_us={} p95_us={} p99_us={}",
+        latency.p50.as_micros(),
+        latency.p95.as_micros(),
+        latency.p99.as_micros()
+    )
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::Money;
+
+/// What an amount is in. Every account holds a separate balance per currency.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Currency {
+    #[default]
+    USD,
+    EUR,
+    GBP,
+    JPY,
+    CHF,
+}
+
+impl Currency {
+    pub const ALL: [Currency; 5] = [
+        Currency::USD,
+        Currency::EUR,
+        Currency::GBP,
+        Currency::JPY,
+        Currency::CHF,
+    ];
+
+    /// The ISO 4217 code.
+    pub fn code(self) -> &'static str {
+        match self {
+            Currency::USD => "USD",
+            Currency::EUR => "EUR",
+            Currency::GBP => "GBP",
+            Currency::JPY => "JPY",
+            Currency::CHF => "CHF",
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// The string wasn't the code of a supported currency.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseCurrencyError(String);
+
+impl fmt::Display for ParseCurrencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown currency: {:?}", self.0)
+    }
+}
+
+impl Error for ParseCurrencyError {}
+
+/// Parses ISO 4217 codes, in any case.
+impl FromStr for Currency {
+    type Err = ParseCurrencyError;
+
+    fn from_str(s: &str) -> Result<Currency, ParseCurrencyError> {
+        Currency::ALL
+            .into_iter()
+            .find(|currency| currency.code().eq_ignore_ascii_case(s))
+            .ok_or_else(|| ParseCurrencyError(s.to_string()))
+    }
+}
+
+/// As its code.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Currency {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.code())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Currency {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Currency, D::Error> {
+        let code = <std::borrow::Cow<'_, str>>::deserialize(deserializer)?;
+        code.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Converts amounts between currencies for `TxType::EXCHANGE`. Without one configured, every
+/// exchange is rejected.
+pub trait ExchangeRates: fmt::Debug + Send + Sync {
+    /// `amount` of `from` in `to`, or `None` if there's no rate between them.
+    fn convert(&self, amount: Money, from: Currency, to: Currency) -> Option<Money>;
+}
+
+/// Rates set up front, each for one direction.
+#[derive(Debug, Clone, Default)]
+pub struct FixedRates {
+    rates: HashMap<(Currency, Currency), Money>,
+}
+
+impl FixedRates {
+    pub fn new() -> FixedRates {
+        FixedRates::default()
+    }
+    /// One unit of `from` buys `rate` of `to`.
+    pub fn rate(mut self, from: Currency, to: Currency, rate: Money) -> FixedRates {
+        self.rates.insert((from, to), rate);
+        self
+    }
+}
+
+impl ExchangeRates for FixedRates {
+    /// Rounds the product half to even, see `Money::checked_mul`.
+    fn convert(&self, amount: Money, from: Currency, to: Currency) -> Option<Money> {
+        if from == to {
+            return Some(amount);
+        }
+        amount.checked_mul(*self.rates.get(&(from, to))?)
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::Clock;
+
+// how often the clock is checked for an occurrence falling due, polled like the schedule's
+const CYCLE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Something that falls due every so often by the engine's clock, such as settlements or
+/// interest.
+pub(crate) struct Cycle {
+    times: Mutex<(Instant, Instant)>, // when the next occurrence falls due, and the last fell
+    every: Duration,
+}
+
+impl Cycle {
+    /// The first one `every` after `now`.
+    pub(crate) fn new(every: Duration, now: Instant) -> Cycle {
+        Cycle {
+            times: Mutex::new((now.checked_add(every).unwrap_or(now), now)),
+            every,
+        }
+    }
+    /// The first one at `at` past midnight UTC by the wall clock, or `every` past it, the
+    /// wall clock reading `wall` at `now` on the engine's.
+    pub(crate) fn aligned(at: Duration, every: Duration, now: Instant, wall: SystemTime) -> Cycle {
+        let since_epoch = wall.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let nanos = every.as_nanos();
+        let into_cycle = since_epoch.as_nanos() % nanos;
+        let until = (at.as_nanos() % nanos + nanos - into_cycle) % nanos;
+        let until = Duration::from_nanos(until as u64);
+        Cycle {
+            times: Mutex::new((now.checked_add(until).unwrap_or(now), now)),
+            every,
+        }
+    }
+    /// Whether an occurrence fell due by `now`, with the time since the one before, moving on to
+    /// the next. Occurrences missed, as when the clock jumps, are skipped rather than run one
+    /// after another, so the time since may be several `every`s.
+    pub(crate) fn take_due(&self, now: Instant) -> Option<Duration> {
+        let mut times = self.times.lock().unwrap();
+        let (next, last) = &mut *times;
+        if now < *next {
+            return None;
+        }
+        while *next <= now {
+            match next.checked_add(self.every) {
+                Some(after) => *next = after,
+                None => break,
+            }
+        }
+        let since = now.saturating_duration_since(*last);
+        *last = now;
+        Some(since)
+    }
+}
+
+/// Has `run` run as the occurrences of `cycle` fall due by `clock`, with the time since the one
+/// before. Stops when dropped.
+pub(crate) struct Ticker {
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Ticker {
+    pub(crate) fn start<F>(cycle: Arc<Cycle>, clock: Arc<dyn Clock>, run: F) -> Ticker
+    where
+        F: Fn(Duration) + Send + 'static,
+    {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopped);
+        let thread = thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                if let Some(since) = cycle.take_due(clock.now()) {
+                    run(since);
+                }
+                thread::park_timeout(CYCLE_POLL_INTERVAL);
+            }
+        });
+        Ticker {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
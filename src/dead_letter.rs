@@ -0,0 +1,62 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::{Clock, Tx, TxError, TxId};
+
+/// A rejected transaction, kept to be looked into and submitted again once corrected, see
+/// `Aptone::dead_letters`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub tx_id: TxId,
+    /// The transaction, or the legs of a batch.
+    pub legs: Vec<Tx>,
+    pub error: TxError,
+    /// How many times it was tried, retries and handler restarts included.
+    pub attempts: u32,
+    pub time: SystemTime,
+}
+
+/// The latest dead letters, oldest first; past `capacity` the oldest are dropped.
+pub(crate) struct DeadLetters {
+    capacity: usize,
+    letters: Mutex<VecDeque<DeadLetter>>,
+    clock: Arc<dyn Clock>, // stamps the letters
+}
+
+impl DeadLetters {
+    pub(crate) fn new(capacity: usize, clock: Arc<dyn Clock>) -> DeadLetters {
+        DeadLetters {
+            capacity,
+            letters: Mutex::new(VecDeque::new()),
+            clock,
+        }
+    }
+    pub(crate) fn push(&self, tx_id: TxId, legs: &[Tx], error: &TxError, attempts: u32) {
+        self.put(DeadLetter {
+            tx_id,
+            legs: legs.to_vec(),
+            error: error.clone(),
+            attempts,
+            time: self.clock.wall_time(),
+        });
+    }
+    pub(crate) fn put(&self, letter: DeadLetter) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut letters = self.letters.lock().unwrap();
+        if letters.len() == self.capacity {
+            letters.pop_front();
+        }
+        letters.push_back(letter);
+    }
+    pub(crate) fn list(&self) -> Vec<DeadLetter> {
+        self.letters.lock().unwrap().iter().cloned().collect()
+    }
+    pub(crate) fn take(&self, tx_id: TxId) -> Option<DeadLetter> {
+        let mut letters = self.letters.lock().unwrap();
+        let index = letters.iter().position(|letter| letter.tx_id == tx_id)?;
+        letters.remove(index)
+    }
+}
@@ -0,0 +1,565 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::sync::{self, Arc, AtomicU32, Mutex, MutexGuard};
+use crate::{
+    AccountId, Currency, HandleId, HistoryEntry, HoldId, Money, Router, ServerData, Tx, TxCount,
+    TxError, TxResult, TxType,
+};
+
+pub(crate) type Shard = Arc<Mutex<ServerData>>;
+pub(crate) type TxCounts = Arc<Vec<AtomicU32>>; // handler id -> pending tx count
+
+type Owners = HashMap<AccountId, HandleId>; // account -> handler id owning its state
+
+/// Submission-side view of the handlers: which handler owns which account, and how much work is
+/// queued on each. Handlers never touch it; they only lock their own shard and decrement their
+/// `tx_count`, so its locks stay off the processing path.
+///
+/// An account is owned by exactly one handler at a time. While it has pending transactions it
+/// stays with that handler, which keeps its transactions in order; once idle, the next
+/// transaction may hand it over to a less busy handler.
+///
+/// Owners are split into stripes by account, each behind its own lock, so submissions on
+/// unrelated accounts don't serialize on each other.
+pub(crate) struct Directory {
+    stripes: Vec<Mutex<Owners>>,
+    shards: Vec<Shard>, // handler id -> owned state
+    tx_count: TxCounts,
+    crossing: Mutex<()>, // held while queueing a transfer and its barrier
+    router: std::sync::Arc<dyn Router>,
+    drained: Vec<AtomicBool>, // handler id -> takes no idle accounts
+    active: AtomicUsize,      // handlers in the pool, the first ones by id
+    shared: bool,             // every account goes to the first handler, whose queue all share
+}
+
+impl Directory {
+    /// A directory over `handlers` empty shards, made by `shard`.
+    pub(crate) fn new(
+        handlers: usize,
+        stripes: usize,
+        router: std::sync::Arc<dyn Router>,
+        shard: impl Fn() -> ServerData,
+    ) -> Directory {
+        assert!(stripes > 0, "the directory needs at least one lock stripe");
+        let shards = (0..handlers)
+            .map(|_| Arc::new(Mutex::new(shard())))
+            .collect();
+        let tx_count = (0..handlers).map(|_| AtomicU32::new(0)).collect();
+        Directory {
+            stripes: (0..stripes).map(|_| Mutex::new(Owners::new())).collect(),
+            shards,
+            tx_count: Arc::new(tx_count),
+            crossing: Mutex::new(()),
+            router,
+            drained: (0..handlers).map(|_| AtomicBool::new(false)).collect(),
+            active: AtomicUsize::new(handlers),
+            shared: false,
+        }
+    }
+    /// Has every account owned by the first handler, for handlers sharing its queue.
+    pub(crate) fn share(&mut self) {
+        self.shared = true;
+    }
+    pub(crate) fn handler_count(&self) -> usize {
+        self.shards.len()
+    }
+    pub(crate) fn shard(&self, handle_id: HandleId) -> &Shard {
+        &self.shards[handle_id as usize]
+    }
+    pub(crate) fn shards(&self) -> Vec<Shard> {
+        self.shards.clone()
+    }
+    pub(crate) fn lock_shard(&self, handle_id: HandleId) -> MutexGuard<'_, ServerData> {
+        sync::lock(self.shard(handle_id))
+    }
+    pub(crate) fn lock_all(&self) -> Vec<MutexGuard<'_, ServerData>> {
+        self.shards.iter().map(|shard| sync::lock(shard)).collect()
+    }
+    fn stripe(&self, account: AccountId) -> usize {
+        account as usize % self.stripes.len()
+    }
+    /// Locks the entries of the accounts `tx_type` on `account` touches.
+    pub(crate) fn lock(&self, account: AccountId, tx_type: TxType) -> Accounts<'_> {
+        match tx_type.payee() {
+            Some(to) => self.lock_accounts(&[account, to]),
+            None => self.lock_accounts(&[account]),
+        }
+    }
+    /// Locks the entries of `accounts`.
+    pub(crate) fn lock_accounts(&self, accounts: &[AccountId]) -> Accounts<'_> {
+        let mut stripes: Vec<_> = accounts
+            .iter()
+            .map(|&account| self.stripe(account))
+            .collect();
+        // always in stripe order, so two submissions over the same stripes can't deadlock
+        stripes.sort_unstable();
+        stripes.dedup();
+        Accounts {
+            directory: self,
+            stripes: stripes
+                .into_iter()
+                .map(|stripe| (stripe, sync::lock(&self.stripes[stripe])))
+                .collect(),
+        }
+    }
+    /// Locks every stripe, shutting out all submissions.
+    pub(crate) fn lock_stripes(&self) -> Vec<MutexGuard<'_, Owners>> {
+        self.stripes.iter().map(sync::lock).collect()
+    }
+    /// Locks the entries of every account, shutting out all submissions.
+    pub(crate) fn lock_every_account(&self) -> Accounts<'_> {
+        Accounts {
+            directory: self,
+            stripes: self.lock_stripes().into_iter().enumerate().collect(),
+        }
+    }
+    /// Handlers with an id from `active_count` on are out of the pool and get no accounts, as
+    /// if drained.
+    pub(crate) fn active_count(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+    pub(crate) fn set_active_count(&self, handlers: usize) {
+        self.active.store(handlers, Ordering::SeqCst);
+    }
+    pub(crate) fn is_drained(&self, handle_id: HandleId) -> bool {
+        self.drained[handle_id as usize].load(Ordering::SeqCst)
+    }
+    fn takes_accounts(&self, handle_id: HandleId) -> bool {
+        if self.shared {
+            return handle_id == 0;
+        }
+        (handle_id as usize) < self.active_count() && !self.is_drained(handle_id)
+    }
+    pub(crate) fn set_drained(&self, handle_id: HandleId, drained: bool) {
+        self.drained[handle_id as usize].store(drained, Ordering::SeqCst);
+    }
+    // A transfer blocks its source handler until the barrier on the peer handler is reached, so
+    // every pair has to be queued in the same order on both sides or two handlers could end up
+    // waiting on each other.
+    pub(crate) fn lock_crossing(&self) -> MutexGuard<'_, ()> {
+        sync::lock(&self.crossing)
+    }
+    pub(crate) fn tx_counts(&self) -> TxCounts {
+        Arc::clone(&self.tx_count)
+    }
+    pub(crate) fn get_tx_count(&self, handle_id: HandleId) -> TxCount {
+        self.tx_count[handle_id as usize].load(Ordering::SeqCst)
+    }
+    /// No handler holds a queued message.
+    pub(crate) fn idle(&self) -> bool {
+        self.tx_count
+            .iter()
+            .all(|count| count.load(Ordering::SeqCst) == 0)
+    }
+    /// Claims a slot on the handler's queue, unless it already holds `capacity` messages.
+    pub(crate) fn try_reserve(&self, handle_id: HandleId, capacity: usize) -> bool {
+        self.tx_count[handle_id as usize]
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                ((count as usize) < capacity).then_some(count + 1)
+            })
+            .is_ok()
+    }
+    #[cfg(feature = "async")]
+    pub(crate) fn reserve(&self, handle_id: HandleId) {
+        self.tx_count[handle_id as usize].fetch_add(1, Ordering::SeqCst);
+    }
+    /// Gives back a slot claimed for a message that was never queued.
+    pub(crate) fn release(&self, handle_id: HandleId) {
+        self.tx_count[handle_id as usize].fetch_sub(1, Ordering::SeqCst);
+    }
+    /// Fills in a reversal from its original, on the handler it was routed to, see
+    /// `ServerData::claim_reversal`. Anything else is left as it is.
+    pub(crate) fn claim_reversal(&self, handle_id: HandleId, tx: &mut Tx) -> Result<(), TxError> {
+        if let TxType::REVERSAL { original } = tx.tx_type {
+            let (currency, change) = self
+                .lock_shard(handle_id)
+                .claim_reversal(tx.account, original)?;
+            tx.currency = currency;
+            tx.amount = change;
+        }
+        Ok(())
+    }
+    /// `claim_reversal` for every leg of a batch, claiming none if one can't be claimed.
+    pub(crate) fn claim_reversals(&self, handle_id: HandleId, legs: &mut [Tx]) -> TxResult {
+        for claimed in 0..legs.len() {
+            if let Err(err) = self.claim_reversal(handle_id, &mut legs[claimed]) {
+                self.unclaim_reversals(handle_id, &legs[..claimed]);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+    pub(crate) fn unclaim_reversals(&self, handle_id: HandleId, legs: &[Tx]) {
+        for leg in legs {
+            self.unclaim_reversal(handle_id, leg.account, leg.tx_type);
+        }
+    }
+    /// For a reversal claimed but never queued.
+    pub(crate) fn unclaim_reversal(
+        &self,
+        handle_id: HandleId,
+        account: AccountId,
+        tx_type: TxType,
+    ) {
+        if let TxType::REVERSAL { original } = tx_type {
+            self.lock_shard(handle_id)
+                .unclaim_reversal(account, original);
+        }
+    }
+    /// Places state restored from a log on a handler.
+    pub(crate) fn insert(&self, account: AccountId, currency: Currency, balance: Money) {
+        let id = (account as usize % self.handler_count()) as HandleId;
+        self.lock_shard(id).set_balance(account, currency, balance);
+        sync::lock(&self.stripes[self.stripe(account)]).insert(account, id);
+    }
+    /// Places a history restored from an exported state with its account.
+    pub(crate) fn insert_history(&self, account: AccountId, history: Vec<HistoryEntry>) {
+        let id = (account as usize % self.handler_count()) as HandleId;
+        self.lock_shard(id).set_history(account, history);
+    }
+    /// Places a hold restored from a log with its account.
+    pub(crate) fn insert_hold(&self, hold: HoldId, currency: Currency, amount: Money) {
+        let id = (hold.account as usize % self.handler_count()) as HandleId;
+        self.lock_shard(id).set_hold(hold, currency, amount);
+    }
+    // the thread-based engine reads balances from its `BalanceCache` instead
+    #[cfg(any(feature = "async", loom))]
+    pub(crate) fn get_balance(&self, account: AccountId, currency: Currency) -> Money {
+        self.lock(account, TxType::DEPOSIT)
+            .get_balance(account, currency)
+    }
+    pub(crate) fn get_available_balance(&self, account: AccountId, currency: Currency) -> Money {
+        let accounts = self.lock(account, TxType::DEPOSIT);
+        match accounts.owner(account) {
+            Some(id) => self.lock_shard(id).get_available_balance(account, currency),
+            None => Money::ZERO,
+        }
+    }
+    #[cfg(feature = "async")]
+    pub(crate) fn get_balances(&self, account: AccountId) -> Vec<(Currency, Money)> {
+        let accounts = self.lock(account, TxType::DEPOSIT);
+        match accounts.owner(account) {
+            Some(id) => self.lock_shard(id).get_balances(account),
+            None => Vec::new(),
+        }
+    }
+    pub(crate) fn get_pending_tx(&self, account: AccountId) -> TxCount {
+        self.lock(account, TxType::DEPOSIT).get_pending_tx(account)
+    }
+    pub(crate) fn history(
+        &self,
+        account: AccountId,
+        limit: usize,
+        offset: usize,
+    ) -> Vec<HistoryEntry> {
+        self.lock(account, TxType::DEPOSIT)
+            .history(account, limit, offset)
+    }
+    /// Runs `f` on the history of `account`, empty for an account not open.
+    pub(crate) fn with_history<T>(
+        &self,
+        account: AccountId,
+        f: impl FnOnce(&[HistoryEntry]) -> T,
+    ) -> T {
+        self.lock(account, TxType::DEPOSIT).with_history(account, f)
+    }
+    // Hands an idle account to the handler in the pool the router picks, or to the least busy
+    // one left if the router picked a drained one.
+    fn pick(&self, account: AccountId) -> HandleId {
+        if self.shared {
+            return 0;
+        }
+        let depths: Vec<TxCount> = (0..self.active_count())
+            .map(|id| self.get_tx_count(id as HandleId))
+            .collect();
+        let id = self.router.route(account, &depths);
+        if !self.is_drained(id) {
+            return id;
+        }
+        (0..depths.len() as HandleId)
+            .filter(|&other| !self.is_drained(other))
+            .min_by_key(|&other| depths[other as usize])
+            .unwrap_or(id)
+    }
+}
+
+/// The directory entries of the accounts one transaction touches, held for the whole submission.
+pub(crate) struct Accounts<'a> {
+    directory: &'a Directory,
+    stripes: Vec<(usize, MutexGuard<'a, Owners>)>,
+}
+
+impl Accounts<'_> {
+    fn owners(&self, account: AccountId) -> &Owners {
+        let stripe = self.directory.stripe(account);
+        let (_, owners) = self
+            .stripes
+            .iter()
+            .find(|(locked, _)| *locked == stripe)
+            .expect("account outside the locked stripes");
+        owners
+    }
+    fn owners_mut(&mut self, account: AccountId) -> &mut Owners {
+        let stripe = self.directory.stripe(account);
+        let (_, owners) = self
+            .stripes
+            .iter_mut()
+            .find(|(locked, _)| *locked == stripe)
+            .expect("account outside the locked stripes");
+        owners
+    }
+    pub(crate) fn owner(&self, account: AccountId) -> Option<HandleId> {
+        self.owners(account).get(&account).copied()
+    }
+    pub(crate) fn get_balance(&self, account: AccountId, currency: Currency) -> Money {
+        match self.owner(account) {
+            Some(id) => self.directory.lock_shard(id).get_balance(account, currency),
+            None => Money::ZERO,
+        }
+    }
+    pub(crate) fn history(
+        &self,
+        account: AccountId,
+        limit: usize,
+        offset: usize,
+    ) -> Vec<HistoryEntry> {
+        self.with_history(account, |history| {
+            let page = history.iter().skip(offset).take(limit);
+            page.cloned().collect()
+        })
+    }
+    pub(crate) fn with_history<T>(
+        &self,
+        account: AccountId,
+        f: impl FnOnce(&[HistoryEntry]) -> T,
+    ) -> T {
+        match self.owner(account) {
+            Some(id) => f(self.directory.lock_shard(id).history(account)),
+            None => f(&[]),
+        }
+    }
+    pub(crate) fn get_pending_tx(&self, account: AccountId) -> TxCount {
+        match self.owner(account) {
+            Some(id) => self.directory.lock_shard(id).get_pending_tx(account),
+            None => 0,
+        }
+    }
+    /// The handler `account` is pinned to, if it has transactions in flight.
+    pub(crate) fn pinned_handle(&self, account: AccountId) -> Option<HandleId> {
+        self.owner(account)
+            .filter(|&id| self.directory.lock_shard(id).get_pending_tx(account) > 0)
+    }
+    /// Every account `tx_type` on `account` touches has to be open.
+    pub(crate) fn check_open(&self, account: AccountId, tx_type: TxType) -> TxResult {
+        let open = |account| {
+            self.owner(account)
+                .is_some_and(|id| self.directory.lock_shard(id).has_account(account))
+        };
+        if !open(account) {
+            return Err(TxError::UnknownAccount(account));
+        }
+        if let Some(to) = tx_type.payee() {
+            if !open(to) {
+                return Err(TxError::UnknownAccount(to));
+            }
+        }
+        Ok(())
+    }
+    /// Places a new account on the handler the router picks.
+    pub(crate) fn open(&mut self, account: AccountId, balance: Money) {
+        let id = self.directory.pick(account);
+        self.directory.lock_shard(id).open_account(account, balance);
+        self.owners_mut(account).insert(account, id);
+    }
+    /// Drops an open account with nothing pending, giving back its balances.
+    pub(crate) fn close(&mut self, account: AccountId) -> Vec<(Currency, Money)> {
+        match self.owners_mut(account).remove(&account) {
+            Some(id) => self.directory.lock_shard(id).close_account(account),
+            None => Vec::new(),
+        }
+    }
+    /// Picks the handler for a transaction on `account`. For a transfer whose `to` is pinned to
+    /// another handler, also returns that handler, which has to hold `to` behind a barrier and
+    /// apply the credit leg itself.
+    pub(crate) fn route(
+        &self,
+        account: AccountId,
+        tx_type: TxType,
+    ) -> (HandleId, Option<HandleId>) {
+        let mut id = match self.pinned_handle(account) {
+            Some(id) => id,
+            None => self.directory.pick(account),
+        };
+        let mut barrier = None;
+        if let Some(to) = tx_type.payee() {
+            if to != account {
+                match (self.pinned_handle(account), self.pinned_handle(to)) {
+                    (None, Some(to_id)) => id = to_id,
+                    (Some(from_id), Some(to_id)) if from_id != to_id => barrier = Some(to_id),
+                    _ => {}
+                }
+            }
+        }
+        (id, barrier)
+    }
+    /// Picks the one handler to apply a batch over `accounts` on, or `None` while they are
+    /// pinned to more than one, which have to drain first. The batch never needs a barrier.
+    pub(crate) fn route_batch(&self, accounts: &[AccountId]) -> Option<HandleId> {
+        let mut pinned = accounts
+            .iter()
+            .filter_map(|&account| self.pinned_handle(account));
+        match pinned.next() {
+            Some(id) => pinned.all(|other| other == id).then_some(id),
+            None => Some(self.directory.pick(accounts[0])),
+        }
+    }
+    /// `track_tx` for a batch over `accounts`, each counted once.
+    pub(crate) fn track_batch(&mut self, accounts: &[AccountId], handle_id: HandleId) {
+        for &account in accounts {
+            self.move_account(account, handle_id);
+            self.directory
+                .lock_shard(handle_id)
+                .increase_pending_tx(account, 1);
+        }
+    }
+    pub(crate) fn untrack_batch(&self, accounts: &[AccountId], handle_id: HandleId) {
+        let mut data = self.directory.lock_shard(handle_id);
+        for &account in accounts {
+            data.decrease_pending_tx(account, 1);
+        }
+    }
+    /// Counts a barrier queued for `account` on the handler owning it.
+    pub(crate) fn track_barrier(&self, account: AccountId, handle_id: HandleId) {
+        self.directory
+            .lock_shard(handle_id)
+            .increase_pending_tx(account, 1);
+    }
+    /// Undoes `track_barrier` for a barrier that could not be queued.
+    pub(crate) fn untrack_barrier(&self, account: AccountId, handle_id: HandleId) {
+        self.directory
+            .lock_shard(handle_id)
+            .decrease_pending_tx(account, 1);
+    }
+    /// Hands the accounts of a transaction to `handle_id` and counts it as pending there. Has to
+    /// happen before the transaction is queued, since the handler may pick it up right away.
+    pub(crate) fn track_tx(
+        &mut self,
+        account: AccountId,
+        tx_type: TxType,
+        handle_id: HandleId,
+        barrier: bool,
+    ) {
+        self.move_account(account, handle_id);
+        self.directory
+            .lock_shard(handle_id)
+            .increase_pending_tx(account, 1);
+        if let Some(to) = tx_type.payee() {
+            if to != account && !barrier {
+                self.move_account(to, handle_id);
+                self.directory
+                    .lock_shard(handle_id)
+                    .increase_pending_tx(to, 1);
+            }
+        }
+    }
+    /// Undoes `track_tx` for a transaction that could not be queued.
+    pub(crate) fn untrack_tx(
+        &self,
+        account: AccountId,
+        tx_type: TxType,
+        handle_id: HandleId,
+        barrier: bool,
+    ) {
+        let mut data = self.directory.lock_shard(handle_id);
+        data.decrease_pending_tx(account, 1);
+        if let Some(to) = tx_type.payee() {
+            if to != account && !barrier {
+                data.decrease_pending_tx(to, 1);
+            }
+        }
+    }
+    /// Moves the idle accounts off drained handlers and those out of the pool, and as many
+    /// others as it takes for every handler left to own about as many accounts, returning how
+    /// many were moved. Has to hold every stripe.
+    pub(crate) fn rebalance(&mut self) -> usize {
+        let directory = self.directory;
+        let handlers = directory.handler_count();
+        let up: Vec<HandleId> = (0..handlers as HandleId)
+            .filter(|&id| directory.takes_accounts(id))
+            .collect();
+        if up.is_empty() {
+            return 0;
+        }
+        let mut owned = vec![Vec::new(); handlers];
+        for (_, owners) in &self.stripes {
+            for (&account, &id) in owners.iter() {
+                owned[id as usize].push(account);
+            }
+        }
+        let mut counts: Vec<usize> = owned.iter().map(Vec::len).collect();
+        let fair = counts.iter().sum::<usize>().div_ceil(up.len());
+        let mut moved = 0;
+        for (id, mut accounts) in owned.into_iter().enumerate() {
+            let keep = if directory.takes_accounts(id as HandleId) {
+                fair
+            } else {
+                0
+            };
+            accounts.sort_unstable();
+            for account in accounts {
+                if counts[id] <= keep {
+                    break;
+                }
+                if directory.lock_shard(id as HandleId).get_pending_tx(account) > 0 {
+                    continue;
+                }
+                let to = up
+                    .iter()
+                    .copied()
+                    .min_by_key(|&to| counts[to as usize])
+                    .expect("some handler is up");
+                self.move_account(account, to);
+                counts[id] -= 1;
+                counts[to as usize] += 1;
+                moved += 1;
+            }
+        }
+        moved
+    }
+    // Only called for idle accounts, which no handler is touching.
+    fn move_account(&mut self, account: AccountId, handle_id: HandleId) {
+        match self.owner(account) {
+            Some(id) if id == handle_id => {}
+            Some(id) => {
+                let (balances, holds, history, outflows) = {
+                    let mut data = self.directory.lock_shard(id);
+                    (
+                        data.take_balances(account),
+                        data.take_holds(account),
+                        data.take_history(account),
+                        data.take_outflows(account),
+                    )
+                };
+                let mut data = self.directory.lock_shard(handle_id);
+                if let Some(balances) = balances {
+                    data.set_balances(account, balances);
+                }
+                if let Some(holds) = holds {
+                    data.set_holds(account, holds);
+                }
+                if let Some(history) = history {
+                    data.set_history(account, history);
+                }
+                if let Some(outflows) = outflows {
+                    data.set_outflows(account, outflows);
+                }
+                drop(data);
+                self.owners_mut(account).insert(account, handle_id);
+            }
+            None => {
+                self.owners_mut(account).insert(account, handle_id);
+            }
+        }
+    }
+}
@@ -0,0 +1,1986 @@
+#[cfg(feature = "export")]
+use std::collections::BTreeMap;
+use std::io;
+#[cfg(feature = "export")]
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::{debug, error, field, info, info_span, Span};
+
+use crate::alerts::Alerts;
+use crate::audit::{Auditor, Replica};
+use crate::autoscale::{Autoscaler, Load};
+use crate::cache::BalanceCache;
+use crate::channel::{channel, Receiver};
+#[cfg(feature = "chaos")]
+use crate::chaos::Injector;
+use crate::cycle::{Cycle, Ticker};
+use crate::dead_letter::DeadLetters;
+use crate::directory::Directory;
+use crate::events::Events;
+use crate::executor::Executor;
+#[cfg(feature = "export")]
+use crate::export::{ExportedAccount, ExportedState, EXPORT_VERSION};
+use crate::handler::{self, Envelope, Message, Peers, TxHandler};
+use crate::latency::LatencyInjector;
+use crate::ledger::EventLog;
+use crate::metrics::{Metrics, MetricsServer};
+use crate::middleware::Pipeline;
+use crate::projection::{Projections, Projector};
+use crate::queue::Queue;
+use crate::rate_limit::RateLimiter;
+use crate::reconcile::Reconciliation;
+use crate::recording::Recorder;
+use crate::rules::RuleBook;
+use crate::scheduler::{Due, Schedule, Scheduler};
+use crate::sequence::{AccountSeq, Sequencer};
+use crate::snapshot::Snapshot;
+use crate::status::Tracker;
+use crate::supervisor::Supervisor;
+use crate::sync;
+use crate::tx;
+use crate::wal::{Seq, Wal};
+use crate::watchdog::{Overdue, Watchdog};
+use crate::webhook::Outbox;
+use crate::{
+    AccountId, Accrual, AptoneBuilder, AptoneStats, BackpressurePolicy, BalanceSnapshot, Clock,
+    Config, Currency, DeadLetter, FeeSchedule, HandleId, HandlerHealth, HandlerStats, HistoryEntry,
+    HistoryQuery, HoldId, InterestPolicy, LedgerEvent, Money, OrderId, Projection,
+    ProjectionHandle, RateLimitPolicy, RetryPolicy, Rules, ServerData, Settlement, ShutdownError,
+    Statement, Storage, Tx, TxCount, TxError, TxEvent, TxId, TxReceipt, TxResult, TxStatus, TxType,
+    Violation, INVALID_HANDLE,
+};
+
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How long a handler may go without reporting in before `Aptone::health` takes it for hung.
+/// Idle handlers report in several times within it.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct Aptone {
+    engine: Arc<Engine>,
+    schedule: Arc<Schedule>,
+    settlements: Option<Arc<Cycle>>, // when the next settlement falls due, if they are scheduled
+    interest: Option<Arc<Cycle>>,    // when interest is paid next, if it is
+    metrics_server: Option<MetricsServer>,
+    _outbox: Option<Outbox>, // delivers applied transactions to the webhooks
+    _projector: Option<Projector>, // feeds the projections the events recorded
+    _autoscaler: Option<Autoscaler>, // resizes the pool by the configured policy
+    _scheduler: Option<Scheduler>, // submits scheduled transactions as they fall due
+    _supervisor: Option<Supervisor>, // restarts handler threads that die
+    _watchdog: Option<Watchdog>, // looks out for transactions stuck on their queues
+    _auditor: Option<Auditor>, // checks the invariants by the configured policy
+    _settler: Option<Ticker>, // settles as settlements fall due
+    _accruer: Option<Ticker>, // pays interest as it falls due
+}
+
+// What submitting takes, shared with the scheduler's thread.
+struct Engine {
+    directory: Directory,
+    cache: Arc<BalanceCache>, // what balance reads go by
+    ledger: Option<Arc<EventLog>>,
+    replica: Mutex<Replica>, // what the ledger adds up to as of the last audit
+    projections: Option<Arc<Projections>>,
+    handles: Arc<Vec<TxHandler>>,
+    queues: Arc<Vec<Queue>>,
+    sequencer: Arc<Sequencer>,
+    paused: AtomicBool,
+    channel_capacity: usize,
+    backpressure: BackpressurePolicy,
+    dropped_tx: AtomicU64,
+    accepting: AtomicBool,
+    frozen: AtomicBool, // intake held off while settling
+    accruals: Vec<Arc<dyn Accrual>>,
+    interest: Option<InterestPolicy>,
+    fees: Option<FeeSchedule>,
+    settled: Mutex<(u64, Instant)>, // the last settlement and when it was, or the start
+    wal: Option<Mutex<Wal>>,
+    storage: Option<Arc<dyn Storage>>,
+    recorder: Option<Recorder>,
+    next_account: AtomicU32,
+    opening: Mutex<()>, // held while opening an account, which takes its id only once open
+    account_ids: Range<AccountId>,
+    next_hold: AtomicU64,
+    cross_in_flight: Arc<AtomicUsize>, // transfers between handlers between debit and credit
+    batches_in_flight: Arc<AtomicUsize>,
+    rate_limiter: Option<RateLimiter>,
+    rate_limit_policy: RateLimitPolicy,
+    retry: RetryPolicy,
+    rules: RuleBook,
+    tracker: Arc<Tracker>,
+    events: Arc<Events>,
+    alerts: Arc<Alerts>,
+    dead_letters: Arc<DeadLetters>,
+    metrics: Arc<Metrics>,
+    clock: Arc<dyn Clock>,
+    executor: Option<Executor>, // runs the handlers in deterministic mode, which has no threads
+    peers: Peers,               // what cancelling a queued transaction takes
+    started: Instant,
+}
+
+impl Aptone {
+    pub fn new() -> Aptone {
+        Aptone::with_config(Config::default())
+    }
+    pub fn builder() -> AptoneBuilder {
+        AptoneBuilder::new()
+    }
+    /// Panics for a config the engine can't run on, see `try_with_config`.
+    pub fn with_config(config: Config) -> Aptone {
+        Aptone::try_with_config(config).unwrap_or_else(|error| panic!("{error}"))
+    }
+    /// Starts on `config`, or fails with `InvalidInput` for one the engine can't run on: any the
+    /// builder would have panicked on, such as a settlement policy with a zero interval or a
+    /// zero rate limit, and one with no handler threads or fewer slots than it starts with.
+    pub fn try_with_config(config: Config) -> io::Result<Aptone> {
+        Aptone::start(config, ServerData::new(), None, None)
+    }
+    /// Restores the balances recorded in the transaction log at `path` and keeps appending to it.
+    pub fn recover<P: AsRef<Path>>(path: P) -> io::Result<Aptone> {
+        Aptone::recover_with_config(Config::default(), path)
+    }
+    pub fn recover_with_config<P: AsRef<Path>>(config: Config, path: P) -> io::Result<Aptone> {
+        let mut data = config.replay_shard();
+        let wal = Wal::open(path.as_ref(), config.checkpoint_interval, &mut data)?;
+        Aptone::start(config, data, Some(wal), None)
+    }
+    /// Restores the balances persisted in `storage` and keeps persisting to it.
+    pub fn persist_with_config(config: Config, storage: Arc<dyn Storage>) -> io::Result<Aptone> {
+        let stored = storage.load()?;
+        let mut data = config.replay_shard();
+        for (account, currency, balance) in stored.balances {
+            data.set_balance(account, currency, balance);
+        }
+        let aptone = Aptone::start(config, data, None, Some(storage))?;
+        aptone
+            .engine
+            .next_account
+            .store(stored.next_account, Ordering::SeqCst);
+        Ok(aptone)
+    }
+    // `restored` seeds the handlers with balances recovered from a log or a storage.
+    fn start(
+        config: Config,
+        mut restored: ServerData,
+        wal: Option<Wal>,
+        storage: Option<Arc<dyn Storage>>,
+    ) -> io::Result<Aptone> {
+        config.check()?;
+        let slots = config
+            .max_threads
+            .unwrap_or(config.threads)
+            .max(config.autoscale.map_or(0, |policy| policy.max));
+        let started = config.clock.now();
+        let mut handlers = Vec::with_capacity(slots);
+
+        // in deterministic mode the executor drains the queues one by one instead
+        let shared = config.shared_queue && config.deterministic_seed.is_none();
+        let cache = Arc::new(BalanceCache::new(config.lock_stripes));
+        let ledger = config
+            .record_events
+            .then(|| Arc::new(EventLog::new(Arc::clone(&config.clock))));
+        let mut directory = Directory::new(
+            slots,
+            config.lock_stripes,
+            Arc::clone(&config.router),
+            || {
+                let data = config.shard().with_balance_cache(Arc::clone(&cache));
+                match &ledger {
+                    Some(ledger) => data.with_event_log(Arc::clone(ledger)),
+                    None => data,
+                }
+            },
+        );
+        directory.set_active_count(config.threads);
+        if shared {
+            directory.share();
+        }
+        for (account, currency, balance) in restored.balances() {
+            directory.insert(account, currency, balance);
+        }
+        for (hold, currency, amount) in restored.holds() {
+            directory.insert_hold(hold, currency, amount);
+        }
+        for (account, history) in restored.take_histories() {
+            directory.insert_history(account, history);
+        }
+        let cross_in_flight = Arc::new(AtomicUsize::new(0));
+        let batches_in_flight = Arc::new(AtomicUsize::new(0));
+        let in_flight = Arc::new((0..slots).map(|_| Mutex::new(None)).collect());
+        let beats = Arc::new((0..slots).map(|_| Mutex::default()).collect());
+        let tracker = Arc::new(Tracker::new(config.dedup_window, Arc::clone(&config.clock)));
+        let events = Arc::new(Events::new(config.balance_thresholds.clone()));
+        let dead_letters = Arc::new(DeadLetters::new(
+            config.dead_letter_capacity,
+            Arc::clone(&config.clock),
+        ));
+        let metrics = Arc::new(Metrics::new(slots));
+        let middleware = Pipeline::new(config.middleware.clone());
+        let alerts = Arc::new(Alerts::new(
+            config.alert_thresholds,
+            config.alert_sinks.clone(),
+            slots,
+        ));
+        #[cfg(feature = "chaos")]
+        let faults = Arc::new(Injector::new(
+            config.faults.clone(),
+            slots,
+            Arc::clone(&config.clock),
+        ));
+        let latency = Arc::new(LatencyInjector::new(
+            config.latency,
+            config.deterministic_seed,
+            slots,
+            Arc::clone(&config.clock),
+        ));
+        let sequencer = Arc::new(Sequencer::new(config.lock_stripes));
+        // queue depth is bounded by the slots reserved in the directory
+        let queues = (0..slots).map(|id| match id {
+            0 if shared => Queue::shared(Arc::clone(&sequencer)),
+            _ => Queue::new(Arc::clone(&sequencer)),
+        });
+        let queues = Arc::new(queues.collect::<Vec<_>>());
+
+        let peers = || Peers {
+            queues: Arc::clone(&queues),
+            shards: directory.shards(),
+            tx_count: directory.tx_counts(),
+            cross_in_flight: Arc::clone(&cross_in_flight),
+            batches_in_flight: Arc::clone(&batches_in_flight),
+            tracker: Arc::clone(&tracker),
+            events: Arc::clone(&events),
+            dead_letters: Arc::clone(&dead_letters),
+            metrics: Arc::clone(&metrics),
+            clock: Arc::clone(&config.clock),
+            latency: Arc::clone(&latency),
+            middleware: middleware.clone(),
+            alerts: Arc::clone(&alerts),
+            storage: storage.clone(),
+            in_flight: Arc::clone(&in_flight),
+            beats: Arc::clone(&beats),
+            #[cfg(feature = "chaos")]
+            faults: Arc::clone(&faults),
+        };
+        let executor = config
+            .deterministic_seed
+            .map(|seed| Executor::new(peers(), seed));
+        for id in 0..slots {
+            let parked = id >= config.threads;
+            let id = id as HandleId;
+            let handler = match executor {
+                Some(_) => TxHandler::inline(id, Arc::clone(&queues)),
+                None if parked => TxHandler::parked(id, peers(), config.steal_threshold, shared),
+                None => TxHandler::new(id, peers(), config.steal_threshold, shared),
+            };
+            if parked {
+                handler.retire();
+            }
+            handlers.push(handler);
+        }
+        let handles = Arc::new(handlers);
+        let peers = peers();
+        // the engine is useful without metrics, so a failure to serve them isn't fatal
+        let metrics_server = config.metrics_addr.and_then(|addr| {
+            let server = MetricsServer::start(
+                addr,
+                Arc::clone(&metrics),
+                directory.tx_counts(),
+                directory.shards(),
+            );
+            server
+                .inspect_err(|err| error!(%err, %addr, "failed to start the metrics server"))
+                .ok()
+        });
+        // nor is one to start the webhooks, though they miss what's applied until a restart
+        let outbox = if config.webhooks.is_empty() {
+            None
+        } else {
+            let dir = config.outbox_dir.as_deref();
+            Outbox::start(&config.webhooks, dir, events.subscribe())
+                .inspect_err(|err| error!(%err, "failed to start the webhook outbox"))
+                .ok()
+        };
+        let recorder = config.recording.as_deref().and_then(|path| {
+            Recorder::create(path, Arc::clone(&config.clock))
+                .inspect_err(|err| error!(%err, "failed to start recording"))
+                .ok()
+        });
+        let engine = Arc::new(Engine {
+            directory,
+            cache,
+            projections: ledger
+                .as_ref()
+                .map(|ledger| Arc::new(Projections::new(Arc::clone(ledger)))),
+            ledger,
+            replica: Mutex::default(),
+            handles,
+            queues,
+            sequencer,
+            paused: AtomicBool::new(false),
+            channel_capacity: config.channel_capacity,
+            backpressure: config.backpressure,
+            dropped_tx: AtomicU64::new(0),
+            accepting: AtomicBool::new(true),
+            frozen: AtomicBool::new(false),
+            accruals: config.accruals.clone(),
+            interest: config.interest,
+            fees: config.fees.clone(),
+            settled: Mutex::new((0, started)),
+            next_account: AtomicU32::new(wal.as_ref().map_or(0, Wal::next_account)),
+            opening: Mutex::new(()),
+            account_ids: config.account_ids.clone(),
+            next_hold: AtomicU64::new(wal.as_ref().map_or(0, Wal::next_hold)),
+            wal: wal.map(Mutex::new),
+            storage,
+            recorder,
+            cross_in_flight,
+            batches_in_flight,
+            rate_limiter: config.rate_limit.map(|limit| {
+                RateLimiter::new(limit, config.lock_stripes, Arc::clone(&config.clock))
+            }),
+            rate_limit_policy: config.rate_limit_policy,
+            retry: config.retry,
+            rules: RuleBook::new(config.rules.clone(), Arc::clone(&config.clock)),
+            tracker,
+            events,
+            alerts,
+            dead_letters,
+            metrics,
+            clock: config.clock,
+            executor,
+            peers,
+            started,
+        });
+        let supervisor = engine.executor.is_none().then(|| {
+            let retirer = Arc::clone(&engine);
+            Supervisor::start(Arc::clone(&engine.handles), move || retirer.retire_idle())
+        });
+        let autoscaler = config
+            .autoscale
+            .filter(|_| engine.executor.is_none())
+            .map(|policy| {
+                let (sampler, resizer) = (Arc::clone(&engine), Arc::clone(&engine));
+                Autoscaler::start(
+                    policy,
+                    move || sampler.load(),
+                    move |handlers| {
+                        resizer.resize(handlers);
+                    },
+                )
+            });
+        let schedule = Arc::new(Schedule::default());
+        // in deterministic mode `run_until_idle` submits what fell due instead
+        let scheduler = engine.executor.is_none().then(|| {
+            let submitter = Arc::clone(&engine);
+            Scheduler::start(
+                Arc::clone(&schedule),
+                Arc::clone(&engine.clock),
+                move |due| submitter.submit_due(due),
+            )
+        });
+        // in deterministic mode `run_until_idle` feeds the projections instead
+        let projector = engine
+            .projections
+            .as_ref()
+            .filter(|_| engine.executor.is_none())
+            .map(|projections| Projector::start(Arc::clone(projections)));
+        let watchdog = config
+            .alert_thresholds
+            .stuck_tx
+            .filter(|_| engine.executor.is_none())
+            .map(|deadline| {
+                let (watched, rerouter) = (Arc::clone(&engine), Arc::clone(&engine));
+                // with a shared queue there is no other queue to go to
+                let reroute = (config.reroute_stuck && !config.shared_queue)
+                    .then_some(move || rerouter.reroute_overdue(deadline));
+                Watchdog::start(
+                    deadline,
+                    Arc::clone(&engine.alerts),
+                    move || watched.overdue(deadline),
+                    reroute,
+                )
+            });
+        let settlements = config.settlement.map(|policy| {
+            let (now, wall) = (engine.clock.now(), engine.clock.wall_time());
+            Arc::new(Cycle::aligned(policy.at, policy.every, now, wall))
+        });
+        let interest = config.interest.map(|policy| {
+            let now = engine.clock.now();
+            Arc::new(Cycle::new(policy.compounding, now))
+        });
+        // in deterministic mode `run_until_idle` settles and pays interest instead
+        let ticker = |cycle: &Option<Arc<Cycle>>, run: fn(&Engine, Duration)| {
+            let cycle = cycle.as_ref().filter(|_| engine.executor.is_none())?;
+            let engine = Arc::clone(&engine);
+            let clock = Arc::clone(&engine.clock);
+            Some(Ticker::start(Arc::clone(cycle), clock, move |since| {
+                run(&engine, since)
+            }))
+        };
+        let settler = ticker(&settlements, |engine, _| {
+            engine.settle();
+        });
+        let accruer = ticker(&interest, Engine::pay_interest);
+        let auditor = config
+            .audit
+            .filter(|_| engine.executor.is_none())
+            .map(|policy| {
+                let auditee = Arc::clone(&engine);
+                Auditor::start(policy, move || auditee.audit())
+            });
+        Ok(Aptone {
+            engine,
+            schedule,
+            settlements,
+            interest,
+            metrics_server,
+            _outbox: outbox,
+            _projector: projector,
+            _autoscaler: autoscaler,
+            _scheduler: scheduler,
+            _supervisor: supervisor,
+            _watchdog: watchdog,
+            _auditor: auditor,
+            _settler: settler,
+            _accruer: accruer,
+        })
+    }
+    pub fn handle_tx(
+        &self,
+        account: AccountId,
+        amount: Money,
+        tx_type: TxType,
+    ) -> Result<TxReceipt, TxError> {
+        self.engine
+            .submit(None, &[Tx::new(account, amount, tx_type)])
+    }
+    /// Like `handle_tx`, but fails with `TxError::Duplicate` if a transaction with the same `key`
+    /// was submitted within the dedup window, so a retried submission is applied at most once.
+    /// The key is freed again if the submission fails before reaching a handler.
+    pub fn handle_tx_with_key(
+        &self,
+        key: &str,
+        account: AccountId,
+        amount: Money,
+        tx_type: TxType,
+    ) -> Result<TxReceipt, TxError> {
+        self.engine
+            .submit(Some(key), &[Tx::new(account, amount, tx_type)])
+    }
+    /// Like `handle_tx`, for a transaction in any currency.
+    pub fn submit_tx(&self, tx: Tx) -> Result<TxReceipt, TxError> {
+        self.engine.submit(None, &[tx])
+    }
+    pub fn submit_tx_with_key(&self, key: &str, tx: Tx) -> Result<TxReceipt, TxError> {
+        self.engine.submit(Some(key), &[tx])
+    }
+    /// Applies `legs` in order as one transaction, under one id: either all of them or, should
+    /// one fail, none, with the receipt holding the first failure. All the accounts the legs
+    /// touch go to one handler, so a batch over accounts pinned to different handlers waits for
+    /// them to drain before it is queued. Checkpoints are put off while a batch is in flight.
+    pub fn submit_batch(&self, legs: Vec<Tx>) -> Result<TxReceipt, TxError> {
+        if legs.is_empty() {
+            self.engine.metrics.reject(&TxError::EmptyBatch);
+            return Err(TxError::EmptyBatch);
+        }
+        self.engine.submit(None, &legs)
+    }
+    /// Holds `tx` back until `execute_at` by the engine's clock, then submits it as `submit_tx`
+    /// would, and gives back the id it will be submitted under. Until then its status is
+    /// `TxStatus::Scheduled`; a failure to queue it once due shows in its status and events only.
+    /// The schedule is kept in memory: neither a restart nor `shutdown` carries it out.
+    pub fn schedule(&self, tx: Tx, execute_at: Instant) -> Result<TxId, TxError> {
+        self.engine.check_amounts(std::slice::from_ref(&tx))?;
+        // held so `shutdown` can't clear the schedule between the check and the insert
+        let _accounts = self.engine.directory.lock(tx.account, tx.tx_type);
+        if !self.engine.accepting.load(Ordering::SeqCst) {
+            self.engine.metrics.reject(&TxError::ShuttingDown);
+            return Err(TxError::ShuttingDown);
+        }
+        // without a key there's nothing to be a duplicate of
+        let tx_id = self.engine.tracker.begin(None)?;
+        self.engine.tracker.mark(tx_id, TxStatus::Scheduled);
+        self.schedule.insert(execute_at, tx_id, tx);
+        Ok(tx_id)
+    }
+    /// Takes a scheduled transaction off the schedule, leaving its status `TxStatus::Cancelled`.
+    /// False if it isn't waiting there, because it was submitted or cancelled already.
+    pub fn cancel_scheduled(&self, tx_id: TxId) -> bool {
+        let cancelled = self.schedule.cancel(tx_id).is_some();
+        if cancelled {
+            self.engine.tracker.mark(tx_id, TxStatus::Cancelled);
+        }
+        cancelled
+    }
+    /// Takes a transaction off the schedule, as `cancel_scheduled` does, or off its handler's
+    /// queue before a handler has taken it up, failing its receipt with `TxError::Cancelled`.
+    /// Either way its status is `TxStatus::Cancelled` from then on. False if it is neither
+    /// scheduled nor queued, being applied or through already, and always false for a queued
+    /// transaction with a transaction log, which would apply it again on recovery.
+    pub fn cancel(&self, tx_id: TxId) -> bool {
+        self.cancel_scheduled(tx_id) || self.engine.cancel(tx_id)
+    }
+    /// Submits `tx` at `first` and every `every` after, up to `until` if one is given, each time
+    /// as a transaction of its own whose outcome shows in its events. An order left behind, as
+    /// when the clock jumps, catches up on every occurrence it missed. Kept in memory, like
+    /// `schedule`, and stopped by `shutdown`. Panics if `every` is zero.
+    pub fn standing_order(
+        &self,
+        tx: Tx,
+        first: Instant,
+        every: Duration,
+        until: Option<Instant>,
+    ) -> Result<OrderId, TxError> {
+        assert!(
+            !every.is_zero(),
+            "a standing order needs a nonzero interval"
+        );
+        self.engine.check_amounts(std::slice::from_ref(&tx))?;
+        let _accounts = self.engine.directory.lock(tx.account, tx.tx_type);
+        if !self.engine.accepting.load(Ordering::SeqCst) {
+            self.engine.metrics.reject(&TxError::ShuttingDown);
+            return Err(TxError::ShuttingDown);
+        }
+        Ok(self.schedule.insert_order(tx, first, every, until))
+    }
+    /// Stops a standing order. False if it ran its course or was stopped already.
+    pub fn cancel_standing_order(&self, order: OrderId) -> bool {
+        self.schedule.cancel_order(order)
+    }
+    /// Opens an account holding `initial_balance` in the default currency and gives back its id.
+    /// Transactions are only taken for open accounts.
+    pub fn open_account(&self, initial_balance: Money) -> Result<AccountId, TxError> {
+        if initial_balance.is_negative() {
+            return Err(TxError::InvalidAmount(initial_balance));
+        }
+        let ids = &self.engine.account_ids;
+        // a refused open leaves the id to the next one, rather than a gap in the ids
+        let _opening = self.engine.opening.lock().unwrap();
+        let account = self
+            .engine
+            .next_account
+            .load(Ordering::SeqCst)
+            .max(ids.start);
+        if account >= ids.end {
+            return Err(TxError::NoAccountsLeft);
+        }
+        let mut accounts = self.engine.directory.lock(account, TxType::DEPOSIT);
+        if !self.engine.accepting.load(Ordering::SeqCst) {
+            return Err(TxError::ShuttingDown);
+        }
+        // placed while the log is still locked, so a checkpoint sees both or neither
+        let mut wal = self.engine.wal.as_ref().map(|wal| wal.lock().unwrap());
+        if let Some(wal) = &mut wal {
+            wal.log_open(account, initial_balance)
+                .map_err(|err| TxError::Wal(err.kind()))?;
+        }
+        if let Some(storage) = &self.engine.storage {
+            storage
+                .open_account(account, initial_balance)
+                .map_err(|err| TxError::Storage(err.kind()))?;
+        }
+        accounts.open(account, initial_balance);
+        self.engine
+            .next_account
+            .store(account + 1, Ordering::SeqCst);
+        if let Some(recorder) = &self.engine.recorder {
+            recorder.opened(account, initial_balance);
+        }
+        Ok(account)
+    }
+    /// Closes `account` and gives back what it held in each currency. Refused while it has
+    /// transactions pending, which would otherwise apply to an account that is gone.
+    pub fn close_account(&self, account: AccountId) -> Result<Vec<(Currency, Money)>, TxError> {
+        let mut accounts = self.engine.directory.lock(account, TxType::DEPOSIT);
+        accounts.check_open(account, TxType::DEPOSIT)?;
+        let pending = accounts.get_pending_tx(account);
+        if pending > 0 {
+            return Err(TxError::AccountBusy { account, pending });
+        }
+        let mut wal = self.engine.wal.as_ref().map(|wal| wal.lock().unwrap());
+        if let Some(wal) = &mut wal {
+            wal.log_close(account)
+                .map_err(|err| TxError::Wal(err.kind()))?;
+        }
+        if let Some(storage) = &self.engine.storage {
+            storage
+                .close_account(account)
+                .map_err(|err| TxError::Storage(err.kind()))?;
+        }
+        if let Some(recorder) = &self.engine.recorder {
+            recorder.closed(account);
+        }
+        Ok(accounts.close(account))
+    }
+    pub fn withdraw(&self, account: AccountId, amount: Money) -> Result<TxReceipt, TxError> {
+        self.handle_tx(account, amount, TxType::WITHDRAW)
+    }
+    pub fn deposit(&self, account: AccountId, amount: Money) -> Result<TxReceipt, TxError> {
+        self.handle_tx(account, amount, TxType::DEPOSIT)
+    }
+    pub fn transfer(
+        &self,
+        from: AccountId,
+        to: AccountId,
+        amount: Money,
+    ) -> Result<TxReceipt, TxError> {
+        self.handle_tx(from, amount, TxType::TRANSFER { to })
+    }
+    /// Reserves `amount` of what `account` has available in the default currency, leaving its
+    /// balance alone until the hold is captured. The hold is in place once the receipt says the
+    /// authorization was applied.
+    pub fn authorize(
+        &self,
+        account: AccountId,
+        amount: Money,
+    ) -> Result<(HoldId, TxReceipt), TxError> {
+        let number = self.engine.next_hold.fetch_add(1, Ordering::SeqCst);
+        let hold = HoldId { account, number };
+        let receipt = self.handle_tx(account, amount, TxType::AUTHORIZE { hold })?;
+        Ok((hold, receipt))
+    }
+    /// Takes what `hold` reserved off the account's balance.
+    pub fn capture(&self, hold: HoldId) -> Result<TxReceipt, TxError> {
+        self.handle_tx(hold.account, Money::ZERO, TxType::CAPTURE { hold })
+    }
+    /// Drops `hold`, making what it reserved available again.
+    pub fn release(&self, hold: HoldId) -> Result<TxReceipt, TxError> {
+        self.handle_tx(hold.account, Money::ZERO, TxType::RELEASE { hold })
+    }
+    /// Undoes `original`, a deposit, withdrawal or capture applied to `account`, see
+    /// `TxType::REVERSAL`.
+    pub fn reverse(&self, account: AccountId, original: TxId) -> Result<TxReceipt, TxError> {
+        self.handle_tx(account, Money::ZERO, TxType::REVERSAL { original })
+    }
+    /// Converts `amount` of `from` in `account` into `to`, see `TxType::EXCHANGE`.
+    pub fn exchange(
+        &self,
+        account: AccountId,
+        amount: Money,
+        from: Currency,
+        to: Currency,
+    ) -> Result<TxReceipt, TxError> {
+        self.submit_tx(Tx::new(account, amount, TxType::EXCHANGE { to }).in_currency(from))
+    }
+    /// Number of transactions discarded under `BackpressurePolicy::Drop`.
+    pub fn dropped_tx(&self) -> u64 {
+        self.engine.dropped_tx.load(Ordering::Relaxed)
+    }
+    /// The balance in the default currency.
+    pub fn get_balance(&self, account: AccountId) -> Money {
+        self.get_balance_in(account, Currency::default())
+    }
+    /// Read from a copy of the balances the handlers keep up to date, so it never waits on one.
+    /// Reflects every transaction whose receipt is through.
+    pub fn get_balance_in(&self, account: AccountId, currency: Currency) -> Money {
+        self.engine.cache.get_balance(account, currency)
+    }
+    /// The balance in the default currency less what holds reserve of it.
+    pub fn get_available_balance(&self, account: AccountId) -> Money {
+        self.engine
+            .directory
+            .get_available_balance(account, Currency::default())
+    }
+    /// Every currency `account` holds, with its balance in it.
+    pub fn balances(&self, account: AccountId) -> Vec<(Currency, Money)> {
+        self.engine.cache.get_balances(account)
+    }
+    /// Transactions on `account` submitted but not through yet, barriers included.
+    pub fn get_pending_tx(&self, account: AccountId) -> TxCount {
+        self.engine.directory.get_pending_tx(account)
+    }
+    /// Up to `limit` of the transactions applied to `account`, oldest first, skipping the first
+    /// `offset`. Covers what this engine applied, not what it recovered from a log.
+    pub fn history(&self, account: AccountId, limit: usize, offset: usize) -> Vec<HistoryEntry> {
+        self.engine.directory.history(account, limit, offset)
+    }
+    /// A search over the histories, see `HistoryQuery`, such as
+    /// `aptone.history_query().account(3).tx_type(TxKind::Withdraw).min_amount(100).fetch()`.
+    /// Covers what `history` does.
+    pub fn history_query(&self) -> HistoryQuery<'_> {
+        HistoryQuery::new(self)
+    }
+    pub(crate) fn with_history<T>(
+        &self,
+        account: AccountId,
+        f: impl FnOnce(&[HistoryEntry]) -> T,
+    ) -> T {
+        self.engine.directory.with_history(account, f)
+    }
+    /// The ids of every account opened so far, and of none the engine can't open.
+    pub(crate) fn opened_accounts(&self) -> Range<AccountId> {
+        let ids = &self.engine.account_ids;
+        let next = self.engine.next_account.load(Ordering::SeqCst);
+        ids.start..next.max(ids.start)
+    }
+    /// The balances of `account` at `from` and at `to`, with the transactions applied to it in
+    /// between, taken at one point like a `snapshot`. Covers what its history does; `None` for
+    /// an account that has no balance or history.
+    pub fn statement(
+        &self,
+        account: AccountId,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> Option<Statement> {
+        self.engine.quiesced(|shards| {
+            let mut balances = Vec::new();
+            let mut history = Vec::new();
+            for data in shards {
+                balances.extend(data.get_balances(account));
+                history.extend_from_slice(data.history(account));
+            }
+            if balances.is_empty() && history.is_empty() {
+                return None;
+            }
+            Some(Statement::new(account, from, to, balances, &history))
+        })
+    }
+    pub fn rules(&self) -> Rules {
+        self.engine.rules.rules()
+    }
+    /// Puts `rules` in force for every transaction submitted from now on.
+    pub fn set_rules(&self, rules: Rules) {
+        self.engine.rules.set(rules);
+    }
+    /// `set_rules` with the rules in a TOML or JSON file, see `Rules::load`. The rules in force
+    /// stay if the file can't be read. Only built with the `rules` feature.
+    #[cfg(feature = "rules")]
+    pub fn reload_rules(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let rules = Rules::load(path)?;
+        info!(?rules, "reloaded rules");
+        self.set_rules(rules);
+        Ok(())
+    }
+    /// The transactions rejected lately, oldest first, with why; up to
+    /// `Config::dead_letter_capacity` of them.
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.engine.dead_letters.list()
+    }
+    /// Takes `letter` off the dead letters and submits its legs, as corrected, under a new id:
+    /// one leg as `submit_tx` would, more as a batch. Should that fail, the letter is kept.
+    pub fn resubmit_dead_letter(&self, letter: DeadLetter) -> Result<TxReceipt, TxError> {
+        if letter.legs.is_empty() {
+            self.engine.metrics.reject(&TxError::EmptyBatch);
+            return Err(TxError::EmptyBatch);
+        }
+        if self.engine.dead_letters.take(letter.tx_id).is_none() {
+            let err = TxError::NotDeadLettered(letter.tx_id);
+            self.engine.metrics.reject(&err);
+            return Err(err);
+        }
+        self.engine
+            .submit(None, &letter.legs)
+            .inspect_err(|_| self.engine.dead_letters.put(letter))
+    }
+    /// Drops a dead letter for good, giving it back.
+    pub fn discard_dead_letter(&self, tx_id: TxId) -> Option<DeadLetter> {
+        self.engine.dead_letters.take(tx_id)
+    }
+    /// Receives an event for every transaction applied or rejected from now on, for every
+    /// configured balance threshold crossed and for every resize of the pool. Each subscriber
+    /// gets its own copy of each event.
+    pub fn subscribe(&self) -> Receiver<TxEvent> {
+        self.engine.events.subscribe()
+    }
+    /// The current metrics in the Prometheus text format, as served at `/metrics`.
+    pub fn metrics(&self) -> String {
+        self.engine.metrics.render(
+            &self.engine.directory.tx_counts(),
+            &self.engine.directory.shards(),
+        )
+    }
+    /// Queue depths, counts of finished transactions and accounts, per handler and overall.
+    pub fn stats(&self) -> AptoneStats {
+        let shards = self.engine.directory.lock_all();
+        let handlers: Vec<_> = shards
+            .iter()
+            .enumerate()
+            .map(|(id, data)| {
+                let id = id as HandleId;
+                let (applied, rejected) = self.engine.metrics.handled(id);
+                let (latency, latency_by_type) = self.engine.metrics.latency(Some(id));
+                HandlerStats {
+                    queue_depth: self.engine.directory.get_tx_count(id),
+                    applied,
+                    rejected,
+                    active_accounts: data.active_account_count(),
+                    accounts: data.account_count(),
+                    drained: self.engine.directory.is_drained(id),
+                    retired: id as usize >= self.engine.directory.active_count(),
+                    latency,
+                    latency_by_type,
+                }
+            })
+            .collect();
+        let (latency, latency_by_type) = self.engine.metrics.latency(None);
+        AptoneStats {
+            applied: self.engine.metrics.applied(),
+            rejected: self.engine.metrics.rejected(),
+            active_accounts: handlers.iter().map(|handler| handler.active_accounts).sum(),
+            accounts: handlers.iter().map(|handler| handler.accounts).sum(),
+            uptime: self
+                .engine
+                .clock
+                .now()
+                .saturating_duration_since(self.engine.started),
+            latency,
+            latency_by_type,
+            handlers,
+        }
+    }
+    /// How each handler is doing, indexed by handler id: whether its thread is alive and
+    /// reporting in, at least every `HEARTBEAT_TIMEOUT`, when it last did and last finished a
+    /// message, and how deep its queue is. A handler busy with one transaction for longer than
+    /// the timeout shows as not alive, as does every handler in deterministic mode, which has no
+    /// threads.
+    pub fn health(&self) -> Vec<HandlerHealth> {
+        let now = self.engine.clock.now();
+        let handlers = self.engine.handles.iter().enumerate();
+        let health = handlers.map(|(id, handler)| {
+            let beat = *self.engine.peers.beats[id].lock().unwrap();
+            let beating = beat.heartbeat.is_some_and(|heartbeat| {
+                now.saturating_duration_since(heartbeat) <= HEARTBEAT_TIMEOUT
+            });
+            HandlerHealth {
+                alive: beating && handler.is_running(),
+                last_heartbeat: beat.heartbeat,
+                last_processed: beat.processed,
+                queue_depth: self.engine.directory.get_tx_count(id as HandleId),
+                retired: id >= self.engine.directory.active_count(),
+            }
+        });
+        health.collect()
+    }
+    /// The events the handlers applied to the account state from the `from`th on, in the order
+    /// they were applied in; none unless built with `AptoneBuilder::record_events`.
+    /// `ServerData::replay` gives back the balances and holds they add up to.
+    pub fn ledger_events(&self, from: usize) -> Vec<LedgerEvent> {
+        self.engine
+            .ledger
+            .as_ref()
+            .map_or(Vec::new(), |ledger| ledger.read(from))
+    }
+    /// Registers `projection` to be fed every event recorded, from the first, on a background
+    /// thread. Needs `AptoneBuilder::record_events`.
+    pub fn project<P: Projection>(&self, projection: P) -> ProjectionHandle<P> {
+        let projections = self.engine.projections.as_ref();
+        let projections = projections.expect("projections need AptoneBuilder::record_events");
+        projections.register(projection)
+    }
+    /// Every balance and pending count at one point, with no transaction half applied. Waits for
+    /// the transfers between handlers halfway through, so while `pause`d it may wait for
+    /// `resume`. Submissions wait while it runs.
+    pub fn snapshot(&self) -> BalanceSnapshot {
+        self.engine.snapshot()
+    }
+    /// Closes the books: holds off new transactions, takes a `snapshot`, sends subscribers a
+    /// `TxEvent::Settled` for every account in it, then submits what the configured accruals
+    /// come to on each balance and waits for them before taking transactions again. Runs on its
+    /// own by `Config::settlement`; settlements never overlap. While `pause`d it waits for
+    /// `resume`.
+    pub fn settle(&self) -> Settlement {
+        self.engine.settle()
+    }
+    /// Compares every balance in the default currency, taken at one point like a `snapshot`, with
+    /// the balances of an external ledger, reporting the accounts that differ or that only one
+    /// side has, with the adjustments that would bring the engine in line.
+    pub fn reconcile(
+        &self,
+        external: impl IntoIterator<Item = (AccountId, Money)>,
+    ) -> Reconciliation {
+        Reconciliation::new(&self.snapshot(), external)
+    }
+    /// Checks the invariants at one point, like a `snapshot`, and gives back those broken: every
+    /// balance is what the events recorded for it add up to, with `AptoneBuilder::record_events`,
+    /// and what balance reads give; no handler's transaction count went below zero, nor is short
+    /// of its queue; and no account has transactions pending on a handler counting none.
+    pub fn audit(&self) -> Vec<Violation> {
+        self.engine.audit()
+    }
+    /// Every account with its balances, holds, pending count and history, taken at one point
+    /// like a `snapshot`, for `from_state`.
+    #[cfg(feature = "export")]
+    pub fn state(&self) -> ExportedState {
+        self.engine.quiesced(|shards| {
+            let mut accounts = BTreeMap::new();
+            for data in shards {
+                for (account, currency, balance) in data.balances() {
+                    let exported = accounts
+                        .entry(account)
+                        .or_insert_with(|| ExportedAccount::new(account));
+                    exported.balances.insert(currency, balance);
+                }
+                for (hold, currency, amount) in data.holds() {
+                    if let Some(exported) = accounts.get_mut(&hold.account) {
+                        exported.holds.insert(hold.number, (currency, amount));
+                    }
+                }
+                for (account, pending) in data.pending() {
+                    if let Some(exported) = accounts.get_mut(&account) {
+                        exported.pending += pending;
+                    }
+                }
+            }
+            for data in shards {
+                for (&account, exported) in accounts.iter_mut() {
+                    exported.history.extend_from_slice(data.history(account));
+                }
+            }
+            ExportedState {
+                version: EXPORT_VERSION,
+                next_account: self.engine.next_account.load(Ordering::SeqCst),
+                next_hold: self.engine.next_hold.load(Ordering::SeqCst),
+                next_tx: self.engine.tracker.next_id(),
+                accounts: accounts.into_values().collect(),
+            }
+        })
+    }
+    /// Writes the `state` to `writer` as JSON.
+    #[cfg(feature = "export")]
+    pub fn export_state(&self, writer: impl Write) -> io::Result<()> {
+        serde_json::to_writer(writer, &self.state())?;
+        Ok(())
+    }
+    /// An engine carrying on from the state `export_state` wrote to `reader`, without a log.
+    #[cfg(feature = "export")]
+    pub fn import_state(reader: impl Read) -> io::Result<Aptone> {
+        Aptone::import_state_with_config(Config::default(), reader)
+    }
+    #[cfg(feature = "export")]
+    pub fn import_state_with_config(config: Config, reader: impl Read) -> io::Result<Aptone> {
+        Aptone::from_state(config, serde_json::from_reader(reader)?)
+    }
+    /// An engine carrying on from `state`, without a log. Fails for a state of another
+    /// `EXPORT_VERSION`.
+    #[cfg(feature = "export")]
+    pub fn from_state(config: Config, state: ExportedState) -> io::Result<Aptone> {
+        if state.version != EXPORT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("cannot read state version {}", state.version),
+            ));
+        }
+        let (mut next_account, mut next_hold, mut next_tx) =
+            (state.next_account, state.next_hold, state.next_tx);
+        let mut data = config.replay_shard();
+        for exported in state.accounts {
+            let account = exported.account;
+            next_account = next_account.max(account + 1);
+            if exported.balances.is_empty() {
+                data.open_account(account, Money::ZERO);
+            }
+            for (currency, balance) in exported.balances {
+                data.set_balance(account, currency, balance);
+            }
+            for (number, (currency, amount)) in exported.holds {
+                next_hold = next_hold.max(number + 1);
+                data.set_hold(HoldId { account, number }, currency, amount);
+            }
+            if let Some(last) = exported.history.iter().map(|entry| entry.tx_id).max() {
+                next_tx = next_tx.max(last + 1);
+            }
+            data.set_history(account, exported.history);
+        }
+        let aptone = Aptone::start(config, data, None, None)?;
+        let engine = &aptone.engine;
+        engine.next_account.store(next_account, Ordering::SeqCst);
+        engine.next_hold.store(next_hold, Ordering::SeqCst);
+        engine.tracker.resume_at(next_tx);
+        Ok(aptone)
+    }
+    /// Starts on the balances and holds a `replication::Follower` kept, giving out ids from
+    /// `next_account` and `next_hold` on.
+    #[cfg(feature = "replication")]
+    pub(crate) fn promoted(
+        config: Config,
+        replica: ServerData,
+        next_account: AccountId,
+        next_hold: u64,
+    ) -> io::Result<Aptone> {
+        let aptone = Aptone::start(config, replica, None, None)?;
+        let engine = &aptone.engine;
+        engine.next_account.store(next_account, Ordering::SeqCst);
+        engine.next_hold.store(next_hold, Ordering::SeqCst);
+        Ok(aptone)
+    }
+    /// The account and hold numbers the engine gives out next.
+    #[cfg(feature = "replication")]
+    pub(crate) fn next_ids(&self) -> (AccountId, u64) {
+        let engine = &self.engine;
+        let next_account = engine.next_account.load(Ordering::SeqCst);
+        (next_account, engine.next_hold.load(Ordering::SeqCst))
+    }
+    #[cfg(feature = "replication")]
+    pub(crate) fn records_events(&self) -> bool {
+        self.engine.ledger.is_some()
+    }
+    /// Grows or shrinks the pool to `handlers`, at most `Config::max_threads`, and rebalances
+    /// the accounts over it, returning how many were moved. Handlers taken out of the pool get
+    /// no more accounts and stop once they have finished what was queued on them; the accounts
+    /// they still own move once idle. Subscribers see a `TxEvent::Resized`. Panics for zero
+    /// handlers or more than the maximum.
+    pub fn resize_workers(&self, handlers: usize) -> usize {
+        self.engine.resize(handlers)
+    }
+    /// Handlers in the pool, see `resize_workers`.
+    pub fn worker_count(&self) -> usize {
+        self.engine.directory.active_count()
+    }
+    /// Keeps new accounts off `handler` and moves its idle accounts to the other handlers,
+    /// returning how many were moved. Accounts with transactions in flight stay until they are
+    /// idle and `rebalance` runs. While every handler is drained, draining is ignored. Panics for
+    /// a handler the engine doesn't have.
+    pub fn drain_handler(&self, handler: HandleId) -> usize {
+        self.engine.directory.set_drained(handler, true);
+        self.rebalance()
+    }
+    /// Lets `handler` take new accounts again after `drain_handler`.
+    pub fn undrain_handler(&self, handler: HandleId) {
+        self.engine.directory.set_drained(handler, false);
+    }
+    /// Moves idle accounts between handlers until each handler not drained owns about as many,
+    /// returning how many were moved. Submissions wait while it runs.
+    pub fn rebalance(&self) -> usize {
+        self.engine.rebalance()
+    }
+    /// Where metrics are served, if the server is up. Tells the port picked for port 0.
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_server.as_ref().map(MetricsServer::local_addr)
+    }
+    /// `None` for ids never handed out, and for transactions older than the dedup window.
+    pub fn get_tx_status(&self, tx_id: TxId) -> Option<TxStatus> {
+        self.engine.tracker.status(tx_id)
+    }
+    /// In deterministic mode, settles and pays interest if either fell due and submits the
+    /// scheduled transactions and standing orders due by the engine's clock, then processes
+    /// every queued transaction on the calling thread and returns once there is nothing left to
+    /// do, feeding the projections what that recorded; receipts only resolve through this.
+    /// Engines with handler threads process transactions on their own, and this does nothing.
+    pub fn run_until_idle(&self) {
+        if let Some(executor) = &self.engine.executor {
+            let now = self.engine.clock.now();
+            let due = |cycle: &Option<Arc<Cycle>>| cycle.as_ref()?.take_due(now);
+            if due(&self.settlements).is_some() {
+                self.engine.settle();
+            }
+            if let Some(since) = due(&self.interest) {
+                self.engine.pay_interest(since);
+            }
+            for due in self.schedule.take_due(self.engine.clock.now()) {
+                self.engine.submit_due(due);
+            }
+            executor.run_until_idle();
+            self.engine.retire_idle();
+            if let Some(projections) = &self.engine.projections {
+                projections.catch_up();
+            }
+        }
+    }
+    /// In deterministic mode, has `handler`, or one drawn from the seed with `None`, take one
+    /// step; `false` if it couldn't. Unlike `run_until_idle` nothing scheduled is submitted.
+    pub(crate) fn step(&self, handler: Option<HandleId>) -> bool {
+        match (&self.engine.executor, handler) {
+            (Some(executor), Some(id)) => executor.step_handler(id),
+            (Some(executor), None) => executor.step(),
+            (None, _) => false,
+        }
+    }
+    // Closes `handler`'s queue for good, as when its thread can't be started again.
+    pub(crate) fn kill_handler(&self, handler: HandleId) {
+        self.engine.handles[handler as usize].retire();
+    }
+    /// Has the handlers finish what they are working on and take nothing more off their queues
+    /// until `resume`. Submissions are still queued, up to the channel capacity. Waiting on a
+    /// receipt or `flush` blocks until then; in deterministic mode `run_until_idle` leaves the
+    /// queues alone.
+    pub fn pause(&self) {
+        info!("pausing handlers");
+        self.set_paused(true);
+    }
+    pub fn resume(&self) {
+        info!("resuming handlers");
+        self.set_paused(false);
+    }
+    pub fn is_paused(&self) -> bool {
+        self.engine.paused.load(Ordering::SeqCst)
+    }
+    fn set_paused(&self, paused: bool) {
+        self.engine.paused.store(paused, Ordering::SeqCst);
+        for queue in self.engine.queues.iter() {
+            queue.set_paused(paused);
+        }
+    }
+    /// Blocks until every transaction submitted so far has been applied or rejected, on every
+    /// handler. In deterministic mode they are processed on the calling thread instead.
+    pub fn flush(&self) {
+        if self.engine.executor.is_some() {
+            self.run_until_idle();
+            return;
+        }
+        while !self.engine.directory.idle() {
+            thread::sleep(QUEUE_POLL_INTERVAL);
+        }
+    }
+    /// Stops accepting transactions, lets every handler drain its queue and joins the threads,
+    /// resuming them if paused, then syncs the transaction log to disk. Gives up once `timeout`
+    /// has elapsed, leaving the remaining work to finish in the background.
+    pub fn shutdown(&self, timeout: Duration) -> Result<(), ShutdownError> {
+        let deadline = Instant::now().checked_add(timeout);
+        {
+            // taken so no submission can slip in between the flag and the termination messages
+            let _stripes = self.engine.directory.lock_stripes();
+            self.engine.accepting.store(false, Ordering::SeqCst);
+        }
+        if self.is_paused() {
+            self.resume();
+        }
+        for (tx_id, tx) in self.schedule.clear() {
+            self.engine
+                .fail_unqueued(tx_id, &tx, TxError::ShuttingDown, 1);
+        }
+
+        self.run_until_idle();
+        // handlers joined by an earlier call are skipped
+        let mut drained = true;
+        for handler in self.engine.handles.iter() {
+            handler.terminate();
+        }
+        for handler in self.engine.handles.iter() {
+            drained &= handler.join(deadline);
+        }
+        if self.engine.executor.is_some() {
+            // nothing runs in the background, so whatever is left is stuck
+            drained &= self.engine.directory.idle();
+        }
+        if let Some(wal) = &self.engine.wal {
+            if let Err(err) = wal.lock().unwrap().sync() {
+                error!(%err, "failed to sync the transaction log");
+            }
+        }
+
+        if drained {
+            Ok(())
+        } else {
+            let pending = (0..self.engine.handles.len())
+                .map(|id| self.engine.directory.get_tx_count(id as HandleId))
+                .sum();
+            Err(ShutdownError { pending })
+        }
+    }
+}
+
+impl Engine {
+    fn snapshot(&self) -> BalanceSnapshot {
+        self.quiesced(|shards| {
+            let mut snapshot = BalanceSnapshot::default();
+            for data in shards {
+                for (account, currency, balance) in data.balances() {
+                    let balances = snapshot.balances.entry(account).or_default();
+                    balances.insert(currency, balance);
+                }
+                for (account, pending) in data.pending() {
+                    *snapshot.pending.entry(account).or_default() += pending;
+                }
+            }
+            snapshot
+        })
+    }
+    fn settle(&self) -> Settlement {
+        let mut settled = self.settled.lock().unwrap();
+        self.frozen.store(true, Ordering::SeqCst);
+        let now = self.clock.now();
+        let period = now.saturating_duration_since(settled.1);
+        let balances = self.snapshot();
+        let number = settled.0 + 1;
+        *settled = (number, now);
+        info!(number, accounts = balances.balances.len(), "settling");
+        self.events.settled(number, &balances);
+        let mut accrued = Vec::new();
+        for (&account, held) in &balances.balances {
+            for (&currency, &balance) in held {
+                for accrual in &self.accruals {
+                    let amount = accrual.accrue(account, currency, balance, period);
+                    let tx_type = match amount {
+                        amount if amount.is_positive() => TxType::DEPOSIT,
+                        amount if amount.is_negative() => TxType::WITHDRAW,
+                        _ => continue,
+                    };
+                    let tx = Tx::new(account, amount.saturating_neg().max(amount), tx_type);
+                    accrued.push(tx.in_currency(currency));
+                }
+            }
+        }
+        let receipts: Vec<_> = accrued
+            .into_iter()
+            .map(|tx| {
+                let receipt = self.submit_system(std::slice::from_ref(&tx));
+                (tx, receipt)
+            })
+            .collect();
+        self.run_executor();
+        let accrued = receipts
+            .into_iter()
+            .filter_map(|(tx, receipt)| match receipt {
+                Ok(receipt) => Some((receipt.tx_id(), tx, receipt.wait())),
+                Err(err) => {
+                    error!(%err, account = tx.account, "failed to queue an accrual");
+                    None
+                }
+            });
+        let accrued = accrued.collect();
+        self.frozen.store(false, Ordering::SeqCst);
+        Settlement {
+            number,
+            balances,
+            accrued,
+        }
+    }
+    // Deposits the interest every positive balance earned over `period`, as of a `snapshot`,
+    // without waiting for the deposits to go through.
+    fn pay_interest(&self, period: Duration) {
+        let Some(policy) = self.interest else {
+            return;
+        };
+        let snapshot = self.snapshot();
+        let mut paid = 0;
+        for (&account, held) in &snapshot.balances {
+            for (&currency, &balance) in held {
+                let interest = policy.interest(balance, period);
+                if !interest.is_positive() {
+                    continue;
+                }
+                let tx = Tx::new(account, interest, TxType::DEPOSIT).in_currency(currency);
+                match self.submit_system(&[tx]) {
+                    Ok(_) => paid += 1,
+                    Err(err) => error!(%err, account, "failed to queue interest"),
+                }
+            }
+        }
+        debug!(paid, ?period, "paid interest");
+    }
+    // Holds a submission back while a settlement has intake frozen.
+    fn wait_for_intake(&self) {
+        while self.frozen.load(Ordering::SeqCst) {
+            self.yield_to_handlers();
+        }
+    }
+    // Runs `f` on every shard at a point where no transaction is half applied. Waits for the
+    // transfers between handlers halfway through, holding off submissions meanwhile.
+    fn quiesced<T>(&self, f: impl FnOnce(&[sync::MutexGuard<'_, ServerData>]) -> T) -> T {
+        let _accounts = self.directory.lock_every_account();
+        loop {
+            let shards = self.directory.lock_all();
+            // a transfer between handlers is counted from before its debit until after its
+            // credit, both of which happen under the shards' locks
+            if self.cross_in_flight.load(Ordering::SeqCst) > 0 {
+                drop(shards);
+                self.yield_to_handlers();
+                continue;
+            }
+            return f(&shards);
+        }
+    }
+    fn audit(&self) -> Vec<Violation> {
+        let mut replica = self.replica.lock().unwrap();
+        self.quiesced(|shards| {
+            let mut violations = Vec::new();
+            let recorded = self.ledger.as_ref().map(|ledger| replica.catch_up(ledger));
+            for data in shards {
+                for (account, currency, balance) in data.balances() {
+                    let cached = self.cache.get_balance(account, currency);
+                    if cached != balance {
+                        violations.push(Violation::StaleBalance {
+                            account,
+                            currency,
+                            balance,
+                            cached,
+                        });
+                    }
+                }
+            }
+            if let Some(recorded) = recorded {
+                let held = shards.iter().flat_map(|data| data.balances());
+                let mut accounts: Vec<_> = held
+                    .chain(recorded.balances())
+                    .map(|(account, currency, _)| (account, currency))
+                    .collect();
+                accounts.sort_unstable();
+                accounts.dedup();
+                for (account, currency) in accounts {
+                    let balance = shards
+                        .iter()
+                        .map(|data| data.get_balance(account, currency))
+                        .sum();
+                    let recorded = recorded.get_balance(account, currency);
+                    if balance != recorded {
+                        violations.push(Violation::Unrecorded {
+                            account,
+                            currency,
+                            balance,
+                            recorded,
+                        });
+                    }
+                }
+            }
+            for (id, data) in shards.iter().enumerate() {
+                let handler = id as HandleId;
+                let count = self.directory.get_tx_count(handler);
+                // no handler could count that many, only one that went below zero
+                if count > TxCount::MAX / 2 {
+                    violations.push(Violation::NegativeCount { handler, count });
+                    continue;
+                }
+                if count == 0 {
+                    for (account, pending) in data.pending() {
+                        violations.push(Violation::Uncounted {
+                            handler,
+                            account,
+                            pending,
+                        });
+                    }
+                }
+                // taken off the queue but not through yet, a transaction is counted and not queued
+                let queued = self.queues[id].len();
+                if queued > count as usize {
+                    violations.push(Violation::Overqueued {
+                        handler,
+                        queued,
+                        count,
+                    });
+                }
+            }
+            violations
+        })
+    }
+    fn resize(&self, handlers: usize) -> usize {
+        let slots = self.handles.len();
+        assert!(
+            (1..=slots).contains(&handlers),
+            "the pool holds from 1 to {} handlers",
+            slots
+        );
+        let before = {
+            let _accounts = self.directory.lock_every_account();
+            let before = self.directory.active_count();
+            for handler in self.handles.iter().take(handlers).skip(before) {
+                handler.start();
+            }
+            self.directory.set_active_count(handlers);
+            before
+        };
+        info!(from = before, to = handlers, "resized the handler pool");
+        if before != handlers {
+            self.events.resized(before, handlers);
+        }
+        let moved = self.rebalance();
+        self.retire_idle();
+        moved
+    }
+    fn rebalance(&self) -> usize {
+        let moved = self.directory.lock_every_account().rebalance();
+        info!(moved, "rebalanced accounts");
+        moved
+    }
+    fn load(&self) -> Load {
+        let (finished, latency_micros) = self.metrics.latency_total();
+        Load {
+            workers: self.directory.active_count(),
+            in_flight: (0..self.handles.len())
+                .map(|id| self.directory.get_tx_count(id as HandleId) as u64)
+                .sum(),
+            finished,
+            latency_micros,
+        }
+    }
+    // Stops the handlers out of the pool once nothing is queued on them.
+    fn retire_idle(&self) {
+        let retiring = |id: usize| !self.queues[id].is_closed();
+        let active = self.directory.active_count();
+        if !(active..self.handles.len()).any(retiring) {
+            return;
+        }
+        // shuts out submissions that could still queue something on them
+        let _accounts = self.directory.lock_every_account();
+        for id in self.directory.active_count()..self.handles.len() {
+            if retiring(id) && self.directory.get_tx_count(id as HandleId) == 0 {
+                info!(handler = id, "retiring handler");
+                self.handles[id].retire();
+            }
+        }
+    }
+    fn cancel(&self, tx_id: TxId) -> bool {
+        if self.wal.is_some() {
+            return false;
+        }
+        for (owner, queue) in self.queues.iter().enumerate() {
+            if let Some(envelope) = queue.cancel(tx_id) {
+                handler::cancel(owner as HandleId, &self.peers, envelope);
+                return true;
+            }
+        }
+        false
+    }
+    // The transactions on every queue submitted longer than `deadline` ago.
+    fn overdue(&self, deadline: Duration) -> Vec<Overdue> {
+        let now = self.clock.now();
+        let Some(before) = now.checked_sub(deadline) else {
+            return Vec::new();
+        };
+        let mut overdue = Vec::new();
+        for (handler, queue) in self.queues.iter().enumerate() {
+            for (tx_id, submitted) in queue.queued_before(before) {
+                overdue.push(Overdue {
+                    handler: handler as HandleId,
+                    tx_id,
+                    waited: now.duration_since(submitted),
+                });
+            }
+        }
+        overdue
+    }
+    // Hands transactions submitted longer than `deadline` ago to the idle handlers in the pool,
+    // one each, taking them off their queues as a thief would.
+    fn reroute_overdue(&self, deadline: Duration) {
+        let Some(before) = self.clock.now().checked_sub(deadline) else {
+            return;
+        };
+        // shuts out retiring handlers and shutting down, either of which would close a queue
+        // with a transaction rerouted onto it
+        let _accounts = self.directory.lock_every_account();
+        if !self.accepting.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut idle: Vec<_> = (0..self.directory.active_count())
+            .filter(|&id| {
+                self.directory.get_tx_count(id as HandleId) == 0 && !self.queues[id].is_closed()
+            })
+            .collect();
+        for (victim, queue) in self.queues.iter().enumerate() {
+            while !idle.is_empty() {
+                let Some((envelope, accounts)) = queue.steal_overdue(before) else {
+                    break;
+                };
+                let target = idle.pop().unwrap();
+                let (tx_id, victim) = (envelope.tx_id, victim as HandleId);
+                // holds the target back from retiring until it has taken the transaction up
+                self.directory
+                    .try_reserve(target as HandleId, self.channel_capacity);
+                match self.handles[target].send(Message::Rerouted(victim, Box::new(envelope))) {
+                    Ok(()) => info!(tx_id, victim, handler = target, "rerouted stuck tx"),
+                    Err(Message::Rerouted(_, envelope)) => {
+                        self.directory.release(target as HandleId);
+                        queue.requeue(Message::NewTx(envelope), &accounts);
+                    }
+                    Err(_) => unreachable!("a queue hands back what it was sent"),
+                }
+            }
+        }
+    }
+    // Submits one transaction, or the legs of a batch.
+    fn submit(&self, key: Option<&str>, legs: &[Tx]) -> Result<TxReceipt, TxError> {
+        self.wait_for_intake();
+        if let Some(recorder) = &self.recorder {
+            recorder.submitted(legs);
+        }
+        self.check_amounts(legs)?;
+        self.check_rules(legs)?;
+        let tx_id = self
+            .tracker
+            .begin(key)
+            .inspect_err(|err| self.metrics.reject(err))?;
+        let charged = self.charge(legs);
+        let (queued, attempts) = self.retrying(tx_id, || {
+            self.take_token(legs[0].account)?;
+            self.dispatch(tx_id, key, &charged)
+        });
+        queued.inspect_err(|err| {
+            self.tracker.abandon(tx_id, key);
+            if attempts > 1 {
+                self.dead_letters.push(tx_id, legs, err, attempts);
+            }
+        })
+    }
+    // `legs` each followed by the transfer of its fee, if fees are charged.
+    fn charge(&self, legs: &[Tx]) -> Vec<Tx> {
+        match &self.fees {
+            Some(fees) => fees.charge(legs),
+            None => legs.to_vec(),
+        }
+    }
+    // Submits a transaction the engine made itself, past a settlement's freeze. It is neither
+    // recorded, since replaying the recording makes it again, nor held to the rules or rate
+    // limits.
+    fn submit_system(&self, legs: &[Tx]) -> Result<TxReceipt, TxError> {
+        self.check_amounts(legs)?;
+        let tx_id = self.tracker.begin(None)?;
+        let (queued, attempts) = self.retrying(tx_id, || self.dispatch(tx_id, None, legs));
+        queued.inspect_err(|err| {
+            self.tracker.abandon(tx_id, None);
+            if attempts > 1 {
+                self.dead_letters.push(tx_id, legs, err, attempts);
+            }
+        })
+    }
+    // Tries `attempt` until it succeeds, fails for good or uses up the retry policy, backing off
+    // in between. Gives back the last outcome, counted if a rejection, and how many tries it
+    // took.
+    fn retrying<T>(
+        &self,
+        tx_id: TxId,
+        mut attempt: impl FnMut() -> Result<T, TxError>,
+    ) -> (Result<T, TxError>, u32) {
+        let mut attempts = 1;
+        loop {
+            let result = attempt();
+            match &result {
+                Err(err) if err.is_transient() && attempts < self.retry.max_attempts => {
+                    debug!(tx_id, attempts, %err, "retrying tx");
+                    self.clock.sleep(self.retry.delay(attempts));
+                    // in deterministic mode nothing drains the queues meanwhile otherwise
+                    self.run_executor();
+                    attempts += 1;
+                }
+                _ => {
+                    if let Err(err) = &result {
+                        self.metrics.reject(err);
+                    }
+                    return (result, attempts);
+                }
+            }
+        }
+    }
+    fn run_executor(&self) {
+        if let Some(executor) = &self.executor {
+            executor.run_until_idle();
+        }
+    }
+    fn check_amounts(&self, legs: &[Tx]) -> TxResult {
+        match legs
+            .iter()
+            .find(|leg| leg.tx_type.has_amount() && !leg.amount.is_positive())
+        {
+            Some(leg) => {
+                let err = TxError::InvalidAmount(leg.amount);
+                self.metrics.reject(&err);
+                Err(err)
+            }
+            None => Ok(()),
+        }
+    }
+    fn check_rules(&self, legs: &[Tx]) -> TxResult {
+        self.rules
+            .check(legs)
+            .inspect_err(|err| self.metrics.reject(err))
+    }
+    fn submit_due(&self, due: Due) {
+        match due {
+            Due::Once(tx_id, tx) => self.submit_scheduled(tx_id, tx),
+            // each occurrence is a transaction of its own; without a key `begin` never fails
+            Due::Order(order, tx) => {
+                if let Ok(tx_id) = self.tracker.begin(None) {
+                    debug!(order, tx_id, "standing order due");
+                    self.submit_scheduled(tx_id, tx);
+                }
+            }
+        }
+    }
+    // Charges a submission to `account`'s rate limit, if there is one.
+    fn take_token(&self, account: AccountId) -> TxResult {
+        let Some(limiter) = &self.rate_limiter else {
+            return Ok(());
+        };
+        while let Err(wait) = limiter.try_take(account) {
+            match self.rate_limit_policy {
+                RateLimitPolicy::Reject => return Err(TxError::RateLimited(account)),
+                RateLimitPolicy::Delay => self.clock.sleep(wait),
+            }
+        }
+        Ok(())
+    }
+    // Submits a scheduled transaction that fell due. Nobody waits on its receipt, so should it
+    // fail before reaching a handler, the failure goes where the handler would have put it.
+    fn submit_scheduled(&self, tx_id: TxId, tx: Tx) {
+        self.wait_for_intake();
+        self.tracker.mark(tx_id, TxStatus::Pending);
+        let legs = std::slice::from_ref(&tx);
+        if let Some(recorder) = &self.recorder {
+            recorder.submitted(legs);
+        }
+        if let Err(err) = self.check_rules(legs) {
+            return self.fail_unqueued(tx_id, &tx, err, 1);
+        }
+        let charged = self.charge(legs);
+        if let (Err(err), attempts) = self.retrying(tx_id, || self.dispatch(tx_id, None, &charged))
+        {
+            self.fail_unqueued(tx_id, &tx, err, attempts);
+        }
+    }
+    // Rejects a transaction nobody waits on that never reached a handler.
+    fn fail_unqueued(&self, tx_id: TxId, tx: &Tx, err: TxError, attempts: u32) {
+        let legs = std::slice::from_ref(tx);
+        self.dead_letters.push(tx_id, legs, &err, attempts);
+        let result = Err(err);
+        self.tracker.finish(tx_id, &result);
+        self.events.finished(tx_id, tx, &result, &[]);
+    }
+    // Queues a submission that has its id already, leaving the id to the caller should it fail.
+    fn dispatch(&self, tx_id: TxId, key: Option<&str>, legs: &[Tx]) -> Result<TxReceipt, TxError> {
+        let account = legs[0].account;
+        let _span = info_span!("submit", tx_id, account, handler = field::Empty).entered();
+        loop {
+            let queued = match legs {
+                [tx] => self.try_handle_tx(tx_id, tx),
+                legs => self.try_handle_batch(tx_id, legs),
+            };
+            let full = match queued {
+                Ok(Ok(receipt)) => {
+                    let id = receipt.handle_id();
+                    self.alerts.queue_depth(id, self.directory.get_tx_count(id));
+                    return Ok(receipt);
+                }
+                Ok(Err(id)) => id,
+                Err(err) => return Err(err),
+            };
+            match self.backpressure {
+                // give the handler a moment to catch up
+                BackpressurePolicy::Block => self.yield_to_handlers(),
+                BackpressurePolicy::Reject => return Err(TxError::QueueFull(full)),
+                BackpressurePolicy::Drop => {
+                    self.dropped_tx.fetch_add(1, Ordering::Relaxed);
+                    let result = Err(TxError::QueueFull(full));
+                    self.metrics.reject(&TxError::QueueFull(full));
+                    self.dead_letters
+                        .push(tx_id, legs, &TxError::QueueFull(full), 1);
+                    self.tracker.finish(tx_id, &result);
+                    self.tracker.release(key);
+                    for leg in legs {
+                        self.events.finished(tx_id, leg, &result, &[]);
+                    }
+                    return Ok(TxReceipt::ready(tx_id, full, result));
+                }
+            }
+        }
+    }
+    fn yield_to_handlers(&self) {
+        match &self.executor {
+            Some(executor) => executor.run_until_idle(),
+            None => thread::sleep(QUEUE_POLL_INTERVAL),
+        }
+    }
+    // Never blocks on a full queue while holding the accounts' locks, since that would stall
+    // every other submitter on them; hands back the id of the full handler instead.
+    fn try_handle_tx(&self, tx_id: TxId, tx: &Tx) -> Result<Result<TxReceipt, HandleId>, TxError> {
+        let (account, tx_type) = (tx.account, tx.tx_type);
+        let mut accounts = self.directory.lock(account, tx_type);
+
+        if !self.accepting.load(Ordering::SeqCst) {
+            return Err(TxError::ShuttingDown);
+        }
+        accounts.check_open(account, tx_type)?;
+
+        let (id, barrier) = accounts.route(account, tx_type);
+        let _crossing = barrier.map(|_| self.directory.lock_crossing());
+        if !self.directory.try_reserve(id, self.channel_capacity) {
+            return Ok(Err(id));
+        }
+        if let Some(to_id) = barrier {
+            if !self.directory.try_reserve(to_id, self.channel_capacity) {
+                self.directory.release(id);
+                return Ok(Err(to_id));
+            }
+        }
+        let release = || {
+            self.directory.release(id);
+            if let Some(to_id) = barrier {
+                self.directory.release(to_id);
+            }
+        };
+        let mut tx = tx.clone();
+        if let Err(err) = self.directory.claim_reversal(id, &mut tx) {
+            release();
+            return Err(err);
+        }
+        Span::current().record("handler", id);
+        debug!(
+            balance = %accounts.get_balance(account, tx.currency),
+            pending = accounts.get_pending_tx(account),
+            amount = %tx.amount,
+            currency = %tx.currency,
+            ?tx_type,
+            "queued tx"
+        );
+        assert!(id != INVALID_HANDLE);
+
+        let seq = match self.log(&tx, id) {
+            Ok(seq) => seq,
+            Err(err) => {
+                self.directory.unclaim_reversal(id, account, tx_type);
+                release();
+                return Err(err);
+            }
+        };
+
+        // `to` is pinned to another handler: park its queue there until the transfer is debited,
+        // so neither account sees its transactions reordered around the transfer
+        let mut credit = None;
+        let mut touched = tx::accounts_of([&tx]);
+        if let (Some(to), Some(to_id)) = (tx_type.payee(), barrier) {
+            let (credit_tx, credit_rx) = channel();
+            accounts.track_barrier(to, to_id);
+            let turn = self.sequencer.issue(to);
+            // the barrier was never queued, so nothing on either handler undoes the above
+            if self.handles[to_id as usize]
+                .send(Message::Barrier(to, turn, credit_rx))
+                .is_err()
+            {
+                self.sequencer.unissue(to, turn);
+                accounts.untrack_barrier(to, to_id);
+                self.unlog(seq, id);
+                self.directory.unclaim_reversal(id, account, tx_type);
+                release();
+                return Err(TxError::HandlerUnavailable(to_id));
+            }
+            credit = Some((to_id, credit_tx));
+            // the barrier takes the target's turn
+            touched.retain(|&account| account != to);
+        }
+        let turns = self.issue(&touched);
+
+        accounts.track_tx(account, tx_type, id, barrier.is_some());
+        let (reply, receiver) = channel::<TxResult>();
+        let sent = self.handles[id as usize].send(Message::NewTx(Box::new(Envelope {
+            tx_id,
+            tx,
+            seq,
+            reply,
+            submitted: self.clock.now(),
+            credit,
+            batch: Vec::new(),
+            restarts: 0,
+            turns: turns.clone(),
+            span: Span::current(),
+            queued: Some(info_span!("queued", handler = id)),
+        })));
+        if sent.is_err() {
+            // a barrier already queued is released by the dropped credit channel
+            self.unissue(&turns);
+            accounts.untrack_tx(account, tx_type, id, barrier.is_some());
+            self.unlog(seq, id);
+            self.directory.unclaim_reversal(id, account, tx_type);
+            self.directory.release(id);
+            return Err(TxError::HandlerUnavailable(id));
+        }
+        drop(accounts);
+
+        self.checkpoint_if_due();
+        Ok(Ok(TxReceipt::new(tx_id, id, receiver)))
+    }
+    // `try_handle_tx` for a batch, queued whole on the one handler its accounts all go to. Its
+    // fees are the exception: the account collecting them may stay with another handler, which
+    // credits them as it would a transfer's credit leg.
+    fn try_handle_batch(
+        &self,
+        tx_id: TxId,
+        legs: &[Tx],
+    ) -> Result<Result<TxReceipt, HandleId>, TxError> {
+        let mut touched = tx::accounts_of(legs);
+        let collector = tx::collector_of(legs);
+        let (mut accounts, id, barrier) = loop {
+            let accounts = self.directory.lock_accounts(&touched);
+            if !self.accepting.load(Ordering::SeqCst) {
+                return Err(TxError::ShuttingDown);
+            }
+            for leg in legs {
+                accounts.check_open(leg.account, leg.tx_type)?;
+            }
+            let pinned = collector.and_then(|to| Some((to, accounts.pinned_handle(to)?)));
+            let mut routed = touched.clone();
+            routed.retain(|&account| Some(account) != pinned.map(|(to, _)| to));
+            match accounts.route_batch(&routed) {
+                Some(id) => {
+                    let barrier = pinned.filter(|&(_, to_id)| to_id != id);
+                    break (accounts, id, barrier);
+                }
+                // handlers never take the directory's locks, so they drain without them
+                None => {
+                    drop(accounts);
+                    self.yield_to_handlers();
+                }
+            }
+        };
+        let _crossing = barrier.map(|_| self.directory.lock_crossing());
+        if !self.directory.try_reserve(id, self.channel_capacity) {
+            return Ok(Err(id));
+        }
+        if let Some((_, to_id)) = barrier {
+            if !self.directory.try_reserve(to_id, self.channel_capacity) {
+                self.directory.release(id);
+                return Ok(Err(to_id));
+            }
+        }
+        let release = || {
+            self.directory.release(id);
+            if let Some((_, to_id)) = barrier {
+                self.directory.release(to_id);
+            }
+        };
+        Span::current().record("handler", id);
+        let mut legs = legs.to_vec();
+        if let Err(err) = self.directory.claim_reversals(id, &mut legs) {
+            release();
+            return Err(err);
+        }
+        debug!(legs = legs.len(), "queued batch");
+
+        if let Err(err) = self.log_batch(&legs) {
+            self.directory.unclaim_reversals(id, &legs);
+            release();
+            return Err(err);
+        }
+        // park the collector's queue on its handler until the batch is staged, as for a transfer
+        let mut credit = None;
+        if let Some((to, to_id)) = barrier {
+            let (credit_tx, credit_rx) = channel();
+            accounts.track_barrier(to, to_id);
+            let seq = self.sequencer.issue(to);
+            // the barrier was never queued, so nothing on either handler undoes the above
+            if self.handles[to_id as usize]
+                .send(Message::Barrier(to, seq, credit_rx))
+                .is_err()
+            {
+                self.sequencer.unissue(to, seq);
+                accounts.untrack_barrier(to, to_id);
+                self.directory.unclaim_reversals(id, &legs);
+                self.batches_in_flight.fetch_sub(1, Ordering::SeqCst);
+                release();
+                return Err(TxError::HandlerUnavailable(to_id));
+            }
+            credit = Some((to_id, credit_tx));
+            // the barrier takes the collector's turn
+            touched.retain(|&account| account != to);
+        }
+        accounts.track_batch(&touched, id);
+        let turns = self.issue(&touched);
+        let (reply, receiver) = channel::<TxResult>();
+        let sent = self.handles[id as usize].send(Message::NewTx(Box::new(Envelope {
+            tx_id,
+            tx: legs[0].clone(),
+            seq: None,
+            reply,
+            submitted: self.clock.now(),
+            credit,
+            batch: legs[1..].to_vec(),
+            restarts: 0,
+            turns: turns.clone(),
+            span: Span::current(),
+            queued: Some(info_span!("queued", handler = id)),
+        })));
+        if sent.is_err() {
+            self.unissue(&turns);
+            accounts.untrack_batch(&touched, id);
+            self.directory.unclaim_reversals(id, &legs);
+            self.batches_in_flight.fetch_sub(1, Ordering::SeqCst);
+            self.directory.release(id);
+            return Err(TxError::HandlerUnavailable(id));
+        }
+        drop(accounts);
+
+        self.checkpoint_if_due();
+        Ok(Ok(TxReceipt::new(tx_id, id, receiver)))
+    }
+    // Numbers the next message on each of `accounts`, whose stripes are held.
+    fn issue(&self, accounts: &[AccountId]) -> Vec<(AccountId, AccountSeq)> {
+        let turns = accounts
+            .iter()
+            .map(|&account| (account, self.sequencer.issue(account)));
+        turns.collect()
+    }
+    fn unissue(&self, turns: &[(AccountId, AccountSeq)]) {
+        for &(account, seq) in turns {
+            self.sequencer.unissue(account, seq);
+        }
+    }
+    fn checkpoint_if_due(&self) {
+        if let Some(wal) = &self.wal {
+            let mut wal = wal.lock().unwrap();
+            if wal.checkpoint_due() {
+                // the transaction is already logged, so a failed checkpoint only delays truncation
+                if let Err(err) = self.checkpoint(&mut wal) {
+                    error!(%err, "checkpoint failed");
+                }
+            }
+        }
+    }
+    // Appends `tx` to the log and records it on the handler's shard in one go, so a checkpoint
+    // never sees it logged but not pending.
+    fn log(&self, tx: &Tx, id: HandleId) -> Result<Option<Seq>, TxError> {
+        let Some(wal) = &self.wal else {
+            return Ok(None);
+        };
+        let mut wal = wal.lock().unwrap();
+        let seq = wal.append(tx).map_err(|err| TxError::Wal(err.kind()))?;
+        self.directory.lock_shard(id).log_queued(seq, tx.clone());
+        Ok(Some(seq))
+    }
+    // Takes a logged transaction that never reached its handler off the shard's unapplied ones,
+    // so checkpoints don't carry it as pending.
+    fn unlog(&self, seq: Option<Seq>, id: HandleId) {
+        if let Some(seq) = seq {
+            self.directory.lock_shard(id).log_applied(seq);
+        }
+    }
+    // Logs a batch and counts it in flight in one go, so a checkpoint never sees it logged but
+    // not in flight. Batches aren't among the shards' unapplied transactions, so checkpoints
+    // wait for them instead.
+    fn log_batch(&self, legs: &[Tx]) -> TxResult {
+        let mut wal = self.wal.as_ref().map(|wal| wal.lock().unwrap());
+        if let Some(wal) = &mut wal {
+            wal.append_batch(legs)
+                .map_err(|err| TxError::Wal(err.kind()))?;
+        }
+        self.batches_in_flight.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+    // Takes every shard, so no handler is between applying a transaction and marking it applied.
+    // A transfer between handlers spans two shards, and a batch isn't among the unapplied
+    // transactions, so with either in flight the checkpoint is left for a later submission.
+    fn checkpoint(&self, wal: &mut Wal) -> io::Result<()> {
+        let shards = self.directory.lock_all();
+        if self.cross_in_flight.load(Ordering::SeqCst) > 0
+            || self.batches_in_flight.load(Ordering::SeqCst) > 0
+        {
+            return Ok(());
+        }
+        let mut pending: Vec<_> = shards.iter().flat_map(|data| data.unapplied()).collect();
+        pending.sort_by_key(|&(seq, _)| seq);
+        wal.checkpoint(Snapshot {
+            next_account: self.next_account.load(Ordering::SeqCst),
+            next_hold: self.next_hold.load(Ordering::SeqCst),
+            balances: shards.iter().flat_map(|data| data.balances()).collect(),
+            holds: shards.iter().flat_map(|data| data.holds()).collect(),
+            pending: pending.into_iter().map(|(_, tx)| tx.clone()).collect(),
+            ..Snapshot::default()
+        })
+    }
+}
+
+impl Default for Aptone {
+    fn default() -> Aptone {
+        Aptone::new()
+    }
+}
+
+impl Drop for Aptone {
+    fn drop(&mut self) {
+        info!("killing all threads");
+        if let Err(err) = self.shutdown(Duration::MAX) {
+            error!(%err, "shutdown failed");
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use crate::{AccountId, Currency, HandleId, HoldId, Money, TxCount, TxId};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxError {
+    InsufficientFunds {
+        account: AccountId,
+        balance: Money,
+        amount: Money,
+    },
+    UnknownAccount(AccountId),
+    /// An account can't be closed with transactions pending on it.
+    AccountBusy {
+        account: AccountId,
+        pending: TxCount,
+    },
+    /// The hold was captured or released already, or never placed.
+    UnknownHold(HoldId),
+    /// Reversals go by the account's history, which only covers what this engine applied.
+    UnknownTx {
+        account: AccountId,
+        tx_id: TxId,
+    },
+    /// Only deposits, withdrawals and captures can be reversed.
+    NotReversible(TxId),
+    AlreadyReversed(TxId),
+    /// Amounts have to be positive.
+    InvalidAmount(Money),
+    /// A batch needs at least one leg.
+    EmptyBatch,
+    Overflow(AccountId),
+    /// Taking the amount out would go past one of the account's velocity limits.
+    LimitExceeded {
+        account: AccountId,
+        limit: Money,
+        window: Duration,
+    },
+    /// No exchange rates are configured, or they have none between the two.
+    NoExchangeRate {
+        from: Currency,
+        to: Currency,
+    },
+    HandlerUnavailable(HandleId),
+    QueueFull(HandleId),
+    ShuttingDown,
+    /// Every id in `Config::account_ids` was given out.
+    NoAccountsLeft,
+    Wal(io::ErrorKind),
+    Storage(io::ErrorKind),
+    /// The idempotency key was already used by the given transaction.
+    Duplicate(TxId),
+    /// The transaction isn't among the dead letters, or was resubmitted already.
+    NotDeadLettered(TxId),
+    /// The account has submitted more than its rate limit lets it, see `RateLimit`.
+    RateLimited(AccountId),
+    /// A `TxMiddleware` turned the transaction down, for the reason given.
+    Rejected(String),
+    /// Taken off its handler's queue before it was applied, see `Aptone::cancel`.
+    Cancelled(TxId),
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxError::InsufficientFunds {
+                account,
+                balance,
+                amount,
+            } => write!(
+                f,
+                "insufficient funds in account {}: balance {}, requested {}",
+                account, balance, amount
+            ),
+            TxError::UnknownAccount(account) => write!(f, "account {} does not exist", account),
+            TxError::AccountBusy { account, pending } => write!(
+                f,
+                "account {} has {} transactions pending",
+                account, pending
+            ),
+            TxError::UnknownHold(hold) => write!(
+                f,
+                "hold {} on account {} does not exist",
+                hold.number, hold.account
+            ),
+            TxError::UnknownTx { account, tx_id } => write!(
+                f,
+                "transaction {} was not applied to account {}",
+                tx_id, account
+            ),
+            TxError::NotReversible(id) => write!(f, "transaction {} cannot be reversed", id),
+            TxError::AlreadyReversed(id) => write!(f, "transaction {} was already reversed", id),
+            TxError::InvalidAmount(amount) => write!(f, "invalid amount {}", amount),
+            TxError::EmptyBatch => write!(f, "batch has no transactions"),
+            TxError::Overflow(account) => write!(f, "balance overflow in account {}", account),
+            TxError::LimitExceeded {
+                account,
+                limit,
+                window,
+            } => write!(
+                f,
+                "account {} would take out more than {} within {:?}",
+                account, limit, window
+            ),
+            TxError::NoExchangeRate { from, to } => {
+                write!(f, "no exchange rate from {} to {}", from, to)
+            }
+            TxError::HandlerUnavailable(id) => write!(f, "handler {} is not running", id),
+            TxError::QueueFull(id) => write!(f, "queue of handler {} is full", id),
+            TxError::ShuttingDown => write!(f, "aptone is shutting down"),
+            TxError::NoAccountsLeft => write!(f, "no account ids left to give out"),
+            TxError::Wal(kind) => write!(f, "failed to log transaction: {}", kind),
+            TxError::Storage(kind) => write!(f, "failed to persist: {}", kind),
+            TxError::Duplicate(id) => write!(f, "duplicate of transaction {}", id),
+            TxError::NotDeadLettered(id) => {
+                write!(f, "transaction {} is not a dead letter", id)
+            }
+            TxError::RateLimited(account) => {
+                write!(f, "account {} is over its rate limit", account)
+            }
+            TxError::Rejected(reason) => write!(f, "transaction rejected: {}", reason),
+            TxError::Cancelled(id) => write!(f, "transaction {} was cancelled", id),
+        }
+    }
+}
+
+impl TxError {
+    /// Whether the same transaction may well go through if tried again later, as when a queue
+    /// was full or a handler was down.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            TxError::QueueFull(_) | TxError::HandlerUnavailable(_) | TxError::RateLimited(_)
+        )
+    }
+}
+
+impl Error for TxError {}
+
+/// Returned by `Aptone::shutdown` when the handlers could not drain in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownError {
+    pub pending: TxCount,
+}
+
+impl fmt::Display for ShutdownError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "shutdown timed out with {} transactions still pending",
+            self.pending
+        )
+    }
+}
+
+impl Error for ShutdownError {}
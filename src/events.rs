@@ -0,0 +1,154 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::channel::{channel, Receiver, Sender};
+use crate::{
+    AccountId, BalanceSnapshot, Currency, HistoryEntry, Money, Tx, TxError, TxId, TxResult,
+};
+
+/// Which way a balance moved across a threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Crossing {
+    /// From below the threshold to at or above it.
+    Up,
+    /// From at or above the threshold to below it.
+    Down,
+}
+
+/// Something the engine did, as seen by `Aptone::subscribe` receivers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxEvent {
+    Applied {
+        tx_id: TxId,
+        tx: Tx,
+    },
+    Rejected {
+        tx_id: TxId,
+        tx: Tx,
+        error: TxError,
+    },
+    /// Thresholds hold for every currency; `balance` is the account's balance in `currency`.
+    ThresholdCrossed {
+        account: AccountId,
+        currency: Currency,
+        threshold: Money,
+        balance: Money,
+        crossing: Crossing,
+    },
+    /// The handler pool was grown or shrunk, by `Aptone::resize_workers` or the autoscaler.
+    Resized {
+        from: usize,
+        to: usize,
+    },
+    /// What `account` held at the close of settlement number `settlement`, in every currency,
+    /// before anything accrued, see `Aptone::settle`.
+    Settled {
+        settlement: u64,
+        account: AccountId,
+        balances: Vec<(Currency, Money)>,
+    },
+}
+
+type Entry = (AccountId, HistoryEntry);
+
+/// Fans events out to every live subscriber.
+pub(crate) struct Events {
+    subscribers: Mutex<Vec<Sender<TxEvent>>>,
+    active: AtomicBool, // anyone subscribed, so handlers can skip building events
+    thresholds: Vec<Money>,
+}
+
+impl Events {
+    pub(crate) fn new(thresholds: Vec<Money>) -> Events {
+        Events {
+            subscribers: Mutex::new(Vec::new()),
+            active: AtomicBool::new(false),
+            thresholds,
+        }
+    }
+    pub(crate) fn subscribe(&self) -> Receiver<TxEvent> {
+        let (sender, receiver) = channel();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.push(sender);
+        self.active.store(true, Ordering::SeqCst);
+        receiver
+    }
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+    /// Reports the outcome of a transaction along with the history entries it produced.
+    pub(crate) fn finished(&self, tx_id: TxId, tx: &Tx, result: &TxResult, entries: &[Entry]) {
+        if !self.is_active() {
+            return;
+        }
+        let mut events = vec![match result {
+            Ok(()) => TxEvent::Applied {
+                tx_id,
+                tx: tx.clone(),
+            },
+            Err(error) => TxEvent::Rejected {
+                tx_id,
+                tx: tx.clone(),
+                error: error.clone(),
+            },
+        }];
+        events.extend(self.crossings(entries));
+        self.publish(events);
+    }
+    /// Reports the credit leg of a transfer applied by the handler owning its target.
+    pub(crate) fn credited(&self, entry: &Entry) {
+        if self.is_active() {
+            self.publish(self.crossings(std::slice::from_ref(entry)).collect());
+        }
+    }
+    pub(crate) fn resized(&self, from: usize, to: usize) {
+        if self.is_active() {
+            self.publish(vec![TxEvent::Resized { from, to }]);
+        }
+    }
+    pub(crate) fn settled(&self, settlement: u64, snapshot: &BalanceSnapshot) {
+        if !self.is_active() {
+            return;
+        }
+        let settled = snapshot.balances.iter().map(|(&account, balances)| {
+            let balances = balances.iter().map(|(&c, &b)| (c, b)).collect();
+            TxEvent::Settled {
+                settlement,
+                account,
+                balances,
+            }
+        });
+        self.publish(settled.collect());
+    }
+    fn crossings<'a>(&'a self, entries: &'a [Entry]) -> impl Iterator<Item = TxEvent> + 'a {
+        entries.iter().flat_map(move |(account, entry)| {
+            let (before, after) = (entry.balance_before(), entry.balance);
+            self.thresholds.iter().filter_map(move |&threshold| {
+                let crossing = if before < threshold && threshold <= after {
+                    Crossing::Up
+                } else if after < threshold && threshold <= before {
+                    Crossing::Down
+                } else {
+                    return None;
+                };
+                Some(TxEvent::ThresholdCrossed {
+                    account: *account,
+                    currency: entry.currency,
+                    threshold,
+                    balance: after,
+                    crossing,
+                })
+            })
+        })
+    }
+    fn publish(&self, events: Vec<TxEvent>) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        // receivers that hung up are dropped from the list
+        subscribers.retain(|subscriber| {
+            events
+                .iter()
+                .all(|event| subscriber.send(event.clone()).is_ok())
+        });
+        self.active.store(!subscribers.is_empty(), Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,172 @@
+use std::sync::Mutex;
+
+use tracing::info_span;
+
+use crate::channel::{Receiver, TryRecvError};
+use crate::handler::{self, Acked, Credit, Message, Peers, Transfer};
+use crate::{AccountId, HandleId};
+
+/// Runs the handlers' work on the calling thread instead of one thread per handler, picking
+/// which handler goes next from a seeded generator. Given the same seed and the same
+/// submissions, every run applies the same transactions in the same order.
+///
+/// Where a handler thread would block, on a barrier waiting for its credit or on a transfer
+/// waiting for the peer's acknowledgement, the handler is set aside instead and only picked
+/// again once that has arrived.
+pub(crate) struct Executor {
+    peers: Peers,
+    state: Mutex<State>,
+}
+
+struct State {
+    rng: u64,
+    // handler id -> a barrier taken off the queue whose credit hasn't arrived yet
+    parked: Vec<Option<Parked>>,
+    // handler id -> a transfer whose peer hasn't acknowledged the credit yet
+    awaiting: Vec<Option<Transfer>>,
+    // handler id -> in the middle of a transaction
+    running: Vec<bool>,
+}
+
+struct Parked {
+    account: AccountId,
+    credit: Receiver<Credit>,
+    accounts: Vec<AccountId>,
+}
+
+enum Work {
+    Tx(Message, Vec<AccountId>),
+    Credit(Parked, Option<Credit>),
+    Settle(Box<Transfer>, Option<Acked>),
+}
+
+impl Executor {
+    pub(crate) fn new(peers: Peers, seed: u64) -> Executor {
+        let handlers = peers.queues.len();
+        Executor {
+            peers,
+            state: Mutex::new(State {
+                // xorshift never leaves zero
+                rng: seed.max(1),
+                parked: (0..handlers).map(|_| None).collect(),
+                awaiting: (0..handlers).map(|_| None).collect(),
+                running: vec![false; handlers],
+            }),
+        }
+    }
+    /// Runs handlers until none of them can make progress.
+    pub(crate) fn run_until_idle(&self) {
+        while self.step() {}
+        for id in 0..self.peers.queues.len() {
+            self.peers.flush(id as HandleId);
+        }
+    }
+    /// Has one handler take one step; `false` if none could.
+    pub(crate) fn step(&self) -> bool {
+        match self.next() {
+            Some((id, work)) => self.run(id, work),
+            None => false,
+        }
+    }
+    /// Has the handler `id` take one step, whichever the generator would have picked; `false` if
+    /// it couldn't, having nothing to do or waiting on another handler.
+    pub(crate) fn step_handler(&self, id: HandleId) -> bool {
+        let work = {
+            let mut state = self.state.lock().unwrap();
+            match state.running.get(id as usize) {
+                Some(false) => {}
+                _ => return false,
+            }
+            let Some(work) = self.take(&mut state, id as usize) else {
+                return false;
+            };
+            state.running[id as usize] = true;
+            work
+        };
+        self.run(id, work)
+    }
+    fn run(&self, id: HandleId, work: Work) -> bool {
+        let mut awaiting = None;
+        match work {
+            Work::Tx(Message::NewTx(envelope), accounts) => {
+                awaiting = handler::start(id, id, &self.peers, *envelope, accounts);
+                if awaiting.is_none() {
+                    self.peers.latency.pause(id);
+                }
+            }
+            Work::Tx(Message::Cancelled(_), accounts) => {
+                handler::pass_cancelled(id, &self.peers, &accounts)
+            }
+            Work::Tx(_, accounts) => self.peers.queues[id as usize].done(&accounts),
+            Work::Credit(parked, credit) => {
+                let _span = info_span!("barrier", account = parked.account, handler = id).entered();
+                handler::apply_credit(id, &self.peers, parked.account, credit, &parked.accounts);
+            }
+            Work::Settle(transfer, acked) => {
+                transfer.settle(&self.peers, acked);
+                self.peers.latency.pause(id);
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        state.awaiting[id as usize] = awaiting;
+        state.running[id as usize] = false;
+        true
+    }
+    // Picks a handler with something to do, starting the search at a random one, and marks it
+    // running.
+    fn next(&self) -> Option<(HandleId, Work)> {
+        let mut state = self.state.lock().unwrap();
+        let handlers = state.running.len();
+        let start = state.next_random() as usize % handlers;
+        for id in (start..handlers).chain(0..start) {
+            if state.running[id] {
+                continue;
+            }
+            if let Some(work) = self.take(&mut state, id) {
+                state.running[id] = true;
+                return Some((id as HandleId, work));
+            }
+        }
+        None
+    }
+    fn take(&self, state: &mut State, id: usize) -> Option<Work> {
+        if let Some(transfer) = &state.awaiting[id] {
+            let acked = match transfer.ack.try_recv() {
+                Ok(result) => Some(result),
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => None,
+            };
+            let transfer = state.awaiting[id].take().unwrap();
+            return Some(Work::Settle(Box::new(transfer), acked));
+        }
+        if state.parked[id].is_none() {
+            match self.peers.queues[id].try_pop()? {
+                (Message::Barrier(account, _, credit), accounts) => {
+                    state.parked[id] = Some(Parked {
+                        account,
+                        credit,
+                        accounts,
+                    })
+                }
+                (message, accounts) => return Some(Work::Tx(message, accounts)),
+            }
+        }
+        // a disconnected channel means the debit failed and there is nothing to credit
+        let credit = match state.parked[id].as_ref()?.credit.try_recv() {
+            Ok(credit) => Some(credit),
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => None,
+        };
+        let parked = state.parked[id].take().unwrap();
+        Some(Work::Credit(parked, credit))
+    }
+}
+
+impl State {
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
@@ -0,0 +1,49 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{AccountId, Currency, HistoryEntry, Money, TxCount, TxId};
+
+/// Bumped whenever `ExportedState` changes in a way older engines can't read.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Everything an engine holds about its accounts, as given by `Aptone::state` for carrying on
+/// elsewhere with `Aptone::from_state`. Any serde format will do; `Aptone::export_state` and
+/// `Aptone::import_state` use JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedState {
+    pub version: u32,
+    /// Above every account ever opened.
+    pub next_account: AccountId,
+    /// Above every hold ever placed.
+    pub next_hold: u64,
+    /// Above every transaction submitted, so none is numbered as one in the histories.
+    pub next_tx: TxId,
+    /// By id.
+    pub accounts: Vec<ExportedAccount>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedAccount {
+    pub account: AccountId,
+    pub balances: BTreeMap<Currency, Money>,
+    /// Hold number -> what it reserves.
+    pub holds: BTreeMap<u64, (Currency, Money)>,
+    /// Transactions in flight when the state was taken. Only for the record: they aren't in it,
+    /// so an engine started from it has none pending.
+    pub pending: TxCount,
+    /// Oldest first.
+    pub history: Vec<HistoryEntry>,
+}
+
+impl ExportedAccount {
+    pub(crate) fn new(account: AccountId) -> ExportedAccount {
+        ExportedAccount {
+            account,
+            balances: BTreeMap::new(),
+            holds: BTreeMap::new(),
+            pending: 0,
+            history: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Writers streaming history entries to files for offline analysis, fed by
+//! `HistoryQuery::export`: CSV, JSON Lines with the `export` feature, and Parquet with the
+//! `parquet` feature. Each writes entries as it is handed them, Parquet a row group at a time.
+
+use std::io::{self, Write};
+#[cfg(feature = "parquet")]
+use std::sync::Arc;
+
+#[cfg(feature = "parquet")]
+use parquet::data_type::{
+    ByteArray, ByteArrayType, DataType, FixedLenByteArray, FixedLenByteArrayType, Int64Type,
+};
+#[cfg(feature = "parquet")]
+use parquet::errors::Result as ParquetResult;
+#[cfg(feature = "parquet")]
+use parquet::file::properties::WriterProperties;
+#[cfg(feature = "parquet")]
+use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+#[cfg(feature = "parquet")]
+use parquet::schema::parser::parse_message_type;
+
+use crate::statement::millis;
+#[cfg(feature = "parquet")]
+use crate::Money;
+use crate::{AccountId, HistoryEntry};
+
+/// Where `HistoryQuery::export` writes the entries it finds.
+pub trait HistoryExporter {
+    fn write_entry(&mut self, account: AccountId, entry: &HistoryEntry) -> io::Result<()>;
+    /// Writes out whatever is buffered and ends the file; called once, after the last entry.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// One row per entry under a header row: the account, transaction id, time in milliseconds
+/// since the epoch, kind, the account, currency, hold or transaction it refers to if any,
+/// currency, amount and the balance it left.
+pub struct CsvExporter<W: Write> {
+    writer: W,
+    header: bool,
+}
+
+impl<W: Write> CsvExporter<W> {
+    /// Best handed a buffered writer.
+    pub fn new(writer: W) -> CsvExporter<W> {
+        CsvExporter {
+            writer,
+            header: false,
+        }
+    }
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.header {
+            self.header = true;
+            writeln!(
+                self.writer,
+                "account,tx_id,time,kind,reference,currency,amount,balance"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> HistoryExporter for CsvExporter<W> {
+    fn write_entry(&mut self, account: AccountId, entry: &HistoryEntry) -> io::Result<()> {
+        self.write_header()?;
+        writeln!(
+            self.writer,
+            "{},{},{},{},{},{},{},{}",
+            account,
+            entry.tx_id,
+            millis(entry.time),
+            entry.kind.name(),
+            entry.kind.reference().unwrap_or_default(),
+            entry.currency,
+            entry.amount,
+            entry.balance
+        )
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        self.writer.flush()
+    }
+}
+
+/// One JSON object per line: the account and the entry as serde gives it. Only built with the
+/// `export` feature.
+#[cfg(feature = "export")]
+pub struct JsonLinesExporter<W: Write> {
+    writer: W,
+}
+
+#[cfg(feature = "export")]
+impl<W: Write> JsonLinesExporter<W> {
+    /// Best handed a buffered writer.
+    pub fn new(writer: W) -> JsonLinesExporter<W> {
+        JsonLinesExporter { writer }
+    }
+}
+
+#[cfg(feature = "export")]
+impl<W: Write> HistoryExporter for JsonLinesExporter<W> {
+    fn write_entry(&mut self, account: AccountId, entry: &HistoryEntry) -> io::Result<()> {
+        #[derive(serde::Serialize)]
+        struct Line<'a> {
+            account: AccountId,
+            #[serde(flatten)]
+            entry: &'a HistoryEntry,
+        }
+        serde_json::to_writer(&mut self.writer, &Line { account, entry })?;
+        writeln!(self.writer)
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// The columns of `CsvExporter` in a Parquet file, with the time as a timestamp and the amounts
+/// as decimals with four places, written a row group of `ParquetExporter::ROW_GROUP` entries
+/// at a time. Only built with the `parquet` feature.
+#[cfg(feature = "parquet")]
+pub struct ParquetExporter<W: Write + Send> {
+    writer: Option<SerializedFileWriter<W>>,
+    rows: Rows,
+}
+
+#[cfg(feature = "parquet")]
+const SCHEMA: &str = "message history_entry {
+    required int64 account;
+    required int64 tx_id;
+    required int64 time (TIMESTAMP(MILLIS, true));
+    required binary kind (UTF8);
+    optional binary reference (UTF8);
+    required binary currency (UTF8);
+    required fixed_len_byte_array(16) amount (DECIMAL(38, 4));
+    required fixed_len_byte_array(16) balance (DECIMAL(38, 4));
+}";
+
+// A row group being gathered, by column.
+#[cfg(feature = "parquet")]
+#[derive(Default)]
+struct Rows {
+    account: Vec<i64>,
+    tx_id: Vec<i64>,
+    time: Vec<i64>,
+    kind: Vec<ByteArray>,
+    reference: Vec<ByteArray>,
+    referenced: Vec<i16>, // definition levels of `reference`
+    currency: Vec<ByteArray>,
+    amount: Vec<FixedLenByteArray>,
+    balance: Vec<FixedLenByteArray>,
+}
+
+#[cfg(feature = "parquet")]
+impl<W: Write + Send> ParquetExporter<W> {
+    /// Entries held before being written out as a row group.
+    pub const ROW_GROUP: usize = 64 * 1024;
+
+    pub fn new(writer: W) -> io::Result<ParquetExporter<W>> {
+        let schema = Arc::new(parse_message_type(SCHEMA).map_err(io::Error::other)?);
+        let properties = Arc::new(WriterProperties::builder().build());
+        let writer =
+            SerializedFileWriter::new(writer, schema, properties).map_err(io::Error::other)?;
+        Ok(ParquetExporter {
+            writer: Some(writer),
+            rows: Rows::default(),
+        })
+    }
+    fn write_row_group(&mut self) -> ParquetResult<()> {
+        let rows = std::mem::take(&mut self.rows);
+        if rows.account.is_empty() {
+            return Ok(());
+        }
+        let writer = self.writer.as_mut().expect("written to after finishing");
+        let mut group = writer.next_row_group()?;
+        write_column::<Int64Type, _>(&mut group, &rows.account, None)?;
+        write_column::<Int64Type, _>(&mut group, &rows.tx_id, None)?;
+        write_column::<Int64Type, _>(&mut group, &rows.time, None)?;
+        write_column::<ByteArrayType, _>(&mut group, &rows.kind, None)?;
+        write_column::<ByteArrayType, _>(&mut group, &rows.reference, Some(&rows.referenced))?;
+        write_column::<ByteArrayType, _>(&mut group, &rows.currency, None)?;
+        write_column::<FixedLenByteArrayType, _>(&mut group, &rows.amount, None)?;
+        write_column::<FixedLenByteArrayType, _>(&mut group, &rows.balance, None)?;
+        group.close()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "parquet")]
+// Writes the next column of `group`, with the definition `levels` of an optional one.
+fn write_column<T: DataType, W: Write + Send>(
+    group: &mut SerializedRowGroupWriter<'_, W>,
+    values: &[T::T],
+    levels: Option<&[i16]>,
+) -> ParquetResult<()> {
+    let mut column = group.next_column()?.expect("a column per field");
+    column.typed::<T>().write_batch(values, levels, None)?;
+    column.close()
+}
+
+#[cfg(feature = "parquet")]
+impl<W: Write + Send> HistoryExporter for ParquetExporter<W> {
+    fn write_entry(&mut self, account: AccountId, entry: &HistoryEntry) -> io::Result<()> {
+        let decimal = |money: Money| FixedLenByteArray::from(money.minor().to_be_bytes().to_vec());
+        let rows = &mut self.rows;
+        rows.account.push(account.into());
+        rows.tx_id.push(entry.tx_id as i64);
+        rows.time.push(millis(entry.time) as i64);
+        rows.kind.push(entry.kind.name().into());
+        match entry.kind.reference() {
+            Some(reference) => {
+                rows.reference.push(reference.as_str().into());
+                rows.referenced.push(1);
+            }
+            None => rows.referenced.push(0),
+        }
+        rows.currency.push(entry.currency.code().into());
+        rows.amount.push(decimal(entry.amount));
+        rows.balance.push(decimal(entry.balance));
+        if rows.account.len() >= Self::ROW_GROUP {
+            self.write_row_group().map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_row_group().map_err(io::Error::other)?;
+        if let Some(writer) = self.writer.take() {
+            writer.close().map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+}
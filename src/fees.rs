@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use crate::{AccountId, Money, Tx, TxKind, TxType};
+
+/// What a transaction is charged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fee {
+    /// This much, whatever the amount.
+    Flat(Money),
+    /// This fraction of the amount, like `0.01`, rounded half to even. Nothing on captures,
+    /// releases and reversals, whose amount isn't known when the fee is worked out.
+    Percentage(Money),
+}
+
+/// The fees each tier of accounts is charged by kind of transaction. Accounts are in tier 0
+/// unless put in another.
+///
+/// A transaction with a fee is applied as a batch with a `TxType::FEE` leg moving the fee, in its
+/// currency, from its account to `revenue`: either both go through or, should the account not
+/// cover both, neither does. The fee shows in the history of both accounts under the
+/// transaction's id, as `EntryKind::FeeOut` and `EntryKind::FeeIn`. While `revenue` is busy on
+/// another handler than the transaction's, that handler credits the fee, as it would the credit
+/// leg of a transfer, rather than the transaction waiting to go there too.
+/// `revenue` has to be open, and is never charged itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeeSchedule {
+    pub revenue: AccountId,
+    pub fees: HashMap<(u32, TxKind), Fee>,
+    pub tiers: HashMap<AccountId, u32>,
+}
+
+impl FeeSchedule {
+    /// No fees yet, collected in `revenue`.
+    pub fn new(revenue: AccountId) -> FeeSchedule {
+        FeeSchedule {
+            revenue,
+            ..FeeSchedule::default()
+        }
+    }
+    /// Charges `fee` on every transaction of `kind` by an account in `tier`.
+    pub fn fee(mut self, tier: u32, kind: TxKind, fee: Fee) -> FeeSchedule {
+        self.fees.insert((tier, kind), fee);
+        self
+    }
+    pub fn tier(mut self, account: AccountId, tier: u32) -> FeeSchedule {
+        self.tiers.insert(account, tier);
+        self
+    }
+    /// What `tx` is charged, zero for nothing.
+    pub fn fee_for(&self, tx: &Tx) -> Money {
+        if tx.account == self.revenue {
+            return Money::ZERO;
+        }
+        let tier = self.tiers.get(&tx.account).copied().unwrap_or(0);
+        let fee = match self.fees.get(&(tier, tx.tx_type.kind())) {
+            Some(&Fee::Flat(fee)) => fee,
+            Some(&Fee::Percentage(_)) if !tx.tx_type.has_amount() => Money::ZERO,
+            Some(&Fee::Percentage(fraction)) => {
+                tx.amount.checked_mul(fraction).unwrap_or(Money::MAX)
+            }
+            None => Money::ZERO,
+        };
+        fee.max(Money::ZERO)
+    }
+    /// `legs`, each followed by the transfer of its fee if it has one.
+    pub(crate) fn charge(&self, legs: &[Tx]) -> Vec<Tx> {
+        let mut charged = Vec::with_capacity(legs.len() * 2);
+        for leg in legs {
+            charged.push(leg.clone());
+            let fee = self.fee_for(leg);
+            if fee.is_positive() {
+                let to = self.revenue;
+                let fee = Tx::new(leg.account, fee, TxType::FEE { to });
+                charged.push(fee.in_currency(leg.currency));
+            }
+        }
+        charged
+    }
+}
@@ -0,0 +1,282 @@
+//! gRPC front end for a running engine, implementing the `aptone.Bank` service from
+//! `proto/aptone.proto` with tonic.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+use tracing::info_span;
+
+use crate::{
+    Aptone, Crossing, Currency, Money, ParseCurrencyError, ParseMoneyError, Tx, TxError, TxType,
+};
+
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("aptone");
+}
+
+use proto::bank_server::{Bank, BankServer};
+use proto::tx_event::{Applied, Event, Rejected, Resized, Settled, ThresholdCrossed};
+use proto::{
+    AmountRequest, BalanceReply, BalanceRequest, CloseAccountReply, CloseAccountRequest,
+    OpenAccountReply, OpenAccountRequest, TxReply, WatchRequest,
+};
+
+// events buffered per watcher before the engine side waits for it to catch up
+const WATCH_BUFFER: usize = 256;
+
+/// `aptone.Bank` backed by an engine.
+pub struct BankService {
+    aptone: Arc<Aptone>,
+}
+
+impl BankService {
+    pub fn new(aptone: Arc<Aptone>) -> BankService {
+        BankService { aptone }
+    }
+    async fn submit(
+        &self,
+        request: Request<AmountRequest>,
+        tx_type: TxType,
+    ) -> Result<Response<TxReply>, Status> {
+        let span = info_span!("request", api = "grpc");
+        #[cfg(feature = "otel")]
+        crate::otel::continue_trace(&span, |name| {
+            request
+                .metadata()
+                .get(name)?
+                .to_str()
+                .ok()
+                .map(str::to_owned)
+        });
+        let request = request.into_inner();
+        let amount: Money = request
+            .amount
+            .parse()
+            .map_err(|err: ParseMoneyError| Status::invalid_argument(err.to_string()))?;
+        let currency = currency(request.currency.as_deref()).map_err(invalid_currency)?;
+        let aptone = Arc::clone(&self.aptone);
+        // submitting may block on a full queue and waiting blocks until the handler is done, so
+        // neither runs on the async workers
+        let tx_id = tokio::task::spawn_blocking(move || {
+            let _span = span.entered();
+            let AmountRequest {
+                account,
+                idempotency_key,
+                ..
+            } = request;
+            let tx = Tx::new(account, amount, tx_type).in_currency(currency);
+            let receipt = match &idempotency_key {
+                Some(key) => aptone.submit_tx_with_key(key, tx),
+                None => aptone.submit_tx(tx),
+            }?;
+            let tx_id = receipt.tx_id();
+            receipt.wait().map(|()| tx_id)
+        })
+        .await
+        .unwrap_or(Err(TxError::ShuttingDown))
+        .map_err(status)?;
+        Ok(Response::new(TxReply { tx_id }))
+    }
+}
+
+/// Serves `aptone` on `addr` until the server fails.
+pub async fn serve(aptone: Arc<Aptone>, addr: SocketAddr) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(BankServer::new(BankService::new(aptone)))
+        .serve(addr)
+        .await
+}
+
+#[tonic::async_trait]
+impl Bank for BankService {
+    async fn open_account(
+        &self,
+        request: Request<OpenAccountRequest>,
+    ) -> Result<Response<OpenAccountReply>, Status> {
+        let initial_balance = match request.into_inner().initial_balance {
+            Some(balance) => balance
+                .parse()
+                .map_err(|err: ParseMoneyError| Status::invalid_argument(err.to_string()))?,
+            None => Money::ZERO,
+        };
+        let account = self.aptone.open_account(initial_balance).map_err(status)?;
+        Ok(Response::new(OpenAccountReply { account }))
+    }
+    async fn close_account(
+        &self,
+        request: Request<CloseAccountRequest>,
+    ) -> Result<Response<CloseAccountReply>, Status> {
+        let account = request.into_inner().account;
+        let balances = self.aptone.close_account(account).map_err(status)?;
+        Ok(Response::new(CloseAccountReply {
+            account,
+            balances: balances
+                .into_iter()
+                .map(|(currency, balance)| proto::close_account_reply::Balance {
+                    currency: currency.to_string(),
+                    balance: balance.to_string(),
+                })
+                .collect(),
+        }))
+    }
+    async fn deposit(&self, request: Request<AmountRequest>) -> Result<Response<TxReply>, Status> {
+        self.submit(request, TxType::DEPOSIT).await
+    }
+    async fn withdraw(&self, request: Request<AmountRequest>) -> Result<Response<TxReply>, Status> {
+        self.submit(request, TxType::WITHDRAW).await
+    }
+    async fn get_balance(
+        &self,
+        request: Request<BalanceRequest>,
+    ) -> Result<Response<BalanceReply>, Status> {
+        let BalanceRequest {
+            account,
+            currency: code,
+        } = request.into_inner();
+        let currency = currency(code.as_deref()).map_err(invalid_currency)?;
+        Ok(Response::new(BalanceReply {
+            account,
+            balance: self.aptone.get_balance_in(account, currency).to_string(),
+            currency: currency.to_string(),
+        }))
+    }
+
+    type WatchEventsStream = ReceiverStream<Result<proto::TxEvent, Status>>;
+
+    async fn watch_events(
+        &self,
+        _request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        let events = self.aptone.subscribe();
+        let (sender, receiver) = mpsc::channel(WATCH_BUFFER);
+        // the subscription is a blocking receiver; forward it until either side hangs up
+        tokio::task::spawn_blocking(move || {
+            for event in events {
+                let event = proto::TxEvent {
+                    event: Some(Event::from(event)),
+                };
+                if sender.blocking_send(Ok(event)).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+// The default currency if none is given.
+fn currency(code: Option<&str>) -> Result<Currency, ParseCurrencyError> {
+    code.map_or(Ok(Currency::default()), str::parse)
+}
+
+fn invalid_currency(err: ParseCurrencyError) -> Status {
+    Status::invalid_argument(err.to_string())
+}
+
+fn status(err: TxError) -> Status {
+    let message = err.to_string();
+    match err {
+        TxError::InsufficientFunds { .. }
+        | TxError::Overflow(_)
+        | TxError::NoExchangeRate { .. }
+        | TxError::AccountBusy { .. }
+        | TxError::NotReversible(_)
+        | TxError::AlreadyReversed(_)
+        | TxError::LimitExceeded { .. }
+        | TxError::Rejected(_) => Status::failed_precondition(message),
+        TxError::InvalidAmount(_) | TxError::EmptyBatch => Status::invalid_argument(message),
+        TxError::UnknownAccount(_)
+        | TxError::UnknownHold(_)
+        | TxError::UnknownTx { .. }
+        | TxError::NotDeadLettered(_) => Status::not_found(message),
+        TxError::Duplicate(_) => Status::already_exists(message),
+        TxError::Cancelled(_) => Status::cancelled(message),
+        TxError::QueueFull(_) | TxError::RateLimited(_) | TxError::NoAccountsLeft => {
+            Status::resource_exhausted(message)
+        }
+        TxError::HandlerUnavailable(_) | TxError::ShuttingDown => Status::unavailable(message),
+        TxError::Wal(_) | TxError::Storage(_) => Status::internal(message),
+    }
+}
+
+impl From<crate::Tx> for proto::Tx {
+    fn from(tx: crate::Tx) -> proto::Tx {
+        let (kind, to, to_currency, hold) = match tx.tx_type {
+            TxType::DEPOSIT => (proto::tx::Kind::Deposit, 0, None, None),
+            TxType::WITHDRAW => (proto::tx::Kind::Withdraw, 0, None, None),
+            TxType::TRANSFER { to } => (proto::tx::Kind::Transfer, to, None, None),
+            TxType::EXCHANGE { to } => (proto::tx::Kind::Exchange, 0, Some(to.to_string()), None),
+            TxType::AUTHORIZE { hold } => (proto::tx::Kind::Authorize, 0, None, Some(hold.number)),
+            TxType::CAPTURE { hold } => (proto::tx::Kind::Capture, 0, None, Some(hold.number)),
+            TxType::RELEASE { hold } => (proto::tx::Kind::Release, 0, None, Some(hold.number)),
+            TxType::REVERSAL { .. } => (proto::tx::Kind::Reversal, 0, None, None),
+            TxType::FEE { to } => (proto::tx::Kind::Fee, to, None, None),
+        };
+        let original = match tx.tx_type {
+            TxType::REVERSAL { original } => Some(original),
+            _ => None,
+        };
+        proto::Tx {
+            account: tx.account,
+            amount: tx.amount.to_string(),
+            kind: kind.into(),
+            to,
+            currency: tx.currency.to_string(),
+            to_currency,
+            hold,
+            original,
+        }
+    }
+}
+
+impl From<crate::TxEvent> for Event {
+    fn from(event: crate::TxEvent) -> Event {
+        match event {
+            crate::TxEvent::Applied { tx_id, tx } => Event::Applied(Applied {
+                tx_id,
+                tx: Some(tx.into()),
+            }),
+            crate::TxEvent::Rejected { tx_id, tx, error } => Event::Rejected(Rejected {
+                tx_id,
+                tx: Some(tx.into()),
+                error: error.to_string(),
+            }),
+            crate::TxEvent::ThresholdCrossed {
+                account,
+                currency,
+                threshold,
+                balance,
+                crossing,
+            } => Event::ThresholdCrossed(ThresholdCrossed {
+                account,
+                threshold: threshold.to_string(),
+                balance: balance.to_string(),
+                up: crossing == Crossing::Up,
+                currency: currency.to_string(),
+            }),
+            crate::TxEvent::Resized { from, to } => Event::Resized(Resized {
+                from: from as u32,
+                to: to as u32,
+            }),
+            crate::TxEvent::Settled {
+                settlement,
+                account,
+                balances,
+            } => Event::Settled(Settled {
+                settlement,
+                account,
+                balances: balances
+                    .into_iter()
+                    .map(|(currency, balance)| proto::close_account_reply::Balance {
+                        currency: currency.to_string(),
+                        balance: balance.to_string(),
+                    })
+                    .collect(),
+            }),
+        }
+    }
+}
@@ -0,0 +1,886 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, error, info, info_span, warn, Span};
+
+use crate::alerts::Alerts;
+use crate::channel::{channel, Receiver, Sender};
+#[cfg(feature = "chaos")]
+use crate::chaos::Injector;
+use crate::dead_letter::DeadLetters;
+use crate::directory::{Shard, TxCounts};
+use crate::events::Events;
+use crate::latency::LatencyInjector;
+use crate::metrics::Metrics;
+use crate::middleware::Pipeline;
+use crate::queue::Queue;
+use crate::sequence::AccountSeq;
+use crate::server_data::StagedBatch;
+use crate::status::{Tracker, TxStatus};
+use crate::storage::Storage;
+use crate::sync;
+use crate::tx;
+use crate::wal::Seq;
+use crate::{
+    AccountId, Clock, Currency, EntryKind, HandleId, HistoryEntry, Money, Tx, TxError, TxId,
+    TxResult, TxType,
+};
+
+#[derive(Clone)]
+pub(crate) struct Envelope {
+    pub(crate) tx_id: TxId,
+    pub(crate) tx: Tx,
+    pub(crate) seq: Option<Seq>, // position in the transaction log, if there is one
+    pub(crate) reply: Sender<TxResult>,
+    pub(crate) submitted: Instant,
+    // for a transfer into an account owned by another handler: that handler and the channel to
+    // hand it the credit leg through
+    pub(crate) credit: Option<(HandleId, Sender<Credit>)>,
+    // the legs after `tx` of a batch it opens, applied together with it or not at all
+    pub(crate) batch: Vec<Tx>,
+    pub(crate) restarts: u32, // handlers that died before applying it
+    // its number on each account it orders, see `Sequencer`: all it touches but the target of a
+    // transfer credited by another handler
+    pub(crate) turns: Vec<(AccountId, AccountSeq)>,
+    pub(crate) span: Span, // the submission's, which those applying it continue
+    pub(crate) queued: Option<Span>, // open until a handler takes it off the queue
+}
+
+impl Envelope {
+    /// `tx` followed by the rest of its batch, if it opens one.
+    pub(crate) fn legs(&self) -> impl Iterator<Item = &Tx> {
+        std::iter::once(&self.tx).chain(&self.batch)
+    }
+}
+
+pub(crate) struct Credit {
+    tx_id: TxId,
+    // the credit legs, each with what it leaves in the history of the account credited
+    credits: Vec<(EntryKind, Currency, Money)>,
+    ack: Sender<Acked>,
+}
+
+// A peer's answer to a credit: the entries it recorded on the account credited.
+pub(crate) type Acked = Result<Vec<(AccountId, HistoryEntry)>, TxError>;
+// How a transaction settled elsewhere came out: the credit legs another handler applied, and for
+// a batch, the rest of it staged here meanwhile.
+type Decided = Result<(Vec<(AccountId, HistoryEntry)>, Option<StagedBatch>), TxError>;
+
+pub(crate) enum Message {
+    NewTx(Box<Envelope>),
+    // holds the account's queue on this handler until a transfer on another handler has been
+    // debited, then applies its credit leg; in turn on the account like a transaction
+    Barrier(AccountId, AccountSeq, Receiver<Credit>),
+    // where a transaction cancelled while queued was, keeping its turns until they come so
+    // nothing after it on its accounts goes before what was ahead of it
+    Cancelled(Vec<(AccountId, AccountSeq)>),
+    // a transaction left too long on the queue of the given handler, to apply on that handler's
+    // shard as a thief would; counted on this handler only until it is taken up
+    Rerouted(HandleId, Box<Envelope>),
+    Terminate,
+}
+
+pub(crate) struct TxHandler {
+    id: HandleId,
+    queues: Arc<Vec<Queue>>,
+    pub(crate) thread: Mutex<Option<thread::JoinHandle<()>>>,
+    worker: Option<Worker>, // what it takes to restart the thread
+}
+
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+// how often an idle handler looks for work to steal
+const STEAL_POLL_INTERVAL: Duration = Duration::from_millis(1);
+// how often an idle handler sharing a queue checks it wasn't retired
+const SHARED_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// how often a handler with nothing to do reports in all the same
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+// a transaction that keeps killing its handler is failed once it has taken down this many
+const MAX_RESTARTS: u32 = 3;
+
+/// The state a handler works on besides its own queue, shared by all of them.
+#[derive(Clone)]
+pub(crate) struct Peers {
+    pub(crate) queues: Arc<Vec<Queue>>,
+    pub(crate) shards: Vec<Shard>,
+    pub(crate) tx_count: TxCounts,
+    pub(crate) cross_in_flight: Arc<AtomicUsize>,
+    pub(crate) batches_in_flight: Arc<AtomicUsize>, // logged and not applied yet
+    pub(crate) tracker: Arc<Tracker>,
+    pub(crate) events: Arc<Events>,
+    pub(crate) dead_letters: Arc<DeadLetters>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) latency: Arc<LatencyInjector>,
+    pub(crate) middleware: Pipeline,
+    pub(crate) alerts: Arc<Alerts>,
+    pub(crate) storage: Option<Arc<dyn Storage>>,
+    pub(crate) in_flight: Arc<Vec<Mutex<Option<InFlight>>>>, // handler id -> what it works on
+    pub(crate) beats: Arc<Vec<Mutex<Beat>>>,                 // handler id -> when it reported in
+    #[cfg(feature = "chaos")]
+    pub(crate) faults: Arc<Injector>,
+}
+
+/// A message a handler thread took off a queue and isn't through with, for whoever restarts the
+/// handler should its thread die.
+pub(crate) struct InFlight {
+    owner: HandleId,
+    accounts: Vec<AccountId>,
+    work: Work,
+}
+
+/// When a handler's thread last reported in, which it does between messages and while waiting
+/// for one, and when it last finished one, by the engine's clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Beat {
+    pub(crate) heartbeat: Option<Instant>,
+    pub(crate) processed: Option<Instant>,
+}
+
+enum Work {
+    // a copy of a transaction nothing of which has been applied yet, to queue again
+    Queued(Box<Envelope>),
+    // a transaction possibly applied in part, which can't be retried, still holding its slot
+    // on the queue
+    Applying,
+    Barrier(AccountId),
+}
+
+impl Peers {
+    fn check_out(&self, worker: HandleId, owner: HandleId, accounts: &[AccountId], mut work: Work) {
+        if let Work::Queued(envelope) = &mut work {
+            // the copy holds nothing up, and shouldn't hold the span of its wait open either
+            envelope.queued = None;
+        }
+        *self.in_flight[worker as usize].lock().unwrap() = Some(InFlight {
+            owner,
+            accounts: accounts.to_vec(),
+            work,
+        });
+    }
+    // From here on the transaction `worker` checked out may be partly applied.
+    fn applying(&self, worker: HandleId) {
+        if let Some(in_flight) = self.in_flight[worker as usize].lock().unwrap().as_mut() {
+            in_flight.work = Work::Applying;
+        }
+    }
+    // Has the storage write out what `worker` committed, when it runs out of work.
+    pub(crate) fn flush(&self, worker: HandleId) {
+        if let Some(storage) = &self.storage {
+            if let Err(err) = storage.flush(worker) {
+                error!(%err, handler = worker, "failed to persist txs");
+            }
+        }
+    }
+    fn beat(&self, worker: HandleId, processed: bool) {
+        let now = self.clock.now();
+        let mut beat = self.beats[worker as usize].lock().unwrap();
+        beat.heartbeat = Some(now);
+        if processed {
+            beat.processed = Some(now);
+        }
+    }
+    // Once the message `worker` checked out has given back its slot and accounts.
+    fn check_in(&self, worker: HandleId) {
+        self.in_flight[worker as usize].lock().unwrap().take();
+    }
+}
+
+#[derive(Clone)]
+struct Worker {
+    peers: Peers,
+    steal_threshold: Option<usize>,
+    shared: bool, // takes work off the first handler's queue rather than its own
+}
+
+impl Worker {
+    fn spawn(&self, id: HandleId) -> io::Result<thread::JoinHandle<()>> {
+        let worker = self.clone();
+        thread::Builder::new().spawn(move || worker.run(id))
+    }
+    fn run(&self, id: HandleId) {
+        let peers = &self.peers;
+        let own = &peers.queues[id as usize];
+        // every account sharing a queue is the first handler's
+        let owner = if self.shared { 0 } else { id };
+        let queue = &peers.queues[owner as usize];
+        let poll = match self.shared {
+            true => Some(SHARED_POLL_INTERVAL),
+            false => self.steal_threshold.map(|_| STEAL_POLL_INTERVAL),
+        };
+        let poll = poll.or(Some(HEARTBEAT_INTERVAL));
+        loop {
+            peers.beat(id, false);
+            let popped = match self.shared && own.is_closed() {
+                true => None,
+                false if peers.storage.is_some() => queue.try_pop().or_else(|| {
+                    peers.flush(id);
+                    queue.pop(poll)
+                }),
+                false => queue.pop(poll),
+            };
+            let Some((message, accounts)) = popped else {
+                if queue.is_closed() || own.is_closed() {
+                    peers.flush(id);
+                    info!(handler = id, "retiring");
+                    // so the supervisor doesn't restart a handler whose shared queue closed
+                    own.close();
+                    break;
+                }
+                if let (Some(threshold), false) = (self.steal_threshold, self.shared) {
+                    steal(id, peers, threshold);
+                }
+                continue;
+            };
+            match message {
+                Message::NewTx(envelope) => {
+                    peers.check_out(id, owner, &accounts, Work::Queued(envelope.clone()));
+                    process(id, owner, peers, *envelope, accounts);
+                    peers.latency.pause(id);
+                }
+                Message::Barrier(account, _, credit) => {
+                    let _span = info_span!("barrier", account, handler = id).entered();
+                    peers.check_out(id, id, &accounts, Work::Barrier(account));
+                    // an error means the debit failed and there is nothing to credit
+                    let credit = credit.recv().ok();
+                    apply_credit(id, peers, account, credit, &accounts);
+                }
+                Message::Cancelled(_) => pass_cancelled(owner, peers, &accounts),
+                Message::Rerouted(victim, envelope) => {
+                    peers.tx_count[id as usize].fetch_sub(1, Ordering::SeqCst);
+                    let accounts = tx::accounts_of(envelope.legs());
+                    debug!(
+                        handler = id,
+                        victim,
+                        tx_id = envelope.tx_id,
+                        "taking rerouted tx"
+                    );
+                    peers.check_out(id, victim, &accounts, Work::Queued(envelope.clone()));
+                    process(id, victim, peers, *envelope, accounts);
+                    peers.latency.pause(id);
+                }
+                Message::Terminate => {
+                    info!(handler = id, "terminating");
+                    peers.flush(id);
+                    // whatever is queued behind it fails, and handlers sharing the queue stop
+                    queue.close();
+                    own.close();
+                    break;
+                }
+            }
+            peers.beat(id, true);
+        }
+    }
+}
+
+impl TxHandler {
+    /// With a `steal_threshold`, the handler takes over transactions from peers holding at least
+    /// that many queued messages whenever its own queue runs dry. A `shared` handler takes its
+    /// work off the first handler's queue instead, and steals nothing.
+    pub(crate) fn new(
+        id: HandleId,
+        peers: Peers,
+        steal_threshold: Option<usize>,
+        shared: bool,
+    ) -> TxHandler {
+        let queues = Arc::clone(&peers.queues);
+        let worker = Worker {
+            peers,
+            steal_threshold,
+            shared,
+        };
+        let thread = worker.spawn(id).expect("failed to spawn a handler thread");
+        TxHandler {
+            id,
+            queues,
+            thread: Mutex::new(Some(thread)),
+            worker: Some(worker),
+        }
+    }
+    /// A handler without a thread until `start`ed.
+    pub(crate) fn parked(
+        id: HandleId,
+        peers: Peers,
+        steal_threshold: Option<usize>,
+        shared: bool,
+    ) -> TxHandler {
+        TxHandler {
+            id,
+            queues: Arc::clone(&peers.queues),
+            thread: Mutex::new(None),
+            worker: Some(Worker {
+                peers,
+                steal_threshold,
+                shared,
+            }),
+        }
+    }
+    /// A handler without a thread of its own, whose queue the deterministic executor drains.
+    pub(crate) fn inline(id: HandleId, queues: Arc<Vec<Queue>>) -> TxHandler {
+        TxHandler {
+            id,
+            queues,
+            thread: Mutex::new(None),
+            worker: None,
+        }
+    }
+    /// Opens the queue of a parked or retired handler and starts a thread on it, unless the last
+    /// one hasn't exited yet and carries on. Has to hold every stripe.
+    pub(crate) fn start(&self) {
+        self.queues[self.id as usize].reopen();
+        let Some(worker) = &self.worker else {
+            return;
+        };
+        let mut thread = self.thread.lock().unwrap();
+        if thread.as_ref().is_some_and(|thread| !thread.is_finished()) {
+            return;
+        }
+        if let Some(finished) = thread.take() {
+            let _ = finished.join();
+        }
+        self.spawn(worker, &mut thread);
+    }
+    /// Takes the handler out of the pool: closes its queue, which its thread exits on. Has to
+    /// hold every stripe, and nothing may be queued on the handler.
+    pub(crate) fn retire(&self) {
+        self.queues[self.id as usize].close();
+    }
+    /// Whether the handler has a thread that hasn't exited.
+    pub(crate) fn is_running(&self) -> bool {
+        let thread = self.thread.lock().unwrap();
+        thread.as_ref().is_some_and(|thread| !thread.is_finished())
+    }
+    /// Hands the message back if the handler has exited.
+    pub(crate) fn send(&self, message: Message) -> Result<(), Message> {
+        self.queues[self.id as usize].push(message)
+    }
+    // Queues `Terminate` behind everything already submitted.
+    pub(crate) fn terminate(&self) {
+        if self.thread.lock().unwrap().is_none() {
+            return;
+        }
+        debug!(handler = self.id, "sending termination message");
+        // a handler that is already gone has nothing left to drain
+        let _ = self.send(Message::Terminate);
+    }
+    // Waits for the thread to exit, restarting it if it dies first; `false` if the deadline
+    // passed first.
+    pub(crate) fn join(&self, deadline: Option<Instant>) -> bool {
+        loop {
+            self.revive();
+            if self.thread.lock().unwrap().is_none() {
+                return true;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return false;
+            }
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+    }
+    /// Reaps the thread if it has exited. If it died of a panic, puts back what it was working
+    /// on and starts a new thread on the same queue and accounts; `true` if it did. Also starts
+    /// one if the thread retired just as the handler was started again.
+    pub(crate) fn revive(&self) -> bool {
+        let mut thread = self.thread.lock().unwrap();
+        if !thread.as_ref().is_some_and(|thread| thread.is_finished()) {
+            return false;
+        }
+        let Some(worker) = &self.worker else {
+            return false;
+        };
+        let Err(panic) = thread.take().unwrap().join() else {
+            if self.queues[self.id as usize].is_closed() {
+                return false;
+            }
+            debug!(
+                handler = self.id,
+                "restarting a handler started while retiring"
+            );
+            self.spawn(worker, &mut thread);
+            return true;
+        };
+        let reason = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown");
+        error!(handler = self.id, reason, "handler died, restarting it");
+        recover(self.id, &worker.peers);
+        self.spawn(worker, &mut thread);
+        true
+    }
+    // Starts a thread on the handler's queue, closing the queue if no thread can be had.
+    fn spawn(&self, worker: &Worker, thread: &mut Option<thread::JoinHandle<()>>) {
+        match worker.spawn(self.id) {
+            Ok(handle) => *thread = Some(handle),
+            Err(err) => {
+                error!(handler = self.id, %err, "failed to start handler");
+                self.queues[self.id as usize].close();
+            }
+        }
+    }
+}
+
+// Cleans up after handler `id`'s thread died, putting the message it was working on back at the
+// front of its queue. A transaction it may have applied in part stays unresolved, pinning its
+// accounts, since retrying it could apply it twice.
+fn recover(id: HandleId, peers: &Peers) {
+    let Some(InFlight {
+        owner,
+        accounts,
+        work,
+    }) = peers.in_flight[id as usize].lock().unwrap().take()
+    else {
+        return;
+    };
+    match work {
+        Work::Queued(mut envelope) => {
+            envelope.restarts += 1;
+            if envelope.restarts > MAX_RESTARTS {
+                warn!(tx_id = envelope.tx_id, "giving up on tx");
+                let err = TxError::HandlerUnavailable(owner);
+                fail(id, owner, peers, *envelope, &accounts, err);
+                return;
+            }
+            debug!(tx_id = envelope.tx_id, handler = owner, "requeueing tx");
+            envelope.queued = Some(info_span!(parent: &envelope.span, "queued", handler = owner));
+            peers.queues[owner as usize].requeue(Message::NewTx(envelope), &accounts);
+        }
+        Work::Applying => {
+            error!(handler = owner, "lost a tx while applying it");
+            let data = sync::lock(&peers.shards[owner as usize]);
+            for &account in &accounts {
+                data.publish(account);
+            }
+            drop(data);
+            peers.tx_count[owner as usize].fetch_sub(1, Ordering::SeqCst);
+            peers.queues[owner as usize].done(&accounts);
+        }
+        // the credit channel went with the thread, so the transfer rolls its debit back
+        Work::Barrier(account) => apply_credit(owner, peers, account, None, &accounts),
+    }
+}
+
+/// A transfer into an account owned by another handler, debited and handed over to that peer,
+/// waiting for the peer to acknowledge the credit. Or a batch whose fees go to such an account,
+/// staged but for them.
+pub(crate) struct Transfer {
+    worker: HandleId,
+    owner: HandleId,
+    envelope: Envelope,
+    accounts: Vec<AccountId>,
+    peer: HandleId,
+    staged: Option<StagedBatch>,
+    pub(crate) ack: Receiver<Acked>,
+}
+
+// Applies a transaction queued on handler `owner` and does its bookkeeping there; `worker` is the
+// handler doing the work.
+fn process(
+    worker: HandleId,
+    owner: HandleId,
+    peers: &Peers,
+    envelope: Envelope,
+    accounts: Vec<AccountId>,
+) {
+    if let Some(transfer) = start(worker, owner, peers, envelope, accounts) {
+        let acked = transfer.ack.recv().ok();
+        transfer.settle(peers, acked);
+    }
+}
+
+/// `process` up to the point where a transfer between handlers waits on its peer: a
+/// transaction needing nothing from other handlers is finished right away, while the transfer is
+/// handed back for `Transfer::settle` once it has been debited and its credit sent.
+pub(crate) fn start(
+    worker: HandleId,
+    owner: HandleId,
+    peers: &Peers,
+    mut envelope: Envelope,
+    accounts: Vec<AccountId>,
+) -> Option<Transfer> {
+    peers.tracker.mark(envelope.tx_id, TxStatus::Processing);
+    envelope.queued = None;
+    #[cfg(feature = "chaos")]
+    if peers.faults.strike(worker, &peers.shards[owner as usize]) {
+        let err = TxError::HandlerUnavailable(owner);
+        fail(worker, owner, peers, envelope, &accounts, err);
+        return None;
+    }
+    if !peers.middleware.is_empty() {
+        let legs: Vec<Tx> = envelope.legs().cloned().collect();
+        if let Err(err) = peers.middleware.before_apply(envelope.tx_id, &legs) {
+            fail(worker, owner, peers, envelope, &accounts, err);
+            return None;
+        }
+    }
+    peers.applying(worker);
+    let Some((peer, credit)) = envelope.credit.take() else {
+        finish(worker, owner, peers, envelope, &accounts, false, None);
+        return None;
+    };
+    let debited = {
+        let Envelope {
+            tx_id, tx, span, ..
+        } = &envelope;
+        let _span =
+            info_span!(parent: span, "apply", tx_id, account = tx.account, handler = owner, worker)
+                .entered();
+        peers.cross_in_flight.fetch_add(1, Ordering::SeqCst);
+        let shard = &peers.shards[owner as usize];
+        match envelope.batch.is_empty() {
+            true => debit_across(shard, *tx_id, tx, peer, &credit).map(|ack| (ack, None)),
+            false => {
+                let legs: Vec<Tx> = envelope.legs().cloned().collect();
+                let staged = stage_across(shard, *tx_id, &legs, peer, &credit);
+                staged.map(|(ack, staged)| (ack, Some(staged)))
+            }
+        }
+    };
+    // dropping `credit` releases the peer if there is nothing to credit
+    drop(credit);
+    match debited {
+        Ok((ack, staged)) => Some(Transfer {
+            worker,
+            owner,
+            envelope,
+            accounts,
+            peer,
+            staged,
+            ack,
+        }),
+        Err(err) => {
+            finish(
+                worker,
+                owner,
+                peers,
+                envelope,
+                &accounts,
+                true,
+                Some(Err(err)),
+            );
+            None
+        }
+    }
+}
+
+// Fails a transaction its handler never got to apply with `err`, releasing its accounts like a
+// rejected one. Dropping its credit channel releases the peer's barrier, if it has one.
+fn fail(
+    worker: HandleId,
+    owner: HandleId,
+    peers: &Peers,
+    mut envelope: Envelope,
+    accounts: &[AccountId],
+    err: TxError,
+) {
+    let across = envelope.credit.take().is_some();
+    if across {
+        // counted out again when it is finished
+        peers.cross_in_flight.fetch_add(1, Ordering::SeqCst);
+    }
+    finish(
+        worker,
+        owner,
+        peers,
+        envelope,
+        accounts,
+        across,
+        Some(Err(err)),
+    );
+}
+
+impl Transfer {
+    /// Finishes the transfer with the peer's answer, `None` if the peer is gone, rolling the
+    /// debit, or the staged batch, back unless the credit went through.
+    pub(crate) fn settle(self, peers: &Peers, acked: Option<Acked>) {
+        let Transfer {
+            worker,
+            owner,
+            envelope,
+            accounts,
+            peer,
+            staged,
+            ..
+        } = self;
+        let tx = &envelope.tx;
+        let result = match acked.unwrap_or(Err(TxError::HandlerUnavailable(peer))) {
+            Ok(credited) => Ok((credited, staged)),
+            // the accounts are pinned to us, so nothing touched them since the debit
+            Err(err) => {
+                let mut data = sync::lock(&peers.shards[owner as usize]);
+                match staged {
+                    Some(staged) => {
+                        data.roll_back_batch(staged);
+                        Err(err)
+                    }
+                    None => data
+                        .refund(tx.account, tx.currency, tx.amount)
+                        .and(Err(err)),
+                }
+            }
+        };
+        finish(
+            worker,
+            owner,
+            peers,
+            envelope,
+            &accounts,
+            true,
+            Some(result),
+        );
+    }
+}
+
+// The bookkeeping after a transaction is through; `decided` holds its outcome if it was settled
+// elsewhere, like a transfer between handlers, which is applied already, and is `None` for a
+// transaction to apply here. `across` as for `ServerData::settle`. Applied transactions are
+// committed to the storage, if there is one, before anyone hears of them.
+fn finish(
+    worker: HandleId,
+    owner: HandleId,
+    peers: &Peers,
+    envelope: Envelope,
+    accounts: &[AccountId],
+    across: bool,
+    decided: Option<Decided>,
+) {
+    let (decided, credited) = match decided {
+        Some(Ok((credited, staged))) => (Some(Ok(staged)), credited),
+        Some(Err(err)) => (Some(Err(err)), Vec::new()),
+        None => (None, Vec::new()),
+    };
+    let legs: Vec<Tx> = envelope.legs().cloned().collect();
+    let Envelope {
+        tx_id,
+        tx,
+        seq,
+        reply,
+        submitted,
+        batch,
+        restarts,
+        span,
+        ..
+    } = envelope;
+    let _span =
+        info_span!(parent: &span, "apply", tx_id, account = tx.account, handler = owner, worker)
+            .entered();
+    let (result, entries) = {
+        let mut data = sync::lock(&peers.shards[owner as usize]);
+        if batch.is_empty() {
+            let decided = decided.map(|decided| decided.map(drop));
+            let (result, entries) = data.settle(tx_id, &tx, seq, across, decided);
+            (result, vec![entries])
+        } else {
+            let collector = across.then(|| tx::collector_of(&legs)).flatten();
+            data.settle_batch(tx_id, &legs, collector, decided)
+        }
+    };
+    if let (Ok(()), Some(storage)) = (&result, &peers.storage) {
+        let committed: Vec<_> = entries.iter().flatten().chain(&credited).cloned().collect();
+        // holds move nothing, so they leave nothing to commit
+        if !committed.is_empty() {
+            if let Err(err) = storage.commit(worker, tx_id, &committed) {
+                error!(%err, "failed to persist tx");
+            }
+        }
+    }
+    if let Err(err) = &result {
+        info!(%err, "rejected tx");
+        peers.dead_letters.push(tx_id, &legs, err, restarts + 1);
+    }
+    if across {
+        peers.cross_in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+    if !batch.is_empty() {
+        peers.batches_in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+    let depth = peers.tx_count[owner as usize].fetch_sub(1, Ordering::SeqCst) - 1;
+    peers.alerts.queue_depth(owner, depth);
+    peers.queues[owner as usize].done(accounts);
+    peers.check_in(worker);
+    peers.tracker.finish(tx_id, &result);
+    let latency = peers.clock.now().saturating_duration_since(submitted);
+    peers.metrics.observe(owner, &legs, &result, latency);
+    peers.middleware.finished(tx_id, &legs, &result);
+    peers.alerts.applied(tx_id, entries.iter().flatten());
+    for (leg, entries) in legs.iter().zip(&entries) {
+        peers.events.finished(tx_id, leg, &result, entries);
+    }
+    // the submitter may have dropped its receipt
+    let _ = reply.send(result);
+}
+
+/// The bookkeeping for a transaction `Queue::cancel` took off handler `owner`'s queue: its
+/// pending counts and any reversal it claimed are given back, and its submitter hears it was
+/// cancelled. Its slot on the queue stays counted until `pass_cancelled`.
+pub(crate) fn cancel(owner: HandleId, peers: &Peers, envelope: Envelope) {
+    let legs: Vec<Tx> = envelope.legs().cloned().collect();
+    let Envelope {
+        tx_id,
+        tx,
+        seq,
+        reply,
+        credit,
+        batch,
+        span,
+        ..
+    } = envelope;
+    let _span =
+        info_span!(parent: &span, "cancel", tx_id, account = tx.account, handler = owner).entered();
+    let cancelled = TxError::Cancelled(tx_id);
+    // the peer's barrier goes with the credit channel, and takes the target's pending count along
+    let across = credit.is_some();
+    drop(credit);
+    let result = {
+        let mut data = sync::lock(&peers.shards[owner as usize]);
+        for leg in &legs {
+            if let TxType::REVERSAL { original } = leg.tx_type {
+                data.unclaim_reversal(leg.account, original);
+            }
+        }
+        match batch.is_empty() {
+            true => data.settle(tx_id, &tx, seq, across, Some(Err(cancelled))).0,
+            false => {
+                let collector = across.then(|| tx::collector_of(&legs)).flatten();
+                data.settle_batch(tx_id, &legs, collector, Some(Err(cancelled)))
+                    .0
+            }
+        }
+    };
+    if !batch.is_empty() {
+        peers.batches_in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+    info!("cancelled tx");
+    peers.tracker.mark(tx_id, TxStatus::Cancelled);
+    if let Err(err) = &result {
+        peers.metrics.reject(err);
+    }
+    for leg in &legs {
+        peers.events.finished(tx_id, leg, &result, &[]);
+    }
+    let _ = reply.send(result);
+}
+
+/// Hands back the slot a cancelled transaction kept on handler `owner`'s queue, once its turn on
+/// `accounts` came.
+pub(crate) fn pass_cancelled(owner: HandleId, peers: &Peers, accounts: &[AccountId]) {
+    peers.tx_count[owner as usize].fetch_sub(1, Ordering::SeqCst);
+    peers.queues[owner as usize].done(accounts);
+}
+
+// Takes one transaction off the first peer with enough of a backlog.
+fn steal(id: HandleId, peers: &Peers, threshold: usize) {
+    for (victim, queue) in peers.queues.iter().enumerate() {
+        if victim == id as usize {
+            continue;
+        }
+        if let Some((envelope, accounts)) = queue.steal(threshold) {
+            debug!(handler = id, victim, "stealing tx");
+            let victim = victim as HandleId;
+            peers.check_out(
+                id,
+                victim,
+                &accounts,
+                Work::Queued(Box::new(envelope.clone())),
+            );
+            process(id, victim, peers, envelope, accounts);
+            peers.latency.pause(id);
+            return;
+        }
+    }
+}
+
+// Releases the account a barrier held on handler `id`, crediting it first if the transfer's debit
+// went through.
+pub(crate) fn apply_credit(
+    id: HandleId,
+    peers: &Peers,
+    account: AccountId,
+    credit: Option<Credit>,
+    accounts: &[AccountId],
+) {
+    let mut data = sync::lock(&peers.shards[id as usize]);
+    if let Some(Credit {
+        tx_id,
+        credits,
+        ack,
+    }) = credit
+    {
+        let acked = data.credit(tx_id, account, &credits);
+        data.publish(account);
+        let _ = ack.send(acked.clone());
+        for entry in acked.iter().flatten() {
+            peers.events.credited(entry);
+        }
+    }
+    data.decrease_pending_tx(account, 1);
+    drop(data);
+    peers.tx_count[id as usize].fetch_sub(1, Ordering::SeqCst);
+    peers.queues[id as usize].done(accounts);
+    peers.check_in(id);
+}
+
+// Debits the source locally and hands the credit to the handler owning `to`, giving back the
+// channel its answer arrives on. Rolls the debit back if the peer is gone.
+fn debit_across(
+    shard: &Shard,
+    tx_id: TxId,
+    tx: &Tx,
+    peer: HandleId,
+    credit: &Sender<Credit>,
+) -> Result<Receiver<Acked>, TxError> {
+    sync::lock(shard).pay_out(tx.account, tx.currency, tx.amount)?;
+
+    let from = tx.account;
+    let kind = match tx.tx_type {
+        TxType::FEE { .. } => EntryKind::FeeIn { from },
+        _ => EntryKind::TransferIn { from },
+    };
+    let (ack, ack_rx) = channel();
+    let sent = credit.send(Credit {
+        tx_id,
+        credits: vec![(kind, tx.currency, tx.amount)],
+        ack,
+    });
+    if sent.is_err() {
+        sync::lock(shard).refund(tx.account, tx.currency, tx.amount)?;
+        return Err(TxError::HandlerUnavailable(peer));
+    }
+    Ok(ack_rx)
+}
+
+// `debit_across` for a batch whose fees go to an account owned by another handler: stages the
+// batch here but for crediting the fees, hands those to the peer and gives back the channel its
+// answer arrives on with the staged batch. Rolls the batch back if the peer is gone.
+fn stage_across(
+    shard: &Shard,
+    tx_id: TxId,
+    legs: &[Tx],
+    peer: HandleId,
+    credit: &Sender<Credit>,
+) -> Result<(Receiver<Acked>, StagedBatch), TxError> {
+    let collector = tx::collector_of(legs);
+    let staged = sync::lock(shard).stage_batch(legs, collector)?;
+
+    let credits = legs.iter().filter_map(|leg| match leg.tx_type {
+        TxType::FEE { to } if Some(to) == collector => {
+            let kind = EntryKind::FeeIn { from: leg.account };
+            Some((kind, leg.currency, leg.amount))
+        }
+        _ => None,
+    });
+    let (ack, ack_rx) = channel();
+    let sent = credit.send(Credit {
+        tx_id,
+        credits: credits.collect(),
+        ack,
+    });
+    if sent.is_err() {
+        sync::lock(shard).roll_back_batch(staged);
+        return Err(TxError::HandlerUnavailable(peer));
+    }
+    Ok((ack_rx, staged))
+}
@@ -0,0 +1,91 @@
+//! A whole engine run on the deterministic executor and a `VirtualClock`, for integration tests
+//! that come out the same on every run and take no real time: the handlers are picked from the
+//! seed, or one at a time by the test, time only moves when the test moves it, and the test
+//! draws whatever it generates from the same seed.
+
+use std::time::Duration;
+
+use crate::{AccountId, Aptone, AptoneBuilder, HandleId, Money, Tx, TxError, TxId, VirtualClock};
+
+/// See the module docs. The engine is reached through `aptone` for anything the harness has no
+/// shortcut for.
+pub struct TestHarness {
+    aptone: Aptone,
+    clock: VirtualClock,
+    rng: u64, // xorshift state, apart from the one the executor draws from
+}
+
+impl TestHarness {
+    /// On the default configuration.
+    pub fn new(seed: u64) -> TestHarness {
+        TestHarness::with_builder(seed, Aptone::builder())
+    }
+    /// On `builder`'s configuration, its clock replaced with the harness's and switched to the
+    /// deterministic executor seeded with `seed`.
+    pub fn with_builder(seed: u64, builder: AptoneBuilder) -> TestHarness {
+        let clock = VirtualClock::new();
+        let aptone = builder.deterministic(seed).clock(clock.clone()).build();
+        TestHarness {
+            aptone,
+            clock,
+            // xorshift never leaves zero
+            rng: (seed ^ 0x9e37_79b9_7f4a_7c15).max(1),
+        }
+    }
+    pub fn aptone(&self) -> &Aptone {
+        &self.aptone
+    }
+    pub fn clock(&self) -> &VirtualClock {
+        &self.clock
+    }
+    /// The next number off the harness's generator, for the test to build its workload from.
+    pub fn next_u64(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+    /// A number drawn below `bound`, which has to be above zero.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+    /// Opens `count` accounts, each holding `balance`.
+    pub fn open_accounts(&self, count: usize, balance: Money) -> Result<Vec<AccountId>, TxError> {
+        (0..count)
+            .map(|_| self.aptone.open_account(balance))
+            .collect()
+    }
+    /// Submits `tx` and runs the handlers until it, and everything else queued, is through.
+    pub fn apply(&self, tx: Tx) -> Result<TxId, TxError> {
+        let receipt = self.aptone.submit_tx(tx)?;
+        let tx_id = receipt.tx_id();
+        self.aptone.run_until_idle();
+        receipt.wait().map(|()| tx_id)
+    }
+    /// Has the handler drawn from the seed take one step, processing one message; `false` if
+    /// none could. Scheduled transactions are left for `run_until_idle` or `advance`.
+    pub fn step(&self) -> bool {
+        self.aptone.step(None)
+    }
+    /// Has `handler` take one step, to put the handlers' work in the order a test is after, as
+    /// for a transaction's receipt's `handle_id`; `false` if it couldn't, having nothing to do
+    /// or waiting on another handler.
+    pub fn step_handler(&self, handler: HandleId) -> bool {
+        self.aptone.step(Some(handler))
+    }
+    /// Takes `handler` down for good, as if its thread died and couldn't be started again: what
+    /// is queued on it fails, and whatever is submitted to it is turned away.
+    pub fn kill_handler(&self, handler: HandleId) {
+        self.aptone.kill_handler(handler);
+    }
+    /// Runs the handlers until none has anything left to do, see `Aptone::run_until_idle`.
+    pub fn run_until_idle(&self) {
+        self.aptone.run_until_idle();
+    }
+    /// Moves the clock on by `duration`, then runs the handlers until idle, submitting what fell
+    /// due meanwhile.
+    pub fn advance(&self, duration: Duration) {
+        self.clock.advance(duration);
+        self.aptone.run_until_idle();
+    }
+}
@@ -0,0 +1,113 @@
+use std::time::SystemTime;
+
+use crate::{AccountId, Currency, HoldId, Money, TxId, TxKind};
+
+/// What an applied transaction did to the account a history entry belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EntryKind {
+    Deposit,
+    Withdraw,
+    TransferOut {
+        to: AccountId,
+    },
+    TransferIn {
+        from: AccountId,
+    },
+    ExchangeOut {
+        to: Currency,
+    },
+    ExchangeIn {
+        from: Currency,
+    },
+    /// Authorizing and releasing a hold leave the posted balance, and so the history, alone.
+    Capture {
+        hold: HoldId,
+    },
+    /// `amount` is what it added to the balance, so negative when it took back a deposit.
+    Reversal {
+        original: TxId,
+    },
+    /// A fee charged to the account, collected in `to`.
+    FeeOut {
+        to: AccountId,
+    },
+    /// A fee `from` was charged, collected in the account.
+    FeeIn {
+        from: AccountId,
+    },
+}
+
+impl EntryKind {
+    /// The kind of transaction the entry is part of.
+    pub fn tx_kind(&self) -> TxKind {
+        match self {
+            EntryKind::Deposit => TxKind::Deposit,
+            EntryKind::Withdraw => TxKind::Withdraw,
+            EntryKind::TransferOut { .. } | EntryKind::TransferIn { .. } => TxKind::Transfer,
+            EntryKind::ExchangeOut { .. } | EntryKind::ExchangeIn { .. } => TxKind::Exchange,
+            EntryKind::Capture { .. } => TxKind::Capture,
+            EntryKind::Reversal { .. } => TxKind::Reversal,
+            EntryKind::FeeOut { .. } | EntryKind::FeeIn { .. } => TxKind::Fee,
+        }
+    }
+    /// How the entry is named in statements and over HTTP.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            EntryKind::Deposit => "deposit",
+            EntryKind::Withdraw => "withdraw",
+            EntryKind::TransferOut { .. } => "transfer_out",
+            EntryKind::TransferIn { .. } => "transfer_in",
+            EntryKind::ExchangeOut { .. } => "exchange_out",
+            EntryKind::ExchangeIn { .. } => "exchange_in",
+            EntryKind::Capture { .. } => "capture",
+            EntryKind::Reversal { .. } => "reversal",
+            EntryKind::FeeOut { .. } => "fee_out",
+            EntryKind::FeeIn { .. } => "fee_in",
+        }
+    }
+    /// The account, currency, hold or transaction the entry refers to.
+    pub(crate) fn reference(&self) -> Option<String> {
+        match *self {
+            EntryKind::Deposit | EntryKind::Withdraw => None,
+            EntryKind::TransferOut { to } | EntryKind::FeeOut { to } => Some(to.to_string()),
+            EntryKind::TransferIn { from } | EntryKind::FeeIn { from } => Some(from.to_string()),
+            EntryKind::ExchangeOut { to } => Some(to.to_string()),
+            EntryKind::ExchangeIn { from } => Some(from.to_string()),
+            EntryKind::Capture { hold } => Some(hold.number.to_string()),
+            EntryKind::Reversal { original } => Some(original.to_string()),
+        }
+    }
+}
+
+/// One applied transaction in an account's history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HistoryEntry {
+    pub tx_id: TxId,
+    pub time: SystemTime,
+    pub kind: EntryKind,
+    /// What `amount` and `balance` are in.
+    pub currency: Currency,
+    pub amount: Money,
+    /// Balance of the account right after the transaction.
+    pub balance: Money,
+}
+
+impl HistoryEntry {
+    /// Balance of the account right before the transaction.
+    pub(crate) fn balance_before(&self) -> Money {
+        match self.kind {
+            EntryKind::Deposit
+            | EntryKind::TransferIn { .. }
+            | EntryKind::ExchangeIn { .. }
+            | EntryKind::Reversal { .. }
+            | EntryKind::FeeIn { .. } => self.balance - self.amount,
+            EntryKind::Withdraw
+            | EntryKind::TransferOut { .. }
+            | EntryKind::ExchangeOut { .. }
+            | EntryKind::Capture { .. }
+            | EntryKind::FeeOut { .. } => self.balance + self.amount,
+        }
+    }
+}
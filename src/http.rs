@@ -0,0 +1,506 @@
+//! REST front end for a running engine, built on axum. Transactions are answered once their
+//! handler has applied or rejected them.
+//!
+//! - `POST /accounts` takes `{"initial_balance": "100"}`, or `{}` to open the account empty,
+//!   and answers with its id. `DELETE /accounts/{id}` closes it, answering with its balances.
+//! - `POST /accounts/{id}/deposit` and `POST /accounts/{id}/withdraw` take `{"amount": "12.5"}`,
+//!   or a whole number for the amount, and an optional `Idempotency-Key` header deduplicating
+//!   retries. Amounts and balances are sent back as decimal strings. An optional `"currency"`
+//!   code picks the balance the amount applies to, the default currency if left out.
+//! - `GET /accounts/{id}/balance?currency=EUR`, the default currency without one
+//! - `GET /accounts/{id}/history?limit=n&offset=n`
+//! - `GET /events?account=n` upgrades to a WebSocket streaming, as JSON text messages, every
+//!   transaction applied or rejected and every threshold crossed from then on, each applied
+//!   transaction followed by the balances it changed, as read just after, and every resize of
+//!   the handler pool. With `account`, only what touches that account is sent.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::info_span;
+
+use crate::tx;
+use crate::{
+    AccountId, Aptone, Crossing, Currency, EntryKind, HistoryEntry, Money, Tx, TxError, TxEvent,
+    TxId, TxType,
+};
+
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+// messages buffered per stream before the engine side waits for it to catch up
+const EVENT_BUFFER: usize = 256;
+
+#[derive(Deserialize)]
+struct AmountRequest {
+    amount: Money,
+    #[serde(default)]
+    currency: Currency,
+}
+
+#[derive(Deserialize)]
+struct OpenRequest {
+    #[serde(default)]
+    initial_balance: Money,
+}
+
+#[derive(Serialize)]
+struct AccountResponse {
+    account: AccountId,
+}
+
+#[derive(Serialize)]
+struct ClosedResponse {
+    account: AccountId,
+    balances: Vec<BalanceEntry>,
+}
+
+#[derive(Serialize)]
+struct BalanceEntry {
+    currency: Currency,
+    balance: Money,
+}
+
+#[derive(Serialize)]
+struct TxResponse {
+    tx_id: TxId,
+}
+
+#[derive(Serialize)]
+struct BalanceResponse {
+    account: AccountId,
+    currency: Currency,
+    balance: Money,
+}
+
+#[derive(Deserialize)]
+struct BalanceQuery {
+    currency: Option<Currency>,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct HistoryResponse {
+    tx_id: TxId,
+    /// Milliseconds since the Unix epoch.
+    time: u128,
+    kind: &'static str,
+    counterparty: Option<AccountId>,
+    currency: Currency,
+    /// The other side of an exchange.
+    counter_currency: Option<Currency>,
+    /// The hold a capture posted.
+    hold: Option<u64>,
+    /// The transaction a reversal undid.
+    original: Option<TxId>,
+    amount: Money,
+    balance: Money,
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    account: Option<AccountId>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum EventMessage {
+    Applied {
+        tx_id: TxId,
+        #[serde(flatten)]
+        tx: TxMessage,
+    },
+    Rejected {
+        tx_id: TxId,
+        #[serde(flatten)]
+        tx: TxMessage,
+        error: String,
+    },
+    Balance {
+        account: AccountId,
+        currency: Currency,
+        balance: Money,
+    },
+    ThresholdCrossed {
+        account: AccountId,
+        currency: Currency,
+        threshold: Money,
+        balance: Money,
+        /// `up` or `down`.
+        crossing: &'static str,
+    },
+    Resized {
+        from: usize,
+        to: usize,
+    },
+    /// One per currency the account held at the close.
+    Settled {
+        settlement: u64,
+        account: AccountId,
+        currency: Currency,
+        balance: Money,
+    },
+}
+
+#[derive(Serialize)]
+struct TxMessage {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    account: AccountId,
+    amount: Money,
+    currency: Currency,
+    /// The target of a transfer.
+    to: Option<AccountId>,
+    /// What an exchange converted into.
+    to_currency: Option<Currency>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+struct ApiError(TxError);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self.0 {
+            TxError::InsufficientFunds { .. }
+            | TxError::Overflow(_)
+            | TxError::NoExchangeRate { .. }
+            | TxError::NotReversible(_)
+            | TxError::LimitExceeded { .. }
+            | TxError::Rejected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            TxError::InvalidAmount(_) | TxError::EmptyBatch => StatusCode::BAD_REQUEST,
+            TxError::UnknownAccount(_)
+            | TxError::UnknownHold(_)
+            | TxError::UnknownTx { .. }
+            | TxError::NotDeadLettered(_) => StatusCode::NOT_FOUND,
+            TxError::Duplicate(_)
+            | TxError::AccountBusy { .. }
+            | TxError::AlreadyReversed(_)
+            | TxError::Cancelled(_) => StatusCode::CONFLICT,
+            TxError::QueueFull(_) | TxError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            TxError::HandlerUnavailable(_) | TxError::ShuttingDown | TxError::NoAccountsLeft => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            TxError::Wal(_) | TxError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = ErrorResponse {
+            error: self.0.to_string(),
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+impl From<HistoryEntry> for HistoryResponse {
+    fn from(entry: HistoryEntry) -> HistoryResponse {
+        let kind = entry.kind.name();
+        let counterparty = match entry.kind {
+            EntryKind::TransferOut { to } | EntryKind::FeeOut { to } => Some(to),
+            EntryKind::TransferIn { from } | EntryKind::FeeIn { from } => Some(from),
+            _ => None,
+        };
+        let counter_currency = match entry.kind {
+            EntryKind::ExchangeOut { to } => Some(to),
+            EntryKind::ExchangeIn { from } => Some(from),
+            _ => None,
+        };
+        let hold = match entry.kind {
+            EntryKind::Capture { hold } => Some(hold.number),
+            _ => None,
+        };
+        let original = match entry.kind {
+            EntryKind::Reversal { original } => Some(original),
+            _ => None,
+        };
+        HistoryResponse {
+            tx_id: entry.tx_id,
+            time: entry
+                .time
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis()),
+            kind,
+            counterparty,
+            currency: entry.currency,
+            counter_currency,
+            hold,
+            original,
+            amount: entry.amount,
+            balance: entry.balance,
+        }
+    }
+}
+
+/// Routes serving `aptone`.
+pub fn router(aptone: Arc<Aptone>) -> Router {
+    Router::new()
+        .route("/accounts", post(open))
+        .route("/accounts/{id}", delete(close))
+        .route("/accounts/{id}/deposit", post(deposit))
+        .route("/accounts/{id}/withdraw", post(withdraw))
+        .route("/accounts/{id}/balance", get(balance))
+        .route("/accounts/{id}/history", get(history))
+        .route("/events", get(events))
+        .with_state(aptone)
+}
+
+/// Serves `aptone` on `addr` until the server fails.
+pub async fn serve(aptone: Arc<Aptone>, addr: SocketAddr) -> io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, router(aptone)).await
+}
+
+async fn open(
+    State(aptone): State<Arc<Aptone>>,
+    Json(request): Json<OpenRequest>,
+) -> Result<Json<AccountResponse>, ApiError> {
+    let account = aptone
+        .open_account(request.initial_balance)
+        .map_err(ApiError)?;
+    Ok(Json(AccountResponse { account }))
+}
+
+async fn close(
+    State(aptone): State<Arc<Aptone>>,
+    Path(account): Path<AccountId>,
+) -> Result<Json<ClosedResponse>, ApiError> {
+    let balances = aptone.close_account(account).map_err(ApiError)?;
+    Ok(Json(ClosedResponse {
+        account,
+        balances: balances
+            .into_iter()
+            .map(|(currency, balance)| BalanceEntry { currency, balance })
+            .collect(),
+    }))
+}
+
+async fn deposit(
+    State(aptone): State<Arc<Aptone>>,
+    Path(account): Path<AccountId>,
+    headers: HeaderMap,
+    Json(request): Json<AmountRequest>,
+) -> Result<Json<TxResponse>, ApiError> {
+    let tx = Tx::new(account, request.amount, TxType::DEPOSIT).in_currency(request.currency);
+    submit(aptone, &headers, tx).await
+}
+
+async fn withdraw(
+    State(aptone): State<Arc<Aptone>>,
+    Path(account): Path<AccountId>,
+    headers: HeaderMap,
+    Json(request): Json<AmountRequest>,
+) -> Result<Json<TxResponse>, ApiError> {
+    let tx = Tx::new(account, request.amount, TxType::WITHDRAW).in_currency(request.currency);
+    submit(aptone, &headers, tx).await
+}
+
+async fn submit(
+    aptone: Arc<Aptone>,
+    headers: &HeaderMap,
+    tx: Tx,
+) -> Result<Json<TxResponse>, ApiError> {
+    let key = headers
+        .get("idempotency-key")
+        .and_then(|key| key.to_str().ok())
+        .map(str::to_owned);
+    let span = info_span!("request", api = "http");
+    #[cfg(feature = "otel")]
+    crate::otel::continue_trace(&span, |name| {
+        headers.get(name)?.to_str().ok().map(str::to_owned)
+    });
+    // submitting may block on a full queue and waiting blocks until the handler is done, so
+    // neither runs on the async workers
+    tokio::task::spawn_blocking(move || {
+        let _span = span.entered();
+        let receipt = match &key {
+            Some(key) => aptone.submit_tx_with_key(key, tx),
+            None => aptone.submit_tx(tx),
+        }
+        .map_err(ApiError)?;
+        let tx_id = receipt.tx_id();
+        receipt.wait().map_err(ApiError)?;
+        Ok(Json(TxResponse { tx_id }))
+    })
+    .await
+    .unwrap_or(Err(ApiError(TxError::ShuttingDown)))
+}
+
+async fn balance(
+    State(aptone): State<Arc<Aptone>>,
+    Path(account): Path<AccountId>,
+    Query(query): Query<BalanceQuery>,
+) -> Json<BalanceResponse> {
+    let currency = query.currency.unwrap_or_default();
+    Json(BalanceResponse {
+        account,
+        currency,
+        balance: aptone.get_balance_in(account, currency),
+    })
+}
+
+async fn history(
+    State(aptone): State<Arc<Aptone>>,
+    Path(account): Path<AccountId>,
+    Query(query): Query<HistoryQuery>,
+) -> Json<Vec<HistoryResponse>> {
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+    let entries = aptone.history(account, limit, query.offset.unwrap_or(0));
+    Json(entries.into_iter().map(HistoryResponse::from).collect())
+}
+
+async fn events(
+    State(aptone): State<Arc<Aptone>>,
+    Query(query): Query<EventsQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.on_upgrade(move |socket| stream_events(aptone, query.account, socket))
+}
+
+async fn stream_events(aptone: Arc<Aptone>, account: Option<AccountId>, mut socket: WebSocket) {
+    let events = aptone.subscribe();
+    let (sender, mut receiver) = mpsc::channel(EVENT_BUFFER);
+    // the subscription is a blocking receiver; forward it until either side hangs up
+    tokio::task::spawn_blocking(move || {
+        for event in events {
+            for message in event_messages(&aptone, event, account) {
+                if sender.blocking_send(message).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    while let Some(message) = receiver.recv().await {
+        let text = serde_json::to_string(&message).expect("event messages always serialize");
+        if socket.send(Message::Text(text.into())).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn event_messages(
+    aptone: &Aptone,
+    event: TxEvent,
+    account: Option<AccountId>,
+) -> Vec<EventMessage> {
+    let wanted = |other: AccountId| account.is_none_or(|account| account == other);
+    match event {
+        TxEvent::Applied { tx_id, tx } => {
+            let accounts = tx::accounts_of([&tx]);
+            if !accounts.iter().any(|&account| wanted(account)) {
+                return Vec::new();
+            }
+            let mut currencies = vec![tx.currency];
+            if let TxType::EXCHANGE { to } = tx.tx_type {
+                currencies.push(to);
+            }
+            let mut messages = vec![EventMessage::Applied {
+                tx_id,
+                tx: TxMessage::from(tx),
+            }];
+            for account in accounts.into_iter().filter(|&account| wanted(account)) {
+                messages.extend(currencies.iter().map(|&currency| EventMessage::Balance {
+                    account,
+                    currency,
+                    balance: aptone.get_balance_in(account, currency),
+                }));
+            }
+            messages
+        }
+        TxEvent::Rejected { tx_id, tx, error } => {
+            if !tx::accounts_of([&tx]).into_iter().any(wanted) {
+                return Vec::new();
+            }
+            vec![EventMessage::Rejected {
+                tx_id,
+                tx: TxMessage::from(tx),
+                error: error.to_string(),
+            }]
+        }
+        TxEvent::ThresholdCrossed {
+            account,
+            currency,
+            threshold,
+            balance,
+            crossing,
+        } => {
+            if !wanted(account) {
+                return Vec::new();
+            }
+            vec![EventMessage::ThresholdCrossed {
+                account,
+                currency,
+                threshold,
+                balance,
+                crossing: match crossing {
+                    Crossing::Up => "up",
+                    Crossing::Down => "down",
+                },
+            }]
+        }
+        // about no account in particular, so only for those watching every account
+        TxEvent::Resized { from, to } if account.is_none() => {
+            vec![EventMessage::Resized { from, to }]
+        }
+        TxEvent::Resized { .. } => Vec::new(),
+        TxEvent::Settled {
+            settlement,
+            account,
+            balances,
+        } => {
+            if !wanted(account) {
+                return Vec::new();
+            }
+            let balances = balances.into_iter();
+            let settled = balances.map(|(currency, balance)| EventMessage::Settled {
+                settlement,
+                account,
+                currency,
+                balance,
+            });
+            settled.collect()
+        }
+    }
+}
+
+impl From<Tx> for TxMessage {
+    fn from(tx: Tx) -> TxMessage {
+        let kind = match tx.tx_type {
+            TxType::DEPOSIT => "deposit",
+            TxType::WITHDRAW => "withdraw",
+            TxType::TRANSFER { .. } => "transfer",
+            TxType::EXCHANGE { .. } => "exchange",
+            TxType::AUTHORIZE { .. } => "authorize",
+            TxType::CAPTURE { .. } => "capture",
+            TxType::RELEASE { .. } => "release",
+            TxType::REVERSAL { .. } => "reversal",
+            TxType::FEE { .. } => "fee",
+        };
+        let to = tx.tx_type.payee();
+        let to_currency = match tx.tx_type {
+            TxType::EXCHANGE { to } => Some(to),
+            _ => None,
+        };
+        TxMessage {
+            kind,
+            account: tx.account,
+            amount: tx.amount,
+            currency: tx.currency,
+            to,
+            to_currency,
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Bulk import of transactions from CSV, one per row:
+//!
+//! ```text
+//! tx_type,account,amount[,to]
+//! deposit,3,100
+//! withdraw,3,40
+//! transfer,3,60.25,7
+//! ```
+//!
+//! Amounts are in the default currency, and the accounts have to be open. A header row is
+//! skipped, as are blank lines. Rows are submitted as they are read, with a bounded number in
+//! flight, so files of any size stream through the handlers.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead};
+
+use crate::{AccountId, Aptone, Money, TxError, TxReceipt, TxType};
+
+/// What an import got through.
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// Rows holding a transaction, applied or not.
+    pub rows: u64,
+    pub applied: u64,
+    /// The rows not applied, in file order.
+    pub errors: Vec<RowError>,
+}
+
+/// A row that was not applied, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RowError {
+    /// 1-based line number in the file.
+    pub line: u64,
+    pub kind: RowErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RowErrorKind {
+    /// The row isn't a transaction; holds the offending text.
+    Malformed(String),
+    Rejected(TxError),
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            RowErrorKind::Malformed(row) => {
+                write!(f, "line {}: malformed row {:?}", self.line, row)
+            }
+            RowErrorKind::Rejected(err) => write!(f, "line {}: {}", self.line, err),
+        }
+    }
+}
+
+impl Error for RowError {}
+
+/// Submits every row read from `reader` to `aptone`, waiting for the oldest transaction once
+/// `max_in_flight` are unfinished. Fails only if reading does; bad rows end up in the report.
+pub fn from_csv<R: BufRead>(
+    aptone: &Aptone,
+    reader: R,
+    max_in_flight: usize,
+) -> io::Result<ImportReport> {
+    let max_in_flight = max_in_flight.max(1);
+    let mut report = ImportReport::default();
+    let mut in_flight: VecDeque<(u64, TxReceipt)> = VecDeque::with_capacity(max_in_flight);
+
+    for (index, row) in reader.lines().enumerate() {
+        let row = row?;
+        let line = index as u64 + 1;
+        let fields: Vec<&str> = row.split(',').map(str::trim).collect();
+        if fields == [""] || (line == 1 && fields[0].eq_ignore_ascii_case("tx_type")) {
+            continue;
+        }
+        report.rows += 1;
+
+        let Some((account, amount, tx_type)) = parse(&fields) else {
+            report.errors.push(RowError {
+                line,
+                kind: RowErrorKind::Malformed(row),
+            });
+            continue;
+        };
+        if in_flight.len() == max_in_flight {
+            let (line, receipt) = in_flight.pop_front().unwrap();
+            report.finish(line, receipt.wait());
+        }
+        match aptone.handle_tx(account, amount, tx_type) {
+            Ok(receipt) => in_flight.push_back((line, receipt)),
+            Err(err) => report.finish(line, Err(err)),
+        }
+    }
+    for (line, receipt) in in_flight {
+        report.finish(line, receipt.wait());
+    }
+    // rows rejected on submission were recorded ahead of earlier rows still in flight
+    report.errors.sort_by_key(|err| err.line);
+    Ok(report)
+}
+
+impl ImportReport {
+    fn finish(&mut self, line: u64, result: Result<(), TxError>) {
+        match result {
+            Ok(()) => self.applied += 1,
+            Err(err) => self.errors.push(RowError {
+                line,
+                kind: RowErrorKind::Rejected(err),
+            }),
+        }
+    }
+}
+
+/// One row on its own, as a Kafka message holds it.
+#[cfg(feature = "kafka")]
+pub(crate) fn parse_row(row: &str) -> Option<(AccountId, Money, TxType)> {
+    let fields: Vec<&str> = row.split(',').map(str::trim).collect();
+    parse(&fields)
+}
+
+fn parse(fields: &[&str]) -> Option<(AccountId, Money, TxType)> {
+    let number = |i: usize| fields.get(i).and_then(|field| field.parse().ok());
+    let amount = |i: usize| fields.get(i).and_then(|field| field.parse().ok());
+    let tx_type = fields[0].to_ascii_lowercase();
+    match (tx_type.as_str(), fields.len()) {
+        ("deposit", 3) => Some((number(1)?, amount(2)?, TxType::DEPOSIT)),
+        ("withdraw", 3) => Some((number(1)?, amount(2)?, TxType::WITHDRAW)),
+        ("transfer", 4) => Some((number(1)?, amount(2)?, TxType::TRANSFER { to: number(3)? })),
+        _ => None,
+    }
+}
@@ -0,0 +1,42 @@
+use std::time::Duration;
+
+use crate::{AccountId, Accrual, Currency, Money};
+
+const YEAR: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Interest on every positive balance, at `rate` a year as a fraction like `0.05`, worked out
+/// every `compounding` and deposited into the account as a transaction of its own, so what was
+/// paid earns interest from then on. Overdrawn balances neither earn nor owe any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterestPolicy {
+    pub rate: Money,
+    pub compounding: Duration,
+}
+
+impl InterestPolicy {
+    pub fn new(rate: Money, compounding: Duration) -> InterestPolicy {
+        InterestPolicy { rate, compounding }
+    }
+    /// What `balance` earns over `period`, on a 365 day year, rounded down to `Money::SCALE`
+    /// places. Nothing on a balance too large to work it out for.
+    pub fn interest(&self, balance: Money, period: Duration) -> Money {
+        if !balance.is_positive() || !self.rate.is_positive() {
+            return Money::ZERO;
+        }
+        let one = 10i128.pow(Money::SCALE);
+        let nanos = period.as_nanos() as i128;
+        let earned = balance
+            .minor()
+            .checked_mul(self.rate.minor())
+            .and_then(|yearly| yearly.checked_mul(nanos))
+            .map_or(0, |earned| earned / one / YEAR.as_nanos() as i128);
+        Money::from_minor(earned)
+    }
+}
+
+/// Pays the interest on each settlement instead, over the time since the one before.
+impl Accrual for InterestPolicy {
+    fn accrue(&self, _: AccountId, _: Currency, balance: Money, period: Duration) -> Money {
+        self.interest(balance, period)
+    }
+}
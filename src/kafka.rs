@@ -0,0 +1,243 @@
+//! Feeds transactions from a Kafka topic into the engine, one per message, in the row format of
+//! `import`:
+//!
+//! ```text
+//! deposit,3,100
+//! transfer,3,60.25,7
+//! ```
+//!
+//! A message's offset is committed only once its transaction was applied or rejected for good.
+//! Each transaction is submitted under a key naming its message, so one read again after a
+//! restart is skipped as a duplicate as long as the dedup window still remembers it. Only built
+//! with the `kafka` feature.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ::kafka::consumer::{Consumer, FetchOffset, GroupOffsetStorage};
+use tracing::{debug, warn};
+
+use crate::import;
+use crate::{Aptone, TxError, TxReceipt};
+
+/// What a `KafkaSource` got through until it was stopped.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KafkaReport {
+    pub messages: u64,
+    pub applied: u64,
+    pub rejected: u64,
+    /// Messages submitted before, under the same key.
+    pub duplicates: u64,
+    /// Messages that aren't a transaction, skipped.
+    pub malformed: u64,
+}
+
+#[derive(Debug)]
+pub enum KafkaError {
+    Kafka(::kafka::Error),
+    /// A transaction couldn't be submitted, for now. Neither it nor anything after it in its
+    /// partition was committed, so they are read again on the next run.
+    Submit {
+        topic: String,
+        partition: i32,
+        offset: i64,
+        error: TxError,
+    },
+}
+
+impl fmt::Display for KafkaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KafkaError::Kafka(err) => write!(f, "kafka: {}", err),
+            KafkaError::Submit {
+                topic,
+                partition,
+                offset,
+                error,
+            } => write!(
+                f,
+                "failed to submit message {} of {}/{}: {}",
+                offset, topic, partition, error
+            ),
+        }
+    }
+}
+
+impl Error for KafkaError {}
+
+impl From<::kafka::Error> for KafkaError {
+    fn from(err: ::kafka::Error) -> KafkaError {
+        KafkaError::Kafka(err)
+    }
+}
+
+/// A message read off a partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KafkaMessage {
+    pub offset: i64,
+    pub value: Vec<u8>,
+}
+
+/// The messages one poll read off one partition, oldest first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KafkaBatch {
+    pub topic: String,
+    pub partition: i32,
+    pub messages: Vec<KafkaMessage>,
+}
+
+/// What a `KafkaSource` reads its messages from and commits its offsets to. A `Consumer` is one;
+/// anything else handing out each partition's messages in order will do, such as a topic kept
+/// in memory for a test.
+pub trait Partitions {
+    /// The messages that came in since the last poll.
+    fn poll(&mut self) -> Result<Vec<KafkaBatch>, KafkaError>;
+    /// Notes every message of `partition` up to `offset` as done with.
+    fn consume(&mut self, topic: &str, partition: i32, offset: i64) -> Result<(), KafkaError>;
+    /// Commits what was noted as done with, so the group doesn't read it again.
+    fn commit(&mut self) -> Result<(), KafkaError>;
+}
+
+impl Partitions for Consumer {
+    fn poll(&mut self) -> Result<Vec<KafkaBatch>, KafkaError> {
+        let sets = Consumer::poll(self)?;
+        let batches = sets.iter().map(|set| KafkaBatch {
+            topic: set.topic().to_string(),
+            partition: set.partition(),
+            messages: set
+                .messages()
+                .iter()
+                .map(|message| KafkaMessage {
+                    offset: message.offset,
+                    value: message.value.to_vec(),
+                })
+                .collect(),
+        });
+        Ok(batches.collect())
+    }
+    fn consume(&mut self, topic: &str, partition: i32, offset: i64) -> Result<(), KafkaError> {
+        Ok(self.consume_message(topic, partition, offset)?)
+    }
+    fn commit(&mut self) -> Result<(), KafkaError> {
+        Ok(self.commit_consumed()?)
+    }
+}
+
+pub struct KafkaSource<P = Consumer> {
+    consumer: P,
+}
+
+impl KafkaSource {
+    /// Consumes `topic` as a member of `group`, committing offsets to Kafka. A group new to the
+    /// topic starts from its oldest message.
+    pub fn new(hosts: Vec<String>, topic: &str, group: &str) -> Result<KafkaSource, KafkaError> {
+        let consumer = Consumer::from_hosts(hosts)
+            .with_topic(topic.to_string())
+            .with_group(group.to_string())
+            .with_fallback_offset(FetchOffset::Earliest)
+            .with_offset_storage(Some(GroupOffsetStorage::Kafka))
+            .create()?;
+        Ok(KafkaSource::with_consumer(consumer))
+    }
+}
+
+impl<P: Partitions> KafkaSource<P> {
+    /// For a consumer set up some other way. A `Consumer` needs a group to commit offsets to.
+    pub fn with_consumer(consumer: P) -> KafkaSource<P> {
+        KafkaSource { consumer }
+    }
+    /// Submits the messages to `aptone` as they arrive, until `stop` is set.
+    pub fn run(&mut self, aptone: &Aptone, stop: &AtomicBool) -> Result<KafkaReport, KafkaError> {
+        let mut report = KafkaReport::default();
+        while !stop.load(Ordering::SeqCst) {
+            let batches = self.consumer.poll()?;
+            let mut submitted = Ok(());
+            for batch in &batches {
+                let (done, failed) = submit(aptone, batch, &mut report);
+                if let Some(offset) = done {
+                    self.consumer
+                        .consume(&batch.topic, batch.partition, offset)?;
+                }
+                if let Some(failed) = failed {
+                    submitted = Err(failed);
+                    break;
+                }
+            }
+            // what was consumed before a failure too, or it would be applied again once the
+            // dedup window forgot it
+            if !batches.is_empty() {
+                self.consumer.commit()?;
+                debug!(?report, "committed kafka offsets");
+            }
+            submitted?;
+        }
+        Ok(report)
+    }
+}
+
+// Submits the messages of one partition, oldest first, and waits for them. Gives back the offset
+// up to which every message was applied or rejected for good, and the failure of the first that
+// wasn't, which stops the partition there.
+fn submit(
+    aptone: &Aptone,
+    batch: &KafkaBatch,
+    report: &mut KafkaReport,
+) -> (Option<i64>, Option<KafkaError>) {
+    let (topic, partition) = (batch.topic.as_str(), batch.partition);
+    let failed = |offset, error| KafkaError::Submit {
+        topic: topic.to_string(),
+        partition,
+        offset,
+        error,
+    };
+    // each with the receipt of its transaction, if it got one
+    let mut in_flight = Vec::new();
+    let mut stopped = None;
+    for &KafkaMessage { offset, ref value } in &batch.messages {
+        report.messages += 1;
+        let row = std::str::from_utf8(value).ok();
+        let Some((account, amount, tx_type)) = row.and_then(import::parse_row) else {
+            warn!(topic, partition, offset, "malformed message");
+            report.malformed += 1;
+            in_flight.push((offset, None));
+            continue;
+        };
+        let key = format!("kafka/{}/{}/{}", topic, partition, offset);
+        match aptone.handle_tx_with_key(&key, account, amount, tx_type) {
+            Ok(receipt) => in_flight.push((offset, Some(receipt))),
+            Err(TxError::Duplicate(_)) => {
+                report.duplicates += 1;
+                in_flight.push((offset, None));
+            }
+            // the key was freed, so the message can be tried again
+            Err(error) if error.is_transient() || retryable(&error) => {
+                stopped = Some(failed(offset, error));
+                break;
+            }
+            Err(_) => {
+                report.rejected += 1;
+                in_flight.push((offset, None));
+            }
+        }
+    }
+    let mut done = None;
+    for (offset, receipt) in in_flight {
+        match receipt.map(TxReceipt::wait) {
+            None => {}
+            Some(Ok(())) => report.applied += 1,
+            // the messages after it are read again too, and skipped as duplicates if applied
+            Some(Err(error)) if error.is_transient() || retryable(&error) => {
+                return (done, Some(failed(offset, error)));
+            }
+            Some(Err(_)) => report.rejected += 1,
+        }
+        done = Some(offset);
+    }
+    (done, stopped)
+}
+
+// Failures on submission that say nothing about the transaction itself.
+fn retryable(err: &TxError) -> bool {
+    matches!(err, TxError::ShuttingDown | TxError::Wal(_))
+}
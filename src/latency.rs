@@ -0,0 +1,86 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::{Clock, HandleId};
+
+/// How long handlers take over each transaction on top of applying it, to see how the engine
+/// behaves in front of a slower backend. Handlers take no extra time by default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Latency {
+    Fixed(Duration),
+    /// Anywhere from `min` to `max`, all equally likely.
+    Uniform {
+        min: Duration,
+        max: Duration,
+    },
+    /// Log-normally distributed around `median`, `sigma` being the standard deviation of the
+    /// logarithm: the long tail of a real backend.
+    LogNormal {
+        median: Duration,
+        sigma: f64,
+    },
+}
+
+/// Draws the latency of every transaction a handler finishes.
+pub(crate) struct LatencyInjector {
+    latency: Option<Latency>,
+    rngs: Vec<Mutex<u64>>, // handler id -> xorshift state
+    clock: Arc<dyn Clock>,
+}
+
+impl LatencyInjector {
+    /// Every handler draws from its own generator seeded from `seed`, so with the deterministic
+    /// executor the same seed gives the same latencies. Without one the seed is picked at random.
+    pub(crate) fn new(
+        latency: Option<Latency>,
+        seed: Option<u64>,
+        handlers: usize,
+        clock: Arc<dyn Clock>,
+    ) -> LatencyInjector {
+        // RandomState is the std source of per-process randomness
+        let seed = seed.unwrap_or_else(|| RandomState::new().hash_one(0u64));
+        let rngs = (0..handlers as u64)
+            // xorshift never leaves zero
+            .map(|id| Mutex::new((seed ^ (id + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15)).max(1)))
+            .collect();
+        LatencyInjector {
+            latency,
+            rngs,
+            clock,
+        }
+    }
+    /// Has `handler` sleep off the latency of the transaction it just finished.
+    pub(crate) fn pause(&self, handler: HandleId) {
+        let delay = self.draw(handler);
+        if !delay.is_zero() {
+            self.clock.sleep(delay);
+        }
+    }
+    pub(crate) fn draw(&self, handler: HandleId) -> Duration {
+        let Some(latency) = self.latency else {
+            return Duration::ZERO;
+        };
+        let mut rng = self.rngs[handler as usize].lock().unwrap();
+        let mut uniform = || {
+            *rng ^= *rng << 13;
+            *rng ^= *rng >> 7;
+            *rng ^= *rng << 17;
+            (*rng >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let secs = match latency {
+            Latency::Fixed(delay) => return delay,
+            Latency::Uniform { min, max } => {
+                min.as_secs_f64() + (max.as_secs_f64() - min.as_secs_f64()) * uniform()
+            }
+            Latency::LogNormal { median, sigma } => {
+                // Box-Muller, with the first draw kept off zero
+                let (u1, u2) = (1.0 - uniform(), uniform());
+                let normal = (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos();
+                median.as_secs_f64() * (sigma * normal).exp()
+            }
+        };
+        Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX)
+    }
+}
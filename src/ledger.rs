@@ -0,0 +1,86 @@
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::{AccountId, Clock, Currency, HoldId, Money, TxError, TxId};
+
+/// A change to the account state. `ServerData` applies every change to balances and holds as
+/// one of these, so its state is what the events it was given add up to, and the events
+/// recorded with `AptoneBuilder::record_events` can be replayed with `ServerData::replay` or
+/// folded into read models of one's own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LedgerEvent {
+    /// Opened with `balance` in the default currency.
+    AccountOpened { account: AccountId, balance: Money },
+    /// Dropped, with its balances and holds.
+    AccountClosed { account: AccountId },
+    /// Put in place when recovering from a log.
+    BalanceRestored {
+        account: AccountId,
+        currency: Currency,
+        balance: Money,
+    },
+    /// Added to the balance, by a deposit, the credit of a transfer or exchange, a reversal or a
+    /// transfer rolled back.
+    Deposited {
+        account: AccountId,
+        currency: Currency,
+        amount: Money,
+    },
+    /// Taken off the balance, by a withdrawal, the debit of a transfer or exchange, a capture or
+    /// a reversal.
+    Withdrawn {
+        account: AccountId,
+        currency: Currency,
+        amount: Money,
+    },
+    HoldPlaced {
+        hold: HoldId,
+        currency: Currency,
+        amount: Money,
+    },
+    /// Released or captured, along with a `Withdrawn` of what it reserved.
+    HoldLifted { hold: HoldId },
+    /// Rejected by the handler once the changes it made, if any, were undone by the events
+    /// before it. A rejected batch leaves no other events. Has no serde form, as `TxError` has
+    /// none.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    TransactionRejected {
+        tx_id: TxId,
+        account: AccountId,
+        error: TxError,
+    },
+}
+
+/// The events every shard applied, with when they were recorded, in the order they were applied
+/// in. Those on one account are always applied holding the shard owning it, so they are in order
+/// here too.
+pub(crate) struct EventLog {
+    events: Mutex<Vec<(SystemTime, LedgerEvent)>>,
+    clock: Arc<dyn Clock>, // stamps the events
+}
+
+impl EventLog {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> EventLog {
+        EventLog {
+            events: Mutex::default(),
+            clock,
+        }
+    }
+    pub(crate) fn append(&self, events: impl IntoIterator<Item = LedgerEvent>) {
+        let now = self.clock.wall_time();
+        let mut log = self.events.lock().unwrap();
+        log.extend(events.into_iter().map(|event| (now, event)));
+    }
+    /// The events from the `from`th on.
+    pub(crate) fn read(&self, from: usize) -> Vec<LedgerEvent> {
+        let events = self.events.lock().unwrap();
+        let events = events.get(from..).unwrap_or_default();
+        events.iter().map(|(_, event)| event.clone()).collect()
+    }
+    /// `read` with when each event was recorded.
+    pub(crate) fn read_timed(&self, from: usize) -> Vec<(SystemTime, LedgerEvent)> {
+        let events = self.events.lock().unwrap();
+        events.get(from..).map_or(Vec::new(), <[_]>::to_vec)
+    }
+}
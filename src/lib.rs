@@ -0,0 +1,156 @@
+mod alerts;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+mod audit;
+mod autoscale;
+mod cache;
+pub mod channel;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod clock;
+#[cfg(feature = "cluster")]
+pub mod cluster;
+mod config;
+#[cfg(unix)]
+pub mod control;
+mod currency;
+mod cycle;
+mod dead_letter;
+mod directory;
+mod engine;
+mod error;
+mod events;
+mod executor;
+#[cfg(feature = "export")]
+mod export;
+mod exporters;
+mod fees;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod handler;
+mod harness;
+mod history;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod import;
+mod interest;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+mod latency;
+mod ledger;
+pub mod load;
+#[cfg(all(test, loom))]
+mod loom_tests;
+mod metrics;
+mod middleware;
+mod money;
+#[cfg(feature = "nats")]
+pub mod nats;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod partition;
+mod projection;
+mod query;
+mod queue;
+mod rate_limit;
+mod receipt;
+mod reconcile;
+pub mod recording;
+pub mod replay;
+#[cfg(feature = "replication")]
+pub mod replication;
+mod router;
+mod rules;
+mod saga;
+mod scheduler;
+#[cfg(feature = "scripting")]
+mod scripting;
+mod sequence;
+mod server_data;
+mod settlement;
+#[cfg(feature = "sled")]
+pub mod sled;
+mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+mod state;
+mod statement;
+mod stats;
+mod status;
+mod storage;
+mod supervisor;
+mod sync;
+pub mod tcp;
+#[cfg(feature = "tui")]
+pub mod top;
+mod tx;
+pub mod wal;
+mod watchdog;
+mod webhook;
+#[cfg(feature = "serde")]
+mod wire;
+
+pub use crate::alerts::{Alert, AlertSink, AlertThresholds};
+pub use crate::audit::Violation;
+#[cfg(feature = "chaos")]
+pub use crate::chaos::Faults;
+pub use crate::clock::{Clock, SystemClock, VirtualClock};
+pub use crate::config::{
+    AptoneBuilder, AuditAction, AuditPolicy, AutoscalePolicy, BackpressurePolicy, Config,
+    Overdraft, OverflowPolicy, RateLimit, RateLimitPolicy, RetryPolicy, SettlementPolicy,
+    VelocityLimit, VelocityLimits, DEFAULT_CHANNEL_CAPACITY, DEFAULT_DEAD_LETTER_CAPACITY,
+    DEFAULT_DEDUP_WINDOW, DEFAULT_LOCK_STRIPES, DEFAULT_THREAD_COUNT,
+};
+pub use crate::currency::{Currency, ExchangeRates, FixedRates, ParseCurrencyError};
+pub use crate::dead_letter::DeadLetter;
+pub use crate::engine::{Aptone, HEARTBEAT_TIMEOUT};
+pub use crate::error::{ShutdownError, TxError};
+pub use crate::events::{Crossing, TxEvent};
+#[cfg(feature = "export")]
+pub use crate::export::{ExportedAccount, ExportedState, EXPORT_VERSION};
+#[cfg(feature = "export")]
+pub use crate::exporters::JsonLinesExporter;
+#[cfg(feature = "parquet")]
+pub use crate::exporters::ParquetExporter;
+pub use crate::exporters::{CsvExporter, HistoryExporter};
+pub use crate::fees::{Fee, FeeSchedule};
+pub use crate::harness::TestHarness;
+pub use crate::history::{EntryKind, HistoryEntry};
+pub use crate::interest::InterestPolicy;
+pub use crate::latency::Latency;
+pub use crate::ledger::LedgerEvent;
+pub use crate::middleware::TxMiddleware;
+pub use crate::money::{Money, ParseMoneyError};
+pub use crate::projection::{
+    AccountTotals, BalanceMap, DailyTotals, Projection, ProjectionHandle, Totals,
+};
+pub use crate::query::{HistoryPage, HistoryQuery};
+pub use crate::receipt::{TxReceipt, TxResult};
+pub use crate::reconcile::{Mismatch, Reconciliation};
+pub use crate::router::{ConsistentHash, LeastQueueDepth, Random, RoundRobin, Router};
+pub use crate::rules::{Rules, VelocityRule};
+pub use crate::saga::{Saga, SagaOutcome, SagaReport, SagaStep};
+#[cfg(feature = "scripting")]
+pub use crate::scripting::ScriptHook;
+pub use crate::server_data::ServerData;
+pub use crate::settlement::{Accrual, Settlement};
+pub use crate::state::{MemoryStore, StateStore};
+pub use crate::statement::Statement;
+pub use crate::stats::{
+    AptoneStats, BalanceSnapshot, HandlerHealth, HandlerStats, LatencyPercentiles,
+};
+pub use crate::status::TxStatus;
+pub use crate::storage::{Storage, Stored};
+pub use crate::tx::{HoldId, Tx, TxKind, TxType};
+#[cfg(feature = "serde")]
+pub use crate::wire::{UnsupportedVersion, WireBody, WireMessage, WIRE_VERSION};
+#[cfg(feature = "wire")]
+pub use crate::wire::{WireError, WIRE_MAGIC};
+
+pub type AccountId = u32;
+pub type HandleId = i32;
+pub type OrderId = u64;
+pub type TxCount = u32;
+pub type TxId = u64;
+
+pub const INVALID_HANDLE: HandleId = -1;
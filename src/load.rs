@@ -0,0 +1,289 @@
+//! A synthetic workload to drive an engine with, as `aptone bench` does: deposits, withdrawals
+//! and transfers in equal parts, of 0.01 to 1, over a set of freshly opened accounts picked
+//! uniformly, along a Zipf distribution or mostly the one hot account, submitted at a steady
+//! rate whether or not the engine keeps up.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{AccountId, Aptone, LatencyPercentiles, Money, TxCount, TxError, TxReceipt, TxType};
+
+const INITIAL_BALANCE: Money = Money::from_minor(1_000 * 10i128.pow(Money::SCALE));
+// how often the queues are looked at, and progress reported
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+// how long the generator sleeps once it has submitted everything due
+const PACING_SLEEP: Duration = Duration::from_millis(1);
+
+/// How the accounts a workload touches are picked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Skew {
+    Uniform,
+    /// The `k`th account is picked in proportion to `1 / k^s`, so a few take most of the load.
+    Zipf(f64),
+    /// The first account is picked that share of the time, from 0 to 1, any account the rest,
+    /// pinning much of the load on the handler owning it.
+    Hot(f64),
+}
+
+/// Read from `uniform`, `zipf:<s>` with `s` above zero, or `hot:<share>` with the share from 0
+/// to 1; `hot` alone puts everything on the one account.
+impl FromStr for Skew {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Skew, String> {
+        let invalid = || {
+            format!(
+                "invalid skew, expected uniform, zipf:<s> or hot[:<share>]: {}",
+                s
+            )
+        };
+        match s {
+            "uniform" => return Ok(Skew::Uniform),
+            "hot" => return Ok(Skew::Hot(1.0)),
+            _ => {}
+        }
+        let (kind, parameter) = s.split_once(':').ok_or_else(invalid)?;
+        let parameter: f64 = parameter.parse().map_err(|_| invalid())?;
+        match kind {
+            "zipf" if parameter.is_finite() && parameter > 0.0 => Ok(Skew::Zipf(parameter)),
+            "hot" if (0.0..=1.0).contains(&parameter) => Ok(Skew::Hot(parameter)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Skew {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Skew::Uniform => write!(f, "uniform"),
+            Skew::Zipf(exponent) => write!(f, "zipf:{}", exponent),
+            Skew::Hot(share) => write!(f, "hot:{}", share),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workload {
+    /// Accounts opened for it, each holding 1000.
+    pub accounts: AccountId,
+    /// Transactions submitted per second.
+    pub tps: u64,
+    pub skew: Skew,
+    /// How long it submits for.
+    pub duration: Duration,
+    /// Seed for the transactions generated.
+    pub seed: u64,
+}
+
+impl Default for Workload {
+    fn default() -> Workload {
+        Workload {
+            accounts: 1_000,
+            tps: 1_000,
+            skew: Skew::Uniform,
+            duration: Duration::from_secs(10),
+            seed: 1,
+        }
+    }
+}
+
+/// Passed to the progress callback about every second.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress {
+    /// Since the first submission.
+    pub elapsed: Duration,
+    pub submitted: u64,
+    /// Messages queued on the handlers together.
+    pub queue_depth: TxCount,
+}
+
+/// How an engine kept up with a workload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadReport {
+    pub submitted: u64,
+    pub applied: u64,
+    /// Turned away, when submitted or by a handler.
+    pub rejected: u64,
+    /// From the first submission until the last transaction was finished.
+    pub elapsed: Duration,
+    /// As the engine measures it, so over every transaction it finished, these included.
+    pub latency: LatencyPercentiles,
+    /// Messages queued on the handlers together, sampled every 100ms while submitting.
+    pub mean_queue_depth: f64,
+    pub max_queue_depth: TxCount,
+    /// Transactions finished off each handler's queue, by handler id, as the engine counts them,
+    /// showing how evenly the accounts picked spread the load.
+    pub handled: Vec<u64>,
+}
+
+impl LoadReport {
+    /// Transactions finished per second.
+    pub fn throughput(&self) -> f64 {
+        (self.applied + self.rejected) as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Opens `workload`'s accounts on `aptone` and submits its transactions, calling `progress`
+/// about every second, then waits for them all to be finished. Fails only if opening an account
+/// does.
+pub fn run(
+    aptone: &Aptone,
+    workload: &Workload,
+    mut progress: impl FnMut(&Progress),
+) -> Result<LoadReport, TxError> {
+    let accounts = (0..workload.accounts.max(1))
+        .map(|_| aptone.open_account(INITIAL_BALANCE))
+        .collect::<Result<Vec<AccountId>, TxError>>()?;
+    let picker = Picker::new(accounts.len(), workload.skew);
+    let mut rng = XorShift(workload.seed.max(1));
+    let (receipts, waiting) = mpsc::channel::<TxReceipt>();
+
+    thread::scope(|scope| {
+        // waits on the receipts in order, which only holds up the count, not the submitting
+        let waiter = scope.spawn(move || {
+            let (mut applied, mut rejected) = (0, 0);
+            for receipt in waiting {
+                match receipt.wait() {
+                    Ok(()) => applied += 1,
+                    Err(_) => rejected += 1,
+                }
+            }
+            (applied, rejected)
+        });
+
+        let started = Instant::now();
+        let (mut submitted, mut refused) = (0, 0);
+        let (mut depth_sum, mut samples, mut max_queue_depth) = (0u64, 0u64, 0);
+        let (mut next_sample, mut next_progress) = (started, started + PROGRESS_INTERVAL);
+        while started.elapsed() < workload.duration {
+            let due = (started.elapsed().as_secs_f64() * workload.tps as f64) as u64;
+            while submitted < due {
+                let account = accounts[picker.pick(rng.next())];
+                // 0.01 to 1, in whole cents
+                let cents = (rng.next() % 100 + 1) as i128;
+                let amount = Money::from_minor(cents * 10i128.pow(Money::SCALE - 2));
+                let tx_type = match rng.next() % 3 {
+                    0 => TxType::DEPOSIT,
+                    1 => TxType::WITHDRAW,
+                    _ => TxType::TRANSFER {
+                        to: accounts[picker.pick(rng.next())],
+                    },
+                };
+                match aptone.handle_tx(account, amount, tx_type) {
+                    Ok(receipt) => receipts
+                        .send(receipt)
+                        .expect("the waiter outlives the loop"),
+                    Err(_) => refused += 1,
+                }
+                submitted += 1;
+            }
+            let now = Instant::now();
+            if now >= next_sample {
+                let depth = queue_depth(aptone);
+                depth_sum += depth as u64;
+                samples += 1;
+                max_queue_depth = max_queue_depth.max(depth);
+                next_sample += SAMPLE_INTERVAL;
+            }
+            if now >= next_progress {
+                progress(&Progress {
+                    elapsed: now - started,
+                    submitted,
+                    queue_depth: queue_depth(aptone),
+                });
+                next_progress += PROGRESS_INTERVAL;
+            }
+            thread::sleep(PACING_SLEEP);
+        }
+        drop(receipts);
+        let (applied, rejected) = waiter.join().expect("the waiter doesn't panic");
+        let stats = aptone.stats();
+        Ok(LoadReport {
+            submitted,
+            applied,
+            rejected: rejected + refused,
+            elapsed: started.elapsed(),
+            latency: stats.latency,
+            mean_queue_depth: depth_sum as f64 / samples.max(1) as f64,
+            max_queue_depth,
+            handled: stats
+                .handlers
+                .iter()
+                .map(|handler| handler.applied + handler.rejected)
+                .collect(),
+        })
+    })
+}
+
+fn queue_depth(aptone: &Aptone) -> TxCount {
+    aptone
+        .health()
+        .iter()
+        .map(|handler| handler.queue_depth)
+        .sum()
+}
+
+/// Picks positions among a number of accounts as a `Skew` has them, from the random numbers it is
+/// handed, so the caller keeps the picks reproducible.
+pub struct Picker(Picks);
+
+enum Picks {
+    Uniform(usize),
+    Zipf(Vec<f64>), // the weights of the positions up to each, adding up to 1
+    Hot { share: f64, accounts: usize },
+}
+
+impl Picker {
+    /// Panics without accounts to pick from.
+    pub fn new(accounts: usize, skew: Skew) -> Picker {
+        assert!(accounts > 0, "nothing to pick from");
+        let exponent = match skew {
+            Skew::Uniform => return Picker(Picks::Uniform(accounts)),
+            Skew::Hot(share) => return Picker(Picks::Hot { share, accounts }),
+            Skew::Zipf(exponent) => exponent,
+        };
+        let weights: Vec<f64> = (1..=accounts)
+            .map(|rank| 1.0 / (rank as f64).powf(exponent))
+            .collect();
+        let total: f64 = weights.iter().sum();
+        let mut cumulative = 0.0;
+        Picker(Picks::Zipf(
+            weights
+                .iter()
+                .map(|weight| {
+                    cumulative += weight / total;
+                    cumulative
+                })
+                .collect(),
+        ))
+    }
+    /// The position `random`, drawn uniformly, comes to.
+    pub fn pick(&self, random: u64) -> usize {
+        // 53 random bits make a uniform float in [0, 1)
+        let draw = (random >> 11) as f64 / (1u64 << 53) as f64;
+        match &self.0 {
+            Picks::Uniform(accounts) => (random % *accounts as u64) as usize,
+            Picks::Zipf(cumulative) => cumulative
+                .partition_point(|&up_to| up_to <= draw)
+                .min(cumulative.len() - 1),
+            Picks::Hot { share, .. } if draw < *share => 0,
+            Picks::Hot { accounts, .. } => (random % *accounts as u64) as usize,
+        }
+    }
+}
+
+// xorshift64; reproducible from the seed
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
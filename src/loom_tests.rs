@@ -0,0 +1,167 @@
+//! Model checks of the handoff between submitters and handlers over the directory and the
+//! shards, run with `RUSTFLAGS="--cfg loom" cargo test --release --lib loom`.
+//!
+//! The submitter and handler sides below follow `Aptone::try_handle_tx` and the handler's
+//! processing step for transactions that stay on one handler, with the queue reduced to a locked
+//! `VecDeque`.
+
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+
+use loom::sync::atomic::AtomicUsize;
+use loom::sync::{Arc, Mutex};
+use loom::thread;
+
+use crate::directory::Directory;
+use crate::{AccountId, Currency, HandleId, Money, RoundRobin, ServerData, Tx, TxType};
+
+struct Model {
+    directory: Directory,
+    queues: Vec<Mutex<VecDeque<Tx>>>,
+    capacity: usize,
+    handled: AtomicUsize,
+}
+
+impl Model {
+    fn new(handlers: usize, capacity: usize) -> Arc<Model> {
+        // round robin moves every idle account, so a transaction routed while another one on the
+        // same account is in flight would show up as a migration
+        let router = std::sync::Arc::new(RoundRobin::new());
+        Arc::new(Model {
+            directory: Directory::new(handlers, 1, router, ServerData::new),
+            queues: (0..handlers).map(|_| Mutex::new(VecDeque::new())).collect(),
+            capacity,
+            handled: AtomicUsize::new(0),
+        })
+    }
+    fn submit(&self, account: AccountId, amount: u32, tx_type: TxType) {
+        loop {
+            let mut accounts = self.directory.lock(account, tx_type);
+            accounts.check_open(account, tx_type).unwrap();
+            let (id, barrier) = accounts.route(account, tx_type);
+            assert_eq!(barrier, None);
+            // a full queue is waited out with the accounts unlocked, as `BackpressurePolicy::Block`
+            if !self.directory.try_reserve(id, self.capacity) {
+                drop(accounts);
+                thread::yield_now();
+                continue;
+            }
+            accounts.track_tx(account, tx_type, id, false);
+            let mut queue = self.queues[id as usize].lock().unwrap();
+            assert!(
+                queue.len() < self.capacity,
+                "queued past the reserved slots"
+            );
+            queue.push_back(Tx::new(account, Money::from(amount), tx_type));
+            return;
+        }
+    }
+    // Works the queues of every handler, in turn, until `total` transactions have been handled.
+    // One thread standing in for all handlers keeps the model small, and the shards are still
+    // only touched under their own locks.
+    fn handle(&self, total: usize) {
+        let mut id = 0;
+        while self.handled.load(Ordering::SeqCst) < total {
+            id = (id + 1) % self.queues.len() as HandleId;
+            let Some(tx) = self.queues[id as usize].lock().unwrap().pop_front() else {
+                thread::yield_now();
+                continue;
+            };
+            {
+                let mut data = self.directory.lock_shard(id);
+                assert!(
+                    data.get_pending_tx(tx.account) > 0,
+                    "{:?} applied before it was counted as pending",
+                    tx
+                );
+                let (result, _) = data.settle(0, &tx, None, false, None);
+                assert_eq!(result, Ok(()), "{:?} missed its account", tx);
+            }
+            let before = self.directory.tx_counts()[id as usize].fetch_sub(1, Ordering::SeqCst);
+            assert!(before > 0, "tx_count of handler {} underflowed", id);
+            self.handled.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    fn assert_drained(&self, handlers: usize) {
+        for id in 0..handlers {
+            assert_eq!(self.directory.get_tx_count(id as HandleId), 0);
+        }
+    }
+}
+
+fn builder() -> loom::model::Builder {
+    let mut builder = loom::model::Builder::new();
+    builder.preemption_bound.get_or_insert(3);
+    builder
+}
+
+// A withdrawal racing a deposit on the same account must stay on the handler holding the
+// balance; had the account been handed over before the withdrawal's pending count was taken,
+// either the pending assertion or the withdrawal itself would fail.
+#[test]
+fn loom_pending_guards_withdrawal() {
+    builder().check(|| {
+        let model = Model::new(2, 4);
+        model
+            .directory
+            .insert(0, Currency::default(), Money::from(100));
+
+        let withdraw = {
+            let model = Arc::clone(&model);
+            thread::spawn(move || model.submit(0, 60, TxType::WITHDRAW))
+        };
+        let deposit = {
+            let model = Arc::clone(&model);
+            thread::spawn(move || model.submit(0, 10, TxType::DEPOSIT))
+        };
+        model.handle(2);
+        for thread in [withdraw, deposit] {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(
+            model.directory.get_balance(0, Currency::default()),
+            Money::from(50)
+        );
+        assert_eq!(
+            model.directory.lock(0, TxType::DEPOSIT).get_pending_tx(0),
+            0
+        );
+        model.assert_drained(2);
+    });
+}
+
+// Submitters contending for a single slot never push the count past the capacity, and the
+// handler never takes it below zero.
+#[test]
+fn loom_tx_count_stays_in_bounds() {
+    builder().check(|| {
+        let model = Model::new(1, 1);
+        for account in 0..2 {
+            model
+                .directory
+                .insert(account, Currency::default(), Money::ZERO);
+        }
+
+        let submitters: Vec<_> = (0..2)
+            .map(|account| {
+                let model = Arc::clone(&model);
+                thread::spawn(move || model.submit(account, 10, TxType::DEPOSIT))
+            })
+            .collect();
+        model.handle(2);
+        for thread in submitters {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(
+            model.directory.get_balance(0, Currency::default()),
+            Money::from(10)
+        );
+        assert_eq!(
+            model.directory.get_balance(1, Currency::default()),
+            Money::from(10)
+        );
+        model.assert_drained(1);
+    });
+}
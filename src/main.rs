@@ -1,296 +1,992 @@
-use std::collections::HashMap;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitCode};
+use std::sync::{mpsc, Arc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-type AccountId = u32;
-type HandleId = i32;
-type TxCount = u32;
+use aptone::load::{LoadReport, Picker, Skew, Workload};
+use aptone::partition::{Partition, PartitionRouter};
+use aptone::replay::Pacing;
+use aptone::{
+    AccountId, Aptone, AptoneBuilder, Currency, Latency, Money, TxEvent, TxType,
+    DEFAULT_THREAD_COUNT,
+};
+use clap::{Args, Parser, Subcommand};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
 
-const INVALID_HANDLE: HandleId = -1;
-const THREAD_COUNT: usize = 4;
+#[cfg(feature = "http")]
+const DEFAULT_ADDR: &str = "127.0.0.1:8080";
 
-struct Tx {
-    account: AccountId,
-    amount: u32,
-    tx_type: TxType,
-}
+const DEFAULT_LINE_ADDR: &str = "127.0.0.1:7070";
+const DEFAULT_IN_FLIGHT: usize = 256;
+const DEFAULT_SHUTDOWN_SECS: u64 = 30;
 
-struct TxHandler {
-    sender: Sender<Message>,
-    thread: Option<thread::JoinHandle<()>>,
+#[derive(Parser)]
+#[command(name = "aptone", about = "A concurrent account ledger")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
 }
-struct ServerData {
-    pending_tx: HashMap<AccountId, TxCount>, // account -> pending tx count
-    tx_count: HashMap<HandleId, TxCount>,    // handler id -> pending tx count
-    handler: HashMap<AccountId, HandleId>,   // account -> handler id
-    balances: HashMap<AccountId, u32>,       // account -> balance
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the REST API until stopped by SIGTERM or SIGINT.
+    #[cfg(feature = "http")]
+    Serve {
+        #[arg(long, default_value = DEFAULT_ADDR)]
+        addr: std::net::SocketAddr,
+        #[command(flatten)]
+        control: Control,
+        #[command(flatten)]
+        log: Log,
+    },
+    /// Serve the TCP line protocol until stopped by SIGTERM or SIGINT.
+    Listen {
+        #[arg(long, default_value = DEFAULT_LINE_ADDR)]
+        addr: std::net::SocketAddr,
+        /// Only open accounts with ids in `<start>..<end>`, as one partition behind `route`.
+        #[arg(long, value_parser = parse_ids)]
+        accounts: Option<Range<AccountId>>,
+        #[command(flatten)]
+        control: Control,
+        #[command(flatten)]
+        log: Log,
+    },
+    /// Serve the TCP line protocol in front of processes each owning a range of the accounts.
+    Route {
+        #[arg(long, default_value = DEFAULT_LINE_ADDR)]
+        addr: std::net::SocketAddr,
+        /// A process and the ids it owns, as `<start>..<end>=<addr>`; once per process.
+        #[arg(long = "partition", required = true)]
+        partitions: Vec<Partition>,
+    },
+    /// Open an account, printing its id.
+    Open {
+        #[arg(default_value_t = Money::ZERO)]
+        initial_balance: Money,
+        #[command(flatten)]
+        log: Log,
+    },
+    /// Close an account, printing what it held.
+    Close {
+        account: AccountId,
+        #[command(flatten)]
+        log: Log,
+    },
+    /// Deposit into an account.
+    Deposit {
+        account: AccountId,
+        amount: Money,
+        #[command(flatten)]
+        log: Log,
+    },
+    /// Withdraw from an account.
+    Withdraw {
+        account: AccountId,
+        amount: Money,
+        #[command(flatten)]
+        log: Log,
+    },
+    /// Print an account's balance.
+    Balance {
+        account: AccountId,
+        #[command(flatten)]
+        log: Log,
+    },
+    /// Replay transactions from a CSV file of `tx_type,account,amount[,to]` rows.
+    Import {
+        file: PathBuf,
+        /// Transactions submitted but not finished before the import waits for the oldest.
+        #[arg(long, default_value_t = DEFAULT_IN_FLIGHT)]
+        in_flight: usize,
+        #[arg(long, default_value_t = DEFAULT_THREAD_COUNT)]
+        threads: usize,
+        #[command(flatten)]
+        log: Log,
+    },
+    /// Replay a transaction log through a fresh in-memory engine and check it comes out with the
+    /// balances recovering the log gives, or those in a checkpoint snapshot.
+    Replay {
+        file: PathBuf,
+        /// Snapshot holding the balances expected, in the checkpoint format.
+        #[arg(long)]
+        expect: Option<PathBuf>,
+        /// Transactions submitted but not finished before the replay waits for the oldest.
+        #[arg(long, default_value_t = DEFAULT_IN_FLIGHT)]
+        in_flight: usize,
+        #[arg(long, default_value_t = DEFAULT_THREAD_COUNT)]
+        threads: usize,
+    },
+    /// Replay a recording through a fresh in-memory engine, paced as it was recorded or faster.
+    ReplayRecording {
+        file: PathBuf,
+        /// How many times as fast as recorded to go.
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+        /// Submit each entry right after the one before, however far apart they were recorded.
+        #[arg(long)]
+        unpaced: bool,
+        /// Transactions submitted but not finished before the replay waits for the oldest.
+        #[arg(long, default_value_t = DEFAULT_IN_FLIGHT)]
+        in_flight: usize,
+        #[arg(long, default_value_t = DEFAULT_THREAD_COUNT)]
+        threads: usize,
+    },
+    /// Read commands from stdin, printing transactions as the handlers finish them.
+    Repl {
+        #[arg(long, default_value_t = DEFAULT_THREAD_COUNT)]
+        threads: usize,
+        /// Time each handler spends on a transaction after applying it, in milliseconds.
+        #[arg(long, default_value_t = 0)]
+        latency_ms: u64,
+        #[command(flatten)]
+        log: Log,
+    },
+    /// Run random transactions against an in-memory engine and check the balances add up.
+    Simulate {
+        #[arg(long, default_value_t = 2)]
+        accounts: AccountId,
+        #[arg(long, default_value_t = 20)]
+        txs: u64,
+        #[arg(long, default_value_t = DEFAULT_THREAD_COUNT)]
+        threads: usize,
+        /// Time each handler spends on a transaction after applying it, in milliseconds.
+        #[arg(long, default_value_t = 0)]
+        latency_ms: u64,
+        /// How the accounts are picked: `uniform`, `zipf:<s>` for a few taking most of it, or
+        /// `hot[:<share>]` for the first taking that share, all of it by default.
+        #[arg(long, default_value = "uniform")]
+        skew: Skew,
+        /// Seed for the generated transactions; picked from the clock if not given.
+        #[arg(long)]
+        seed: Option<u64>,
+        /// Process everything on one thread in an order drawn from the seed, so a run can be
+        /// repeated exactly.
+        #[arg(long)]
+        deterministic: bool,
+    },
+    /// Drive an in-memory engine with a synthetic workload, then summarize how it kept up.
+    Bench {
+        /// Accounts opened for the workload; takes a k or m suffix.
+        #[arg(long, default_value = "1k", value_parser = parse_count)]
+        accounts: u64,
+        /// Transactions submitted per second; takes a k or m suffix.
+        #[arg(long, default_value = "1k", value_parser = parse_count)]
+        tps: u64,
+        /// How the accounts are picked: `uniform`, `zipf:<s>` for a few taking most of it, or
+        /// `hot[:<share>]` for the first taking that share, all of it by default.
+        #[arg(long, default_value = "uniform")]
+        skew: Skew,
+        /// How long to submit for, such as `500ms`, `60s` or `5m`.
+        #[arg(long, default_value = "10s", value_parser = parse_duration)]
+        duration: Duration,
+        #[arg(long, default_value_t = DEFAULT_THREAD_COUNT)]
+        threads: usize,
+        /// Seed for the generated transactions; picked from the clock if not given.
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Watch an in-memory engine under a synthetic workload in a live dashboard, until `q`.
+    #[cfg(feature = "tui")]
+    Top {
+        /// Accounts opened for the workload; takes a k or m suffix.
+        #[arg(long, default_value = "1k", value_parser = parse_count)]
+        accounts: u64,
+        /// Transactions submitted per second; takes a k or m suffix.
+        #[arg(long, default_value = "1k", value_parser = parse_count)]
+        tps: u64,
+        /// How the accounts are picked: `uniform`, `zipf:<s>` for a few taking most of it, or
+        /// `hot[:<share>]` for the first taking that share, all of it by default.
+        #[arg(long, default_value = "uniform")]
+        skew: Skew,
+        /// How long to submit for, such as `500ms`, `60s` or `5m`; the dashboard stays up after.
+        #[arg(long, default_value = "1h", value_parser = parse_duration)]
+        duration: Duration,
+        #[arg(long, default_value_t = DEFAULT_THREAD_COUNT)]
+        threads: usize,
+        /// Seed for the generated transactions; picked from the clock if not given.
+        #[arg(long)]
+        seed: Option<u64>,
+        /// How often the dashboard is redrawn, such as `250ms` or `1s`.
+        #[arg(long, default_value = "1s", value_parser = parse_duration)]
+        refresh: Duration,
+    },
 }
 
-enum Message {
-    NewTx(Tx),
-    Terminate,
+/// The state commands share between runs.
+#[derive(Args)]
+struct Log {
+    /// Transaction log to recover the balances from and append to.
+    #[arg(long, default_value = "aptone.wal")]
+    wal: PathBuf,
+    /// Keep the balances and history in this SQLite database instead of a transaction log.
+    #[cfg(feature = "sqlite")]
+    #[arg(long)]
+    sqlite: Option<PathBuf>,
+    /// Keep them in this sled database instead, for write-heavy workloads.
+    #[cfg(feature = "sled")]
+    #[arg(long)]
+    sled: Option<PathBuf>,
+    /// Also record every submission to this file as it comes in, for `replay-recording`.
+    #[arg(long)]
+    record: Option<PathBuf>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum TxType {
-    DEPOSIT,
-    WITHDRAW,
+impl Log {
+    fn open(&self) -> Result<Aptone, String> {
+        self.open_with(Aptone::builder())
+    }
+    fn open_with(&self, mut builder: AptoneBuilder) -> Result<Aptone, String> {
+        if let Some(path) = &self.record {
+            builder = builder.record_to(path);
+        }
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = &self.sqlite {
+            let opened = aptone::sqlite::SqliteStorage::open(path)
+                .and_then(|storage| builder.persist_to(storage));
+            return opened.map_err(|err| format!("failed to open {}: {}", path.display(), err));
+        }
+        #[cfg(feature = "sled")]
+        if let Some(path) = &self.sled {
+            let opened = aptone::sled::SledStorage::open(path)
+                .and_then(|storage| builder.persist_to(storage));
+            return opened.map_err(|err| format!("failed to open {}: {}", path.display(), err));
+        }
+        builder
+            .recover(&self.wal)
+            .map_err(|err| format!("failed to open {}: {}", self.wal.display(), err))
+    }
 }
 
-struct Aptone {
-    server_data: Arc<Mutex<ServerData>>,
-    handles: Vec<TxHandler>,
+/// What the commands serving until stopped share.
+#[derive(Args)]
+struct Control {
+    /// Also serve admin commands, such as `stats` and `rebalance`, on a Unix socket here.
+    #[cfg(unix)]
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+    /// Once stopped, how long the handlers get to drain their queues before the process exits
+    /// regardless, in seconds. A second signal exits at once.
+    #[arg(long, default_value_t = DEFAULT_SHUTDOWN_SECS)]
+    shutdown_timeout: u64,
 }
 
-impl ServerData {
-    fn increase_pending_tx(&mut self, account: AccountId, amount: TxCount) -> TxCount {
-        let pending = self.pending_tx.entry(account).or_insert(0);
-        *pending += amount;
-        *pending
-    }
-    fn decrease_pending_tx(&mut self, account: AccountId, amount: TxCount) -> TxCount {
-        match self.pending_tx.get_mut(&account) {
-            None => 0,
-            Some(pending) => {
-                if *pending > amount {
-                    *pending -= amount;
-                } else {
-                    *pending = 0;
+impl Control {
+    fn start(&self, aptone: &Arc<Aptone>) {
+        #[cfg(unix)]
+        if let Some(path) = self.control_socket.clone() {
+            let aptone = Arc::clone(aptone);
+            thread::spawn(move || {
+                if let Err(err) = aptone::control::serve(aptone, &path) {
+                    eprintln!("control socket {} failed: {}", path.display(), err);
                 }
-                *pending
-            }
+            });
         }
     }
-    fn get_pending_tx(&self, account: AccountId) -> TxCount {
-        match self.pending_tx.get(&account) {
-            None => 0,
-            Some(pending) => *pending,
+    /// Runs `serve` until it fails or the process gets SIGTERM or SIGINT, then stops taking
+    /// transactions and shuts `aptone` down, draining the queues, flushing what is persisted and
+    /// joining the handlers, within the shutdown timeout.
+    fn serve_until_stopped(
+        &self,
+        aptone: Arc<Aptone>,
+        serve: impl FnOnce() -> Result<(), String> + Send + 'static,
+    ) -> Result<(), String> {
+        #[cfg(not(unix))]
+        {
+            let _ = aptone;
+            serve()
         }
-    }
-    fn increase_tx_count(&mut self, handle_id: HandleId, amount: TxCount) {
-        *self.tx_count.entry(handle_id).or_insert(0) += amount;
-    }
-    fn decrease_tx_count(&mut self, handle_id: HandleId, amount: TxCount) {
-        match self.tx_count.get_mut(&handle_id) {
-            None => {}
-            Some(count) => {
-                if *count > amount {
-                    *count -= amount;
-                } else {
-                    *count = 0;
+        #[cfg(unix)]
+        {
+            use signal_hook::consts::{SIGINT, SIGTERM};
+            use signal_hook::iterator::Signals;
+
+            let mut signals = Signals::new([SIGTERM, SIGINT])
+                .map_err(|err| format!("failed to handle signals: {}", err))?;
+            let (stopped, stop) = mpsc::channel();
+            let failed = stopped.clone();
+            // the servers never return but on failure; once shut down, exiting stops them
+            thread::spawn(move || failed.send(Err(serve())));
+            thread::spawn(move || {
+                for signal in signals.forever() {
+                    if stopped.send(Ok(signal)).is_err() {
+                        break;
+                    }
                 }
-            }
+            });
+            let signal = match stop.recv() {
+                Ok(Ok(signal)) => signal,
+                Ok(Err(failed)) => return failed,
+                Err(_) => unreachable!("the signal thread never hangs up first"),
+            };
+            eprintln!("got signal {}, shutting down", signal);
+            thread::spawn(move || {
+                while let Ok(stopped) = stop.recv() {
+                    if stopped.is_ok() {
+                        eprintln!("got another signal, exiting without draining");
+                        process::exit(1);
+                    }
+                }
+            });
+            aptone
+                .shutdown(Duration::from_secs(self.shutdown_timeout))
+                .map_err(|err| err.to_string())
         }
     }
-    fn increase_balance(&mut self, account: AccountId, amount: u32) {
-        *self.balances.entry(account).or_insert(0) += amount;
+}
+
+fn main() -> ExitCode {
+    // RUST_LOG picks the levels, e.g. RUST_LOG=aptone=debug; commands print their own results
+    let logs = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")));
+    let subscriber = tracing_subscriber::registry().with(logs);
+    // with OTEL_EXPORTER_OTLP_ENDPOINT set, spans go to that collector too, flushed on the way out
+    #[cfg(feature = "otel")]
+    let (subscriber, _otlp) = {
+        let otlp = aptone::otel::OtlpTracing::from_env().unwrap_or_else(|err| {
+            eprintln!("warning: {}", err);
+            None
+        });
+        (
+            subscriber.with(otlp.as_ref().map(|otlp| otlp.layer())),
+            otlp,
+        )
+    };
+    subscriber.init();
+
+    match run(Cli::parse().command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
     }
-    fn decrease_balance(&mut self, account: AccountId, amount: u32) {
-        match self.balances.get_mut(&account) {
-            None => {
-                panic!("balance entry does not exist for account: {}", account);
+}
+
+fn run(command: Command) -> Result<(), String> {
+    match command {
+        #[cfg(feature = "http")]
+        Command::Serve { addr, control, log } => {
+            let aptone = Arc::new(log.open()?);
+            control.start(&aptone);
+            let runtime = tokio::runtime::Runtime::new().map_err(|err| err.to_string())?;
+            println!("serving on {}", addr);
+            let served = Arc::clone(&aptone);
+            control.serve_until_stopped(aptone, move || {
+                runtime
+                    .block_on(aptone::http::serve(served, addr))
+                    .map_err(|err| format!("server failed: {}", err))
+            })
+        }
+        Command::Listen {
+            addr,
+            accounts,
+            control,
+            log,
+        } => {
+            let builder = Aptone::builder();
+            let builder = match accounts {
+                Some(ids) => builder.account_ids(ids),
+                None => builder,
+            };
+            let aptone = Arc::new(log.open_with(builder)?);
+            control.start(&aptone);
+            println!("listening on {}", addr);
+            let served = Arc::clone(&aptone);
+            control.serve_until_stopped(aptone, move || {
+                aptone::tcp::serve(served, addr).map_err(|err| format!("server failed: {}", err))
+            })
+        }
+        Command::Route { addr, partitions } => {
+            let router = Arc::new(PartitionRouter::new(partitions));
+            println!("routing on {}", addr);
+            aptone::partition::serve(router, addr).map_err(|err| format!("router failed: {}", err))
+        }
+        Command::Open {
+            initial_balance,
+            log,
+        } => {
+            let aptone = log.open()?;
+            let account = aptone
+                .open_account(initial_balance)
+                .map_err(|err| err.to_string())?;
+            println!("account {} opened", account);
+            Ok(())
+        }
+        Command::Close { account, log } => {
+            let aptone = log.open()?;
+            let balances = aptone
+                .close_account(account)
+                .map_err(|err| err.to_string())?;
+            print_closed(account, &balances);
+            Ok(())
+        }
+        Command::Deposit {
+            account,
+            amount,
+            log,
+        } => submit(&log, account, amount, TxType::DEPOSIT),
+        Command::Withdraw {
+            account,
+            amount,
+            log,
+        } => submit(&log, account, amount, TxType::WITHDRAW),
+        Command::Balance { account, log } => {
+            let aptone = log.open()?;
+            print_balance(&aptone, account);
+            Ok(())
+        }
+        Command::Import {
+            file,
+            in_flight,
+            threads,
+            log,
+        } => {
+            let reader = File::open(&file)
+                .map(BufReader::new)
+                .map_err(|err| format!("failed to open {}: {}", file.display(), err))?;
+            let aptone = log.open_with(Aptone::builder().threads(threads))?;
+            import(&aptone, reader, in_flight)
+        }
+        Command::Replay {
+            file,
+            expect,
+            in_flight,
+            threads,
+        } => {
+            let aptone = Aptone::builder().threads(threads).build();
+            replay(&aptone, &file, expect.as_deref(), in_flight)
+        }
+        Command::ReplayRecording {
+            file,
+            speed,
+            unpaced,
+            in_flight,
+            threads,
+        } => {
+            let pacing = match unpaced {
+                true => Pacing::Unpaced,
+                false if speed > 0.0 => Pacing::Speed(speed),
+                false => return Err(format!("the speed has to be above zero: {}", speed)),
+            };
+            let aptone = Aptone::builder().threads(threads).build();
+            replay_recording(&aptone, &file, pacing, in_flight)
+        }
+        Command::Repl {
+            threads,
+            latency_ms,
+            log,
+        } => {
+            let builder = with_latency(Aptone::builder().threads(threads), latency_ms);
+            repl(log.open_with(builder)?)
+        }
+        Command::Simulate {
+            accounts,
+            txs,
+            threads,
+            latency_ms,
+            skew,
+            seed,
+            deterministic,
+        } => {
+            let seed = seed.unwrap_or_else(clock_seed);
+            let mut builder = Aptone::builder().threads(threads);
+            if deterministic {
+                builder = builder.deterministic(seed);
             }
-            Some(balance) => {
-                if *balance < amount {
-                    panic!("Insufficient balance!");
-                } else {
-                    *balance -= amount;
-                }
+            let aptone = with_latency(builder, latency_ms).build();
+            simulate(&aptone, accounts.max(1), txs, skew, seed)
+        }
+        Command::Bench {
+            accounts,
+            tps,
+            skew,
+            duration,
+            threads,
+            seed,
+        } => {
+            let workload = Workload {
+                accounts: AccountId::try_from(accounts)
+                    .map_err(|_| format!("too many accounts: {}", accounts))?,
+                tps,
+                skew,
+                duration,
+                seed: seed.unwrap_or_else(clock_seed),
+            };
+            let aptone = Aptone::builder().threads(threads).build();
+            println!(
+                "{} tx/s over {} accounts, {}, for {:?} on {} handlers (seed {})",
+                workload.tps,
+                workload.accounts,
+                workload.skew,
+                workload.duration,
+                threads,
+                workload.seed
+            );
+            let report = aptone::load::run(&aptone, &workload, |progress| {
+                println!(
+                    "{:>4}s: {} submitted \t queue depth: {}",
+                    progress.elapsed.as_secs(),
+                    progress.submitted,
+                    progress.queue_depth
+                )
+            })
+            .map_err(|err| err.to_string())?;
+            print_load(&report);
+            Ok(())
+        }
+        #[cfg(feature = "tui")]
+        Command::Top {
+            accounts,
+            tps,
+            skew,
+            duration,
+            threads,
+            seed,
+            refresh,
+        } => {
+            if refresh.is_zero() {
+                return Err("the refresh interval must be above zero".to_string());
             }
+            let workload = Workload {
+                accounts: AccountId::try_from(accounts)
+                    .map_err(|_| format!("too many accounts: {}", accounts))?,
+                tps,
+                skew,
+                duration,
+                seed: seed.unwrap_or_else(clock_seed),
+            };
+            let aptone = Arc::new(Aptone::builder().threads(threads).build());
+            let loaded = Arc::clone(&aptone);
+            // left running when the dashboard is closed, as the process exits then
+            thread::spawn(move || aptone::load::run(&loaded, &workload, |_| {}));
+            aptone::top::run(&aptone, refresh).map_err(|err| format!("dashboard failed: {}", err))
         }
     }
-    fn get_balance(&self, account: AccountId) -> u32 {
-        if let Some(x) = self.balances.get(&account) {
-            *x
-        } else {
-            // panic!("account {} does not exist!", account);
-            0
-        }
+}
+
+fn clock_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(1, |since| since.as_nanos() as u64)
+}
+
+// Reads a count such as `5000`, `10k` or `2m`.
+fn parse_count(count: &str) -> Result<u64, String> {
+    let invalid = || format!("expected a count such as 5000, 10k or 2m: {}", count);
+    let (digits, scale) = match count.strip_suffix(['k', 'K']) {
+        Some(digits) => (digits, 1_000),
+        None => match count.strip_suffix(['m', 'M']) {
+            Some(digits) => (digits, 1_000_000),
+            None => (count, 1),
+        },
+    };
+    let count: u64 = digits.parse().map_err(|_| invalid())?;
+    count.checked_mul(scale).ok_or_else(invalid)
+}
+
+// Reads a duration such as `500ms`, `60s` or `5m`.
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let invalid = || format!("expected a duration such as 500ms, 60s or 5m: {}", duration);
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let count: u64 = duration[..split].parse().map_err(|_| invalid())?;
+    match &duration[split..] {
+        "ms" => Ok(Duration::from_millis(count)),
+        "s" => Ok(Duration::from_secs(count)),
+        "m" => Ok(Duration::from_secs(count * 60)),
+        "h" => Ok(Duration::from_secs(count * 3600)),
+        _ => Err(invalid()),
     }
-    fn set_handle(&mut self, account: AccountId, handle_id: HandleId) {
-        assert!(handle_id == INVALID_HANDLE || handle_id >= 0);
-        *self.handler.entry(account).or_insert(INVALID_HANDLE) = handle_id;
+}
+
+fn print_load(report: &LoadReport) {
+    println!(
+        "submitted: {} \t applied: {} \t rejected: {}",
+        report.submitted, report.applied, report.rejected
+    );
+    println!(
+        "throughput: {:.0} tx/s over {:?}",
+        report.throughput(),
+        report.elapsed
+    );
+    println!(
+        "latency: p50 {:?} \t p95 {:?} \t p99 {:?}",
+        report.latency.p50, report.latency.p95, report.latency.p99
+    );
+    println!(
+        "queue depth: mean {:.1} \t max {}",
+        report.mean_queue_depth, report.max_queue_depth
+    );
+    let handled: Vec<String> = (report.handled.iter().enumerate())
+        .map(|(id, count)| format!("{}={}", id, count))
+        .collect();
+    println!("finished per handler: {}", handled.join(" "));
+}
+
+fn parse_ids(ids: &str) -> Result<Range<AccountId>, String> {
+    let invalid = || format!("expected <start>..<end>: {}", ids);
+    let (start, end) = ids.split_once("..").ok_or_else(invalid)?;
+    Ok(start.parse().map_err(|_| invalid())?..end.parse().map_err(|_| invalid())?)
+}
+
+fn with_latency(builder: AptoneBuilder, latency_ms: u64) -> AptoneBuilder {
+    match latency_ms {
+        0 => builder,
+        ms => builder.latency(Latency::Fixed(Duration::from_millis(ms))),
     }
-    fn get_handle(&mut self, account: AccountId) -> HandleId {
-        let current_handle = self.handler.entry(account).or_insert(INVALID_HANDLE);
-        if *current_handle != INVALID_HANDLE {
-            *current_handle
-        } else {
-            let mut hid: HandleId = INVALID_HANDLE;
-            let mut min_count: TxCount = TxCount::MAX;
-
-            for id in 0..THREAD_COUNT {
-                let count = *self.tx_count.entry(id as HandleId).or_insert(0);
-
-                if count < min_count {
-                    min_count = count;
-                    hid = id as HandleId;
-                }
-            }
+}
+
+fn submit(log: &Log, account: AccountId, amount: Money, tx_type: TxType) -> Result<(), String> {
+    let aptone = log.open()?;
+    let receipt = aptone
+        .handle_tx(account, amount, tx_type)
+        .map_err(|err| err.to_string())?;
+    let tx_id = receipt.tx_id();
+    receipt.wait().map_err(|err| err.to_string())?;
+    println!("transaction {} applied", tx_id);
+    print_balance(&aptone, account);
+    Ok(())
+}
+
+fn print_balance(aptone: &Aptone, account: AccountId) {
+    println!(
+        "account: {} \t balance: {}",
+        account,
+        aptone.get_balance(account)
+    );
+}
+
+fn open_account(aptone: &Aptone, initial_balance: Money) {
+    match aptone.open_account(initial_balance) {
+        Ok(account) => println!("account {} opened", account),
+        Err(err) => println!("error: {}", err),
+    }
+}
+
+fn print_closed(account: AccountId, balances: &[(Currency, Money)]) {
+    println!("account {} closed", account);
+    for (currency, balance) in balances {
+        println!("balance: {} {}", balance, currency);
+    }
+}
+
+fn import(aptone: &Aptone, reader: impl BufRead, in_flight: usize) -> Result<(), String> {
+    let started = Instant::now();
+    let report =
+        aptone::import::from_csv(aptone, reader, in_flight).map_err(|err| err.to_string())?;
+    let elapsed = started.elapsed();
+    for err in &report.errors {
+        eprintln!("{}", err);
+    }
+    println!(
+        "{} rows in {:?}: {} applied, {} failed",
+        report.rows,
+        elapsed,
+        report.applied,
+        report.errors.len()
+    );
+    println!(
+        "throughput: {:.0} tx/s",
+        report.rows as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+    Ok(())
+}
+
+fn replay_recording(
+    aptone: &Aptone,
+    file: &Path,
+    pacing: Pacing,
+    in_flight: usize,
+) -> Result<(), String> {
+    let recording = aptone::recording::read(file)
+        .map_err(|err| format!("failed to read {}: {}", file.display(), err))?;
+    let recorded = recording.last().map_or(Duration::ZERO, |last| last.at);
+    let started = Instant::now();
+    let report = aptone::replay::replay_recording(aptone, &recording, pacing, in_flight);
+    let elapsed = started.elapsed();
+    for err in &report.errors {
+        eprintln!("{}", err);
+    }
+    println!(
+        "{} transactions recorded over {:?} replayed in {:?}: {} applied, {} failed",
+        report.transactions,
+        recorded,
+        elapsed,
+        report.applied,
+        report.errors.len()
+    );
+    println!(
+        "total balance: {}",
+        report.balances.total(Currency::default())
+    );
+    Ok(())
+}
 
-            hid
+fn replay(
+    aptone: &Aptone,
+    file: &Path,
+    expect: Option<&Path>,
+    in_flight: usize,
+) -> Result<(), String> {
+    let read_failed =
+        |path: &Path, err: io::Error| format!("failed to read {}: {}", path.display(), err);
+    let entries = aptone::wal::read(file).map_err(|err| read_failed(file, err))?;
+    let expected = match expect {
+        Some(path) => {
+            aptone::replay::expected_from_snapshot(path).map_err(|err| read_failed(path, err))?
         }
+        None => aptone::replay::expected(&entries),
+    };
+    let started = Instant::now();
+    let report = aptone::replay::replay(aptone, &entries, in_flight);
+    let elapsed = started.elapsed();
+    for err in &report.errors {
+        eprintln!("{}", err);
+    }
+    println!(
+        "{} transactions in {:?}: {} applied, {} failed",
+        report.transactions,
+        elapsed,
+        report.applied,
+        report.errors.len()
+    );
+    let divergences = aptone::replay::compare(&expected, &report.balances);
+    for divergence in &divergences {
+        println!(
+            "account {} {}: expected {}, replayed {}",
+            divergence.account, divergence.currency, divergence.expected, divergence.actual
+        );
+    }
+    match divergences.len() {
+        0 => {
+            println!("balances match");
+            Ok(())
+        }
+        n => Err(format!("{} balances differ", n)),
     }
 }
 
-impl TxHandler {
-    fn new(
-        id: HandleId,
-        sender: Sender<Message>,
-        receiver: Receiver<Message>,
-        server_data: Arc<Mutex<ServerData>>,
-    ) -> TxHandler {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.recv().unwrap();
-
-            match message {
-                Message::NewTx(Tx {
+const REPL_HELP: &str = "\
+open [initial balance]
+close <account>
+deposit <account> <amount>
+withdraw <account> <amount>
+transfer <from> <to> <amount>
+balance <account>
+stats
+quit";
+
+fn repl(aptone: Aptone) -> Result<(), String> {
+    // transactions are queued without waiting, so results show up whenever a handler gets to them
+    let events = aptone.subscribe();
+    let printer = thread::spawn(move || {
+        for event in events {
+            match event {
+                TxEvent::Applied { tx_id, .. } => println!("transaction {} applied", tx_id),
+                TxEvent::Rejected { tx_id, error, .. } => {
+                    println!("transaction {} rejected: {}", tx_id, error)
+                }
+                TxEvent::Resized { from, to } => {
+                    println!("handler pool resized from {} to {}", from, to)
+                }
+                TxEvent::Settled {
+                    settlement,
                     account,
-                    amount,
-                    tx_type,
-                }) => {
-                    {
-                        let mut data = server_data.lock().unwrap();
-                        match tx_type {
-                            TxType::DEPOSIT => {
-                                data.increase_balance(account, amount);
-                            }
-                            TxType::WITHDRAW => {
-                                data.decrease_balance(account, amount);
-                            }
-                        }
-                        if data.decrease_pending_tx(account, 1) == 0 {
-                            data.set_handle(account, INVALID_HANDLE);
-                        }
-                        data.decrease_tx_count(id, 1);
+                    balances,
+                } => {
+                    for (currency, balance) in balances {
+                        println!(
+                            "settlement {}: account {} closed at {} {}",
+                            settlement, account, balance, currency
+                        );
                     }
-                    thread::sleep(Duration::from_millis(500)); // forcing delay for experimental purpose
-                }
-                Message::Terminate => {
-                    println!("Terminating thread {}", id);
-                    break;
                 }
+                TxEvent::ThresholdCrossed { .. } => {}
             }
-        });
-        TxHandler {
-            sender,
-            thread: Some(thread),
         }
-    }
-}
+    });
 
-impl Aptone {
-    fn new() -> Aptone {
-        let mut handlers = Vec::with_capacity(THREAD_COUNT);
-
-        let pending_tx = HashMap::new();
-        let tx_count = HashMap::new();
-        let handler = HashMap::new();
-        let balances = HashMap::new();
-        let server_data = ServerData {
-            pending_tx,
-            tx_count,
-            handler,
-            balances,
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        let _ = io::stdout().flush();
+        let Some(line) = lines.next() else {
+            println!();
+            break;
         };
-        let server_data = Arc::new(Mutex::new(server_data));
-
-        for id in 0..THREAD_COUNT {
-            let (sender, receiver) = channel::<Message>();
-            let shared = Arc::clone(&server_data);
-            handlers.push(TxHandler::new(id as HandleId, sender, receiver, shared));
-        }
-        Aptone {
-            server_data,
-            handles: handlers,
+        let line = line.map_err(|err| err.to_string())?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let submitted = match words.as_slice() {
+            [] => continue,
+            ["quit"] | ["exit"] => break,
+            ["help"] => {
+                println!("{}", REPL_HELP);
+                continue;
+            }
+            ["stats"] => {
+                // the Prometheus rendering, minus its HELP and TYPE comments
+                for line in aptone.metrics().lines() {
+                    if !line.starts_with('#') {
+                        println!("{}", line);
+                    }
+                }
+                continue;
+            }
+            ["balance", account] => match account.parse() {
+                Ok(account) => {
+                    print_balance(&aptone, account);
+                    continue;
+                }
+                Err(_) => None,
+            },
+            ["open"] => {
+                open_account(&aptone, Money::ZERO);
+                continue;
+            }
+            ["open", amount] => match amount.parse() {
+                Ok(amount) => {
+                    open_account(&aptone, amount);
+                    continue;
+                }
+                Err(_) => None,
+            },
+            ["close", account] => match account.parse() {
+                Ok(account) => {
+                    match aptone.close_account(account) {
+                        Ok(balances) => print_closed(account, &balances),
+                        Err(err) => println!("error: {}", err),
+                    }
+                    continue;
+                }
+                Err(_) => None,
+            },
+            ["deposit", account, amount] => parse_tx(account, amount, Some(TxType::DEPOSIT)),
+            ["withdraw", account, amount] => parse_tx(account, amount, Some(TxType::WITHDRAW)),
+            ["transfer", from, to, amount] => parse_tx(
+                from,
+                amount,
+                to.parse().ok().map(|to| TxType::TRANSFER { to }),
+            ),
+            _ => None,
+        };
+        match submitted {
+            Some((account, amount, tx_type)) => match aptone.handle_tx(account, amount, tx_type) {
+                Ok(receipt) => println!(
+                    "transaction {} queued on handler {}",
+                    receipt.tx_id(),
+                    receipt.handle_id()
+                ),
+                Err(err) => println!("error: {}", err),
+            },
+            None => println!("unknown command, try `help`"),
         }
     }
-    fn handle_tx(&self, account: u32, amount: u32, tx_type: TxType) {
-        let mut data = self.server_data.lock().unwrap();
 
-        let id = data.get_handle(account);
-        println!(
-            "account: {} \t balance = {}\t pending = {} \t amount: {} \t type: {:?} --> {}",
-            account,
-            data.get_balance(account),
-            data.get_pending_tx(account),
-            amount,
-            tx_type,
-            id
-        );
-        data.set_handle(account, id);
-        data.increase_pending_tx(account, 1);
-        data.increase_tx_count(id, 1);
+    // lets the handlers drain, which also ends the event stream
+    drop(aptone);
+    let _ = printer.join();
+    Ok(())
+}
 
-        assert!(id != INVALID_HANDLE);
+fn parse_tx(
+    account: &str,
+    amount: &str,
+    tx_type: Option<TxType>,
+) -> Option<(AccountId, Money, TxType)> {
+    Some((account.parse().ok()?, amount.parse().ok()?, tx_type?))
+}
 
-        self.handles[id as usize]
-            .sender
-            .send(Message::NewTx(Tx {
-                account,
-                amount,
-                tx_type,
-            }))
-            .unwrap();
+fn simulate(
+    aptone: &Aptone,
+    accounts: AccountId,
+    txs: u64,
+    skew: Skew,
+    seed: u64,
+) -> Result<(), String> {
+    let mut rng = XorShift(seed.max(1));
+    let picker = Picker::new(accounts as usize, skew);
+    for _ in 0..accounts {
+        aptone
+            .open_account(Money::ZERO)
+            .map_err(|err| err.to_string())?;
     }
-    fn withdraw(&self, account: AccountId, amount: u32) {
-        self.handle_tx(account, amount, TxType::WITHDRAW);
-    }
-    fn deposit(&self, account: AccountId, amount: u32) {
-        self.handle_tx(account, amount, TxType::DEPOSIT);
-    }
-    fn get_balance(&self, account: AccountId) -> u32 {
-        self.server_data.lock().unwrap().get_balance(account)
+    let started = Instant::now();
+    let mut submitted = Vec::with_capacity(txs as usize);
+    let mut rejected = 0;
+    for _ in 0..txs {
+        let account = picker.pick(rng.next()) as AccountId;
+        // 0.01 to 100, in whole cents
+        let cents = (rng.next() % 10_000 + 1) as i128;
+        let amount = Money::from_minor(cents * 10i128.pow(Money::SCALE - 2));
+        let tx_type = match rng.next() % 3 {
+            0 => TxType::DEPOSIT,
+            1 => TxType::WITHDRAW,
+            _ => TxType::TRANSFER {
+                to: picker.pick(rng.next()) as AccountId,
+            },
+        };
+        match aptone.handle_tx(account, amount, tx_type) {
+            Ok(receipt) => submitted.push((amount, tx_type, receipt)),
+            Err(_) => rejected += 1,
+        }
     }
-}
 
-impl Drop for Aptone {
-    fn drop(&mut self) {
-        println!("--- Killing all threads...");
-        for handler in &mut self.handles {
-            println!("          Sending termination message...");
-            handler.sender.send(Message::Terminate).unwrap();
-        }
+    aptone.flush();
 
-        for handler in &mut self.handles {
-            if let Some(thread) = handler.thread.take() {
-                thread.join().unwrap();
-            } else {
-                println!("oops");
+    // transfers move money around; only deposits and withdrawals change the total
+    let (mut applied, mut expected) = (0, Money::ZERO);
+    for (amount, tx_type, receipt) in submitted {
+        match receipt.wait() {
+            Ok(()) => {
+                applied += 1;
+                match tx_type {
+                    TxType::DEPOSIT => expected += amount,
+                    TxType::WITHDRAW => expected -= amount,
+                    // the simulation only makes transfers besides
+                    _ => {}
+                }
             }
+            Err(_) => rejected += 1,
         }
     }
+    let elapsed = started.elapsed();
+    let total: Money = (0..accounts)
+        .map(|account| aptone.get_balance(account))
+        .sum();
+
+    println!(
+        "{} transactions over {} accounts in {:?} (seed {})",
+        txs, accounts, elapsed, seed
+    );
+    println!("applied: {} \t rejected: {}", applied, rejected);
+    println!(
+        "throughput: {:.0} tx/s",
+        txs as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+    println!("total balance: {} \t expected: {}", total, expected);
+    if total == expected {
+        Ok(())
+    } else {
+        Err(String::from("balances don't add up"))
+    }
 }
 
-fn main() {
-    let aptone = Arc::new(Mutex::new(Aptone::new()));
-    let aptone_one = Arc::clone(&aptone);
-    let simulator = thread::spawn(move || {
-        for _ in 0..4 {
-            let aptone = aptone_one.lock().unwrap();
-            aptone.deposit(0, 500);
-            aptone.deposit(1, 400);
-            aptone.withdraw(1, 300);
-            aptone.withdraw(0, 100);
-            thread::sleep(Duration::from_millis(700));
-        }
-    });
-    simulator.join().unwrap();
-
-    println!("wait until all transactions are finished");
-    thread::sleep(Duration::from_secs(10));
-    {
-        let aptone = Arc::clone(&aptone);
-        let aptone = aptone.lock().unwrap();
-        for account in 0..2 {
-            println!(
-                "account: {} \t balance: {}",
-                account,
-                aptone.get_balance(account)
-            );
-        }
+// xorshift64; good enough to spread transactions over accounts, and reproducible from a seed
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
     }
-    println!("Terminating program...");
 }
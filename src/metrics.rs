@@ -0,0 +1,480 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+use crate::directory::{Shard, TxCounts};
+use crate::sync::{self, AtomicU32};
+use crate::{HandleId, LatencyPercentiles, Tx, TxError, TxResult, TxType};
+
+// upper bounds, in seconds, of the latency histogram buckets
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+// the finer histograms percentiles are read off have this many buckets per doubling, from 1µs
+// up to 2^FINE_OCTAVES µs, over two minutes, with slower transactions landing in the last
+const FINE_PER_OCTAVE: u32 = 4;
+const FINE_OCTAVES: u32 = 27;
+const FINE_BUCKETS: usize = (FINE_PER_OCTAVE * FINE_OCTAVES) as usize + 1;
+
+const PERCENTILES: [f64; 3] = [0.5, 0.95, 0.99];
+
+const TX_TYPES: [&str; 10] = [
+    "deposit",
+    "withdraw",
+    "transfer",
+    "exchange",
+    "authorize",
+    "capture",
+    "release",
+    "reversal",
+    "fee",
+    "batch",
+];
+
+// The position in `TX_TYPES` of a transaction made of `legs`.
+fn tx_type(legs: &[Tx]) -> usize {
+    if legs.len() > 1 {
+        return 9;
+    }
+    match legs[0].tx_type {
+        TxType::DEPOSIT => 0,
+        TxType::WITHDRAW => 1,
+        TxType::TRANSFER { .. } => 2,
+        TxType::EXCHANGE { .. } => 3,
+        TxType::AUTHORIZE { .. } => 4,
+        TxType::CAPTURE { .. } => 5,
+        TxType::RELEASE { .. } => 6,
+        TxType::REVERSAL { .. } => 7,
+        TxType::FEE { .. } => 8,
+    }
+}
+
+// Latencies in buckets each 2^(1/FINE_PER_OCTAVE) times as wide as the one before, the first
+// holding those under a microsecond.
+struct Histogram {
+    buckets: [AtomicU64; FINE_BUCKETS],
+    micros: AtomicU64, // sum over every observed transaction
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            micros: AtomicU64::new(0),
+        }
+    }
+    fn record(&self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        let bucket = match micros {
+            0 => 0,
+            micros => ((micros as f64).log2() * FINE_PER_OCTAVE as f64) as usize + 1,
+        };
+        self.buckets[bucket.min(FINE_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.micros.fetch_add(micros, Ordering::Relaxed);
+    }
+}
+
+// The upper bound of the `bucket`th fine bucket.
+fn fine_bound(bucket: usize) -> Duration {
+    let micros = 2f64.powf(bucket as f64 / FINE_PER_OCTAVE as f64);
+    Duration::from_secs_f64(micros / 1e6)
+}
+
+// The percentiles of everything `histograms` observed together, each the upper bound of the
+// bucket it falls in.
+fn percentiles<'a>(histograms: impl Iterator<Item = &'a Histogram>) -> LatencyPercentiles {
+    let mut counts = [0; FINE_BUCKETS];
+    for histogram in histograms {
+        for (count, bucket) in counts.iter_mut().zip(&histogram.buckets) {
+            *count += bucket.load(Ordering::Relaxed);
+        }
+    }
+    let count: u64 = counts.iter().sum();
+    let [p50, p95, p99] = PERCENTILES.map(|percentile| {
+        let rank = (percentile * count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        counts
+            .iter()
+            .position(|&in_bucket| {
+                seen += in_bucket;
+                seen >= rank
+            })
+            .map_or(Duration::ZERO, fine_bound)
+    });
+    LatencyPercentiles {
+        count,
+        p50,
+        p95,
+        p99,
+    }
+}
+
+const REASONS: [&str; 23] = [
+    "insufficient_funds",
+    "unknown_account",
+    "overflow",
+    "handler_unavailable",
+    "queue_full",
+    "shutting_down",
+    "wal",
+    "duplicate",
+    "invalid_amount",
+    "no_exchange_rate",
+    "account_busy",
+    "unknown_hold",
+    "unknown_tx",
+    "not_reversible",
+    "already_reversed",
+    "empty_batch",
+    "rate_limited",
+    "limit_exceeded",
+    "not_dead_lettered",
+    "rejected",
+    "storage",
+    "no_accounts_left",
+    "cancelled",
+];
+
+impl TxError {
+    fn reason(&self) -> usize {
+        match self {
+            TxError::InsufficientFunds { .. } => 0,
+            TxError::UnknownAccount(_) => 1,
+            TxError::Overflow(_) => 2,
+            TxError::HandlerUnavailable(_) => 3,
+            TxError::QueueFull(_) => 4,
+            TxError::ShuttingDown => 5,
+            TxError::Wal(_) => 6,
+            TxError::Duplicate(_) => 7,
+            TxError::InvalidAmount(_) => 8,
+            TxError::NoExchangeRate { .. } => 9,
+            TxError::AccountBusy { .. } => 10,
+            TxError::UnknownHold(_) => 11,
+            TxError::UnknownTx { .. } => 12,
+            TxError::NotReversible(_) => 13,
+            TxError::AlreadyReversed(_) => 14,
+            TxError::EmptyBatch => 15,
+            TxError::RateLimited(_) => 16,
+            TxError::LimitExceeded { .. } => 17,
+            TxError::NotDeadLettered(_) => 18,
+            TxError::Rejected(_) => 19,
+            TxError::Storage(_) => 20,
+            TxError::NoAccountsLeft => 21,
+            TxError::Cancelled(_) => 22,
+        }
+    }
+}
+
+/// Counters the handlers and the submission path update as they go.
+pub(crate) struct Metrics {
+    applied: AtomicU64,
+    rejected: [AtomicU64; REASONS.len()],
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_micros: AtomicU64, // sum over every observed transaction
+    latency_count: AtomicU64,
+    // handler id -> (applied, rejected) of the transactions queued on it
+    handled: Vec<(AtomicU64, AtomicU64)>,
+    // handler id * TX_TYPES.len() + tx type -> the latencies of those queued on it
+    latencies: Vec<Histogram>,
+    last_scrape: Mutex<(Instant, u64)>, // when, and how many transactions were finished by then
+}
+
+impl Metrics {
+    pub(crate) fn new(handlers: usize) -> Metrics {
+        Metrics {
+            applied: AtomicU64::new(0),
+            rejected: Default::default(),
+            latency_buckets: Default::default(),
+            latency_micros: AtomicU64::new(0),
+            latency_count: AtomicU64::new(0),
+            handled: (0..handlers).map(|_| Default::default()).collect(),
+            latencies: (0..handlers * TX_TYPES.len())
+                .map(|_| Histogram::new())
+                .collect(),
+            last_scrape: Mutex::new((Instant::now(), 0)),
+        }
+    }
+    /// Counts a transaction made of `legs` queued on `handler` that was finished, `latency`
+    /// after it was submitted.
+    pub(crate) fn observe(
+        &self,
+        handler: HandleId,
+        legs: &[Tx],
+        result: &TxResult,
+        latency: Duration,
+    ) {
+        let (applied, rejected) = &self.handled[handler as usize];
+        match result {
+            Ok(()) => {
+                self.applied.fetch_add(1, Ordering::Relaxed);
+                applied.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => {
+                self.reject(err);
+                rejected.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let seconds = latency.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+        self.latencies[handler as usize * TX_TYPES.len() + tx_type(legs)].record(latency);
+    }
+    /// Counts a transaction turned away, by a handler or before reaching one.
+    pub(crate) fn reject(&self, err: &TxError) {
+        self.rejected[err.reason()].fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn applied(&self) -> u64 {
+        self.applied.load(Ordering::Relaxed)
+    }
+    pub(crate) fn rejected(&self) -> u64 {
+        self.rejected
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+    /// How many transactions were finished and their latencies summed, in microseconds.
+    pub(crate) fn latency_total(&self) -> (u64, u64) {
+        (
+            self.latency_count.load(Ordering::Relaxed),
+            self.latency_micros.load(Ordering::Relaxed),
+        )
+    }
+    /// Applied and rejected transactions that were queued on `handler`.
+    pub(crate) fn handled(&self, handler: HandleId) -> (u64, u64) {
+        let (applied, rejected) = &self.handled[handler as usize];
+        (
+            applied.load(Ordering::Relaxed),
+            rejected.load(Ordering::Relaxed),
+        )
+    }
+    /// Latency percentiles of the transactions queued on `handler`, or on any handler, overall
+    /// and by the type of those there were any of.
+    pub(crate) fn latency(
+        &self,
+        handler: Option<HandleId>,
+    ) -> (
+        LatencyPercentiles,
+        BTreeMap<&'static str, LatencyPercentiles>,
+    ) {
+        let cells: Vec<(usize, &Histogram)> = self
+            .latencies
+            .iter()
+            .enumerate()
+            .filter(|(cell, _)| {
+                handler.is_none_or(|handler| cell / TX_TYPES.len() == handler as usize)
+            })
+            .map(|(cell, histogram)| (cell % TX_TYPES.len(), histogram))
+            .collect();
+        let overall = percentiles(cells.iter().map(|(_, histogram)| *histogram));
+        let by_type = TX_TYPES
+            .iter()
+            .enumerate()
+            .map(|(tx_type, &name)| {
+                let of_type = cells.iter().filter(|(cell, _)| *cell == tx_type);
+                (name, percentiles(of_type.map(|(_, histogram)| *histogram)))
+            })
+            .filter(|(_, percentiles)| percentiles.count > 0)
+            .collect();
+        (overall, by_type)
+    }
+    /// Renders everything in the Prometheus text exposition format.
+    pub(crate) fn render(&self, tx_count: &[AtomicU32], shards: &[Shard]) -> String {
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP aptone_queue_depth Transactions queued on a handler and not finished.\n",
+        );
+        out.push_str("# TYPE aptone_queue_depth gauge\n");
+        for (id, count) in tx_count.iter().enumerate() {
+            let depth = count.load(Ordering::SeqCst);
+            let _ = writeln!(out, "aptone_queue_depth{{handler=\"{}\"}} {}", id, depth);
+        }
+
+        let applied = self.applied.load(Ordering::Relaxed);
+        out.push_str("# HELP aptone_applied_total Transactions applied.\n");
+        out.push_str("# TYPE aptone_applied_total counter\n");
+        let _ = writeln!(out, "aptone_applied_total {}", applied);
+
+        out.push_str("# HELP aptone_rejected_total Transactions rejected, by reason.\n");
+        out.push_str("# TYPE aptone_rejected_total counter\n");
+        for (reason, count) in REASONS.iter().zip(&self.rejected) {
+            let count = count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "aptone_rejected_total{{reason=\"{}\"}} {}",
+                reason, count
+            );
+        }
+
+        let finished = self.latency_count.load(Ordering::Relaxed);
+        let rate = {
+            let mut last = self.last_scrape.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(last.0).as_secs_f64();
+            let rate = if elapsed > 0.0 {
+                (finished - last.1) as f64 / elapsed
+            } else {
+                0.0
+            };
+            *last = (now, finished);
+            rate
+        };
+        out.push_str("# HELP aptone_transactions_per_second Transactions finished per second since the previous scrape.\n");
+        out.push_str("# TYPE aptone_transactions_per_second gauge\n");
+        let _ = writeln!(out, "aptone_transactions_per_second {}", rate);
+
+        out.push_str("# HELP aptone_tx_latency_seconds Time from submission to being finished by a handler.\n");
+        out.push_str("# TYPE aptone_tx_latency_seconds histogram\n");
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "aptone_tx_latency_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "aptone_tx_latency_seconds_bucket{{le=\"+Inf\"}} {}",
+            finished
+        );
+        let sum = self.latency_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "aptone_tx_latency_seconds_sum {}", sum);
+        let _ = writeln!(out, "aptone_tx_latency_seconds_count {}", finished);
+
+        out.push_str("# HELP aptone_tx_latency_percentile_seconds Percentiles of the time from submission to being finished, by handler and tx type.\n");
+        out.push_str("# TYPE aptone_tx_latency_percentile_seconds summary\n");
+        for (cell, histogram) in self.latencies.iter().enumerate() {
+            let estimate = percentiles(std::iter::once(histogram));
+            if estimate.count == 0 {
+                continue;
+            }
+            let labels = format!(
+                "handler=\"{}\",type=\"{}\"",
+                cell / TX_TYPES.len(),
+                TX_TYPES[cell % TX_TYPES.len()]
+            );
+            let quantiles = [estimate.p50, estimate.p95, estimate.p99];
+            for (percentile, value) in PERCENTILES.iter().zip(quantiles) {
+                let _ = writeln!(
+                    out,
+                    "aptone_tx_latency_percentile_seconds{{{},quantile=\"{}\"}} {}",
+                    labels,
+                    percentile,
+                    value.as_secs_f64()
+                );
+            }
+            let sum = histogram.micros.load(Ordering::Relaxed) as f64 / 1e6;
+            let _ = writeln!(
+                out,
+                "aptone_tx_latency_percentile_seconds_sum{{{}}} {}",
+                labels, sum
+            );
+            let _ = writeln!(
+                out,
+                "aptone_tx_latency_percentile_seconds_count{{{}}} {}",
+                labels, estimate.count
+            );
+        }
+
+        let accounts: usize = shards
+            .iter()
+            .map(|shard| sync::lock(shard).account_count())
+            .sum();
+        out.push_str("# HELP aptone_accounts Accounts holding a balance.\n");
+        out.push_str("# TYPE aptone_accounts gauge\n");
+        let _ = writeln!(out, "aptone_accounts {}", accounts);
+
+        out
+    }
+}
+
+/// Serves `GET /metrics` on its own thread until stopped.
+pub(crate) struct MetricsServer {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    pub(crate) fn start(
+        addr: SocketAddr,
+        metrics: Arc<Metrics>,
+        tx_count: TxCounts,
+        shards: Vec<Shard>,
+    ) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopped);
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let served =
+                    stream.and_then(|stream| serve(stream, || metrics.render(&tx_count, &shards)));
+                if let Err(err) = served {
+                    debug!(%err, "metrics request failed");
+                }
+            }
+        });
+        Ok(MetricsServer {
+            addr,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wakes the listener up so it sees the flag
+        let mut wake = self.addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        if let Err(err) = TcpStream::connect(wake) {
+            warn!(%err, "failed to stop the metrics server");
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(mut stream: TcpStream, render: impl FnOnce() -> String) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4", render()),
+        _ => ("404 Not Found", "text/plain", String::from("not found\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
@@ -0,0 +1,49 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{Tx, TxError, TxId, TxResult};
+
+/// Hooks the handlers run around every transaction they pick up, for validation, logging,
+/// fraud checks and the like. `legs` holds the transaction, or every leg of a batch.
+///
+/// The log records a transaction before a middleware sees it, so replaying the log applies a
+/// transaction that `before_apply` turned down.
+pub trait TxMiddleware: fmt::Debug + Send + Sync {
+    /// Runs before the transaction is applied; an error rejects it with that error, and the
+    /// middleware registered after this one don't see it.
+    fn before_apply(&self, _tx_id: TxId, _legs: &[Tx]) -> TxResult {
+        Ok(())
+    }
+    fn after_apply(&self, _tx_id: TxId, _legs: &[Tx]) {}
+    /// Runs once the transaction is rejected, by a middleware or by the engine.
+    fn on_reject(&self, _tx_id: TxId, _legs: &[Tx], _err: &TxError) {}
+}
+
+/// The middleware registered on the builder, in order.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Pipeline {
+    chain: Vec<Arc<dyn TxMiddleware>>,
+}
+
+impl Pipeline {
+    pub(crate) fn new(chain: Vec<Arc<dyn TxMiddleware>>) -> Pipeline {
+        Pipeline { chain }
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.chain.is_empty()
+    }
+    pub(crate) fn before_apply(&self, tx_id: TxId, legs: &[Tx]) -> TxResult {
+        self.chain
+            .iter()
+            .try_for_each(|middleware| middleware.before_apply(tx_id, legs))
+    }
+    /// Runs `after_apply` or `on_reject` by how the transaction turned out.
+    pub(crate) fn finished(&self, tx_id: TxId, legs: &[Tx], result: &TxResult) {
+        for middleware in &self.chain {
+            match result {
+                Ok(()) => middleware.after_apply(tx_id, legs),
+                Err(err) => middleware.on_reject(tx_id, legs, err),
+            }
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use std::error::Error;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+/// An amount of money, held exactly to `Money::SCALE` decimal places.
+///
+/// Adding and subtracting is exact up to `MIN` and `MAX`: the operators saturate there rather
+/// than panic or wrap, and the checked forms fail, for paths that have to tell. Digits are only
+/// lost when parsing a decimal with more than `SCALE` places, multiplying, as when converting
+/// between currencies, and in `round_dp`; all of them round half to even.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(i128); // in units of 10^-SCALE
+
+impl Money {
+    pub const SCALE: u32 = 4;
+    pub const ZERO: Money = Money(0);
+    pub const MAX: Money = Money(i128::MAX);
+    pub const MIN: Money = Money(i128::MIN);
+
+    const ONE: i128 = 10i128.pow(Money::SCALE);
+
+    /// `minor` units of 10^-SCALE.
+    pub const fn from_minor(minor: i128) -> Money {
+        Money(minor)
+    }
+    pub const fn minor(self) -> i128 {
+        self.0
+    }
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+    pub fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        self.0.checked_add(other.0).map(Money)
+    }
+    pub fn checked_sub(self, other: Money) -> Option<Money> {
+        self.0.checked_sub(other.0).map(Money)
+    }
+    pub fn saturating_add(self, other: Money) -> Money {
+        Money(self.0.saturating_add(other.0))
+    }
+    pub fn saturating_sub(self, other: Money) -> Money {
+        Money(self.0.saturating_sub(other.0))
+    }
+    pub fn saturating_neg(self) -> Money {
+        Money(self.0.saturating_neg())
+    }
+    /// The product rounded to `SCALE` places, halves to even. `None` if it doesn't fit.
+    pub fn checked_mul(self, other: Money) -> Option<Money> {
+        let product = self.0.checked_mul(other.0)?;
+        Some(Money(round_half_even(product, Money::ONE)))
+    }
+    /// Rounds to `places` decimal places, halves to even, saturating at `MIN` and `MAX` like the
+    /// operators. Places beyond `SCALE` change nothing.
+    pub fn round_dp(self, places: u32) -> Money {
+        if places >= Money::SCALE {
+            return self;
+        }
+        let unit = 10i128.pow(Money::SCALE - places);
+        match round_half_even(self.0, unit).checked_mul(unit) {
+            Some(rounded) => Money(rounded),
+            None if self.is_negative() => Money::MIN,
+            None => Money::MAX,
+        }
+    }
+}
+
+// `value / unit`, rounded half to even.
+fn round_half_even(value: i128, unit: i128) -> i128 {
+    let quotient = value.div_euclid(unit);
+    let remainder = value.rem_euclid(unit);
+    match (2 * remainder).cmp(&unit) {
+        std::cmp::Ordering::Less => quotient,
+        std::cmp::Ordering::Greater => quotient + 1,
+        std::cmp::Ordering::Equal => quotient + quotient.rem_euclid(2),
+    }
+}
+
+macro_rules! from_integer {
+    ($($int:ty),*) => {
+        $(
+            /// Whole units.
+            impl From<$int> for Money {
+                fn from(units: $int) -> Money {
+                    Money(units as i128 * Money::ONE)
+                }
+            }
+        )*
+    };
+}
+
+from_integer!(i32, u32, i64, u64);
+
+impl Add for Money {
+    type Output = Money;
+    fn add(self, other: Money) -> Money {
+        self.saturating_add(other)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        *self = self.saturating_add(other);
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+    fn sub(self, other: Money) -> Money {
+        self.saturating_sub(other)
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, other: Money) {
+        *self = self.saturating_sub(other);
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+    fn neg(self) -> Money {
+        self.saturating_neg()
+    }
+}
+
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, Add::add)
+    }
+}
+
+/// Prints as few decimal places as it takes, e.g. `12`, `-0.5` or `3.1416`.
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let units = self.0.unsigned_abs() / Money::ONE as u128;
+        let fraction = self.0.unsigned_abs() % Money::ONE as u128;
+        if fraction == 0 {
+            return write!(f, "{}{}", sign, units);
+        }
+        let digits = format!("{:0width$}", fraction, width = Money::SCALE as usize);
+        write!(f, "{}{}.{}", sign, units, digits.trim_end_matches('0'))
+    }
+}
+
+/// The string wasn't a decimal number, or it doesn't fit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseMoneyError(String);
+
+impl fmt::Display for ParseMoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid amount of money: {:?}", self.0)
+    }
+}
+
+impl Error for ParseMoneyError {}
+
+/// Parses decimals like `12`, `-0.5` or `+3.14159`, rounding any places beyond `SCALE` half to
+/// even.
+impl FromStr for Money {
+    type Err = ParseMoneyError;
+
+    fn from_str(s: &str) -> Result<Money, ParseMoneyError> {
+        let invalid = || ParseMoneyError(s.to_string());
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (units, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if units.is_empty() && fraction.is_empty()
+            || !units
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+        let kept = fraction.len().min(Money::SCALE as usize);
+        let mut minor: i128 = 0;
+        for digit in units.bytes().chain(fraction[..kept].bytes()) {
+            minor = minor
+                .checked_mul(10)
+                .and_then(|minor| minor.checked_add((digit - b'0') as i128))
+                .ok_or_else(invalid)?;
+        }
+        minor = minor
+            .checked_mul(10i128.pow(Money::SCALE - kept as u32))
+            .ok_or_else(invalid)?;
+        let mut dropped = fraction[kept..].bytes();
+        let round_up = match dropped.next() {
+            Some(digit) if digit > b'5' => true,
+            Some(b'5') => dropped.any(|digit| digit != b'0') || minor % 2 == 1,
+            _ => false,
+        };
+        if round_up {
+            minor = minor.checked_add(1).ok_or_else(invalid)?;
+        }
+        Ok(Money(if negative { -minor } else { minor }))
+    }
+}
+
+/// As a decimal string, so no client reads it through a float; in binary formats, which aren't
+/// read by hand, as the number of minor units.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Money {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_i128(self.0)
+        }
+    }
+}
+
+/// From a decimal string or an integer; floats are refused as they may not be what was meant.
+/// Binary formats hold the minor units, as written.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Money {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Money, D::Error> {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = Money;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a decimal string or an integer")
+            }
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Money, E> {
+                s.parse().map_err(E::custom)
+            }
+            fn visit_i64<E: serde::de::Error>(self, units: i64) -> Result<Money, E> {
+                Ok(Money::from(units))
+            }
+            fn visit_u64<E: serde::de::Error>(self, units: u64) -> Result<Money, E> {
+                Ok(Money::from(units))
+            }
+        }
+
+        if !deserializer.is_human_readable() {
+            return <i128 as serde::Deserialize>::deserialize(deserializer).map(Money);
+        }
+        deserializer.deserialize_any(Visitor)
+    }
+}
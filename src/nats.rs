@@ -0,0 +1,104 @@
+//! NATS front end for a running engine, answering requests on `aptone.tx.deposit` and
+//! `aptone.tx.withdraw` once their handler has applied or rejected them.
+//!
+//! A request carries `{"account": 3, "amount": "12.5"}`, with an optional `"currency"` code as in
+//! the REST API, and an optional `Idempotency-Key` header deduplicating retries. The reply is
+//! `{"tx_id": 8, "account": 3, "currency": "USD", "balance": "87.5"}`, the balance as read right
+//! after the transaction was applied, or `{"error": "..."}`. Only built with the `nats` feature.
+
+use std::sync::Arc;
+
+use async_nats::{Client, Message};
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use tracing::warn;
+
+use crate::{AccountId, Aptone, Currency, Money, Tx, TxError, TxId, TxType};
+
+/// The subjects served, one per kind of transaction.
+pub const SUBJECTS: &str = "aptone.tx.*";
+
+#[derive(Deserialize)]
+struct AmountRequest {
+    account: AccountId,
+    amount: Money,
+    #[serde(default)]
+    currency: Currency,
+}
+
+#[derive(Serialize)]
+struct TxReply {
+    tx_id: TxId,
+    account: AccountId,
+    currency: Currency,
+    balance: Money,
+}
+
+#[derive(Serialize)]
+struct ErrorReply {
+    error: String,
+}
+
+/// Connects to the NATS server at `url` and serves `aptone` on `SUBJECTS` until the connection
+/// is closed.
+pub async fn serve(aptone: Arc<Aptone>, url: &str) -> Result<(), async_nats::Error> {
+    let client = async_nats::connect(url).await?;
+    let mut requests = client.subscribe(SUBJECTS).await?;
+    while let Some(request) = requests.next().await {
+        tokio::spawn(answer(Arc::clone(&aptone), client.clone(), request));
+    }
+    Ok(())
+}
+
+async fn answer(aptone: Arc<Aptone>, client: Client, request: Message) {
+    let reply = match handle(aptone, &request).await {
+        Ok(reply) => serde_json::to_vec(&reply),
+        Err(error) => serde_json::to_vec(&ErrorReply { error }),
+    };
+    // without a reply subject the sender isn't listening for how it went
+    let Some(reply_to) = request.reply else {
+        return;
+    };
+    let reply = reply.expect("replies always serialize");
+    if let Err(err) = client.publish(reply_to, reply.into()).await {
+        warn!(%err, subject = %request.subject, "failed to reply over nats");
+    }
+}
+
+async fn handle(aptone: Arc<Aptone>, request: &Message) -> Result<TxReply, String> {
+    let tx_type = match request.subject.as_str() {
+        "aptone.tx.deposit" => TxType::DEPOSIT,
+        "aptone.tx.withdraw" => TxType::WITHDRAW,
+        subject => return Err(format!("no transactions on {}", subject)),
+    };
+    let AmountRequest {
+        account,
+        amount,
+        currency,
+    } = serde_json::from_slice(&request.payload).map_err(|err| err.to_string())?;
+    let key = request
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get("Idempotency-Key"))
+        .map(|key| key.to_string());
+    // submitting may block on a full queue and waiting blocks until the handler is done, so
+    // neither runs on the async workers
+    tokio::task::spawn_blocking(move || {
+        let tx = Tx::new(account, amount, tx_type).in_currency(currency);
+        let receipt = match &key {
+            Some(key) => aptone.submit_tx_with_key(key, tx),
+            None => aptone.submit_tx(tx),
+        }?;
+        let tx_id = receipt.tx_id();
+        receipt.wait()?;
+        Ok(TxReply {
+            tx_id,
+            account,
+            currency,
+            balance: aptone.get_balance_in(account, currency),
+        })
+    })
+    .await
+    .unwrap_or(Err(TxError::ShuttingDown))
+    .map_err(|err: TxError| err.to_string())
+}
@@ -0,0 +1,79 @@
+//! Exports the engine's spans to an OpenTelemetry collector over OTLP, so a transaction can be
+//! followed from the request that submitted it to the handler that applied it: each submission
+//! opens a `submit` span, its wait on a handler's queue is a `queued` span under it, and the
+//! handler's work an `apply` (or `cancel`) span under it too. The HTTP and gRPC front ends
+//! continue the trace a request's W3C `traceparent` header carries, if any.
+
+use std::collections::HashMap;
+use std::env;
+
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::{error, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{EnvFilter, Layer};
+
+const SERVICE_NAME: &str = "aptone";
+
+/// Ships spans to an OTLP collector over HTTP in batches, flushing what is left when dropped.
+pub struct OtlpTracing {
+    provider: SdkTracerProvider,
+}
+
+impl OtlpTracing {
+    /// Exports to the collector `OTEL_EXPORTER_OTLP_ENDPOINT` or
+    /// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` names, or to none if neither is set.
+    pub fn from_env() -> Result<Option<OtlpTracing>, String> {
+        let configured = [
+            "OTEL_EXPORTER_OTLP_ENDPOINT",
+            "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+        ]
+        .iter()
+        .any(|var| env::var_os(var).is_some());
+        if !configured {
+            return Ok(None);
+        }
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .build()
+            .map_err(|err| format!("failed to set up the OTLP exporter: {}", err))?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+            .build();
+        Ok(Some(OtlpTracing { provider }))
+    }
+    /// The layer turning the engine's spans, from info level up, into OpenTelemetry ones.
+    pub fn layer<S>(&self) -> impl Layer<S>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        tracing_opentelemetry::layer()
+            .with_tracer(self.provider.tracer(SERVICE_NAME))
+            .with_filter(EnvFilter::new("aptone=info"))
+    }
+}
+
+impl Drop for OtlpTracing {
+    fn drop(&mut self) {
+        if let Err(err) = self.provider.shutdown() {
+            error!(%err, "failed to flush spans to the collector");
+        }
+    }
+}
+
+#[cfg(any(feature = "http", feature = "grpc"))]
+/// Makes `span` part of the trace the W3C trace context headers `header` looks up carry.
+pub(crate) fn continue_trace(span: &Span, header: impl Fn(&str) -> Option<String>) {
+    let propagator = TraceContextPropagator::new();
+    let carrier: HashMap<String, String> = propagator
+        .fields()
+        .filter_map(|name| Some((name.to_string(), header(name)?)))
+        .collect();
+    let _ = span.set_parent(propagator.extract(&carrier));
+}
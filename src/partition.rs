@@ -0,0 +1,246 @@
+//! Runs one ledger as several processes, each owning a contiguous range of the account ids,
+//! given with `AptoneBuilder::account_ids`, and serving the `tcp` line protocol. Clients talk the
+//! same protocol to a `PartitionRouter` in front of them, which forwards each request to the
+//! process owning its account, opens accounts on each process in turn, passing over those with
+//! no ids left or out of reach, and adds up `TOTAL` over all of them.
+//!
+//! A transfer between two processes is a withdrawal on one and a deposit on the other, the
+//! amount deposited back should the deposit fail. It isn't atomic: reads in between see the
+//! amount on neither account, and a router stopped halfway leaves it withdrawn.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use tracing::{debug, error};
+
+use crate::{AccountId, Money, TxError};
+
+/// A process and the account ids it owns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub accounts: Range<AccountId>,
+    pub addr: SocketAddr,
+}
+
+/// Read from `<start>..<end>=<addr>`, the end left out of the range.
+impl FromStr for Partition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Partition, String> {
+        let invalid = || format!("invalid partition, expected <start>..<end>=<addr>: {}", s);
+        let (range, addr) = s.split_once('=').ok_or_else(invalid)?;
+        let (start, end) = range.split_once("..").ok_or_else(invalid)?;
+        let (start, end) = (start.parse(), end.parse());
+        Ok(Partition {
+            accounts: start.map_err(|_| invalid())?..end.map_err(|_| invalid())?,
+            addr: addr.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}..{}={}",
+            self.accounts.start, self.accounts.end, self.addr
+        )
+    }
+}
+
+/// Forwards the line protocol to the partitions, see the module docs.
+#[derive(Debug)]
+pub struct PartitionRouter {
+    partitions: Vec<Partition>, // by range
+    next_open: AtomicUsize,     // the partition to open the next account on
+}
+
+impl PartitionRouter {
+    /// Panics without partitions, or with an empty range or two overlapping.
+    pub fn new(mut partitions: Vec<Partition>) -> PartitionRouter {
+        assert!(!partitions.is_empty(), "a router needs partitions");
+        partitions.sort_by_key(|partition| partition.accounts.start);
+        for partition in &partitions {
+            assert!(!partition.accounts.is_empty(), "{} owns no ids", partition);
+        }
+        for pair in partitions.windows(2) {
+            assert!(
+                pair[0].accounts.end <= pair[1].accounts.start,
+                "{} and {} overlap",
+                pair[0],
+                pair[1]
+            );
+        }
+        PartitionRouter {
+            partitions,
+            next_open: AtomicUsize::new(0),
+        }
+    }
+    pub fn partitions(&self) -> &[Partition] {
+        &self.partitions
+    }
+    /// The position in `partitions` of the one owning `account`.
+    pub fn owner(&self, account: AccountId) -> Option<usize> {
+        let after = self
+            .partitions
+            .partition_point(|partition| partition.accounts.start <= account);
+        let owner = after.checked_sub(1)?;
+        self.partitions[owner]
+            .accounts
+            .contains(&account)
+            .then_some(owner)
+    }
+}
+
+/// Serves the line protocol on `addr` in front of `router`'s partitions, a thread per client
+/// with its own connection to each, until accepting fails.
+pub fn serve(router: Arc<PartitionRouter>, addr: impl ToSocketAddrs) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    loop {
+        let (stream, peer) = listener.accept()?;
+        let router = Arc::clone(&router);
+        thread::spawn(move || {
+            if let Err(err) = client(&router, stream) {
+                debug!(%err, %peer, "line client went away");
+            }
+        });
+    }
+}
+
+fn client(router: &PartitionRouter, stream: TcpStream) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut upstreams = Upstreams {
+        router,
+        connections: router.partitions.iter().map(|_| None).collect(),
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some(command) = words.first() else {
+            continue;
+        };
+        if command.eq_ignore_ascii_case("quit") {
+            break;
+        }
+        let command = command.to_ascii_uppercase();
+        match request(router, &mut upstreams, &command, &words[1..]) {
+            Ok(result) => writeln!(writer, "OK {}", result)?,
+            Err(reason) => writeln!(writer, "ERR {}", reason)?,
+        }
+    }
+    Ok(())
+}
+
+// A client's connections to the partitions, opened as needed.
+struct Upstreams<'a> {
+    router: &'a PartitionRouter,
+    connections: Vec<Option<(BufReader<TcpStream>, TcpStream)>>,
+}
+
+impl Upstreams<'_> {
+    // Sends `line` to the `partition`th and gives back what it answered with.
+    fn call(&mut self, partition: usize, line: &str) -> Result<String, String> {
+        let addr = self.router.partitions[partition].addr;
+        let answer = self.exchange(partition, line).map_err(|err| {
+            self.connections[partition] = None;
+            format!("partition {} unreachable: {}", addr, err)
+        })?;
+        match answer.trim_end().split_once(' ') {
+            Some(("OK", result)) => Ok(result.to_string()),
+            Some(("ERR", reason)) => Err(reason.to_string()),
+            _ => Err(format!(
+                "partition {} answered: {}",
+                addr,
+                answer.trim_end()
+            )),
+        }
+    }
+    fn exchange(&mut self, partition: usize, line: &str) -> io::Result<String> {
+        let connection = &mut self.connections[partition];
+        if connection.is_none() {
+            let stream = TcpStream::connect(self.router.partitions[partition].addr)?;
+            *connection = Some((BufReader::new(stream.try_clone()?), stream));
+        }
+        let (reader, writer) = connection.as_mut().unwrap();
+        writeln!(writer, "{}", line)?;
+        let mut answer = String::new();
+        if reader.read_line(&mut answer)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(answer)
+    }
+}
+
+fn request(
+    router: &PartitionRouter,
+    upstreams: &mut Upstreams<'_>,
+    command: &str,
+    args: &[&str],
+) -> Result<String, String> {
+    let line = [&[command], args].concat().join(" ");
+    let owner = |i: usize| -> Result<usize, String> {
+        let arg = args.get(i).ok_or("missing account")?;
+        let account = arg
+            .parse()
+            .map_err(|_| format!("invalid account: {}", arg))?;
+        router
+            .owner(account)
+            .ok_or_else(|| format!("account {} is in no partition", account))
+    };
+    match command {
+        "OPEN" => {
+            let count = router.partitions.len();
+            let first = router.next_open.fetch_add(1, Ordering::Relaxed);
+            let full = TxError::NoAccountsLeft.to_string();
+            let mut failed = full.clone();
+            for partition in (first..first + count).map(|i| i % count) {
+                match upstreams.call(partition, &line) {
+                    Ok(account) => return Ok(account),
+                    Err(reason) if reason == full || upstreams.connections[partition].is_none() => {
+                        failed = reason
+                    }
+                    Err(reason) => return Err(reason),
+                }
+            }
+            Err(failed)
+        }
+        "DEPOSIT" | "WITHDRAW" | "BALANCE" => upstreams.call(owner(0)?, &line),
+        "TRANSFER" => {
+            let (from, to) = (owner(0)?, owner(1)?);
+            if from == to {
+                return upstreams.call(from, &line);
+            }
+            let amount = args.get(2).ok_or("missing amount")?;
+            amount.parse::<Money>().map_err(|err| err.to_string())?;
+            let balance = upstreams.call(from, &format!("WITHDRAW {} {}", args[0], amount))?;
+            if let Err(reason) = upstreams.call(to, &format!("DEPOSIT {} {}", args[1], amount)) {
+                let refund = format!("DEPOSIT {} {}", args[0], amount);
+                if let Err(unrefunded) = upstreams.call(from, &refund) {
+                    error!(%reason, %unrefunded, "failed to refund a transfer between partitions");
+                    return Err(format!(
+                        "{}, and {} withdrawn from account {} could not be refunded: {}",
+                        reason, amount, args[0], unrefunded
+                    ));
+                }
+                return Err(reason);
+            }
+            Ok(balance)
+        }
+        "TOTAL" => {
+            let mut total = Money::ZERO;
+            for partition in 0..router.partitions.len() {
+                let held = upstreams.call(partition, &line)?;
+                let held = held.parse::<Money>().map_err(|err| err.to_string())?;
+                total = total.checked_add(held).ok_or("total overflows")?;
+            }
+            Ok(total.to_string())
+        }
+        _ => Err(format!("unknown command: {}", command)),
+    }
+}
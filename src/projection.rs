@@ -0,0 +1,287 @@
+//! Read models kept apart from the account state, each folding the recorded `LedgerEvent`s into
+//! a shape of its own. `Aptone::project` registers one; a background thread feeds it every event
+//! recorded since it last looked, so it trails the engine a little and never holds up a handler.
+//! In deterministic mode `Aptone::run_until_idle` feeds them instead.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::ledger::EventLog;
+use crate::{AccountId, Currency, LedgerEvent, Money};
+
+// how often the projections are brought up to date with the log
+const PROJECTION_INTERVAL: Duration = Duration::from_millis(5);
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A read model built from the ledger events, one at a time in the order they were applied.
+pub trait Projection: Send + 'static {
+    /// Folds in the next event, recorded at `at`.
+    fn apply(&mut self, event: &LedgerEvent, at: SystemTime);
+    /// Goes back to how it was before the first event, to be rebuilt from scratch.
+    fn reset(&mut self);
+}
+
+struct Followed<P> {
+    projection: P,
+    position: usize, // events folded in so far
+}
+
+// A projection as the background thread sees it, whatever its type.
+trait Follower: Send + Sync {
+    fn catch_up(&self, log: &EventLog);
+}
+
+impl<P: Projection> Follower for Mutex<Followed<P>> {
+    fn catch_up(&self, log: &EventLog) {
+        let mut followed = self.lock().unwrap();
+        let events = log.read_timed(followed.position);
+        followed.position += events.len();
+        for (at, event) in &events {
+            followed.projection.apply(event, *at);
+        }
+    }
+}
+
+/// A projection registered with `Aptone::project`, for reading it.
+pub struct ProjectionHandle<P> {
+    followed: Arc<Mutex<Followed<P>>>,
+    log: Arc<EventLog>,
+}
+
+impl<P> Clone for ProjectionHandle<P> {
+    fn clone(&self) -> ProjectionHandle<P> {
+        ProjectionHandle {
+            followed: Arc::clone(&self.followed),
+            log: Arc::clone(&self.log),
+        }
+    }
+}
+
+impl<P: Projection> ProjectionHandle<P> {
+    /// Reads the projection as it stands, holding off the background thread meanwhile.
+    pub fn read<R>(&self, read: impl FnOnce(&P) -> R) -> R {
+        read(&self.followed.lock().unwrap().projection)
+    }
+    /// How many events the projection folded in.
+    pub fn position(&self) -> usize {
+        self.followed.lock().unwrap().position
+    }
+    /// Folds in every event recorded so far on the caller's thread, say before a read that has to
+    /// see the transactions whose receipts are through.
+    pub fn catch_up(&self) {
+        self.followed.catch_up(&self.log);
+    }
+    /// Resets the projection, for the background thread to feed it every event again from the
+    /// first.
+    pub fn rebuild(&self) {
+        let mut followed = self.followed.lock().unwrap();
+        followed.projection.reset();
+        followed.position = 0;
+    }
+}
+
+/// The projections registered, and the log they follow.
+pub(crate) struct Projections {
+    log: Arc<EventLog>,
+    followers: Mutex<Vec<Arc<dyn Follower>>>,
+}
+
+impl Projections {
+    pub(crate) fn new(log: Arc<EventLog>) -> Projections {
+        Projections {
+            log,
+            followers: Mutex::new(Vec::new()),
+        }
+    }
+    pub(crate) fn register<P: Projection>(&self, projection: P) -> ProjectionHandle<P> {
+        let followed = Arc::new(Mutex::new(Followed {
+            projection,
+            position: 0,
+        }));
+        self.followers()
+            .push(Arc::clone(&followed) as Arc<dyn Follower>);
+        ProjectionHandle {
+            followed,
+            log: Arc::clone(&self.log),
+        }
+    }
+    fn followers(&self) -> MutexGuard<'_, Vec<Arc<dyn Follower>>> {
+        self.followers.lock().unwrap()
+    }
+    pub(crate) fn catch_up(&self) {
+        // not holding the list, so registering doesn't wait on a projection catching up
+        let followers = self.followers().clone();
+        for follower in followers {
+            follower.catch_up(&self.log);
+        }
+    }
+}
+
+/// Feeds the projections the events recorded every `PROJECTION_INTERVAL`. Stops when dropped.
+pub(crate) struct Projector {
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Projector {
+    pub(crate) fn start(projections: Arc<Projections>) -> Projector {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopped);
+        let thread = thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                projections.catch_up();
+                thread::park_timeout(PROJECTION_INTERVAL);
+            }
+        });
+        Projector {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Projector {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Money moved in one currency, in and out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Totals {
+    pub deposited: Money,
+    pub withdrawn: Money,
+    /// The `Deposited` and `Withdrawn` events counted.
+    pub movements: u64,
+}
+
+impl Totals {
+    fn count(&mut self, event: &LedgerEvent) {
+        match *event {
+            LedgerEvent::Deposited { amount, .. } => self.deposited += amount,
+            LedgerEvent::Withdrawn { amount, .. } => self.withdrawn += amount,
+            _ => return,
+        }
+        self.movements += 1;
+    }
+}
+
+// The account and currency `event` moved money in, if it did.
+fn moved(event: &LedgerEvent) -> Option<(AccountId, Currency)> {
+    match *event {
+        LedgerEvent::Deposited {
+            account, currency, ..
+        }
+        | LedgerEvent::Withdrawn {
+            account, currency, ..
+        } => Some((account, currency)),
+        _ => None,
+    }
+}
+
+/// Every open account's balance in each currency it holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BalanceMap {
+    pub balances: BTreeMap<(AccountId, Currency), Money>,
+}
+
+impl BalanceMap {
+    pub fn balance(&self, account: AccountId, currency: Currency) -> Money {
+        let balance = self.balances.get(&(account, currency));
+        balance.copied().unwrap_or(Money::ZERO)
+    }
+}
+
+impl Projection for BalanceMap {
+    fn apply(&mut self, event: &LedgerEvent, _: SystemTime) {
+        match *event {
+            LedgerEvent::AccountOpened { account, balance } => {
+                self.balances
+                    .insert((account, Currency::default()), balance);
+            }
+            LedgerEvent::AccountClosed { account } => {
+                self.balances.retain(|&(held_by, _), _| held_by != account);
+            }
+            LedgerEvent::BalanceRestored {
+                account,
+                currency,
+                balance,
+            } => {
+                self.balances.insert((account, currency), balance);
+            }
+            LedgerEvent::Deposited {
+                account,
+                currency,
+                amount,
+            } => *self.balances.entry((account, currency)).or_default() += amount,
+            LedgerEvent::Withdrawn {
+                account,
+                currency,
+                amount,
+            } => *self.balances.entry((account, currency)).or_default() -= amount,
+            _ => {}
+        }
+    }
+    fn reset(&mut self) {
+        self.balances.clear();
+    }
+}
+
+/// What went into and out of each account in each currency, closed accounts included, and how
+/// many of the transactions on it were rejected.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountTotals {
+    pub totals: BTreeMap<(AccountId, Currency), Totals>,
+    pub rejected: BTreeMap<AccountId, u64>,
+}
+
+impl Projection for AccountTotals {
+    fn apply(&mut self, event: &LedgerEvent, _: SystemTime) {
+        if let Some(key) = moved(event) {
+            self.totals.entry(key).or_default().count(event);
+        } else if let LedgerEvent::TransactionRejected { account, .. } = *event {
+            *self.rejected.entry(account).or_default() += 1;
+        }
+    }
+    fn reset(&mut self) {
+        *self = AccountTotals::default();
+    }
+}
+
+/// What moved in each currency across every account, and the transactions rejected, by the UTC
+/// day the events were recorded on, counted from the Unix epoch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DailyTotals {
+    pub totals: BTreeMap<(u64, Currency), Totals>,
+    pub rejected: BTreeMap<u64, u64>,
+}
+
+impl DailyTotals {
+    /// The day `at` falls on.
+    pub fn day(at: SystemTime) -> u64 {
+        let since = at.duration_since(SystemTime::UNIX_EPOCH);
+        since.map_or(0, |since| since.as_secs() / SECONDS_PER_DAY)
+    }
+}
+
+impl Projection for DailyTotals {
+    fn apply(&mut self, event: &LedgerEvent, at: SystemTime) {
+        let day = DailyTotals::day(at);
+        if let Some((_, currency)) = moved(event) {
+            self.totals.entry((day, currency)).or_default().count(event);
+        } else if let LedgerEvent::TransactionRejected { .. } = event {
+            *self.rejected.entry(day).or_default() += 1;
+        }
+    }
+    fn reset(&mut self) {
+        *self = DailyTotals::default();
+    }
+}
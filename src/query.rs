@@ -0,0 +1,160 @@
+use std::io;
+use std::ops::Range;
+use std::time::SystemTime;
+
+use crate::{AccountId, Aptone, HistoryEntry, HistoryExporter, Money, TxKind};
+
+// entries on a page unless `HistoryQuery::limit` says otherwise
+const DEFAULT_LIMIT: usize = 100;
+
+/// A search over the history of every account, or of one, as made by `Aptone::history_query`.
+/// Each filter narrows it down further; `fetch` runs it for a page, `export` for everything.
+#[derive(Clone)]
+pub struct HistoryQuery<'a> {
+    aptone: &'a Aptone,
+    account: Option<AccountId>,
+    kind: Option<TxKind>,
+    min_amount: Option<Money>,
+    between: Option<Range<SystemTime>>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// What a `HistoryQuery` found, by account and oldest first within each.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryPage {
+    pub entries: Vec<(AccountId, HistoryEntry)>,
+    /// The offset of the next page, if anything matched past this one.
+    pub next: Option<usize>,
+}
+
+impl<'a> HistoryQuery<'a> {
+    /// Every entry of every account, a hundred to a page.
+    pub(crate) fn new(aptone: &'a Aptone) -> HistoryQuery<'a> {
+        HistoryQuery {
+            aptone,
+            account: None,
+            kind: None,
+            min_amount: None,
+            between: None,
+            offset: 0,
+            limit: None,
+        }
+    }
+    pub fn account(mut self, account: AccountId) -> HistoryQuery<'a> {
+        self.account = Some(account);
+        self
+    }
+    /// Entries of transactions of `kind`: both sides of a transfer or exchange.
+    pub fn tx_type(mut self, kind: TxKind) -> HistoryQuery<'a> {
+        self.kind = Some(kind);
+        self
+    }
+    /// Entries moving at least `amount` either way, so reversals of large deposits too.
+    pub fn min_amount(mut self, amount: impl Into<Money>) -> HistoryQuery<'a> {
+        self.min_amount = Some(amount.into());
+        self
+    }
+    /// Entries from `from` up to, but not including, `to`.
+    pub fn between(mut self, from: SystemTime, to: SystemTime) -> HistoryQuery<'a> {
+        self.between = Some(from..to);
+        self
+    }
+    /// Skips the first `offset` entries that match, as for the page at `HistoryPage::next`.
+    pub fn offset(mut self, offset: usize) -> HistoryQuery<'a> {
+        self.offset = offset;
+        self
+    }
+    /// Gives back at most `limit` entries, a hundred if not set. Panics for zero, which would
+    /// never get past the first page.
+    pub fn limit(mut self, limit: usize) -> HistoryQuery<'a> {
+        assert!(limit > 0, "a page needs room for at least one entry");
+        self.limit = Some(limit);
+        self
+    }
+    /// The page of entries matching every filter. Accounts are searched one at a time, so
+    /// transactions applied meanwhile may or may not show, and only the entries on the page are
+    /// copied.
+    pub fn fetch(&self) -> HistoryPage {
+        let mut page = HistoryPage::default();
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT);
+        let more = self.scan(limit, |account, entries| {
+            page.entries
+                .extend(entries.into_iter().map(|entry| (account, entry)));
+            Ok(())
+        });
+        if more.unwrap_or(false) {
+            page.next = Some(self.offset + page.entries.len());
+        }
+        page
+    }
+    /// Streams every entry matching the filters to `exporter`, then finishes it, giving back how
+    /// many were written. Skips the first `offset` and stops at `limit` only if they were set.
+    /// Holds no more than the matching entries of one account at a time.
+    pub fn export(&self, exporter: &mut impl HistoryExporter) -> io::Result<u64> {
+        let mut written = 0;
+        self.scan(self.limit.unwrap_or(usize::MAX), |account, entries| {
+            for entry in &entries {
+                exporter.write_entry(account, entry)?;
+                written += 1;
+            }
+            Ok(())
+        })?;
+        exporter.finish()?;
+        Ok(written)
+    }
+    // Hands `take` the matching entries past the offset, up to `limit` of them, an account at a
+    // time and outside its lock. Tells whether more matched.
+    fn scan(
+        &self,
+        limit: usize,
+        mut take: impl FnMut(AccountId, Vec<HistoryEntry>) -> io::Result<()>,
+    ) -> io::Result<bool> {
+        let accounts = match self.account {
+            Some(account) => account..account.saturating_add(1),
+            None => self.aptone.opened_accounts(),
+        };
+        let (mut skipped, mut taken) = (0, 0);
+        for account in accounts {
+            let mut entries = Vec::new();
+            let more = self.aptone.with_history(account, |history| {
+                for entry in history.iter().filter(|entry| self.matches(entry)) {
+                    if skipped < self.offset {
+                        skipped += 1;
+                    } else if taken < limit {
+                        entries.push(entry.clone());
+                        taken += 1;
+                    } else {
+                        return true;
+                    }
+                }
+                false
+            });
+            if !entries.is_empty() {
+                take(account, entries)?;
+            }
+            if more {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        if self.kind.is_some_and(|kind| entry.kind.tx_kind() != kind) {
+            return false;
+        }
+        if let Some(min) = self.min_amount {
+            let amount = match entry.amount.is_negative() {
+                true => entry.amount.saturating_neg(),
+                false => entry.amount,
+            };
+            if amount < min {
+                return false;
+            }
+        }
+        match &self.between {
+            Some(between) => between.contains(&entry.time),
+            None => true,
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::handler::{Envelope, Message};
+use crate::sequence::{AccountSeq, Sequencer};
+use crate::tx;
+use crate::{AccountId, TxId};
+
+// how often a handler waiting on a message out of turn looks again, as the one ahead of it may be
+// on another queue, which doesn't wake this one
+const TURN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A handler's message queue. Unlike a channel it can be searched, which lets idle handlers take
+/// over transactions from a busy one.
+///
+/// Accounts whose transaction is being applied, by the owner or by a thief, are marked busy;
+/// nothing else on them is handed out until they are done, so every account still sees its
+/// transactions one at a time. Nor is anything handed out before its turn on each account by
+/// the `Sequencer`, which keeps them in the order they were submitted whichever queue they are
+/// on; a message out of turn waits on the queue until the ones ahead of it are done.
+pub(crate) struct Queue {
+    state: Mutex<State>,
+    changed: Condvar,
+    sequencer: Arc<Sequencer>,
+}
+
+#[derive(Default)]
+struct State {
+    messages: VecDeque<Message>,
+    busy: Vec<AccountId>,
+    turns: Vec<(AccountId, AccountSeq)>, // of the messages handed out and not done yet
+    closed: bool,                        // the handler is gone and nothing will drain the queue
+    paused: bool,                        // nothing is handed out, though messages are still taken
+    shared: bool, // several handlers take from it, so not only the front is handed out
+}
+
+impl Message {
+    fn accounts(&self) -> Vec<AccountId> {
+        match self {
+            Message::NewTx(envelope) => tx::accounts_of(envelope.legs()),
+            Message::Barrier(account, _, _) => vec![*account],
+            Message::Cancelled(turns) => turns.iter().map(|&(account, _)| account).collect(),
+            // its accounts and turns are marked on the queue it was taken off
+            Message::Rerouted(..) | Message::Terminate => Vec::new(),
+        }
+    }
+    fn turns(&self) -> Vec<(AccountId, AccountSeq)> {
+        match self {
+            Message::NewTx(envelope) => envelope.turns.clone(),
+            Message::Barrier(account, seq, _) => vec![(*account, *seq)],
+            Message::Cancelled(turns) => turns.clone(),
+            Message::Rerouted(..) | Message::Terminate => Vec::new(),
+        }
+    }
+    fn in_turn(&self, sequencer: &Sequencer) -> bool {
+        self.turns()
+            .iter()
+            .all(|&(account, seq)| sequencer.is_next(account, seq))
+    }
+}
+
+impl State {
+    fn conflicts(&self, accounts: &[AccountId]) -> bool {
+        accounts.iter().any(|account| self.busy.contains(account))
+    }
+    // Clears the busy marks, giving back the turns on them to the sequencer if `sequencer` is
+    // given, or keeping them for the message to be handed out again.
+    fn release(&mut self, accounts: &[AccountId], sequencer: Option<&Sequencer>) {
+        for account in accounts {
+            if let Some(index) = self.busy.iter().position(|busy| busy == account) {
+                self.busy.swap_remove(index);
+            }
+        }
+        self.turns.retain(|&(account, seq)| {
+            if !accounts.contains(&account) {
+                return true;
+            }
+            if let Some(sequencer) = sequencer {
+                sequencer.advance(account, seq);
+            }
+            false
+        });
+    }
+    fn pop_front(&mut self, sequencer: &Sequencer) -> Option<(Message, Vec<AccountId>)> {
+        if self.paused {
+            return None;
+        }
+        let index = if self.shared {
+            self.first_free(sequencer)?
+        } else {
+            0
+        };
+        let message = self.messages.get(index)?;
+        let accounts = message.accounts();
+        if self.conflicts(&accounts) || !message.in_turn(sequencer) {
+            return None;
+        }
+        let message = self.messages.remove(index).unwrap();
+        self.busy.extend(&accounts);
+        self.turns.extend(message.turns());
+        Some((message, accounts))
+    }
+    // The first message in turn touching no account that is busy or that an earlier message
+    // touches. Termination waits for its turn at the front.
+    fn first_free(&self, sequencer: &Sequencer) -> Option<usize> {
+        let mut seen = self.busy.clone();
+        for (index, message) in self.messages.iter().enumerate() {
+            if let Message::Terminate = message {
+                return (index == 0).then_some(index);
+            }
+            let accounts = message.accounts();
+            let free = !accounts.iter().any(|account| seen.contains(account));
+            if free && message.in_turn(sequencer) {
+                return Some(index);
+            }
+            seen.extend(accounts);
+        }
+        None
+    }
+}
+
+impl Queue {
+    pub(crate) fn new(sequencer: Arc<Sequencer>) -> Queue {
+        Queue {
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+            sequencer,
+        }
+    }
+    /// A queue every handler takes messages off, handing out any message that doesn't have to
+    /// wait on the accounts of an earlier one.
+    pub(crate) fn shared(sequencer: Arc<Sequencer>) -> Queue {
+        let queue = Queue::new(sequencer);
+        queue.state.lock().unwrap().shared = true;
+        queue
+    }
+    /// Hands the message back if the handler is gone.
+    pub(crate) fn push(&self, message: Message) -> Result<(), Message> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(message);
+        }
+        state.messages.push_back(message);
+        drop(state);
+        self.changed.notify_all();
+        Ok(())
+    }
+    /// Takes the next message, marking its accounts busy. Waits while it touches an account a
+    /// thief is still working on or isn't in turn, and gives up after `timeout` without one to
+    /// hand out or once the queue is closed.
+    pub(crate) fn pop(&self, timeout: Option<Duration>) -> Option<(Message, Vec<AccountId>)> {
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some(popped) = state.pop_front(&self.sequencer) {
+                return Some(popped);
+            }
+            let left = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            match left {
+                Some(left) if left.is_zero() => return None,
+                Some(left) if state.messages.is_empty() => {
+                    state = self.changed.wait_timeout(state, left).unwrap().0;
+                }
+                None if state.messages.is_empty() => state = self.changed.wait(state).unwrap(),
+                _ => {
+                    let waited = self.changed.wait_timeout(state, TURN_POLL_INTERVAL);
+                    state = waited.unwrap().0;
+                }
+            }
+        }
+    }
+    /// Like `pop`, but never waits.
+    pub(crate) fn try_pop(&self) -> Option<(Message, Vec<AccountId>)> {
+        self.state.lock().unwrap().pop_front(&self.sequencer)
+    }
+    /// Takes a transaction off a queue holding at least `threshold` messages, if one can be
+    /// applied out of turn: it must not wait on another handler, and no earlier message or busy
+    /// transaction may touch its accounts.
+    pub(crate) fn steal(&self, threshold: usize) -> Option<(Envelope, Vec<AccountId>)> {
+        let state = self.state.lock().unwrap();
+        if state.messages.len() < threshold {
+            return None;
+        }
+        self.take_free(state, |_| true)
+    }
+    /// Takes a transaction submitted before `before` off the queue, if one can be applied out of
+    /// turn as for `steal`.
+    pub(crate) fn steal_overdue(&self, before: Instant) -> Option<(Envelope, Vec<AccountId>)> {
+        let state = self.state.lock().unwrap();
+        self.take_free(state, |envelope| envelope.submitted < before)
+    }
+    /// The transactions submitted before `before` still queued, with when each was.
+    pub(crate) fn queued_before(&self, before: Instant) -> Vec<(TxId, Instant)> {
+        let state = self.state.lock().unwrap();
+        let queued = state.messages.iter().filter_map(|message| match message {
+            Message::NewTx(envelope) if envelope.submitted < before => {
+                Some((envelope.tx_id, envelope.submitted))
+            }
+            _ => None,
+        });
+        queued.collect()
+    }
+    // The first transaction `wanted` that `steal` could take.
+    fn take_free(
+        &self,
+        mut state: MutexGuard<'_, State>,
+        wanted: impl Fn(&Envelope) -> bool,
+    ) -> Option<(Envelope, Vec<AccountId>)> {
+        if state.paused {
+            return None;
+        }
+        let mut seen = state.busy.clone();
+        let mut found = None;
+        for (index, message) in state.messages.iter().enumerate() {
+            let accounts = message.accounts();
+            let stealable = match message {
+                Message::NewTx(envelope) => {
+                    envelope.credit.is_none()
+                        && wanted(envelope)
+                        && message.in_turn(&self.sequencer)
+                }
+                _ => false,
+            };
+            if stealable && !accounts.iter().any(|account| seen.contains(account)) {
+                found = Some((index, accounts));
+                break;
+            }
+            seen.extend(accounts);
+        }
+        let (index, accounts) = found?;
+        match state.messages.remove(index) {
+            Some(Message::NewTx(envelope)) => {
+                state.busy.extend(&accounts);
+                state.turns.extend(&envelope.turns);
+                Some((*envelope, accounts))
+            }
+            _ => unreachable!("only transactions are stolen"),
+        }
+    }
+    /// Takes the queued transaction `tx_id` out, leaving `Message::Cancelled` with its turns in
+    /// its place, to be handed out and passed over like any other message.
+    pub(crate) fn cancel(&self, tx_id: TxId) -> Option<Envelope> {
+        let mut state = self.state.lock().unwrap();
+        let message = state.messages.iter_mut().find(|message| match message {
+            Message::NewTx(envelope) => envelope.tx_id == tx_id,
+            _ => false,
+        })?;
+        let turns = message.turns();
+        match std::mem::replace(message, Message::Cancelled(turns)) {
+            Message::NewTx(envelope) => Some(*envelope),
+            _ => unreachable!("only transactions are cancelled"),
+        }
+    }
+    /// Clears the busy mark `pop` or `steal` put on `accounts`, letting what comes next on them
+    /// go.
+    pub(crate) fn done(&self, accounts: &[AccountId]) {
+        let mut state = self.state.lock().unwrap();
+        state.release(accounts, Some(&self.sequencer));
+        drop(state);
+        self.changed.notify_all();
+    }
+    /// Puts a message taken off the queue back at its front and clears the busy mark on
+    /// `accounts`, in one go so nothing else on them is handed out in between.
+    pub(crate) fn requeue(&self, message: Message, accounts: &[AccountId]) {
+        let mut state = self.state.lock().unwrap();
+        state.release(accounts, None);
+        state.messages.push_front(message);
+        drop(state);
+        self.changed.notify_all();
+    }
+    /// Stops handing out messages, or starts again.
+    pub(crate) fn set_paused(&self, paused: bool) {
+        self.state.lock().unwrap().paused = paused;
+        self.changed.notify_all();
+    }
+    /// Refuses further messages and drops the queued ones, failing their receipts. A handler
+    /// waiting on the queue gives up.
+    pub(crate) fn close(&self) {
+        let messages = {
+            let mut state = self.state.lock().unwrap();
+            state.closed = true;
+            std::mem::take(&mut state.messages)
+        };
+        self.changed.notify_all();
+        drop(messages);
+    }
+    /// Takes messages again after `close`.
+    pub(crate) fn reopen(&self) {
+        self.state.lock().unwrap().closed = false;
+    }
+    /// Messages waiting on the queue, each holding a slot the handler counts, which termination
+    /// doesn't.
+    pub(crate) fn len(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        let messages = state.messages.iter();
+        messages
+            .filter(|message| !matches!(message, Message::Terminate))
+            .count()
+    }
+    pub(crate) fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+}
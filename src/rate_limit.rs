@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{AccountId, Clock, RateLimit};
+
+/// The token buckets of `RateLimit`, split by account over a few locks so submitters on
+/// different accounts rarely wait on each other.
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    interval: Duration, // between two tokens
+    clock: Arc<dyn Clock>,
+    buckets: Vec<Mutex<HashMap<AccountId, Bucket>>>,
+}
+
+struct Bucket {
+    tokens: u32,
+    refilled: Instant, // when the tokens were last topped up, to the token
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit, stripes: usize, clock: Arc<dyn Clock>) -> RateLimiter {
+        RateLimiter {
+            limit,
+            interval: Duration::from_secs(1) / limit.per_second,
+            clock,
+            buckets: (0..stripes.max(1)).map(|_| Mutex::default()).collect(),
+        }
+    }
+    /// Takes one of `account`'s tokens, or tells how long until it gets the next one.
+    pub(crate) fn try_take(&self, account: AccountId) -> Result<(), Duration> {
+        let now = self.clock.now();
+        let mut buckets = self.buckets[account as usize % self.buckets.len()]
+            .lock()
+            .unwrap();
+        // an account not seen yet starts with a full bucket
+        let bucket = buckets.entry(account).or_insert(Bucket {
+            tokens: self.limit.burst,
+            refilled: now,
+        });
+        let earned = now.saturating_duration_since(bucket.refilled).as_nanos()
+            / self.interval.as_nanos().max(1);
+        if earned > 0 {
+            let tokens = bucket.tokens as u128 + earned;
+            if tokens >= self.limit.burst as u128 {
+                bucket.tokens = self.limit.burst;
+                bucket.refilled = now;
+            } else {
+                bucket.tokens = tokens as u32;
+                bucket.refilled += self.interval * earned as u32;
+            }
+        }
+        if bucket.tokens == 0 {
+            return Err((bucket.refilled + self.interval).saturating_duration_since(now));
+        }
+        bucket.tokens -= 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use crate::channel::{channel, Receiver, RecvTimeoutError, TryRecvError};
+use crate::{HandleId, TxError, TxId};
+
+pub type TxResult = Result<(), TxError>;
+
+/// Completion handle for a submitted transaction.
+pub struct TxReceipt {
+    tx_id: TxId,
+    handle_id: HandleId,
+    receiver: Receiver<TxResult>,
+}
+
+impl TxReceipt {
+    pub(crate) fn new(tx_id: TxId, handle_id: HandleId, receiver: Receiver<TxResult>) -> TxReceipt {
+        TxReceipt {
+            tx_id,
+            handle_id,
+            receiver,
+        }
+    }
+    pub(crate) fn ready(tx_id: TxId, handle_id: HandleId, result: TxResult) -> TxReceipt {
+        let (sender, receiver) = channel();
+        sender.send(result).unwrap();
+        TxReceipt::new(tx_id, handle_id, receiver)
+    }
+    pub fn tx_id(&self) -> TxId {
+        self.tx_id
+    }
+    pub fn handle_id(&self) -> HandleId {
+        self.handle_id
+    }
+    /// Blocks until the transaction has been applied or rejected by its handler.
+    pub fn wait(self) -> TxResult {
+        self.receiver
+            .recv()
+            .unwrap_or(Err(TxError::HandlerUnavailable(self.handle_id)))
+    }
+    /// Like `wait`, but gives up after `timeout` and returns `None`.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<TxResult> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => Some(result),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                Some(Err(TxError::HandlerUnavailable(self.handle_id)))
+            }
+        }
+    }
+    /// Returns the result if the transaction has already been processed.
+    pub fn try_result(&self) -> Option<TxResult> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                Some(Err(TxError::HandlerUnavailable(self.handle_id)))
+            }
+        }
+    }
+}
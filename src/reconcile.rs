@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::{AccountId, BalanceSnapshot, Currency, Money, Tx, TxType};
+
+/// An account whose balance in the default currency isn't the one an external ledger gives it.
+/// `None` on a side that has no such account, which counts as holding nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    pub account: AccountId,
+    pub engine: Option<Money>,
+    pub external: Option<Money>,
+}
+
+impl Mismatch {
+    /// What the external ledger has over the engine, negative if less.
+    pub fn diff(&self) -> Money {
+        self.external.unwrap_or(Money::ZERO) - self.engine.unwrap_or(Money::ZERO)
+    }
+    /// A deposit or withdrawal bringing the engine in line with the external ledger, for an
+    /// account the engine has open; one it doesn't have has to be opened first. `None` too for
+    /// an account missing from the external ledger with nothing in the engine.
+    pub fn adjustment(&self) -> Option<Tx> {
+        self.engine?;
+        let diff = self.diff();
+        if diff == Money::ZERO {
+            return None;
+        }
+        let adjustment = if diff.is_negative() {
+            Tx::new(self.account, -diff, TxType::WITHDRAW)
+        } else {
+            Tx::new(self.account, diff, TxType::DEPOSIT)
+        };
+        Some(adjustment)
+    }
+}
+
+/// How the engine's balances compare with an external ledger's, as returned by
+/// `Aptone::reconcile`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Reconciliation {
+    /// Accounts on both sides with the same balance.
+    pub matched: usize,
+    /// By account.
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Reconciliation {
+    /// Compares the default currency balances in `snapshot` with `external`, where an account
+    /// given more than once goes by the last balance given.
+    pub(crate) fn new(
+        snapshot: &BalanceSnapshot,
+        external: impl IntoIterator<Item = (AccountId, Money)>,
+    ) -> Reconciliation {
+        let external: BTreeMap<_, _> = external.into_iter().collect();
+        let mut accounts: Vec<_> = snapshot.balances.keys().chain(external.keys()).collect();
+        accounts.sort_unstable();
+        accounts.dedup();
+        let mut reconciliation = Reconciliation::default();
+        for &account in accounts {
+            let engine = snapshot
+                .balances
+                .contains_key(&account)
+                .then(|| snapshot.balance(account, Currency::default()));
+            let mismatch = Mismatch {
+                account,
+                engine,
+                external: external.get(&account).copied(),
+            };
+            match mismatch.engine == mismatch.external {
+                true => reconciliation.matched += 1,
+                false => reconciliation.mismatches.push(mismatch),
+            }
+        }
+        reconciliation
+    }
+    pub fn is_reconciled(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+    /// The `Mismatch::adjustment` of every mismatch that has one.
+    pub fn adjustments(&self) -> Vec<Tx> {
+        let adjustments = self.mismatches.iter().filter_map(Mismatch::adjustment);
+        adjustments.collect()
+    }
+}
+
+/// One line per mismatch, under a line with the counts.
+impl fmt::Display for Reconciliation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} matched, {} mismatched",
+            self.matched,
+            self.mismatches.len()
+        )?;
+        let side = |balance: Option<Money>| balance.map_or("-".to_string(), |b| b.to_string());
+        for mismatch in &self.mismatches {
+            writeln!(
+                f,
+                "account {}: engine {}, external {}, diff {}",
+                mismatch.account,
+                side(mismatch.engine),
+                side(mismatch.external),
+                mismatch.diff()
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+//! Live workloads written down as they come in, to replay against a fresh engine later, say to
+//! reproduce an incident, with `replay::replay_recording`. With `AptoneBuilder::record_to` every
+//! transaction or batch submitted, and every account opened or closed, goes on a line of its own
+//! in the transaction log format, after when it came in, in microseconds since recording
+//! started:
+//!
+//! ```text
+//! 0 OPEN 0 100
+//! 1520 DEPOSIT 0 5 EUR
+//! 1740 BATCH 2
+//! WITHDRAW 0 1 EUR
+//! DEPOSIT 1 1 EUR
+//! ```
+//!
+//! Transactions are written down whether or not they are accepted, and as submitted: one put on
+//! the schedule isn't, and goes in only once it is due.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::error;
+
+use crate::wal::{self, Entry};
+use crate::{AccountId, Clock, Money, Tx};
+
+/// An entry of a recording, and when it came in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recorded {
+    /// Since recording started.
+    pub at: Duration,
+    pub entry: Entry,
+}
+
+/// Writes the entries down as they come in. A failed write is logged and leaves the entry out,
+/// rather than failing what it records.
+pub(crate) struct Recorder {
+    file: Mutex<File>,
+    clock: Arc<dyn Clock>,
+    started: Instant,
+}
+
+impl Recorder {
+    /// Starts a recording at `path`, replacing whatever was there.
+    pub(crate) fn create(path: &Path, clock: Arc<dyn Clock>) -> io::Result<Recorder> {
+        Ok(Recorder {
+            file: Mutex::new(File::create(path)?),
+            started: clock.now(),
+            clock,
+        })
+    }
+    pub(crate) fn submitted(&self, legs: &[Tx]) {
+        match legs {
+            [tx] => self.write(&wal::encode(tx)),
+            legs => {
+                let mut lines = format!("BATCH {}\n", legs.len());
+                for leg in legs {
+                    lines.push_str(&wal::encode(leg));
+                }
+                self.write(&lines)
+            }
+        }
+    }
+    pub(crate) fn opened(&self, account: AccountId, balance: Money) {
+        self.write(&format!("OPEN {} {}\n", account, balance));
+    }
+    pub(crate) fn closed(&self, account: AccountId) {
+        self.write(&format!("CLOSE {}\n", account));
+    }
+    fn write(&self, lines: &str) {
+        let mut file = self.file.lock().unwrap();
+        // timed under the lock, so the entries are in the order of their times
+        let at = self.clock.now().saturating_duration_since(self.started);
+        let entry = format!("{} {}", at.as_micros(), lines);
+        if let Err(err) = file.write_all(entry.as_bytes()) {
+            error!(%err, "failed to record an entry");
+        }
+    }
+}
+
+/// Reads every complete entry of the recording at `path`. A torn last entry, as left behind by
+/// a crash in the middle of a write, is ignored.
+pub fn read(path: &Path) -> io::Result<Vec<Recorded>> {
+    read_from(BufReader::new(File::open(path)?))
+}
+
+/// Like `read`, from whatever the recording is held in.
+pub fn read_from(mut reader: impl BufRead) -> io::Result<Vec<Recorded>> {
+    let mut recorded = Vec::new();
+    let mut line = String::new();
+    while wal::next_line(&mut reader, &mut line)?.is_some() {
+        let (at, entry) = line
+            .trim_end()
+            .split_once(' ')
+            .ok_or_else(|| wal::invalid(&line))?;
+        let at = Duration::from_micros(at.parse().map_err(|_| wal::invalid(&line))?);
+        let entry = match entry.strip_prefix("BATCH ") {
+            Some(count) => {
+                let count: usize = count.parse().map_err(|_| wal::invalid(&line))?;
+                let mut legs = Vec::with_capacity(count.min(wal::MAX_RESERVED_LEGS));
+                while legs.len() < count {
+                    if wal::next_line(&mut reader, &mut line)?.is_none() {
+                        return Ok(recorded);
+                    }
+                    legs.push(wal::decode(line.trim_end())?);
+                }
+                Entry::Batch(legs)
+            }
+            None => wal::decode_entry(entry)?,
+        };
+        recorded.push(Recorded { at, entry });
+    }
+    Ok(recorded)
+}
@@ -0,0 +1,274 @@
+//! Replays a transaction log through a fresh engine, to check a run against what its log adds
+//! up to. Recovery applies the log on one thread, entry by entry; a replay submits the same
+//! entries to the handlers as they were submitted the first time, so balances coming out
+//! different point at the concurrent path miscounting:
+//!
+//! ```no_run
+//! # fn main() -> std::io::Result<()> {
+//! use aptone::{replay, wal, Aptone};
+//!
+//! let entries = wal::read("aptone.wal".as_ref())?;
+//! let report = replay::replay(&Aptone::new(), &entries, 256);
+//! for divergence in replay::compare(&replay::expected(&entries), &report.balances) {
+//!     println!("{:?}", divergence);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A log truncated by a checkpoint only holds what came after it, so replays from scratch need
+//! checkpointing off.
+//!
+//! `replay_recording` replays a workload written down by `AptoneBuilder::record_to` the same
+//! way, paced as it came in, or faster.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::recording::Recorded;
+use crate::snapshot;
+use crate::wal::{self, Entry};
+use crate::{AccountId, Aptone, BalanceSnapshot, Currency, Money, ServerData, TxError, TxReceipt};
+
+/// What a replay got through.
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    /// Entries holding a transaction or batch, applied or not.
+    pub transactions: u64,
+    pub applied: u64,
+    /// The entries not applied, in log order. Transactions rejected the first time around are
+    /// rejected again, so these aren't divergences in themselves.
+    pub errors: Vec<EntryError>,
+    /// The engine's balances once everything replayed was through.
+    pub balances: BalanceSnapshot,
+}
+
+/// An entry that was not applied, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryError {
+    /// 0-based position of the entry in the log.
+    pub entry: usize,
+    pub kind: EntryErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryErrorKind {
+    /// Opens an account the engine already gave out, as in a log that doesn't start from
+    /// scratch.
+    Reopened(AccountId),
+    Rejected(TxError),
+}
+
+impl fmt::Display for EntryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            EntryErrorKind::Reopened(account) => {
+                write!(
+                    f,
+                    "entry {}: account {} opened already",
+                    self.entry, account
+                )
+            }
+            EntryErrorKind::Rejected(err) => write!(f, "entry {}: {}", self.entry, err),
+        }
+    }
+}
+
+impl Error for EntryError {}
+
+/// A balance the replay came out with that isn't the expected one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    pub account: AccountId,
+    pub currency: Currency,
+    pub expected: Money,
+    pub actual: Money,
+}
+
+/// How `replay_recording` spaces the entries out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pacing {
+    /// Each entry as long after the first as it came in, divided by the factor: 1 keeps to the
+    /// recording, 10 goes ten times as fast.
+    Speed(f64),
+    /// Each entry right after the one before.
+    Unpaced,
+}
+
+/// Submits `entries` to `aptone`, which has to be fresh, in log order, waiting for the oldest
+/// transaction once `max_in_flight` are unfinished. Accounts get the ids they had in the log:
+/// those given out but never logged, as by an open that failed, are opened and closed again.
+pub fn replay(aptone: &Aptone, entries: &[Entry], max_in_flight: usize) -> ReplayReport {
+    run(
+        aptone,
+        entries.iter().map(|entry| (None, entry)),
+        max_in_flight,
+    )
+}
+
+/// Submits the entries of `recording` to `aptone` as `replay` does, spaced out as `pacing` has
+/// them, falling behind where waiting on the oldest transaction holds the next up. `aptone` has
+/// to start out as the recorded engine did, fresh or recovered from the same state.
+pub fn replay_recording(
+    aptone: &Aptone,
+    recording: &[Recorded],
+    pacing: Pacing,
+    max_in_flight: usize,
+) -> ReplayReport {
+    let started = Instant::now();
+    let due = |at: Duration| match pacing {
+        Pacing::Speed(speed) if speed > 0.0 => Some(started + at.div_f64(speed)),
+        _ => None,
+    };
+    let entries = recording
+        .iter()
+        .map(|recorded| (due(recorded.at), &recorded.entry));
+    run(aptone, entries, max_in_flight)
+}
+
+// Submits each entry once it is due, if it has a time to be.
+fn run<'a>(
+    aptone: &Aptone,
+    entries: impl Iterator<Item = (Option<Instant>, &'a Entry)>,
+    max_in_flight: usize,
+) -> ReplayReport {
+    let max_in_flight = max_in_flight.max(1);
+    let mut report = ReplayReport::default();
+    let mut in_flight: VecDeque<(usize, TxReceipt)> = VecDeque::with_capacity(max_in_flight);
+
+    for (index, (due, entry)) in entries.enumerate() {
+        if let Some(due) = due {
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+        let submitted = match entry {
+            Entry::Tx(tx) => aptone.submit_tx(tx.clone()),
+            Entry::Batch(legs) => aptone.submit_batch(legs.clone()),
+            &Entry::Open { account, balance } => {
+                if let Err(kind) = reopen(aptone, account, balance) {
+                    report.errors.push(EntryError { entry: index, kind });
+                }
+                continue;
+            }
+            &Entry::Close(account) => {
+                // an account is only closed with nothing pending
+                for (index, receipt) in in_flight.drain(..) {
+                    report.finish(index, receipt.wait());
+                }
+                if let Err(err) = aptone.close_account(account) {
+                    report.finish(index, Err(err));
+                }
+                continue;
+            }
+        };
+        report.transactions += 1;
+        if in_flight.len() == max_in_flight {
+            let (index, receipt) = in_flight.pop_front().unwrap();
+            report.finish(index, receipt.wait());
+        }
+        match submitted {
+            Ok(receipt) => in_flight.push_back((index, receipt)),
+            Err(err) => report.finish(index, Err(err)),
+        }
+    }
+    for (index, receipt) in in_flight {
+        report.finish(index, receipt.wait());
+    }
+    report.errors.sort_by_key(|err| err.entry);
+    report.balances = aptone.snapshot();
+    report
+}
+
+// Opens accounts until `account` is, closing those the log skips.
+fn reopen(aptone: &Aptone, account: AccountId, balance: Money) -> Result<(), EntryErrorKind> {
+    loop {
+        let opened = aptone
+            .open_account(balance)
+            .map_err(EntryErrorKind::Rejected)?;
+        if opened == account {
+            return Ok(());
+        }
+        if opened > account {
+            return Err(EntryErrorKind::Reopened(account));
+        }
+        let _ = aptone.close_account(opened);
+    }
+}
+
+impl ReplayReport {
+    fn finish(&mut self, entry: usize, result: Result<(), TxError>) {
+        match result {
+            Ok(()) => self.applied += 1,
+            Err(err) => self.errors.push(EntryError {
+                entry,
+                kind: EntryErrorKind::Rejected(err),
+            }),
+        }
+    }
+}
+
+/// What `entries` add up to applied one by one on one thread, as recovery applies them.
+pub fn expected(entries: &[Entry]) -> BalanceSnapshot {
+    let mut data = ServerData::new();
+    for entry in entries {
+        wal::replay_entry(&mut data, entry);
+    }
+    balances_of(&data)
+}
+
+/// The balances in a checkpoint snapshot at `path`, with the transactions it held pending
+/// applied, as recovery restores them.
+pub fn expected_from_snapshot(path: &Path) -> io::Result<BalanceSnapshot> {
+    let snapshot = snapshot::read(path)?.ok_or(io::ErrorKind::NotFound)?;
+    let mut data = ServerData::new();
+    for &(account, currency, balance) in &snapshot.balances {
+        data.set_balance(account, currency, balance);
+    }
+    for &(hold, currency, amount) in &snapshot.holds {
+        data.set_hold(hold, currency, amount);
+    }
+    for tx in &snapshot.pending {
+        let _ = data.apply(tx);
+    }
+    Ok(balances_of(&data))
+}
+
+fn balances_of(data: &ServerData) -> BalanceSnapshot {
+    let mut snapshot = BalanceSnapshot::default();
+    for (account, currency, balance) in data.balances() {
+        let balances = snapshot.balances.entry(account).or_default();
+        balances.insert(currency, balance);
+    }
+    snapshot
+}
+
+/// Every balance of `actual` that isn't the one in `expected`, by account and currency; a
+/// balance missing from either counts as zero.
+pub fn compare(expected: &BalanceSnapshot, actual: &BalanceSnapshot) -> Vec<Divergence> {
+    let mut keys: Vec<(AccountId, Currency)> = [expected, actual]
+        .iter()
+        .flat_map(|snapshot| {
+            snapshot.balances.iter().flat_map(|(&account, balances)| {
+                balances.keys().map(move |&currency| (account, currency))
+            })
+        })
+        .collect();
+    keys.sort_unstable();
+    keys.dedup();
+    keys.into_iter()
+        .filter_map(|(account, currency)| {
+            let expected = expected.balance(account, currency);
+            let actual = actual.balance(account, currency);
+            (expected != actual).then_some(Divergence {
+                account,
+                currency,
+                expected,
+                actual,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,379 @@
+//! Leader–follower replication over TCP. A `Primary` streams the `LedgerEvent`s its engine
+//! applies to every `Follower` connected to it, which applies them to a `ServerData` of its own
+//! and so keeps a hot standby of the balances and holds. Should the primary fail,
+//! `Follower::promote` starts an engine from where the standby got to. Replication is
+//! asynchronous: what the primary applied and hadn't sent yet is lost with it.
+//!
+//! ```no_run
+//! # fn main() -> std::io::Result<()> {
+//! use std::sync::Arc;
+//! use aptone::replication::{Follower, Primary};
+//! use aptone::{Aptone, Config};
+//!
+//! let primary = Arc::new(Aptone::builder().record_events().build());
+//! let _serving = Primary::start(Arc::clone(&primary), "127.0.0.1:7171")?;
+//!
+//! // elsewhere
+//! let follower = Follower::connect("127.0.0.1:7171")?;
+//! // once the primary is gone, `follower.is_connected()` turns false
+//! let config = Config {
+//!     record_events: true,
+//!     ..Config::default()
+//! };
+//! let aptone = follower.promote(config)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A follower opens with `HELLO` and the version as a big-endian `u32`; the primary answers with
+//! the id of its log as a `u64`, and the follower with how many of its events it has applied,
+//! starting over if it followed another log. From there the primary sends frames, each a
+//! big-endian `u32` length and a postcard `Frame`, and an empty one whenever it has sent nothing
+//! for `HEARTBEAT_INTERVAL`. A follower hearing nothing for `PRIMARY_TIMEOUT` takes the primary
+//! for gone and keeps reconnecting. Only built with the `replication` feature.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{AccountId, Aptone, Config, Currency, LedgerEvent, Money, ServerData};
+
+/// Opens every connection a follower makes.
+pub const HELLO: [u8; 4] = *b"APTR";
+
+/// Bumped whenever the stream changes in a way older followers would misread.
+pub const REPLICATION_VERSION: u32 = 1;
+
+/// The longest a primary goes without sending its followers a frame.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a follower waits to hear from its primary before taking it for gone.
+pub const PRIMARY_TIMEOUT: Duration = Duration::from_secs(1);
+
+// how often a primary looks for events to send, and a follower tries to reconnect
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+// events sent in one frame at most, so a follower starting from scratch gets them in pieces
+const FRAME_EVENTS: usize = 4096;
+
+// the longest frame a follower takes, so a garbled length can't exhaust memory
+const MAX_FRAME: usize = 64 << 20;
+
+#[derive(Serialize, Deserialize)]
+struct Frame {
+    position: u64, // events of the log through this frame, rejections included
+    next_account: AccountId,
+    next_hold: u64,
+    events: Vec<LedgerEvent>, // rejections left out, as they change nothing
+}
+
+/// Streams an engine's events to its followers, a thread each, until dropped.
+pub struct Primary {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Primary {
+    /// Serves the followers of `aptone` on `addr`. Needs `AptoneBuilder::record_events`.
+    pub fn start(aptone: Arc<Aptone>, addr: impl ToSocketAddrs) -> io::Result<Primary> {
+        if !aptone.records_events() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "replication needs AptoneBuilder::record_events",
+            ));
+        }
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        // RandomState is the std source of per-process randomness
+        let log = RandomState::new().hash_one(addr);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopped);
+        let thread = thread::spawn(move || {
+            let mut streams: Vec<thread::JoinHandle<()>> = Vec::new();
+            for stream in listener.incoming() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        debug!(%err, "failed to accept a follower");
+                        continue;
+                    }
+                };
+                let (aptone, stop) = (Arc::clone(&aptone), Arc::clone(&stop));
+                streams.retain(|stream| !stream.is_finished());
+                streams.push(thread::spawn(move || {
+                    if let Err(err) = stream_to(&aptone, log, stream, &stop) {
+                        debug!(%err, "follower went away");
+                    }
+                }));
+            }
+            for stream in streams {
+                let _ = stream.join();
+            }
+        });
+        Ok(Primary {
+            addr,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Primary {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wakes the listener up so it sees the flag
+        let mut wake = self.addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        if let Err(err) = TcpStream::connect(wake) {
+            warn!(%err, "failed to stop the replication primary");
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn stream_to(
+    aptone: &Aptone,
+    log: u64,
+    mut stream: TcpStream,
+    stopped: &AtomicBool,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(PRIMARY_TIMEOUT))?;
+    stream.set_write_timeout(Some(PRIMARY_TIMEOUT))?;
+    let mut hello = [0; 8];
+    stream.read_exact(&mut hello)?;
+    let version = u32::from_be_bytes(hello[4..].try_into().unwrap());
+    if hello[..4] != HELLO || version != REPLICATION_VERSION {
+        let err = format!("not a follower of version {}", REPLICATION_VERSION);
+        return Err(invalid(err));
+    }
+    stream.write_all(&log.to_be_bytes())?;
+    let mut position = [0; 8];
+    stream.read_exact(&mut position)?;
+    let mut position = u64::from_be_bytes(position);
+    let mut sent = Instant::now();
+    while !stopped.load(Ordering::SeqCst) {
+        let events = aptone.ledger_events(position as usize);
+        // read after the events, so they are past every id in them
+        let (next_account, next_hold) = aptone.next_ids();
+        let mut frame = |position, events| {
+            let frame = Frame {
+                position,
+                next_account,
+                next_hold,
+                events,
+            };
+            write_frame(&mut stream, &frame)
+        };
+        for chunk in events.chunks(FRAME_EVENTS) {
+            position += chunk.len() as u64;
+            let changes = chunk
+                .iter()
+                .filter(|event| !matches!(event, LedgerEvent::TransactionRejected { .. }));
+            frame(position, changes.cloned().collect())?;
+            sent = Instant::now();
+        }
+        if events.is_empty() {
+            if sent.elapsed() >= HEARTBEAT_INTERVAL {
+                frame(position, Vec::new())?;
+                sent = Instant::now();
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+    Ok(())
+}
+
+fn write_frame(stream: &mut TcpStream, frame: &Frame) -> io::Result<()> {
+    let bytes = postcard::to_allocvec(frame).map_err(invalid)?;
+    stream.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(&bytes)
+}
+
+fn read_frame(stream: &mut TcpStream) -> io::Result<Frame> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(invalid(format!(
+            "frame of {} bytes is over {}",
+            len, MAX_FRAME
+        )));
+    }
+    let mut bytes = vec![0; len];
+    stream.read_exact(&mut bytes)?;
+    postcard::from_bytes(&bytes).map_err(invalid)
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+// What a follower has of its primary's state.
+struct Standby {
+    data: ServerData,
+    log: u64,      // the id of the log followed
+    position: u64, // events of it applied, rejections included
+    next_account: AccountId,
+    next_hold: u64,
+}
+
+impl Standby {
+    fn new(log: u64) -> Standby {
+        Standby {
+            data: ServerData::new(),
+            log,
+            position: 0,
+            next_account: 0,
+            next_hold: 0,
+        }
+    }
+}
+
+// What a follower shares with its thread.
+struct Replica {
+    standby: Mutex<Standby>,
+    stream: Mutex<Option<TcpStream>>, // the connection to the primary, to shut down on stopping
+    stopped: AtomicBool,
+}
+
+/// A hot standby of a primary's balances and holds, kept up to date on a background thread that
+/// reconnects whenever it loses the primary. Stops when dropped.
+pub struct Follower {
+    replica: Arc<Replica>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Follower {
+    /// Follows the primary at `addr`, failing if it can't be reached the first time round.
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Follower> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let stream = TcpStream::connect(&addrs[..])?;
+        let replica = Arc::new(Replica {
+            standby: Mutex::new(Standby::new(0)),
+            stream: Mutex::new(None),
+            stopped: AtomicBool::new(false),
+        });
+        let following = Arc::clone(&replica);
+        let thread = thread::spawn(move || {
+            let mut stream = Some(stream);
+            while !following.stopped.load(Ordering::SeqCst) {
+                if let Some(stream) = stream.take() {
+                    if let Err(err) = follow(&following, stream) {
+                        debug!(%err, "lost the replication primary");
+                    }
+                    *following.stream.lock().unwrap() = None;
+                    continue;
+                }
+                thread::park_timeout(RECONNECT_INTERVAL);
+                stream = TcpStream::connect(&addrs[..]).ok();
+            }
+        });
+        Ok(Follower {
+            replica,
+            thread: Some(thread),
+        })
+    }
+    /// Whether the primary is connected, as of its last frame at most `PRIMARY_TIMEOUT` ago.
+    pub fn is_connected(&self) -> bool {
+        self.replica.stream.lock().unwrap().is_some()
+    }
+    /// The events of the primary's log applied, rejections included, to compare with the length
+    /// of its `Aptone::ledger_events`.
+    pub fn position(&self) -> u64 {
+        self.replica.standby.lock().unwrap().position
+    }
+    pub fn get_balance(&self, account: AccountId) -> Money {
+        self.get_balance_in(account, Currency::default())
+    }
+    pub fn get_balance_in(&self, account: AccountId, currency: Currency) -> Money {
+        self.read(|data| data.get_balance(account, currency))
+    }
+    /// Reads the standby as it stands, holding off the events coming in meanwhile.
+    pub fn read<R>(&self, read: impl FnOnce(&ServerData) -> R) -> R {
+        read(&self.replica.standby.lock().unwrap().data)
+    }
+    /// Stops following and starts an engine with `config` on the standby, giving out ids above
+    /// those the primary gave out. Its own followers need `Config::record_events`. Fails for a
+    /// config the engine can't run on, as `Aptone::try_with_config` does.
+    pub fn promote(mut self, config: Config) -> io::Result<Aptone> {
+        self.stop();
+        let standby = mem::replace(&mut *self.replica.standby.lock().unwrap(), Standby::new(0));
+        Aptone::promoted(
+            config,
+            standby.data,
+            standby.next_account,
+            standby.next_hold,
+        )
+    }
+    fn stop(&mut self) {
+        self.replica.stopped.store(true, Ordering::SeqCst);
+        if let Some(stream) = &*self.replica.stream.lock().unwrap() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn follow(replica: &Replica, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(PRIMARY_TIMEOUT))?;
+    *replica.stream.lock().unwrap() = Some(stream.try_clone()?);
+    // stopped before the stream was there to shut down
+    if replica.stopped.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    let mut hello = HELLO.to_vec();
+    hello.extend_from_slice(&REPLICATION_VERSION.to_be_bytes());
+    stream.write_all(&hello)?;
+    let mut log = [0; 8];
+    stream.read_exact(&mut log)?;
+    let log = u64::from_be_bytes(log);
+    let position = {
+        let mut standby = replica.standby.lock().unwrap();
+        if standby.log != log {
+            *standby = Standby::new(log);
+        }
+        standby.position
+    };
+    stream.write_all(&position.to_be_bytes())?;
+    loop {
+        let frame = read_frame(&mut stream)?;
+        let mut standby = replica.standby.lock().unwrap();
+        for event in &frame.events {
+            standby.data.apply_event(event);
+        }
+        standby.position = frame.position;
+        standby.next_account = standby.next_account.max(frame.next_account);
+        standby.next_hold = standby.next_hold.max(frame.next_hold);
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::{AccountId, HandleId, TxCount, INVALID_HANDLE};
+
+/// Picks the handler that takes over an account with no transactions in flight. Accounts with
+/// pending transactions always stay on their handler, so routing never reorders an account.
+pub trait Router: fmt::Debug + Send + Sync {
+    /// `queue_depths` holds, per handler id, the transactions queued on it and not finished yet.
+    /// Has to return an id within it.
+    fn route(&self, account: AccountId, queue_depths: &[TxCount]) -> HandleId;
+}
+
+/// Cycles through the handlers regardless of their load.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl RoundRobin {
+    pub fn new() -> RoundRobin {
+        RoundRobin::default()
+    }
+}
+
+impl Router for RoundRobin {
+    fn route(&self, _account: AccountId, queue_depths: &[TxCount]) -> HandleId {
+        (self.next.fetch_add(1, Ordering::Relaxed) % queue_depths.len()) as HandleId
+    }
+}
+
+/// Always sends an account to the same handler, trading balance for affinity.
+#[derive(Debug, Default)]
+pub struct ConsistentHash;
+
+impl Router for ConsistentHash {
+    fn route(&self, account: AccountId, queue_depths: &[TxCount]) -> HandleId {
+        let mut hasher = DefaultHasher::new();
+        account.hash(&mut hasher);
+        (hasher.finish() % queue_depths.len() as u64) as HandleId
+    }
+}
+
+/// Sends work to the handler with the shallowest queue. The default.
+#[derive(Debug, Default)]
+pub struct LeastQueueDepth;
+
+impl Router for LeastQueueDepth {
+    fn route(&self, _account: AccountId, queue_depths: &[TxCount]) -> HandleId {
+        let mut hid: HandleId = INVALID_HANDLE;
+        let mut min_count: TxCount = TxCount::MAX;
+
+        for (id, &count) in queue_depths.iter().enumerate() {
+            if count < min_count {
+                min_count = count;
+                hid = id as HandleId;
+            }
+        }
+
+        hid
+    }
+}
+
+/// Picks a handler uniformly at random.
+#[derive(Debug)]
+pub struct Random {
+    state: AtomicU64,
+}
+
+impl Random {
+    pub fn new() -> Random {
+        // any nonzero seed will do; RandomState is the std source of per-process randomness
+        let seed = RandomState::new().hash_one(0u64) | 1;
+        Random::with_seed(seed)
+    }
+    /// Repeats the same sequence of picks for the same seed.
+    pub fn with_seed(seed: u64) -> Random {
+        Random {
+            state: AtomicU64::new(seed.max(1)),
+        }
+    }
+}
+
+impl Default for Random {
+    fn default() -> Random {
+        Random::new()
+    }
+}
+
+impl Router for Random {
+    fn route(&self, _account: AccountId, queue_depths: &[TxCount]) -> HandleId {
+        // xorshift64; a lost race between two submitters only repeats a pick
+        let mut x = self.state.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state.store(x, Ordering::Relaxed);
+        (x % queue_depths.len() as u64) as HandleId
+    }
+}
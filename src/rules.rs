@@ -0,0 +1,191 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+#[cfg(feature = "rules")]
+use std::io;
+#[cfg(feature = "rules")]
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::tx;
+use crate::{AccountId, Clock, Money, Tx, TxError, TxResult};
+
+/// Checks every transaction has to pass before it is queued, rejecting it with
+/// `TxError::Rejected` otherwise. None by default; `Aptone::set_rules` swaps them on a running
+/// engine.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rules {
+    /// Largest amount a transaction, or any leg of a batch, may move.
+    pub max_amount: Option<Money>,
+    /// Accounts no transaction may touch.
+    pub blocked_accounts: HashSet<AccountId>,
+    pub velocity: Vec<VelocityRule>,
+}
+
+/// Lets an account submit at most `transactions` within any `window`, a batch counting once for
+/// every account its legs are on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VelocityRule {
+    pub transactions: u32,
+    pub window: Duration,
+}
+
+#[cfg(feature = "rules")]
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    max_amount: Option<Money>,
+    #[serde(default)]
+    blocked_accounts: HashSet<AccountId>,
+    #[serde(default)]
+    velocity: Vec<VelocityFile>,
+}
+
+#[cfg(feature = "rules")]
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VelocityFile {
+    transactions: u32,
+    window_secs: u64,
+}
+
+#[cfg(feature = "rules")]
+impl Rules {
+    /// Reads rules from a TOML file if its name ends in `.toml`, from a JSON file otherwise, see
+    /// `from_toml` and `from_json`. Only built with the `rules` feature.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Rules> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Rules::from_toml(&text),
+            _ => Rules::from_json(&text),
+        }
+    }
+    /// Reads rules such as `{"max_amount": "1000.00", "blocked_accounts": [7], "velocity":
+    /// [{"transactions": 10, "window_secs": 60}]}`; every field may be left out.
+    pub fn from_json(json: &str) -> io::Result<Rules> {
+        let file: RulesFile = serde_json::from_str(json)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(file.into())
+    }
+    /// `from_json` for the same fields in TOML:
+    ///
+    /// ```toml
+    /// max_amount = "1000.00"
+    /// blocked_accounts = [7]
+    ///
+    /// [[velocity]]
+    /// transactions = 10
+    /// window_secs = 60
+    /// ```
+    pub fn from_toml(toml: &str) -> io::Result<Rules> {
+        let file: RulesFile =
+            toml::from_str(toml).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(file.into())
+    }
+}
+
+#[cfg(feature = "rules")]
+impl From<RulesFile> for Rules {
+    fn from(file: RulesFile) -> Rules {
+        Rules {
+            max_amount: file.max_amount,
+            blocked_accounts: file.blocked_accounts,
+            velocity: file
+                .velocity
+                .into_iter()
+                .map(|rule| VelocityRule {
+                    transactions: rule.transactions,
+                    window: Duration::from_secs(rule.window_secs),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The rules in force, and when each account submitted what the velocity rules count.
+pub(crate) struct RuleBook {
+    rules: RwLock<Rules>,
+    submitted: Mutex<HashMap<AccountId, VecDeque<Instant>>>, // oldest first
+    clock: Arc<dyn Clock>,
+}
+
+impl RuleBook {
+    pub(crate) fn new(rules: Rules, clock: Arc<dyn Clock>) -> RuleBook {
+        RuleBook {
+            rules: RwLock::new(rules),
+            submitted: Mutex::default(),
+            clock,
+        }
+    }
+    pub(crate) fn rules(&self) -> Rules {
+        self.rules.read().unwrap().clone()
+    }
+    /// What was submitted under the old rules still counts towards the new velocity rules.
+    pub(crate) fn set(&self, rules: Rules) {
+        *self.rules.write().unwrap() = rules;
+    }
+    /// Checks `legs` against the rules, counting them towards the velocity rules if they pass.
+    pub(crate) fn check(&self, legs: &[Tx]) -> TxResult {
+        let rules = self.rules.read().unwrap();
+        if let Some(max) = rules.max_amount {
+            let over = legs
+                .iter()
+                .find(|leg| leg.tx_type.has_amount() && leg.amount > max);
+            if let Some(leg) = over {
+                let reason = format!("amount {} is over the limit of {}", leg.amount, max);
+                return Err(TxError::Rejected(reason));
+            }
+        }
+        let blocked = tx::accounts_of(legs)
+            .into_iter()
+            .find(|account| rules.blocked_accounts.contains(account));
+        if let Some(account) = blocked {
+            return Err(TxError::Rejected(format!("account {} is blocked", account)));
+        }
+        if rules.velocity.is_empty() {
+            return Ok(());
+        }
+
+        let now = self.clock.now();
+        let longest = rules
+            .velocity
+            .iter()
+            .map(|rule| rule.window)
+            .max()
+            .unwrap_or_default();
+        let mut accounts: Vec<AccountId> = legs.iter().map(|leg| leg.account).collect();
+        accounts.sort_unstable();
+        accounts.dedup();
+        let mut submitted = self.submitted.lock().unwrap();
+        for &account in &accounts {
+            let Some(times) = submitted.get_mut(&account) else {
+                continue;
+            };
+            // nothing older than the longest window counts any more
+            while times
+                .front()
+                .is_some_and(|&at| now.saturating_duration_since(at) >= longest)
+            {
+                times.pop_front();
+            }
+            for rule in &rules.velocity {
+                let recent = times
+                    .iter()
+                    .filter(|&&at| now.saturating_duration_since(at) < rule.window)
+                    .count();
+                if recent >= rule.transactions as usize {
+                    let reason = format!(
+                        "account {} made {} transactions within {:?}",
+                        account, recent, rule.window
+                    );
+                    return Err(TxError::Rejected(reason));
+                }
+            }
+        }
+        for account in accounts {
+            submitted.entry(account).or_default().push_back(now);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use tracing::{debug, warn};
+
+use crate::{Aptone, Money, Tx, TxError, TxId, TxType};
+
+/// Transactions applied one after the other, each only once the one before it was, with those
+/// applied undone in reverse order should one of them be rejected. Unlike `Aptone::submit_batch`
+/// the steps can include anything, but aren't atomic: others see each step as it applies, and
+/// the compensations that undo them are transactions of their own, which may be rejected too.
+#[derive(Debug, Clone, Default)]
+pub struct Saga {
+    steps: Vec<(Tx, Option<Tx>)>, // each step with what undoes it, if anything does
+}
+
+/// What came of a step run once the one before it was applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SagaStep {
+    pub tx: Tx,
+    /// The id it was applied under.
+    pub result: Result<TxId, TxError>,
+    /// What undid it once a later step was rejected, and what that came to; `None` if it wasn't
+    /// undone.
+    pub compensation: Option<(Tx, Result<TxId, TxError>)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SagaOutcome {
+    /// Every step was applied.
+    Completed,
+    /// The `step`th was rejected, and every step before it undone.
+    Compensated { step: usize, error: TxError },
+    /// The `step`th was rejected, and some step before it is still applied: it had no
+    /// compensation, or its compensation was rejected.
+    Stuck { step: usize, error: TxError },
+}
+
+/// How a saga went, step by step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SagaReport {
+    pub outcome: SagaOutcome,
+    /// The steps run, in order; the rejected one last unless the saga completed.
+    pub steps: Vec<SagaStep>,
+}
+
+impl Saga {
+    pub fn new() -> Saga {
+        Saga::default()
+    }
+    /// Adds `tx` as the next step, undone by what undoes it plainly: a reversal of a deposit,
+    /// withdrawal or capture, the transfer back, or the release of a hold. Exchanges, releases
+    /// and reversals have nothing undoing them that way, and need `step_with`.
+    pub fn step(mut self, tx: Tx) -> Saga {
+        self.steps.push((tx, None));
+        self
+    }
+    /// Adds `tx` as the next step, undone by `compensation`.
+    pub fn step_with(mut self, tx: Tx, compensation: Tx) -> Saga {
+        self.steps.push((tx, Some(compensation)));
+        self
+    }
+    /// Runs the steps on `aptone`, waiting for each.
+    pub fn run(&self, aptone: &Aptone) -> SagaReport {
+        let mut steps = Vec::with_capacity(self.steps.len());
+        for (tx, _) in &self.steps {
+            let result = apply(aptone, tx.clone());
+            let rejected = result.clone().err();
+            debug!(step = steps.len(), ?tx, ?result, "saga step run");
+            steps.push(SagaStep {
+                tx: tx.clone(),
+                result,
+                compensation: None,
+            });
+            let Some(error) = rejected else {
+                continue;
+            };
+            let step = steps.len() - 1;
+            let mut undone = true;
+            for (record, (_, given)) in steps[..step].iter_mut().zip(&self.steps).rev() {
+                let tx_id = *record.result.as_ref().unwrap();
+                let Some(compensation) = given.clone().or_else(|| undoing(&record.tx, tx_id))
+                else {
+                    undone = false;
+                    continue;
+                };
+                let result = apply(aptone, compensation.clone());
+                if let Err(err) = &result {
+                    warn!(%err, tx = ?record.tx, "saga compensation rejected");
+                    undone = false;
+                }
+                record.compensation = Some((compensation, result));
+            }
+            let outcome = match undone {
+                true => SagaOutcome::Compensated { step, error },
+                false => SagaOutcome::Stuck { step, error },
+            };
+            return SagaReport { outcome, steps };
+        }
+        SagaReport {
+            outcome: SagaOutcome::Completed,
+            steps,
+        }
+    }
+}
+
+fn apply(aptone: &Aptone, tx: Tx) -> Result<TxId, TxError> {
+    let receipt = aptone.submit_tx(tx)?;
+    let tx_id = receipt.tx_id();
+    receipt.wait().map(|()| tx_id)
+}
+
+// What plainly undoes `tx`, applied as `tx_id`.
+fn undoing(tx: &Tx, tx_id: TxId) -> Option<Tx> {
+    let tx_type = match tx.tx_type {
+        TxType::DEPOSIT | TxType::WITHDRAW | TxType::CAPTURE { .. } => {
+            TxType::REVERSAL { original: tx_id }
+        }
+        TxType::TRANSFER { to } | TxType::FEE { to } => {
+            let back = Tx::new(to, tx.amount, TxType::TRANSFER { to: tx.account });
+            return Some(back.in_currency(tx.currency));
+        }
+        TxType::AUTHORIZE { hold } => TxType::RELEASE { hold },
+        TxType::EXCHANGE { .. } | TxType::RELEASE { .. } | TxType::REVERSAL { .. } => return None,
+    };
+    Some(Tx::new(tx.account, Money::ZERO, tx_type).in_currency(tx.currency))
+}
@@ -0,0 +1,189 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Clock, OrderId, Tx, TxId};
+
+// how often the clock is checked for transactions falling due; polled rather than slept on, so
+// a `VirtualClock` moved by hand is noticed too
+const SCHEDULE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Transactions held back until a time, see `Aptone::schedule`, and standing orders, see
+/// `Aptone::standing_order`, earliest first.
+#[derive(Default)]
+pub(crate) struct Schedule {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    once: Timeline<TxId, Tx>,
+    orders: Timeline<OrderId, Order>,
+    next_order: OrderId,
+}
+
+struct Order {
+    tx: Tx,
+    every: Duration,
+    until: Option<Instant>,
+}
+
+/// Something that fell due, in the order it did.
+pub(crate) enum Due {
+    /// A transaction scheduled once, under the id it was given then.
+    Once(TxId, Tx),
+    /// An occurrence of a standing order, which has no id yet.
+    Order(OrderId, Tx),
+}
+
+// entries by when they're due, each under a key of its own
+struct Timeline<K, V> {
+    queue: BTreeMap<(Instant, K), V>,
+    times: HashMap<K, Instant>,
+}
+
+impl<K, V> Default for Timeline<K, V> {
+    fn default() -> Timeline<K, V> {
+        Timeline {
+            queue: BTreeMap::new(),
+            times: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Copy + Ord + Hash, V> Timeline<K, V> {
+    fn insert(&mut self, at: Instant, key: K, value: V) {
+        self.queue.insert((at, key), value);
+        self.times.insert(key, at);
+    }
+    fn remove(&mut self, key: K) -> Option<V> {
+        let at = self.times.remove(&key)?;
+        self.queue.remove(&(at, key))
+    }
+    fn pop_first(&mut self) -> Option<(Instant, K, V)> {
+        let ((at, key), value) = self.queue.pop_first()?;
+        self.times.remove(&key);
+        Some((at, key, value))
+    }
+    fn next(&self) -> Option<Instant> {
+        self.queue.first_key_value().map(|(&(at, _), _)| at)
+    }
+}
+
+impl Schedule {
+    pub(crate) fn insert(&self, execute_at: Instant, tx_id: TxId, tx: Tx) {
+        self.state
+            .lock()
+            .unwrap()
+            .once
+            .insert(execute_at, tx_id, tx);
+    }
+    /// Takes `tx_id` off the schedule, unless it was taken already.
+    pub(crate) fn cancel(&self, tx_id: TxId) -> Option<Tx> {
+        self.state.lock().unwrap().once.remove(tx_id)
+    }
+    /// Puts `tx` on the schedule at `first` and every `every` after, up to `until`.
+    pub(crate) fn insert_order(
+        &self,
+        tx: Tx,
+        first: Instant,
+        every: Duration,
+        until: Option<Instant>,
+    ) -> OrderId {
+        let mut state = self.state.lock().unwrap();
+        let order = state.next_order;
+        state.next_order += 1;
+        if until.is_none_or(|until| first <= until) {
+            state
+                .orders
+                .insert(first, order, Order { tx, every, until });
+        }
+        order
+    }
+    /// Stops a standing order, unless it ran its course or was stopped already.
+    pub(crate) fn cancel_order(&self, order: OrderId) -> bool {
+        self.state.lock().unwrap().orders.remove(order).is_some()
+    }
+    /// Takes off every transaction due by `now`, in the order they fell due. A standing order
+    /// behind by several occurrences hands over each of them.
+    pub(crate) fn take_due(&self, now: Instant) -> Vec<Due> {
+        let mut state = self.state.lock().unwrap();
+        let mut due = Vec::new();
+        loop {
+            let once = state.once.next().filter(|&at| at <= now);
+            let order = state.orders.next().filter(|&at| at <= now);
+            let order_first = match (once, order) {
+                (None, None) => break,
+                // ties go to the one-off
+                (Some(once), Some(order)) => order < once,
+                (once, _) => once.is_none(),
+            };
+            if !order_first {
+                let (_, tx_id, tx) = state.once.pop_first().unwrap();
+                due.push(Due::Once(tx_id, tx));
+                continue;
+            }
+            let (at, key, order) = state.orders.pop_first().unwrap();
+            due.push(Due::Order(key, order.tx.clone()));
+            let next = at
+                .checked_add(order.every)
+                .filter(|&next| order.until.is_none_or(|until| next <= until));
+            if let Some(next) = next {
+                state.orders.insert(next, key, order);
+            }
+        }
+        due
+    }
+    /// Takes off every one-off transaction, due or not, and stops every standing order.
+    pub(crate) fn clear(&self) -> Vec<(TxId, Tx)> {
+        let mut state = self.state.lock().unwrap();
+        state.orders = Timeline::default();
+        state.once.times.clear();
+        std::mem::take(&mut state.once.queue)
+            .into_iter()
+            .map(|((_, tx_id), tx)| (tx_id, tx))
+            .collect()
+    }
+}
+
+/// Hands the scheduled transactions and standing orders to `dispatch` as they fall due by
+/// `clock`. Stops when dropped.
+pub(crate) struct Scheduler {
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Scheduler {
+    pub(crate) fn start<F>(schedule: Arc<Schedule>, clock: Arc<dyn Clock>, dispatch: F) -> Scheduler
+    where
+        F: Fn(Due) + Send + 'static,
+    {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopped);
+        let thread = thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                for due in schedule.take_due(clock.now()) {
+                    dispatch(due);
+                }
+                thread::park_timeout(SCHEDULE_INTERVAL);
+            }
+        });
+        Scheduler {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Middleware written in rhai, so operators can add their own policies without rebuilding the
+//! engine. Only built with the `scripting` feature.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use rhai::{Array, Dynamic, Map, Scope, AST};
+use tracing::{debug, info, warn};
+
+use crate::{Money, Tx, TxError, TxId, TxMiddleware, TxResult, TxType};
+
+// keeps a runaway script from stalling its handler for good
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// A `TxMiddleware` running whichever of `before_apply(tx_id, legs)`,
+/// `after_apply(tx_id, legs)` and `on_reject(tx_id, legs, error)` a script defines, `legs` being
+/// an array of maps with `account`, `amount`, as a float, `currency`, `type` and, for transfers
+/// and exchanges, `to`.
+///
+/// `before_apply` rejects the transaction by returning `false` or a string saying why, or by
+/// failing. Transactions are logged before any middleware sees them, so scripts can turn them
+/// down but not change them. `print` and `debug` go to the engine's log.
+pub struct ScriptHook {
+    engine: rhai::Engine,
+    ast: AST,
+    before_apply: bool,
+    after_apply: bool,
+    on_reject: bool,
+}
+
+impl ScriptHook {
+    pub fn new(script: &str) -> io::Result<ScriptHook> {
+        let mut engine = rhai::Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.on_print(|text| info!(text, "script"));
+        engine.on_debug(|text, _, _| debug!(text, "script"));
+        let ast = engine
+            .compile(script)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let defines = |name: &str, params: usize| {
+            ast.iter_functions()
+                .any(|f| f.name == name && f.params.len() == params)
+        };
+        Ok(ScriptHook {
+            before_apply: defines("before_apply", 2),
+            after_apply: defines("after_apply", 2),
+            on_reject: defines("on_reject", 3),
+            engine,
+            ast,
+        })
+    }
+    pub fn load(path: impl AsRef<Path>) -> io::Result<ScriptHook> {
+        ScriptHook::new(&std::fs::read_to_string(path)?)
+    }
+    fn call(&self, name: &str, args: impl rhai::FuncArgs) -> Result<Dynamic, String> {
+        self.engine
+            .call_fn(&mut Scope::new(), &self.ast, name, args)
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl fmt::Debug for ScriptHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScriptHook")
+            .field("before_apply", &self.before_apply)
+            .field("after_apply", &self.after_apply)
+            .field("on_reject", &self.on_reject)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TxMiddleware for ScriptHook {
+    fn before_apply(&self, tx_id: TxId, legs: &[Tx]) -> TxResult {
+        if !self.before_apply {
+            return Ok(());
+        }
+        let verdict = self
+            .call("before_apply", (tx_id as rhai::INT, to_array(legs)))
+            .map_err(|err| TxError::Rejected(format!("script failed: {}", err)))?;
+        if verdict.as_bool() == Ok(false) {
+            return Err(TxError::Rejected("turned down by script".to_string()));
+        }
+        match verdict.into_string() {
+            Ok(reason) => Err(TxError::Rejected(reason)),
+            Err(_) => Ok(()),
+        }
+    }
+    fn after_apply(&self, tx_id: TxId, legs: &[Tx]) {
+        if self.after_apply {
+            if let Err(err) = self.call("after_apply", (tx_id as rhai::INT, to_array(legs))) {
+                warn!(tx_id, %err, "after_apply script failed");
+            }
+        }
+    }
+    fn on_reject(&self, tx_id: TxId, legs: &[Tx], err: &TxError) {
+        if self.on_reject {
+            let args = (tx_id as rhai::INT, to_array(legs), err.to_string());
+            if let Err(err) = self.call("on_reject", args) {
+                warn!(tx_id, %err, "on_reject script failed");
+            }
+        }
+    }
+}
+
+fn to_array(legs: &[Tx]) -> Array {
+    legs.iter()
+        .map(|leg| Dynamic::from_map(to_map(leg)))
+        .collect()
+}
+
+fn to_map(tx: &Tx) -> Map {
+    let (kind, to) = match tx.tx_type {
+        TxType::DEPOSIT => ("deposit", None),
+        TxType::WITHDRAW => ("withdraw", None),
+        TxType::TRANSFER { to } => ("transfer", Some(Dynamic::from(to as rhai::INT))),
+        TxType::EXCHANGE { to } => ("exchange", Some(Dynamic::from(to.code()))),
+        TxType::AUTHORIZE { .. } => ("authorize", None),
+        TxType::CAPTURE { .. } => ("capture", None),
+        TxType::RELEASE { .. } => ("release", None),
+        TxType::REVERSAL { .. } => ("reversal", None),
+        TxType::FEE { to } => ("fee", Some(Dynamic::from(to as rhai::INT))),
+    };
+    let amount = tx.amount.minor() as f64 / 10f64.powi(Money::SCALE as i32);
+    let mut map = Map::new();
+    map.insert("account".into(), Dynamic::from(tx.account as rhai::INT));
+    map.insert("amount".into(), Dynamic::from(amount as rhai::FLOAT));
+    map.insert("currency".into(), Dynamic::from(tx.currency.code()));
+    map.insert("type".into(), Dynamic::from(kind));
+    if let Some(to) = to {
+        map.insert("to".into(), to);
+    }
+    map
+}
@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::AccountId;
+
+/// Where a message stands among those on one account, counting from zero.
+pub(crate) type AccountSeq = u64;
+
+#[derive(Default)]
+struct Counters {
+    issued: AccountSeq,  // the next to hand out
+    applied: AccountSeq, // the next allowed to be applied
+}
+
+/// Numbers every account's transactions in the order they were submitted, and tells the queues
+/// whose turn it is, so no routing change or stolen transaction can apply one before another
+/// submitted ahead of it. Split into stripes by account like the directory.
+pub(crate) struct Sequencer {
+    stripes: Vec<Mutex<HashMap<AccountId, Counters>>>,
+}
+
+impl Sequencer {
+    pub(crate) fn new(stripes: usize) -> Sequencer {
+        Sequencer {
+            stripes: (0..stripes).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+    fn stripe(&self, account: AccountId) -> &Mutex<HashMap<AccountId, Counters>> {
+        &self.stripes[account as usize % self.stripes.len()]
+    }
+    /// The next number on `account`. Has to be called holding the account's directory stripe
+    /// through queueing the message, so the numbers are in the order the messages were queued.
+    pub(crate) fn issue(&self, account: AccountId) -> AccountSeq {
+        let mut stripe = self.stripe(account).lock().unwrap();
+        let counters = stripe.entry(account).or_default();
+        counters.issued += 1;
+        counters.issued - 1
+    }
+    /// Gives back the number of a message that couldn't be queued, still holding the stripe it
+    /// was issued under.
+    pub(crate) fn unissue(&self, account: AccountId, seq: AccountSeq) {
+        let mut stripe = self.stripe(account).lock().unwrap();
+        if let Some(counters) = stripe.get_mut(&account) {
+            debug_assert_eq!(counters.issued, seq + 1, "numbers issued since");
+            counters.issued = seq;
+        }
+    }
+    /// Whether everything on `account` numbered before `seq` was applied or rejected.
+    pub(crate) fn is_next(&self, account: AccountId, seq: AccountSeq) -> bool {
+        let stripe = self.stripe(account).lock().unwrap();
+        stripe.get(&account).map_or(0, |counters| counters.applied) == seq
+    }
+    /// Lets the message after `seq` on `account` go.
+    pub(crate) fn advance(&self, account: AccountId, seq: AccountSeq) {
+        let mut stripe = self.stripe(account).lock().unwrap();
+        let counters = stripe.entry(account).or_default();
+        counters.applied = counters.applied.max(seq + 1);
+    }
+}
@@ -0,0 +1,879 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+use crate::cache::BalanceCache;
+use crate::ledger::EventLog;
+use crate::tx;
+use crate::wal::Seq;
+use crate::{
+    AccountId, Clock, Currency, EntryKind, ExchangeRates, HistoryEntry, HoldId, LedgerEvent, Money,
+    Overdraft, OverflowPolicy, StateStore, Tx, TxCount, TxError, TxId, TxResult, TxType,
+    VelocityLimits,
+};
+
+pub(crate) type Balances = BTreeMap<Currency, Money>; // currency -> balance
+pub(crate) type Holds = BTreeMap<u64, (Currency, Money)>; // hold number -> what it reserves
+pub(crate) type Outflows = VecDeque<(Instant, Currency, Money)>; // taken out when, oldest first
+type Saved = (AccountId, Option<Balances>, Option<Holds>, Option<Outflows>);
+
+/// A batch `ServerData::stage_batch` applied, waiting on its fees to be credited elsewhere
+/// before it is committed or rolled back.
+pub(crate) struct StagedBatch {
+    // what the accounts it touches held before it
+    before: Vec<Saved>,
+    events: Option<Vec<LedgerEvent>>, // held back from the event log until committed
+    moved: Vec<(Currency, Money)>,
+}
+
+/// State of the accounts owned by one handler. Only the owning handler applies transactions to
+/// it; the submission path only bumps pending counts, claims the originals of reversals and hands
+/// idle accounts between handlers.
+///
+/// Balances and holds are a projection of `LedgerEvent`s: applying a transaction works out the
+/// events it makes and hands each to `apply_event`, the one place they change but for accounts
+/// moved between handlers and batches rolled back.
+#[derive(Default)]
+pub struct ServerData {
+    state: Box<dyn StateStore>,   // balances and pending counts
+    unapplied: BTreeMap<Seq, Tx>, // logged transactions not applied yet
+    history: HashMap<AccountId, Vec<HistoryEntry>>, // account -> applied txs, oldest first
+    holds: HashMap<AccountId, Holds>,
+    reversing: HashSet<(AccountId, TxId)>, // originals of the reversals queued, not applied yet
+    overflow: OverflowPolicy,
+    overdraft: Overdraft,
+    rates: Option<Arc<dyn ExchangeRates>>,
+    velocity: Option<(VelocityLimits, Arc<dyn Clock>)>,
+    clock: Option<Arc<dyn Clock>>, // stamps the history, the system's clock if none
+    outflows: HashMap<AccountId, Outflows>, // only kept under velocity limits
+    cache: Option<Arc<BalanceCache>>, // kept up to date as transactions are settled
+    log: Option<Arc<EventLog>>,    // where the events applied are recorded, if anywhere
+    staged: Option<Vec<LedgerEvent>>, // a batch's events, recorded once it all went through
+}
+
+impl ServerData {
+    pub fn new() -> ServerData {
+        ServerData::default()
+    }
+    pub fn with_limits(overflow: OverflowPolicy, overdraft: Overdraft) -> ServerData {
+        ServerData {
+            overflow,
+            overdraft,
+            ..ServerData::default()
+        }
+    }
+    /// Keeps the balances and pending counts in `store` instead of in memory. The store is taken
+    /// as it is, so it may come with accounts in it.
+    pub fn with_state_store(mut self, store: impl StateStore + 'static) -> ServerData {
+        self.state = Box::new(store);
+        self
+    }
+    pub fn with_exchange_rates(mut self, rates: Arc<dyn ExchangeRates>) -> ServerData {
+        self.rates = Some(rates);
+        self
+    }
+    /// Holds the accounts to `limits`, over windows measured on `clock`.
+    pub fn with_velocity_limits(
+        mut self,
+        limits: VelocityLimits,
+        clock: Arc<dyn Clock>,
+    ) -> ServerData {
+        self.velocity = Some((limits, clock));
+        self
+    }
+    /// Stamps the history entries with the `wall_time` of `clock`.
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> ServerData {
+        self.clock = Some(clock);
+        self
+    }
+    fn wall_time(&self) -> SystemTime {
+        self.clock
+            .as_ref()
+            .map_or_else(SystemTime::now, |clock| clock.wall_time())
+    }
+    /// Records the events applied here in `log`.
+    pub(crate) fn with_event_log(mut self, log: Arc<EventLog>) -> ServerData {
+        self.log = Some(log);
+        self
+    }
+    /// What `events` add up to, applied to an empty shard in order.
+    pub fn replay<'a>(events: impl IntoIterator<Item = &'a LedgerEvent>) -> ServerData {
+        let mut data = ServerData::new();
+        for event in events {
+            data.apply_event(event);
+        }
+        data
+    }
+    pub fn apply_event(&mut self, event: &LedgerEvent) {
+        match *event {
+            LedgerEvent::AccountOpened { account, balance } => {
+                self.state
+                    .set_balance(account, Currency::default(), balance);
+            }
+            LedgerEvent::AccountClosed { account } => {
+                self.state.set_balances(account, None);
+                self.holds.remove(&account);
+            }
+            LedgerEvent::BalanceRestored {
+                account,
+                currency,
+                balance,
+            } => self.state.set_balance(account, currency, balance),
+            LedgerEvent::Deposited {
+                account,
+                currency,
+                amount,
+            } => {
+                let balance = self.get_balance(account, currency);
+                self.state.set_balance(account, currency, balance + amount);
+            }
+            LedgerEvent::Withdrawn {
+                account,
+                currency,
+                amount,
+            } => {
+                let balance = self.get_balance(account, currency);
+                self.state.set_balance(account, currency, balance - amount);
+            }
+            LedgerEvent::HoldPlaced {
+                hold,
+                currency,
+                amount,
+            } => {
+                let holds = self.holds.entry(hold.account).or_default();
+                holds.insert(hold.number, (currency, amount));
+            }
+            LedgerEvent::HoldLifted { hold } => {
+                if let Some(holds) = self.holds.get_mut(&hold.account) {
+                    holds.remove(&hold.number);
+                }
+            }
+            LedgerEvent::TransactionRejected { .. } => {}
+        }
+    }
+    // Applies `event` and records it.
+    fn emit(&mut self, event: LedgerEvent) {
+        self.apply_event(&event);
+        match (&mut self.staged, &self.log) {
+            (Some(staged), _) => staged.push(event),
+            (None, Some(log)) => log.append([event]),
+            (None, None) => {}
+        }
+    }
+    /// Publishes the balances of the accounts here to `cache` as transactions settle.
+    pub(crate) fn with_balance_cache(mut self, cache: Arc<BalanceCache>) -> ServerData {
+        self.cache = Some(cache);
+        self
+    }
+    /// Brings the cached balances of `account` up to date with what it holds here.
+    pub(crate) fn publish(&self, account: AccountId) {
+        if let Some(cache) = &self.cache {
+            cache.publish(account, self.state.get_balances(account));
+        }
+    }
+    pub(crate) fn increase_pending_tx(&mut self, account: AccountId, amount: TxCount) -> TxCount {
+        let pending = self.state.get_pending(account) + amount;
+        self.state.set_pending(account, pending);
+        pending
+    }
+    pub(crate) fn decrease_pending_tx(&mut self, account: AccountId, amount: TxCount) -> TxCount {
+        let pending = self.state.get_pending(account).saturating_sub(amount);
+        self.state.set_pending(account, pending);
+        pending
+    }
+    /// Accounts with transactions pending here, and how many.
+    pub(crate) fn pending(&self) -> impl Iterator<Item = (AccountId, TxCount)> + '_ {
+        self.state.pending().filter(|&(_, pending)| pending > 0)
+    }
+    pub fn get_pending_tx(&self, account: AccountId) -> TxCount {
+        self.state.get_pending(account)
+    }
+    pub fn increase_balance(
+        &mut self,
+        account: AccountId,
+        currency: Currency,
+        amount: Money,
+    ) -> Result<(), TxError> {
+        let balance = self.get_balance(account, currency);
+        let after = match self.overflow {
+            OverflowPolicy::Reject => balance
+                .checked_add(amount)
+                .ok_or(TxError::Overflow(account))?,
+            OverflowPolicy::Saturate => balance.saturating_add(amount),
+        };
+        self.emit(LedgerEvent::Deposited {
+            account,
+            currency,
+            amount: after - balance,
+        });
+        Ok(())
+    }
+    /// Takes `amount` out of what is available, so never out of what holds reserved. An account
+    /// holding other currencies, but none of `currency`, may still overdraw it.
+    pub fn decrease_balance(
+        &mut self,
+        account: AccountId,
+        currency: Currency,
+        amount: Money,
+    ) -> Result<(), TxError> {
+        self.check_available(account, currency, amount)?;
+        self.debit(account, currency, amount);
+        Ok(())
+    }
+    // Fails unless taking `amount` off the available balance keeps it within the overdraft
+    // limit.
+    fn check_available(
+        &self,
+        account: AccountId,
+        currency: Currency,
+        amount: Money,
+    ) -> Result<(), TxError> {
+        if !self.has_account(account) {
+            return Err(TxError::UnknownAccount(account));
+        }
+        let floor = self.overdraft.limit(account).saturating_neg();
+        let available = self.get_available_balance(account, currency);
+        match available.checked_sub(amount) {
+            Some(after) if after >= floor => Ok(()),
+            _ => Err(TxError::InsufficientFunds {
+                account,
+                balance: available,
+                amount,
+            }),
+        }
+    }
+    /// `decrease_balance` for money leaving the account, as by a withdrawal or a transfer, which
+    /// also has to stay within its velocity limits.
+    pub fn pay_out(
+        &mut self,
+        account: AccountId,
+        currency: Currency,
+        amount: Money,
+    ) -> Result<(), TxError> {
+        let Some((limits, clock)) = &self.velocity else {
+            return self.decrease_balance(account, currency, amount);
+        };
+        let limits = limits.limits(account);
+        if limits.is_empty() {
+            return self.decrease_balance(account, currency, amount);
+        }
+        let now = clock.now();
+        let longest = limits.iter().map(|limit| limit.window).max().unwrap();
+        let outflows = self.outflows.entry(account).or_default();
+        while let Some(&(at, _, _)) = outflows.front() {
+            if now.saturating_duration_since(at) < longest {
+                break;
+            }
+            outflows.pop_front();
+        }
+        for limit in limits {
+            let taken: Money = outflows
+                .iter()
+                .filter(|&&(at, taken_in, _)| {
+                    taken_in == currency && now.saturating_duration_since(at) < limit.window
+                })
+                .map(|&(_, _, taken)| taken)
+                .sum();
+            if taken
+                .checked_add(amount)
+                .is_none_or(|total| total > limit.amount)
+            {
+                return Err(TxError::LimitExceeded {
+                    account,
+                    limit: limit.amount,
+                    window: limit.window,
+                });
+            }
+        }
+        self.decrease_balance(account, currency, amount)?;
+        let outflows = self.outflows.entry(account).or_default();
+        outflows.push_back((now, currency, amount));
+        Ok(())
+    }
+    /// Undoes the latest `pay_out` of `account`, for a transfer that failed after its debit.
+    pub fn refund(
+        &mut self,
+        account: AccountId,
+        currency: Currency,
+        amount: Money,
+    ) -> Result<(), TxError> {
+        if let Some(outflows) = self.outflows.get_mut(&account) {
+            if let Some(latest) = outflows
+                .iter()
+                .rposition(|&(_, c, a)| (c, a) == (currency, amount))
+            {
+                outflows.remove(latest);
+            }
+        }
+        self.increase_balance(account, currency, amount)
+    }
+    // Only for amounts known to be there; what is left stays above what holds reserve.
+    fn debit(&mut self, account: AccountId, currency: Currency, amount: Money) {
+        self.emit(LedgerEvent::Withdrawn {
+            account,
+            currency,
+            amount,
+        });
+    }
+    /// Reserves `amount` of what `account` has available under `hold`.
+    pub fn authorize(
+        &mut self,
+        hold: HoldId,
+        currency: Currency,
+        amount: Money,
+    ) -> Result<(), TxError> {
+        self.check_available(hold.account, currency, amount)?;
+        self.set_hold(hold, currency, amount);
+        Ok(())
+    }
+    /// Posts what `hold` reserved, giving it back.
+    pub fn capture(&mut self, hold: HoldId) -> Result<(Currency, Money), TxError> {
+        let (currency, amount) = self.take_hold(hold)?;
+        self.debit(hold.account, currency, amount);
+        Ok((currency, amount))
+    }
+    pub fn release(&mut self, hold: HoldId) -> Result<(), TxError> {
+        self.take_hold(hold).map(|_| ())
+    }
+    fn take_hold(&mut self, hold: HoldId) -> Result<(Currency, Money), TxError> {
+        let holds = self.holds.get(&hold.account);
+        let reserved = holds.and_then(|holds| holds.get(&hold.number)).copied();
+        let reserved = reserved.ok_or(TxError::UnknownHold(hold))?;
+        self.emit(LedgerEvent::HoldLifted { hold });
+        Ok(reserved)
+    }
+    pub(crate) fn set_hold(&mut self, hold: HoldId, currency: Currency, amount: Money) {
+        self.emit(LedgerEvent::HoldPlaced {
+            hold,
+            currency,
+            amount,
+        });
+    }
+    /// Every hold in place, with what it reserves.
+    pub fn holds(&self) -> impl Iterator<Item = (HoldId, Currency, Money)> + '_ {
+        self.holds.iter().flat_map(|(&account, holds)| {
+            holds.iter().map(move |(&number, &(currency, amount))| {
+                (HoldId { account, number }, currency, amount)
+            })
+        })
+    }
+    pub(crate) fn take_holds(&mut self, account: AccountId) -> Option<Holds> {
+        self.holds.remove(&account)
+    }
+    pub(crate) fn set_holds(&mut self, account: AccountId, holds: Holds) {
+        self.holds.insert(account, holds);
+    }
+    /// What reversing `original` takes: its currency, and what it adds to `account`'s balance.
+    /// Claims `original` until the reversal is applied, so no other reversal of it gets in
+    /// meanwhile. Accounts only move between handlers while idle, so a claim never has to move.
+    pub(crate) fn claim_reversal(
+        &mut self,
+        account: AccountId,
+        original: TxId,
+    ) -> Result<(Currency, Money), TxError> {
+        let history = self.history(account);
+        if self.reversing.contains(&(account, original))
+            || history
+                .iter()
+                .any(|entry| entry.kind == EntryKind::Reversal { original })
+        {
+            return Err(TxError::AlreadyReversed(original));
+        }
+        let entry =
+            history
+                .iter()
+                .find(|entry| entry.tx_id == original)
+                .ok_or(TxError::UnknownTx {
+                    account,
+                    tx_id: original,
+                })?;
+        let change = match entry.kind {
+            EntryKind::Deposit => -entry.amount,
+            EntryKind::Withdraw | EntryKind::Capture { .. } => entry.amount,
+            _ => return Err(TxError::NotReversible(original)),
+        };
+        let currency = entry.currency;
+        self.reversing.insert((account, original));
+        Ok((currency, change))
+    }
+    /// For a reversal claimed but never queued.
+    pub(crate) fn unclaim_reversal(&mut self, account: AccountId, original: TxId) {
+        self.reversing.remove(&(account, original));
+    }
+    pub fn transfer(
+        &mut self,
+        from: AccountId,
+        to: AccountId,
+        currency: Currency,
+        amount: Money,
+    ) -> Result<(), TxError> {
+        self.pay_out(from, currency, amount)?;
+        if let Err(err) = self.increase_balance(to, currency, amount) {
+            self.refund(from, currency, amount)?;
+            return Err(err);
+        }
+        Ok(())
+    }
+    /// Converts `amount` of `from` into `to` within `account`, giving back what was credited.
+    pub fn exchange(
+        &mut self,
+        account: AccountId,
+        amount: Money,
+        from: Currency,
+        to: Currency,
+    ) -> Result<Money, TxError> {
+        let converted = match &self.rates {
+            _ if from == to => Some(amount),
+            Some(rates) => rates.convert(amount, from, to),
+            None => None,
+        };
+        let converted = converted.ok_or(TxError::NoExchangeRate { from, to })?;
+        self.decrease_balance(account, from, amount)?;
+        if let Err(err) = self.increase_balance(account, to, converted) {
+            self.increase_balance(account, from, amount)?;
+            return Err(err);
+        }
+        Ok(converted)
+    }
+    pub fn apply(&mut self, tx: &Tx) -> TxResult {
+        self.apply_moved(tx).map(|_| ())
+    }
+    // Like `apply`, giving back what the transaction's last leg moved, where the transaction
+    // doesn't say: what an exchange credited, or what a capture posted.
+    fn apply_moved(&mut self, tx: &Tx) -> Result<(Currency, Money), TxError> {
+        let currency = tx.currency;
+        match tx.tx_type {
+            TxType::DEPOSIT => self.increase_balance(tx.account, currency, tx.amount),
+            TxType::WITHDRAW => self.pay_out(tx.account, currency, tx.amount),
+            TxType::TRANSFER { to } | TxType::FEE { to } => {
+                self.transfer(tx.account, to, currency, tx.amount)
+            }
+            TxType::EXCHANGE { to } => {
+                let credited = self.exchange(tx.account, tx.amount, currency, to)?;
+                return Ok((to, credited));
+            }
+            TxType::AUTHORIZE { hold } => self.authorize(hold, currency, tx.amount),
+            TxType::CAPTURE { hold } => return self.capture(hold),
+            TxType::RELEASE { hold } => self.release(hold),
+            TxType::REVERSAL { original } => {
+                self.reversing.remove(&(tx.account, original));
+                if tx.amount.is_negative() {
+                    self.decrease_balance(tx.account, currency, -tx.amount)
+                } else {
+                    self.increase_balance(tx.account, currency, tx.amount)
+                }
+            }
+        }
+        .map(|()| (currency, tx.amount))
+    }
+    /// Applies every leg of a batch in order, or none of them: once one fails, the accounts the
+    /// batch touches get back the balances and holds they had before it. Gives back what each
+    /// leg moved, as `apply_moved` does.
+    pub(crate) fn apply_batch(&mut self, legs: &[Tx]) -> Result<Vec<(Currency, Money)>, TxError> {
+        let staged = self.stage_batch(legs, None)?;
+        Ok(self.commit_batch(staged))
+    }
+    /// `apply_batch` up to committing it. The fee legs into `across`, which the peer owning it
+    /// credits, are only debited here.
+    pub(crate) fn stage_batch(
+        &mut self,
+        legs: &[Tx],
+        across: Option<AccountId>,
+    ) -> Result<StagedBatch, TxError> {
+        let mut accounts = tx::accounts_of(legs);
+        accounts.retain(|&account| Some(account) != across);
+        if self.log.is_some() {
+            self.staged = Some(Vec::new());
+        }
+        let before: Vec<_> = accounts
+            .iter()
+            .map(|account| {
+                let balances = self.state.get_balances(*account);
+                let holds = self.holds.get(account).cloned();
+                (
+                    *account,
+                    balances,
+                    holds,
+                    self.outflows.get(account).cloned(),
+                )
+            })
+            .collect();
+        let moved: Result<Vec<_>, _> = legs
+            .iter()
+            .map(|leg| match leg.tx_type {
+                TxType::FEE { to } if Some(to) == across => self
+                    .pay_out(leg.account, leg.currency, leg.amount)
+                    .map(|()| (leg.currency, leg.amount)),
+                _ => self.apply_moved(leg),
+            })
+            .collect();
+        let events = self.staged.take();
+        let staged = StagedBatch {
+            before,
+            events,
+            moved: Vec::new(),
+        };
+        match moved {
+            Ok(moved) => Ok(StagedBatch { moved, ..staged }),
+            Err(err) => {
+                self.roll_back_batch(staged);
+                Err(err)
+            }
+        }
+    }
+    /// Records the events of a staged batch, giving back what each leg moved.
+    pub(crate) fn commit_batch(&mut self, staged: StagedBatch) -> Vec<(Currency, Money)> {
+        if let (Some(log), Some(events)) = (&self.log, staged.events) {
+            log.append(events);
+        }
+        staged.moved
+    }
+    /// Gives the accounts of a staged batch back what they had before it.
+    pub(crate) fn roll_back_batch(&mut self, staged: StagedBatch) {
+        for (account, balances, holds, outflows) in staged.before {
+            self.state.set_balances(account, balances);
+            match holds {
+                Some(holds) => self.holds.insert(account, holds),
+                None => self.holds.remove(&account),
+            };
+            match outflows {
+                Some(outflows) => self.outflows.insert(account, outflows),
+                None => self.outflows.remove(&account),
+            };
+        }
+    }
+    pub fn balances(&self) -> impl Iterator<Item = (AccountId, Currency, Money)> + '_ {
+        self.state.balances()
+    }
+    /// Puts a balance recovered from a log in place.
+    pub(crate) fn set_balance(&mut self, account: AccountId, currency: Currency, balance: Money) {
+        self.emit(LedgerEvent::BalanceRestored {
+            account,
+            currency,
+            balance,
+        });
+        self.publish(account);
+    }
+    pub(crate) fn open_account(&mut self, account: AccountId, balance: Money) {
+        self.emit(LedgerEvent::AccountOpened { account, balance });
+        self.publish(account);
+    }
+    /// Drops everything held about `account`, giving back its balances.
+    pub(crate) fn close_account(&mut self, account: AccountId) -> Vec<(Currency, Money)> {
+        let balances = self.get_balances(account);
+        self.emit(LedgerEvent::AccountClosed { account });
+        self.history.remove(&account);
+        self.outflows.remove(&account);
+        self.state.set_pending(account, 0);
+        self.publish(account);
+        balances
+    }
+    pub(crate) fn take_outflows(&mut self, account: AccountId) -> Option<Outflows> {
+        self.outflows.remove(&account)
+    }
+    pub(crate) fn set_outflows(&mut self, account: AccountId, outflows: Outflows) {
+        self.outflows.insert(account, outflows);
+    }
+    pub(crate) fn take_balances(&mut self, account: AccountId) -> Option<Balances> {
+        self.state.set_balances(account, None)
+    }
+    pub(crate) fn set_balances(&mut self, account: AccountId, balances: Balances) {
+        self.state.set_balances(account, Some(balances));
+    }
+    /// Accounts with transactions pending here.
+    pub fn active_account_count(&self) -> usize {
+        self.pending().count()
+    }
+    pub fn account_count(&self) -> usize {
+        self.state.account_count()
+    }
+    pub fn has_account(&self, account: AccountId) -> bool {
+        self.state.has_account(account)
+    }
+    pub fn get_balance(&self, account: AccountId, currency: Currency) -> Money {
+        self.state
+            .get_balance(account, currency)
+            .unwrap_or(Money::ZERO)
+    }
+    /// The balance less what holds reserve of it.
+    pub fn get_available_balance(&self, account: AccountId, currency: Currency) -> Money {
+        let held: Money = self.holds.get(&account).map_or(Money::ZERO, |holds| {
+            holds
+                .values()
+                .filter(|&&(held, _)| held == currency)
+                .map(|&(_, amount)| amount)
+                .sum()
+        });
+        self.get_balance(account, currency).saturating_sub(held)
+    }
+    /// Every currency `account` holds, with its balance in it.
+    pub fn get_balances(&self, account: AccountId) -> Vec<(Currency, Money)> {
+        self.state
+            .get_balances(account)
+            .map_or(Vec::new(), |balances| balances.into_iter().collect())
+    }
+    /// Transactions applied to `account` since the engine started, oldest first.
+    pub fn history(&self, account: AccountId) -> &[HistoryEntry] {
+        self.history.get(&account).map_or(&[], Vec::as_slice)
+    }
+    /// Adds the history entries of an applied transaction, whose last leg moved `moved`, and
+    /// returns them. With `across`, only its debit leg was applied here and the credit is
+    /// recorded by `record_credit` on the peer.
+    pub(crate) fn record(
+        &mut self,
+        tx_id: TxId,
+        tx: &Tx,
+        moved: (Currency, Money),
+        across: bool,
+    ) -> Vec<(AccountId, HistoryEntry)> {
+        let time = self.wall_time();
+        let currency = tx.currency;
+        let mut entries = Vec::with_capacity(2);
+        let mut push = |data: &mut ServerData, account, kind, currency, amount| {
+            entries.push(data.push_entry(account, tx_id, time, kind, currency, amount))
+        };
+        match tx.tx_type {
+            TxType::DEPOSIT => push(self, tx.account, EntryKind::Deposit, currency, tx.amount),
+            TxType::WITHDRAW => push(self, tx.account, EntryKind::Withdraw, currency, tx.amount),
+            TxType::TRANSFER { to } => {
+                let out = EntryKind::TransferOut { to };
+                push(self, tx.account, out, currency, tx.amount);
+                if !across {
+                    let into = EntryKind::TransferIn { from: tx.account };
+                    push(self, to, into, currency, tx.amount);
+                }
+            }
+            TxType::EXCHANGE { to } => {
+                let out = EntryKind::ExchangeOut { to };
+                push(self, tx.account, out, currency, tx.amount);
+                let into = EntryKind::ExchangeIn { from: currency };
+                push(self, tx.account, into, to, moved.1);
+            }
+            TxType::AUTHORIZE { .. } | TxType::RELEASE { .. } => {}
+            TxType::CAPTURE { hold } => {
+                let (currency, amount) = moved;
+                push(
+                    self,
+                    tx.account,
+                    EntryKind::Capture { hold },
+                    currency,
+                    amount,
+                );
+            }
+            TxType::REVERSAL { original } => push(
+                self,
+                tx.account,
+                EntryKind::Reversal { original },
+                currency,
+                tx.amount,
+            ),
+            TxType::FEE { to } => {
+                push(
+                    self,
+                    tx.account,
+                    EntryKind::FeeOut { to },
+                    currency,
+                    tx.amount,
+                );
+                if !across {
+                    let into = EntryKind::FeeIn { from: tx.account };
+                    push(self, to, into, currency, tx.amount);
+                }
+            }
+        }
+        entries
+    }
+    /// Credits `account` with what a peer handler left it, as for a transfer or fees debited
+    /// there, all or nothing, and records each credit as `kind`.
+    pub(crate) fn credit(
+        &mut self,
+        tx_id: TxId,
+        account: AccountId,
+        credits: &[(EntryKind, Currency, Money)],
+    ) -> Result<Vec<(AccountId, HistoryEntry)>, TxError> {
+        let deposits: Vec<Tx> = credits
+            .iter()
+            .map(|&(_, currency, amount)| {
+                Tx::new(account, amount, TxType::DEPOSIT).in_currency(currency)
+            })
+            .collect();
+        self.apply_batch(&deposits)?;
+        let mut entries: Vec<_> = credits
+            .iter()
+            .map(|&(kind, currency, amount)| {
+                vec![self.record_credit(tx_id, kind, account, currency, amount)]
+            })
+            .collect();
+        self.restate(&mut entries);
+        Ok(entries.into_iter().flatten().collect())
+    }
+    // Adds the history entry of a credit leg `record` left to the peer owning `to`.
+    fn record_credit(
+        &mut self,
+        tx_id: TxId,
+        kind: EntryKind,
+        to: AccountId,
+        currency: Currency,
+        amount: Money,
+    ) -> (AccountId, HistoryEntry) {
+        let time = self.wall_time();
+        self.push_entry(to, tx_id, time, kind, currency, amount)
+    }
+    fn push_entry(
+        &mut self,
+        account: AccountId,
+        tx_id: TxId,
+        time: SystemTime,
+        kind: EntryKind,
+        currency: Currency,
+        amount: Money,
+    ) -> (AccountId, HistoryEntry) {
+        let entry = HistoryEntry {
+            tx_id,
+            time,
+            kind,
+            currency,
+            amount,
+            balance: self.get_balance(account, currency),
+        };
+        self.history.entry(account).or_default().push(entry.clone());
+        (account, entry)
+    }
+    pub(crate) fn take_history(&mut self, account: AccountId) -> Option<Vec<HistoryEntry>> {
+        self.history.remove(&account)
+    }
+    /// Takes every account's history out, as for placing it on another shard.
+    pub(crate) fn take_histories(
+        &mut self,
+    ) -> impl Iterator<Item = (AccountId, Vec<HistoryEntry>)> + '_ {
+        self.history.drain()
+    }
+    pub(crate) fn set_history(&mut self, account: AccountId, history: Vec<HistoryEntry>) {
+        self.history.insert(account, history);
+    }
+    /// A handler's critical section for a transaction queued on it: applies it, unless `decided`
+    /// already holds its outcome, records its history and releases its accounts. With `across`,
+    /// it is a transfer whose credit leg went to the peer owning `to`. Doing it all under one
+    /// lock keeps checkpoints consistent, and the accounts pinned until the transaction is
+    /// through.
+    pub(crate) fn settle(
+        &mut self,
+        tx_id: TxId,
+        tx: &Tx,
+        seq: Option<Seq>,
+        across: bool,
+        decided: Option<TxResult>,
+    ) -> (TxResult, Vec<(AccountId, HistoryEntry)>) {
+        // only transfers are decided elsewhere, and they move their own amount
+        let result = match decided {
+            Some(decided) => decided.map(|()| (tx.currency, tx.amount)),
+            None => self.apply_moved(tx),
+        };
+        let entries = match result {
+            Ok(moved) => self.record(tx_id, tx, moved, across),
+            Err(ref error) => {
+                self.reject(tx_id, tx.account, error);
+                Vec::new()
+            }
+        };
+        let result = result.map(|_| ());
+        if let Some(seq) = seq {
+            self.log_applied(seq);
+        }
+        self.decrease_pending_tx(tx.account, 1);
+        self.publish(tx.account);
+        if let Some(to) = tx.tx_type.payee() {
+            // with a barrier in place the peer handler owns `to` and releases it
+            if to != tx.account && !across {
+                self.decrease_pending_tx(to, 1);
+                self.publish(to);
+            }
+        }
+        (result, entries)
+    }
+    /// `settle` for a batch, applied atomically by `apply_batch` unless `decided` holds it
+    /// rejected, or staged by `stage_batch` and credited across. With `across`, the fees into
+    /// that account were credited by the peer owning it. Gives back the history entries of each
+    /// leg.
+    pub(crate) fn settle_batch(
+        &mut self,
+        tx_id: TxId,
+        legs: &[Tx],
+        across: Option<AccountId>,
+        decided: Option<Result<Option<StagedBatch>, TxError>>,
+    ) -> (TxResult, Vec<Vec<(AccountId, HistoryEntry)>>) {
+        let result = match decided {
+            Some(Err(err)) => Err(err),
+            Some(Ok(Some(staged))) => Ok(self.commit_batch(staged)),
+            Some(Ok(None)) => Ok(Vec::new()),
+            None => self.apply_batch(legs),
+        };
+        let entries = match &result {
+            Ok(moved) => {
+                let mut entries: Vec<_> = legs
+                    .iter()
+                    .zip(moved)
+                    .map(|(leg, &moved)| {
+                        let credited = across.is_some() && leg.tx_type.payee() == across;
+                        self.record(tx_id, leg, moved, credited)
+                    })
+                    .collect();
+                self.restate(&mut entries);
+                entries
+            }
+            Err(error) => {
+                self.reject(tx_id, legs[0].account, error);
+                legs.iter().map(|_| Vec::new()).collect()
+            }
+        };
+        // with a barrier in place the peer handler owns `across` and releases it
+        for account in tx::accounts_of(legs) {
+            if Some(account) != across {
+                self.decrease_pending_tx(account, 1);
+                self.publish(account);
+            }
+        }
+        (result.map(|_| ()), entries)
+    }
+    // Recorded once the whole batch is applied, its entries all hold the balance it left. Gives
+    // each the balance right after its own leg instead, in `entries` and in the history.
+    fn restate(&mut self, entries: &mut [Vec<(AccountId, HistoryEntry)>]) {
+        let mut after = HashMap::new();
+        for (account, entry) in entries
+            .iter_mut()
+            .rev()
+            .flat_map(|leg| leg.iter_mut().rev())
+        {
+            let key = (*account, entry.currency);
+            if let Some(&balance) = after.get(&key) {
+                entry.balance = balance;
+            }
+            after.insert(key, entry.balance_before());
+        }
+        let mut restated: HashMap<AccountId, Vec<HistoryEntry>> = HashMap::new();
+        for (account, entry) in entries.iter().flatten() {
+            restated.entry(*account).or_default().push(entry.clone());
+        }
+        for (account, restated) in restated {
+            let history = self.history.entry(account).or_default();
+            let start = history.len() - restated.len();
+            history.splice(start.., restated);
+        }
+    }
+    fn reject(&mut self, tx_id: TxId, account: AccountId, error: &TxError) {
+        self.emit(LedgerEvent::TransactionRejected {
+            tx_id,
+            account,
+            error: error.clone(),
+        });
+    }
+    pub(crate) fn log_queued(&mut self, seq: Seq, tx: Tx) {
+        self.unapplied.insert(seq, tx);
+    }
+    pub(crate) fn log_applied(&mut self, seq: Seq) {
+        self.unapplied.remove(&seq);
+    }
+    pub(crate) fn unapplied(&self) -> impl Iterator<Item = (Seq, &Tx)> + '_ {
+        self.unapplied.iter().map(|(&seq, tx)| (seq, tx))
+    }
+}
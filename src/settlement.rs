@@ -0,0 +1,30 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::{AccountId, BalanceSnapshot, Currency, Money, Tx, TxId, TxResult};
+
+/// What a settlement pays into or charges an account, such as interest or a maintenance fee,
+/// worked out from what it held in one currency at the close.
+pub trait Accrual: fmt::Debug + Send + Sync {
+    /// Positive to pay in, negative to charge, zero for nothing; `period` is the time since the
+    /// settlement before, or since the engine started for the first.
+    fn accrue(
+        &self,
+        account: AccountId,
+        currency: Currency,
+        balance: Money,
+        period: Duration,
+    ) -> Money;
+}
+
+/// What `Aptone::settle` did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Settlement {
+    /// Counting from one, since the engine started.
+    pub number: u64,
+    /// Every balance at the close, before anything accrued.
+    pub balances: BalanceSnapshot,
+    /// The deposits and withdrawals the accruals came to, with their ids and how they turned
+    /// out; a charge an account can't cover is rejected like any withdrawal.
+    pub accrued: Vec<(TxId, Tx, TxResult)>,
+}
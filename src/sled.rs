@@ -0,0 +1,233 @@
+//! A `Storage` in a sled database, for write-heavy workloads SQLite can't keep up with. Each
+//! handler gathers what it commits, adding up what it moved on every balance, and writes it out
+//! in one go when it runs out of work or has gathered `BATCH_SIZE` entries. Receipts resolve
+//! before that, so a crash loses what the handlers were holding back. Keys are big-endian, to
+//! sort by number:
+//!
+//! ```text
+//! accounts: account -> 1 while open, 0 once closed
+//! balances: account ++ currency -> balance in minor units, an i128
+//! history:  account ++ id -> "tx_id time kind detail currency amount balance"
+//! ```
+//!
+//! `time` is in milliseconds since the Unix epoch, `detail` is as for `sqlite` or `-`, and `id`
+//! comes from `Db::generate_id`, so each account's history reads in the order it was written.
+//! Only built with the `sled` feature.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::str;
+use std::sync::{Mutex, RwLock};
+use std::time::UNIX_EPOCH;
+
+use ::sled::transaction::{
+    ConflictableTransactionError, TransactionError, TransactionResult, Transactional,
+};
+use ::sled::{Batch, Db, Tree};
+
+use crate::storage::{describe, parse};
+use crate::{AccountId, Currency, HandleId, HistoryEntry, Money, Storage, Stored, TxId};
+
+/// Entries a handler holds back at most before writing them out.
+pub const BATCH_SIZE: usize = 1024;
+
+// What a handler committed and hasn't written out yet.
+#[derive(Debug, Default)]
+struct Pending {
+    moved: HashMap<(AccountId, Currency), Money>,
+    history: Batch,
+    entries: usize,
+}
+
+/// A sled database, see the module docs.
+#[derive(Debug)]
+pub struct SledStorage {
+    db: Db,
+    accounts: Tree,
+    balances: Tree,
+    history: Tree,
+    pending: RwLock<HashMap<HandleId, Mutex<Pending>>>, // handler id -> what it holds back
+}
+
+impl SledStorage {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<SledStorage> {
+        SledStorage::with_db(::sled::open(path).map_err(failed)?)
+    }
+    /// A database in a temporary directory, removed with the storage.
+    pub fn temporary() -> io::Result<SledStorage> {
+        let db = ::sled::Config::new().temporary(true).open();
+        SledStorage::with_db(db.map_err(failed)?)
+    }
+    fn with_db(db: Db) -> io::Result<SledStorage> {
+        Ok(SledStorage {
+            accounts: db.open_tree("accounts").map_err(failed)?,
+            balances: db.open_tree("balances").map_err(failed)?,
+            history: db.open_tree("history").map_err(failed)?,
+            db,
+            pending: RwLock::default(),
+        })
+    }
+    fn with_pending<T>(&self, handler: HandleId, f: impl FnOnce(&mut Pending) -> T) -> T {
+        if let Some(pending) = self.pending.read().unwrap().get(&handler) {
+            return f(&mut pending.lock().unwrap());
+        }
+        let mut handlers = self.pending.write().unwrap();
+        f(handlers.entry(handler).or_default().get_mut().unwrap())
+    }
+    // Adds what `pending` moved to the balances and appends its history, all in one transaction.
+    fn write(&self, pending: &mut Pending) -> io::Result<()> {
+        if pending.entries == 0 {
+            return Ok(());
+        }
+        let written = (&self.balances, &self.history).transaction(|(balances, history)| {
+            for (&(account, currency), &moved) in &pending.moved {
+                let key = balance_key(account, currency);
+                let balance = match balances.get(&key)? {
+                    Some(value) => money(&value).map_err(ConflictableTransactionError::Abort)?,
+                    None => Money::ZERO,
+                };
+                balances.insert(key, &(balance + moved).minor().to_be_bytes()[..])?;
+            }
+            history.apply_batch(&pending.history)?;
+            Ok(())
+        });
+        settled(written)?;
+        *pending = Pending::default();
+        self.db.flush().map_err(failed)?;
+        Ok(())
+    }
+}
+
+impl Storage for SledStorage {
+    fn load(&self) -> io::Result<Stored> {
+        let next_account = match self.accounts.last().map_err(failed)? {
+            Some((key, _)) => account_of(&key)? + 1,
+            None => 0,
+        };
+        let mut balances = Vec::new();
+        for item in self.balances.iter() {
+            let (key, value) = item.map_err(failed)?;
+            let currency = str::from_utf8(&key[4..]).map_err(invalid)?;
+            balances.push((account_of(&key)?, parse(currency)?, money(&value)?));
+        }
+        Ok(Stored {
+            balances,
+            next_account,
+        })
+    }
+    fn open_account(&self, account: AccountId, balance: Money) -> io::Result<()> {
+        let key = balance_key(account, Currency::default());
+        let opened = (&self.accounts, &self.balances).transaction(|(accounts, balances)| {
+            accounts.insert(&account.to_be_bytes()[..], &[1][..])?;
+            balances.insert(&key[..], &balance.minor().to_be_bytes()[..])?;
+            Ok(())
+        });
+        settled(opened)?;
+        self.db.flush().map_err(failed)?;
+        Ok(())
+    }
+    fn close_account(&self, account: AccountId) -> io::Result<()> {
+        // so no balance of the account is written after it is gone
+        for pending in self.pending.read().unwrap().values() {
+            self.write(&mut pending.lock().unwrap())?;
+        }
+        let mut removed = Batch::default();
+        for key in self.balances.scan_prefix(account.to_be_bytes()).keys() {
+            removed.remove(key.map_err(failed)?);
+        }
+        let closed = (&self.accounts, &self.balances).transaction(|(accounts, balances)| {
+            accounts.insert(&account.to_be_bytes()[..], &[0][..])?;
+            balances.apply_batch(&removed)?;
+            Ok(())
+        });
+        settled(closed)?;
+        self.db.flush().map_err(failed)?;
+        Ok(())
+    }
+    fn commit(
+        &self,
+        handler: HandleId,
+        tx_id: TxId,
+        entries: &[(AccountId, HistoryEntry)],
+    ) -> io::Result<()> {
+        let mut records = Vec::with_capacity(entries.len());
+        for (account, entry) in entries {
+            let id = self.db.generate_id().map_err(failed)?;
+            records.push((history_key(*account, id), record(tx_id, entry)));
+        }
+        self.with_pending(handler, |pending| {
+            for ((account, entry), (key, record)) in entries.iter().zip(records) {
+                let moved = pending
+                    .moved
+                    .entry((*account, entry.currency))
+                    .or_insert(Money::ZERO);
+                *moved += entry.balance - entry.balance_before();
+                pending.history.insert(key, record.into_bytes());
+                pending.entries += 1;
+            }
+            if pending.entries >= BATCH_SIZE {
+                self.write(pending)?;
+            }
+            Ok(())
+        })
+    }
+    fn flush(&self, handler: HandleId) -> io::Result<()> {
+        self.with_pending(handler, |pending| self.write(pending))
+    }
+}
+
+fn balance_key(account: AccountId, currency: Currency) -> Vec<u8> {
+    let mut key = account.to_be_bytes().to_vec();
+    key.extend_from_slice(currency.to_string().as_bytes());
+    key
+}
+
+fn history_key(account: AccountId, id: u64) -> Vec<u8> {
+    let mut key = account.to_be_bytes().to_vec();
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+fn record(tx_id: TxId, entry: &HistoryEntry) -> String {
+    let (kind, detail) = describe(entry.kind);
+    let time = entry.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "{} {} {} {} {} {} {}",
+        tx_id,
+        time.as_millis(),
+        kind,
+        detail.as_deref().unwrap_or("-"),
+        entry.currency,
+        entry.amount,
+        entry.balance
+    )
+}
+
+fn account_of(key: &[u8]) -> io::Result<AccountId> {
+    let bytes = key.get(..4).and_then(|bytes| bytes.try_into().ok());
+    bytes
+        .map(AccountId::from_be_bytes)
+        .ok_or_else(|| invalid("short key"))
+}
+
+fn money(value: &[u8]) -> io::Result<Money> {
+    let bytes = value.try_into().map_err(|_| invalid("bad balance"))?;
+    Ok(Money::from_minor(i128::from_be_bytes(bytes)))
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn settled(result: TransactionResult<(), io::Error>) -> io::Result<()> {
+    result.map_err(|err| match err {
+        TransactionError::Abort(err) => err,
+        TransactionError::Storage(err) => failed(err),
+    })
+}
+
+fn failed(err: ::sled::Error) -> io::Error {
+    io::Error::other(err)
+}
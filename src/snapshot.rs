@@ -0,0 +1,112 @@
+//! Checkpoint of the balances and holds plus the transactions that were still queued when it was
+//! taken:
+//!
+//! ```text
+//! GENERATION <n>
+//! NEXT_ACCOUNT <account>
+//! NEXT_HOLD <hold>
+//! BALANCE <account> <balance> <currency>
+//! HOLD <account> <hold> <amount> <currency>
+//! DEPOSIT <account> <amount> <currency>
+//! ```
+//!
+//! As in the log, a balance without a currency is in the default one.
+//!
+//! The generation ties a snapshot to the log written after it, so a log left over from before
+//! the snapshot is never replayed on top of it.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::wal::{decode, encode, invalid};
+use crate::{AccountId, Currency, HoldId, Money, Tx};
+
+#[derive(Default)]
+pub(crate) struct Snapshot {
+    pub(crate) generation: u64,
+    pub(crate) next_account: AccountId,
+    pub(crate) next_hold: u64,
+    pub(crate) balances: Vec<(AccountId, Currency, Money)>,
+    pub(crate) holds: Vec<(HoldId, Currency, Money)>,
+    pub(crate) pending: Vec<Tx>,
+}
+
+pub(crate) fn path_for(wal_path: &Path) -> PathBuf {
+    let mut path = wal_path.as_os_str().to_owned();
+    path.push(".snapshot");
+    PathBuf::from(path)
+}
+
+pub(crate) fn write(path: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "GENERATION {}", snapshot.generation)?;
+        writeln!(file, "NEXT_ACCOUNT {}", snapshot.next_account)?;
+        writeln!(file, "NEXT_HOLD {}", snapshot.next_hold)?;
+        for (account, currency, balance) in &snapshot.balances {
+            writeln!(file, "BALANCE {} {} {}", account, balance, currency)?;
+        }
+        for (hold, currency, amount) in &snapshot.holds {
+            let HoldId { account, number } = hold;
+            writeln!(file, "HOLD {} {} {} {}", account, number, amount, currency)?;
+        }
+        for tx in &snapshot.pending {
+            file.write_all(encode(tx).as_bytes())?;
+        }
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+pub(crate) fn read(path: &Path) -> io::Result<Option<Snapshot>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut snapshot = Snapshot::default();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if let Some(number) = line.strip_prefix("GENERATION ") {
+            snapshot.generation = number.parse().map_err(|_| invalid(&line))?;
+        } else if let Some(account) = line.strip_prefix("NEXT_ACCOUNT ") {
+            snapshot.next_account = account.parse().map_err(|_| invalid(&line))?;
+        } else if let Some(number) = line.strip_prefix("NEXT_HOLD ") {
+            snapshot.next_hold = number.parse().map_err(|_| invalid(&line))?;
+        } else if let Some(entry) = line.strip_prefix("HOLD ") {
+            let [account, number, amount, currency] = entry.split(' ').collect::<Vec<_>>()[..]
+            else {
+                return Err(invalid(&line));
+            };
+            let hold = HoldId {
+                account: account.parse().map_err(|_| invalid(&line))?,
+                number: number.parse().map_err(|_| invalid(&line))?,
+            };
+            snapshot.holds.push((
+                hold,
+                currency.parse().map_err(|_| invalid(&line))?,
+                amount.parse().map_err(|_| invalid(&line))?,
+            ));
+        } else if let Some(entry) = line.strip_prefix("BALANCE ") {
+            let fields: Vec<&str> = entry.split(' ').collect();
+            let (account, balance, currency) = match fields[..] {
+                [account, balance] => (account, balance, None),
+                [account, balance, currency] => (account, balance, Some(currency)),
+                _ => return Err(invalid(&line)),
+            };
+            snapshot.balances.push((
+                account.parse().map_err(|_| invalid(&line))?,
+                match currency {
+                    Some(currency) => currency.parse().map_err(|_| invalid(&line))?,
+                    None => Currency::default(),
+                },
+                balance.parse().map_err(|_| invalid(&line))?,
+            ));
+        } else {
+            snapshot.pending.push(decode(&line)?);
+        }
+    }
+    Ok(Some(snapshot))
+}
@@ -0,0 +1,186 @@
+//! A `Storage` in a SQLite database, for durability without anything else to run:
+//!
+//! ```text
+//! accounts (account INTEGER PRIMARY KEY, open INTEGER)
+//! balances (account INTEGER, currency TEXT, balance TEXT)
+//! history  (tx_id INTEGER, account INTEGER, time INTEGER, kind TEXT, detail TEXT,
+//!           currency TEXT, amount TEXT, balance TEXT)
+//! ```
+//!
+//! Amounts are decimal text, so none is rounded; `time` is in milliseconds since the Unix
+//! epoch, and `detail` names the other side of a transfer or exchange, the hold a capture
+//! posted or the transaction a reversal undid. Only built with the `sqlite` feature.
+
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::storage::{describe, parse};
+use crate::{AccountId, Currency, HandleId, HistoryEntry, Money, Storage, Stored, TxId};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS accounts (
+        account INTEGER PRIMARY KEY,
+        open INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS balances (
+        account INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        balance TEXT NOT NULL,
+        PRIMARY KEY (account, currency)
+    );
+    CREATE TABLE IF NOT EXISTS history (
+        tx_id INTEGER NOT NULL,
+        account INTEGER NOT NULL,
+        time INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        detail TEXT,
+        currency TEXT NOT NULL,
+        amount TEXT NOT NULL,
+        balance TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS history_by_account ON history (account);
+";
+
+/// A SQLite database, written to by one handler at a time.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it and its tables if needed.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<SqliteStorage> {
+        SqliteStorage::with_connection(Connection::open(path).map_err(failed)?)
+    }
+    /// A database in memory, gone with the storage.
+    pub fn in_memory() -> io::Result<SqliteStorage> {
+        SqliteStorage::with_connection(Connection::open_in_memory().map_err(failed)?)
+    }
+    fn with_connection(connection: Connection) -> io::Result<SqliteStorage> {
+        connection.execute_batch(SCHEMA).map_err(failed)?;
+        Ok(SqliteStorage {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load(&self) -> io::Result<Stored> {
+        let connection = self.connection.lock().unwrap();
+        let next_account: Option<i64> = connection
+            .query_row("SELECT MAX(account) FROM accounts", [], |row| row.get(0))
+            .map_err(failed)?;
+        let mut select = connection
+            .prepare(
+                "SELECT balances.account, currency, balance FROM balances
+                 JOIN accounts ON accounts.account = balances.account WHERE open",
+            )
+            .map_err(failed)?;
+        let rows = select
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })
+            .map_err(failed)?;
+        let mut balances = Vec::new();
+        for row in rows {
+            let (account, currency, balance) = row.map_err(failed)?;
+            balances.push((account, parse(&currency)?, parse(&balance)?));
+        }
+        Ok(Stored {
+            balances,
+            next_account: next_account.map_or(0, |account| account as AccountId + 1),
+        })
+    }
+    fn open_account(&self, account: AccountId, balance: Money) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction().map_err(failed)?;
+        tx.execute(
+            "INSERT INTO accounts (account, open) VALUES (?1, 1)",
+            params![account],
+        )
+        .map_err(failed)?;
+        tx.execute(
+            "INSERT INTO balances (account, currency, balance) VALUES (?1, ?2, ?3)",
+            params![
+                account,
+                Currency::default().to_string(),
+                balance.to_string()
+            ],
+        )
+        .map_err(failed)?;
+        tx.commit().map_err(failed)
+    }
+    fn close_account(&self, account: AccountId) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction().map_err(failed)?;
+        tx.execute(
+            "UPDATE accounts SET open = 0 WHERE account = ?1",
+            params![account],
+        )
+        .map_err(failed)?;
+        tx.execute("DELETE FROM balances WHERE account = ?1", params![account])
+            .map_err(failed)?;
+        tx.commit().map_err(failed)
+    }
+    fn commit(
+        &self,
+        _handler: HandleId,
+        tx_id: TxId,
+        entries: &[(AccountId, HistoryEntry)],
+    ) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction().map_err(failed)?;
+        for (account, entry) in entries {
+            let currency = entry.currency.to_string();
+            let balance: Option<String> = tx
+                .query_row(
+                    "SELECT balance FROM balances WHERE account = ?1 AND currency = ?2",
+                    params![account, currency],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(failed)?;
+            let balance = match balance {
+                Some(balance) => parse::<Money>(&balance)?,
+                None => Money::ZERO,
+            };
+            let moved = entry.balance - entry.balance_before();
+            tx.execute(
+                "INSERT OR REPLACE INTO balances (account, currency, balance) VALUES (?1, ?2, ?3)",
+                params![account, currency, (balance + moved).to_string()],
+            )
+            .map_err(failed)?;
+            let (kind, detail) = describe(entry.kind);
+            let time = entry.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            tx.execute(
+                "INSERT INTO history
+                 (tx_id, account, time, kind, detail, currency, amount, balance)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    tx_id as i64,
+                    account,
+                    time.as_millis() as i64,
+                    kind,
+                    detail,
+                    currency,
+                    entry.amount.to_string(),
+                    entry.balance.to_string()
+                ],
+            )
+            .map_err(failed)?;
+        }
+        tx.commit().map_err(failed)
+    }
+}
+
+fn failed(err: rusqlite::Error) -> io::Error {
+    io::Error::other(err)
+}
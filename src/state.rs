@@ -0,0 +1,98 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{AccountId, Currency, Money, TxCount};
+
+/// Where a `ServerData` keeps the balances of its accounts and the transactions pending on them,
+/// given to it with `ServerData::with_state_store`. Only its handler and the submission path
+/// holding its lock get at it, so implementations need no locking of their own.
+pub trait StateStore: Send {
+    /// Whether `account` is held here, even with nothing in it.
+    fn has_account(&self, account: AccountId) -> bool;
+    fn account_count(&self) -> usize;
+    /// `None` if the account isn't held here or holds nothing in the currency.
+    fn get_balance(&self, account: AccountId, currency: Currency) -> Option<Money>;
+    /// Holds the account here if it wasn't.
+    fn set_balance(&mut self, account: AccountId, currency: Currency, balance: Money);
+    /// Every currency `account` holds, with its balance in it; `None` if it isn't held here.
+    fn get_balances(&self, account: AccountId) -> Option<BTreeMap<Currency, Money>>;
+    /// Puts `balances` in place of what `account` held, or drops the account with `None`, and
+    /// gives back what it held.
+    fn set_balances(
+        &mut self,
+        account: AccountId,
+        balances: Option<BTreeMap<Currency, Money>>,
+    ) -> Option<BTreeMap<Currency, Money>>;
+    /// Every balance held here, in no particular order.
+    fn balances(&self) -> Box<dyn Iterator<Item = (AccountId, Currency, Money)> + '_>;
+    fn get_pending(&self, account: AccountId) -> TxCount;
+    fn set_pending(&mut self, account: AccountId, pending: TxCount);
+    /// Accounts with transactions pending, and how many.
+    fn pending(&self) -> Box<dyn Iterator<Item = (AccountId, TxCount)> + '_>;
+}
+
+impl Default for Box<dyn StateStore> {
+    fn default() -> Self {
+        Box::new(MemoryStore::default())
+    }
+}
+
+/// Keeps everything in hash maps, the default.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    balances: HashMap<AccountId, BTreeMap<Currency, Money>>, // account -> currency -> balance
+    pending: HashMap<AccountId, TxCount>,                    // account -> pending tx count
+}
+
+impl StateStore for MemoryStore {
+    fn has_account(&self, account: AccountId) -> bool {
+        self.balances.contains_key(&account)
+    }
+    fn account_count(&self) -> usize {
+        self.balances.len()
+    }
+    fn get_balance(&self, account: AccountId, currency: Currency) -> Option<Money> {
+        self.balances.get(&account)?.get(&currency).copied()
+    }
+    fn set_balance(&mut self, account: AccountId, currency: Currency, balance: Money) {
+        self.balances
+            .entry(account)
+            .or_default()
+            .insert(currency, balance);
+    }
+    fn get_balances(&self, account: AccountId) -> Option<BTreeMap<Currency, Money>> {
+        self.balances.get(&account).cloned()
+    }
+    fn set_balances(
+        &mut self,
+        account: AccountId,
+        balances: Option<BTreeMap<Currency, Money>>,
+    ) -> Option<BTreeMap<Currency, Money>> {
+        match balances {
+            Some(balances) => self.balances.insert(account, balances),
+            None => self.balances.remove(&account),
+        }
+    }
+    fn balances(&self) -> Box<dyn Iterator<Item = (AccountId, Currency, Money)> + '_> {
+        Box::new(self.balances.iter().flat_map(|(&account, balances)| {
+            balances
+                .iter()
+                .map(move |(&currency, &balance)| (account, currency, balance))
+        }))
+    }
+    fn get_pending(&self, account: AccountId) -> TxCount {
+        self.pending.get(&account).copied().unwrap_or(0)
+    }
+    fn set_pending(&mut self, account: AccountId, pending: TxCount) {
+        match pending {
+            0 => self.pending.remove(&account),
+            _ => self.pending.insert(account, pending),
+        };
+    }
+    fn pending(&self) -> Box<dyn Iterator<Item = (AccountId, TxCount)> + '_> {
+        Box::new(
+            self.pending
+                .iter()
+                .map(|(&account, &pending)| (account, pending)),
+        )
+    }
+}
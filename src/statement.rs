@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{AccountId, Currency, HistoryEntry, Money};
+
+/// What happened to an account over a period, as returned by `Aptone::statement`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Statement {
+    pub account: AccountId,
+    pub from: SystemTime,
+    /// Not included.
+    pub to: SystemTime,
+    /// The balance in every currency the account held or moved, at `from`, by currency.
+    pub opening: Vec<(Currency, Money)>,
+    /// The history entries from `from` up to `to`, oldest first.
+    pub entries: Vec<HistoryEntry>,
+    /// The balances at `to`, in the same currencies as `opening`.
+    pub closing: Vec<(Currency, Money)>,
+}
+
+impl Statement {
+    /// The statement over `from..to` of `account`, which holds `balances` now and whose whole
+    /// history is `history`. The balances at a point come from the first entry after it, or
+    /// are those held now if none is.
+    pub(crate) fn new(
+        account: AccountId,
+        from: SystemTime,
+        to: SystemTime,
+        balances: Vec<(Currency, Money)>,
+        history: &[HistoryEntry],
+    ) -> Statement {
+        let mut closing: BTreeMap<_, _> = balances.into_iter().collect();
+        for entry in history {
+            closing.entry(entry.currency).or_insert(Money::ZERO);
+        }
+        let mut opening = closing.clone();
+        for entry in history.iter().rev() {
+            if entry.time >= to {
+                closing.insert(entry.currency, entry.balance_before());
+            }
+            if entry.time >= from {
+                opening.insert(entry.currency, entry.balance_before());
+            }
+        }
+        let entries = history
+            .iter()
+            .filter(|entry| (from..to).contains(&entry.time))
+            .cloned()
+            .collect();
+        Statement {
+            account,
+            from,
+            to,
+            opening: opening.into_iter().collect(),
+            entries,
+            closing: closing.into_iter().collect(),
+        }
+    }
+    /// Writes one row per entry, under a header row: its id, time in milliseconds since the
+    /// epoch, kind, the account, currency, hold or transaction it refers to if any, currency,
+    /// amount and the balance it left. The opening and closing balances go in rows of their own
+    /// before and after, with a kind of `opening` or `closing` and no id.
+    pub fn write_csv(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "tx_id,time,kind,reference,currency,amount,balance")?;
+        for (currency, balance) in &self.opening {
+            let time = millis(self.from);
+            writeln!(writer, ",{},opening,,{},,{}", time, currency, balance)?;
+        }
+        for entry in &self.entries {
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{}",
+                entry.tx_id,
+                millis(entry.time),
+                entry.kind.name(),
+                entry.kind.reference().unwrap_or_default(),
+                entry.currency,
+                entry.amount,
+                entry.balance
+            )?;
+        }
+        for (currency, balance) in &self.closing {
+            let time = millis(self.to);
+            writeln!(writer, ",{},closing,,{},,{}", time, currency, balance)?;
+        }
+        Ok(())
+    }
+    /// Writes the statement as JSON. Only built with the `export` feature.
+    #[cfg(feature = "export")]
+    pub fn write_json(&self, writer: impl Write) -> io::Result<()> {
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+}
+
+/// The opening balances, one line per entry and the closing balances, in plain text.
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "statement of account {} from {} to {} ms since the epoch",
+            self.account,
+            millis(self.from),
+            millis(self.to)
+        )?;
+        for (currency, balance) in &self.opening {
+            writeln!(f, "opening balance: {} {}", balance, currency)?;
+        }
+        for entry in &self.entries {
+            write!(
+                f,
+                "tx {} at {}: {}",
+                entry.tx_id,
+                millis(entry.time),
+                entry.kind.name()
+            )?;
+            if let Some(reference) = entry.kind.reference() {
+                write!(f, " ({})", reference)?;
+            }
+            writeln!(
+                f,
+                " {} {}, balance {}",
+                entry.amount, entry.currency, entry.balance
+            )?;
+        }
+        for (currency, balance) in &self.closing {
+            writeln!(f, "closing balance: {} {}", balance, currency)?;
+        }
+        Ok(())
+    }
+}
+
+// Milliseconds since the epoch.
+pub(crate) fn millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis())
+}
@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::{AccountId, Currency, Money, TxCount};
+
+/// A snapshot of a running engine, as returned by `Aptone::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AptoneStats {
+    /// Indexed by handler id.
+    pub handlers: Vec<HandlerStats>,
+    /// Transactions applied, by any handler.
+    pub applied: u64,
+    /// Transactions rejected, by a handler or before reaching one.
+    pub rejected: u64,
+    /// Accounts with transactions queued or being applied.
+    pub active_accounts: usize,
+    /// Accounts holding a balance.
+    pub accounts: usize,
+    /// Time since the engine started, on its clock.
+    pub uptime: Duration,
+    /// Time from submission to being finished, over every transaction.
+    pub latency: LatencyPercentiles,
+    /// The same by the type of transaction: `deposit`, `withdraw`, `transfer`, `exchange`,
+    /// `authorize`, `capture`, `release`, `reversal` or `batch`, for those there were any of.
+    pub latency_by_type: BTreeMap<&'static str, LatencyPercentiles>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandlerStats {
+    /// Messages queued on the handler and not finished.
+    pub queue_depth: u32,
+    /// Transactions queued on the handler that were applied, whichever handler did the work.
+    pub applied: u64,
+    /// Transactions queued on the handler that it rejected.
+    pub rejected: u64,
+    /// Accounts pinned to the handler by transactions in flight.
+    pub active_accounts: usize,
+    /// Accounts whose balance the handler holds.
+    pub accounts: usize,
+    /// Taking no new accounts, see `Aptone::drain_handler`.
+    pub drained: bool,
+    /// Out of the pool, see `Aptone::resize_workers`.
+    pub retired: bool,
+    /// Time from submission to being finished, of the transactions queued on the handler.
+    pub latency: LatencyPercentiles,
+    /// The same by the type of transaction, as for `AptoneStats::latency_by_type`.
+    pub latency_by_type: BTreeMap<&'static str, LatencyPercentiles>,
+}
+
+/// Percentiles of the time transactions took from submission to being finished by a handler,
+/// read off a histogram whose buckets grow by a fifth or so each: a percentile is the upper
+/// bound of the bucket it falls in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyPercentiles {
+    /// Transactions observed.
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// How a handler is doing, as returned by `Aptone::health`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandlerHealth {
+    /// Its thread is running and reported in within `HEARTBEAT_TIMEOUT`.
+    pub alive: bool,
+    /// When its thread last reported in, by the engine's clock.
+    pub last_heartbeat: Option<Instant>,
+    /// When it last finished a message, by the engine's clock.
+    pub last_processed: Option<Instant>,
+    /// Messages queued on the handler and not finished.
+    pub queue_depth: TxCount,
+    /// Out of the pool, and not expected to be alive, see `Aptone::resize_workers`.
+    pub retired: bool,
+}
+
+/// Every balance and pending count at one point, as returned by `Aptone::snapshot`: each
+/// transaction shows in it applied in full or not at all.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BalanceSnapshot {
+    /// Each account's balance in every currency it holds.
+    pub balances: BTreeMap<AccountId, BTreeMap<Currency, Money>>,
+    /// Transactions queued or being applied, for the accounts that have any.
+    pub pending: BTreeMap<AccountId, TxCount>,
+}
+
+impl BalanceSnapshot {
+    pub fn balance(&self, account: AccountId, currency: Currency) -> Money {
+        self.balances
+            .get(&account)
+            .and_then(|balances| balances.get(&currency))
+            .copied()
+            .unwrap_or(Money::ZERO)
+    }
+    /// What every account holds in `currency` together.
+    pub fn total(&self, currency: Currency) -> Money {
+        self.balances
+            .values()
+            .filter_map(|balances| balances.get(&currency))
+            .copied()
+            .sum()
+    }
+}
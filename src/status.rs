@@ -0,0 +1,121 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{Clock, TxError, TxId, TxResult};
+
+/// Where a submitted transaction stands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxStatus {
+    /// Waiting for its time, see `Aptone::schedule`.
+    Scheduled,
+    /// Taken off the schedule before its time, or off its handler's queue before it was
+    /// applied, see `Aptone::cancel`.
+    Cancelled,
+    /// Queued on a handler and not processed yet.
+    Pending,
+    /// Taken off the queue by a handler, which is applying it.
+    Processing,
+    Applied,
+    Rejected(TxError),
+}
+
+/// Hands out transaction ids and remembers, for one dedup window, the status of every
+/// transaction and the idempotency key it was submitted with.
+pub(crate) struct Tracker {
+    next_id: AtomicU64,
+    window: Duration,
+    clock: Arc<dyn Clock>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    statuses: HashMap<TxId, TxStatus>,
+    keys: HashMap<String, TxId>,
+    submitted: VecDeque<(Instant, TxId, Option<String>)>, // oldest first
+}
+
+impl Tracker {
+    pub(crate) fn new(window: Duration, clock: Arc<dyn Clock>) -> Tracker {
+        Tracker {
+            next_id: AtomicU64::new(0),
+            window,
+            clock,
+            state: Mutex::new(State::default()),
+        }
+    }
+    /// Assigns an id to a new submission, or fails with the id already holding `key`.
+    pub(crate) fn begin(&self, key: Option<&str>) -> Result<TxId, TxError> {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        state.expire(now, self.window);
+        if let Some(&id) = key.and_then(|key| state.keys.get(key)) {
+            return Err(TxError::Duplicate(id));
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        state.statuses.insert(id, TxStatus::Pending);
+        if let Some(key) = key {
+            state.keys.insert(key.to_owned(), id);
+        }
+        state.submitted.push_back((now, id, key.map(str::to_owned)));
+        Ok(id)
+    }
+    /// The id the next transaction gets.
+    #[cfg(feature = "export")]
+    pub(crate) fn next_id(&self) -> TxId {
+        self.next_id.load(Ordering::Relaxed)
+    }
+    /// Hands out ids from `id` on, as when carrying on from an engine that got that far.
+    #[cfg(feature = "export")]
+    pub(crate) fn resume_at(&self, id: TxId) {
+        self.next_id.store(id, Ordering::Relaxed);
+    }
+    /// Forgets a submission that never reached a handler, so its key can be retried.
+    pub(crate) fn abandon(&self, id: TxId, key: Option<&str>) {
+        self.state.lock().unwrap().statuses.remove(&id);
+        self.release(key);
+    }
+    /// Lets `key` be used again, for a submission that failed without being applied.
+    pub(crate) fn release(&self, key: Option<&str>) {
+        if let Some(key) = key {
+            self.state.lock().unwrap().keys.remove(key);
+        }
+    }
+    pub(crate) fn finish(&self, id: TxId, result: &TxResult) {
+        let status = match result {
+            Ok(()) => TxStatus::Applied,
+            Err(err) => TxStatus::Rejected(err.clone()),
+        };
+        self.mark(id, status);
+    }
+    pub(crate) fn mark(&self, id: TxId, status: TxStatus) {
+        // a transaction outliving the window has been forgotten already
+        if let Some(held) = self.state.lock().unwrap().statuses.get_mut(&id) {
+            *held = status;
+        }
+    }
+    pub(crate) fn status(&self, id: TxId) -> Option<TxStatus> {
+        self.state.lock().unwrap().statuses.get(&id).cloned()
+    }
+}
+
+impl State {
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while let Some(&(submitted, id, _)) = self.submitted.front() {
+            if now.duration_since(submitted) < window {
+                break;
+            }
+            let (_, _, key) = self.submitted.pop_front().unwrap();
+            self.statuses.remove(&id);
+            if let Some(key) = key {
+                // the key may have been abandoned and taken by a newer submission
+                if self.keys.get(&key) == Some(&id) {
+                    self.keys.remove(&key);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::fmt;
+use std::io;
+
+#[cfg(any(feature = "sqlite", feature = "sled"))]
+use crate::EntryKind;
+use crate::{AccountId, Currency, HandleId, HistoryEntry, Money, TxId};
+
+/// What a `Storage` holds when the engine starts on it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stored {
+    /// Each open account's balance in every currency it holds.
+    pub balances: Vec<(AccountId, Currency, Money)>,
+    /// Above every account ever opened, so none is opened twice.
+    pub next_account: AccountId,
+}
+
+/// Where an engine built with `AptoneBuilder::persist_to` keeps its balances and history, in
+/// place of a transaction log. The handlers commit each transaction once applied, before its
+/// receipt resolves; a transfer between handlers commits both legs together, on the handler
+/// that debited it. Holds aren't kept, so they are gone after a restart.
+///
+/// A storage may hold back what a handler commits and write it out in one go when `flush`ed,
+/// which the handler does whenever it runs out of work.
+pub trait Storage: fmt::Debug + Send + Sync {
+    fn load(&self) -> io::Result<Stored>;
+    fn open_account(&self, account: AccountId, balance: Money) -> io::Result<()>;
+    /// Drops the account's balances, keeping its history.
+    fn close_account(&self, account: AccountId) -> io::Result<()>;
+    /// Adds what each of `entries` moved to its account's balance and appends them to the
+    /// history, all or nothing. Commits on different accounts may come in any order.
+    fn commit(
+        &self,
+        handler: HandleId,
+        tx_id: TxId,
+        entries: &[(AccountId, HistoryEntry)],
+    ) -> io::Result<()>;
+    /// Writes out what `handler` committed and is held back.
+    fn flush(&self, _handler: HandleId) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What kind of entry the backends record, named as in the REST API, and the other side of it:
+/// of a transfer, fee or exchange, the hold a capture posted or the transaction a reversal undid.
+#[cfg(any(feature = "sqlite", feature = "sled"))]
+pub(crate) fn describe(kind: EntryKind) -> (&'static str, Option<String>) {
+    match kind {
+        EntryKind::Deposit => ("deposit", None),
+        EntryKind::Withdraw => ("withdraw", None),
+        EntryKind::TransferOut { to } => ("transfer_out", Some(to.to_string())),
+        EntryKind::TransferIn { from } => ("transfer_in", Some(from.to_string())),
+        EntryKind::ExchangeOut { to } => ("exchange_out", Some(to.to_string())),
+        EntryKind::ExchangeIn { from } => ("exchange_in", Some(from.to_string())),
+        EntryKind::Capture { hold } => ("capture", Some(hold.number.to_string())),
+        EntryKind::Reversal { original } => ("reversal", Some(original.to_string())),
+        EntryKind::FeeOut { to } => ("fee_out", Some(to.to_string())),
+        EntryKind::FeeIn { from } => ("fee_in", Some(from.to_string())),
+    }
+}
+
+#[cfg(any(feature = "sqlite", feature = "sled"))]
+pub(crate) fn parse<T: std::str::FromStr>(text: &str) -> io::Result<T> {
+    text.parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("bad value {:?}", text)))
+}
@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::handler::TxHandler;
+
+// how often the handler threads are checked on
+const SUPERVISE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Watches the handler threads and restarts any that died of a panic, see `TxHandler::revive`,
+/// calling `retire` along the way to stop those left out of the pool. Stops when dropped.
+pub(crate) struct Supervisor {
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Supervisor {
+    pub(crate) fn start(
+        handlers: Arc<Vec<TxHandler>>,
+        retire: impl Fn() + Send + 'static,
+    ) -> Supervisor {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopped);
+        let thread = thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                for handler in handlers.iter() {
+                    handler.revive();
+                }
+                retire();
+                thread::park_timeout(SUPERVISE_INTERVAL);
+            }
+        });
+        Supervisor {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,27 @@
+//! The primitives guarding account state, swapped for loom's under `--cfg loom` so the critical
+//! sections between submitters and handlers can be model checked.
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::AtomicU32;
+#[cfg(loom)]
+pub(crate) use loom::sync::{Arc, Mutex, MutexGuard};
+
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::AtomicU32;
+#[cfg(not(loom))]
+pub(crate) use std::sync::{Arc, Mutex, MutexGuard};
+
+use tracing::warn;
+
+/// Locks account state whether or not a thread panicked holding it, clearing the poison. None of
+/// the `ServerData` and directory methods panic halfway through an update, so a panic under the
+/// lock leaves the state as it was before or after, and carrying on beats failing every later
+/// transaction on those accounts.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        warn!("recovering account state locked by a thread that panicked");
+        #[cfg(not(loom))]
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
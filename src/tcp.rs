@@ -0,0 +1,120 @@
+//! A line protocol over plain TCP, for quick integration tests and poking at an engine with
+//! netcat. Each request is one line, answered with `OK <result>` or `ERR <reason>` once done:
+//!
+//! - `OPEN [initial_balance]` answers with the new account's id
+//! - `DEPOSIT <account> <amount> [currency]` and `WITHDRAW <account> <amount> [currency]`
+//! - `TRANSFER <from> <to> <amount>`
+//! - `BALANCE <account> [currency]`
+//! - `TOTAL [currency]` answers with what every account holds in all
+//! - `QUIT` closes the connection
+//!
+//! Transactions answer with the balance of the account they were submitted on, as read right
+//! after they were applied. Commands are case insensitive.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+
+use tracing::debug;
+
+use crate::{AccountId, Aptone, Currency, Money, Tx, TxType};
+
+/// Serves `aptone` on `addr`, a thread per client, until accepting fails.
+pub fn serve(aptone: Arc<Aptone>, addr: impl ToSocketAddrs) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    loop {
+        let (stream, peer) = listener.accept()?;
+        let aptone = Arc::clone(&aptone);
+        thread::spawn(move || {
+            if let Err(err) = client(&aptone, stream) {
+                debug!(%err, %peer, "line client went away");
+            }
+        });
+    }
+}
+
+fn client(aptone: &Aptone, stream: TcpStream) -> io::Result<()> {
+    let writer = stream.try_clone()?;
+    session(aptone, BufReader::new(stream), writer)
+}
+
+/// Serves one client's requests read from `reader` on `aptone`, answering on `writer`, until
+/// `QUIT` or the end of the requests.
+pub fn session(aptone: &Aptone, reader: impl BufRead, mut writer: impl Write) -> io::Result<()> {
+    for line in reader.lines() {
+        let line = line?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some(command) = words.first() else {
+            continue;
+        };
+        if command.eq_ignore_ascii_case("quit") {
+            break;
+        }
+        match request(aptone, &command.to_ascii_uppercase(), &words[1..]) {
+            Ok(result) => writeln!(writer, "OK {}", result)?,
+            Err(reason) => writeln!(writer, "ERR {}", reason)?,
+        }
+    }
+    Ok(())
+}
+
+fn request(aptone: &Aptone, command: &str, args: &[&str]) -> Result<String, String> {
+    let account = |i: usize| -> Result<AccountId, String> {
+        let arg = args.get(i).ok_or("missing account")?;
+        arg.parse().map_err(|_| format!("invalid account: {}", arg))
+    };
+    let amount = |i: usize| -> Result<Money, String> {
+        let arg = args.get(i).ok_or("missing amount")?;
+        arg.parse::<Money>().map_err(|err| err.to_string())
+    };
+    let currency = |i: usize| -> Result<Currency, String> {
+        args.get(i).map_or(Ok(Currency::default()), |arg| {
+            arg.parse::<Currency>().map_err(|err| err.to_string())
+        })
+    };
+    match command {
+        "OPEN" => {
+            let initial_balance = match args.first() {
+                Some(_) => amount(0)?,
+                None => Money::ZERO,
+            };
+            let account = aptone
+                .open_account(initial_balance)
+                .map_err(|err| err.to_string())?;
+            Ok(account.to_string())
+        }
+        "DEPOSIT" | "WITHDRAW" => {
+            let tx_type = if command == "DEPOSIT" {
+                TxType::DEPOSIT
+            } else {
+                TxType::WITHDRAW
+            };
+            let tx = Tx::new(account(0)?, amount(1)?, tx_type).in_currency(currency(2)?);
+            apply(aptone, tx)
+        }
+        "TRANSFER" => {
+            let to = account(1)?;
+            let tx = Tx::new(account(0)?, amount(2)?, TxType::TRANSFER { to });
+            apply(aptone, tx)
+        }
+        "BALANCE" => Ok(aptone.get_balance_in(account(0)?, currency(1)?).to_string()),
+        "TOTAL" => {
+            let currency = currency(0)?;
+            let mut total = Money::ZERO;
+            for balances in aptone.snapshot().balances.values() {
+                let held = balances.get(&currency).copied().unwrap_or(Money::ZERO);
+                total = total.checked_add(held).ok_or("total overflows")?;
+            }
+            Ok(total.to_string())
+        }
+        _ => Err(format!("unknown command: {}", command)),
+    }
+}
+
+fn apply(aptone: &Aptone, tx: Tx) -> Result<String, String> {
+    let (account, currency) = (tx.account, tx.currency);
+    let receipt = aptone.submit_tx(tx).map_err(|err| err.to_string())?;
+    receipt.wait().map_err(|err| err.to_string())?;
+    Ok(aptone.get_balance_in(account, currency).to_string())
+}
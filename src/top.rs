@@ -0,0 +1,295 @@
+//! A live dashboard of a running engine in the terminal, as `aptone top` shows it: the queue
+//! depth and throughput of each handler, sparklines of the transactions finished and rejected
+//! per second, the accounts busiest lately and the latest rejections. Built from `Aptone::stats`
+//! and the events of `Aptone::subscribe`; `q`, Esc or Ctrl-C leaves it.
+//!
+//! Only built with the `tui` feature.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, Row, Sparkline, Table};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::channel::Receiver;
+use crate::{AccountId, Aptone, AptoneStats, HandlerStats, TxError, TxEvent, TxId, TxKind};
+
+// samples kept for the sparklines, at most as many as fit across
+const HISTORY: usize = 240;
+// samples the busiest accounts are counted over
+const HOT_WINDOW: usize = 10;
+const HOT_ACCOUNTS: usize = 10;
+const REJECTIONS: usize = 50;
+
+/// Shows the dashboard of `aptone` until left, sampling it every `refresh`. Takes over the
+/// terminal meanwhile, handing it back as it was.
+pub fn run(aptone: &Aptone, refresh: Duration) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let shown = show(&mut terminal, aptone, refresh);
+    ratatui::restore();
+    shown
+}
+
+fn show(terminal: &mut DefaultTerminal, aptone: &Aptone, refresh: Duration) -> io::Result<()> {
+    let mut dashboard = Dashboard::new(aptone);
+    let mut next = Instant::now();
+    loop {
+        let now = Instant::now();
+        if now >= next {
+            dashboard.sample(aptone);
+            terminal.draw(|frame| dashboard.draw(frame))?;
+            next = now + refresh;
+        }
+        if event::poll(next.saturating_duration_since(Instant::now()))? {
+            if let Event::Key(key) = event::read()? {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                let quit = matches!(key.code, KeyCode::Char('q') | KeyCode::Esc);
+                if key.kind == KeyEventKind::Press && (quit || ctrl_c) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// A rejection as listed.
+struct Rejection {
+    tx_id: TxId,
+    account: AccountId,
+    kind: TxKind,
+    error: TxError,
+}
+
+struct Dashboard {
+    events: Receiver<TxEvent>,
+    stats: AptoneStats,
+    // when `stats` were taken
+    sampled: Instant,
+    // transactions each handler finished per second since the sample before
+    rates: Vec<f64>,
+    // finished and rejected per second, oldest first
+    throughput: VecDeque<u64>,
+    rejected: VecDeque<u64>,
+    // the time each sample covered and the transactions on each account in it, oldest first
+    hot: VecDeque<(Duration, HashMap<AccountId, u64>)>,
+    // newest first
+    rejections: VecDeque<Rejection>,
+}
+
+impl Dashboard {
+    fn new(aptone: &Aptone) -> Dashboard {
+        let stats = aptone.stats();
+        Dashboard {
+            events: aptone.subscribe(),
+            sampled: Instant::now(),
+            rates: Vec::new(),
+            stats,
+            throughput: VecDeque::with_capacity(HISTORY),
+            rejected: VecDeque::with_capacity(HISTORY),
+            hot: VecDeque::with_capacity(HOT_WINDOW),
+            rejections: VecDeque::with_capacity(REJECTIONS),
+        }
+    }
+    // Takes in the stats and the events since the sample before.
+    fn sample(&mut self, aptone: &Aptone) {
+        let stats = aptone.stats();
+        // drawing and polling for keys make the samples drift apart from `refresh`
+        let sampled = Instant::now();
+        let since = sampled.duration_since(self.sampled);
+        let elapsed = since.as_secs_f64().max(f64::EPSILON);
+        let per_second = |count: u64| (count as f64 / elapsed).round() as u64;
+        let applied = stats.applied.saturating_sub(self.stats.applied);
+        let rejected = stats.rejected.saturating_sub(self.stats.rejected);
+        push(
+            &mut self.throughput,
+            per_second(applied + rejected),
+            HISTORY,
+        );
+        push(&mut self.rejected, per_second(rejected), HISTORY);
+        self.rates = stats
+            .handlers
+            .iter()
+            .enumerate()
+            .map(|(id, handler)| {
+                let before = self.stats.handlers.get(id).map_or(0, finished);
+                finished(handler).saturating_sub(before) as f64 / elapsed
+            })
+            .collect();
+        let mut touched = HashMap::new();
+        while let Ok(event) = self.events.try_recv() {
+            let tx = match event {
+                TxEvent::Applied { tx, .. } => tx,
+                TxEvent::Rejected { tx_id, tx, error } => {
+                    let rejection = Rejection {
+                        tx_id,
+                        account: tx.account,
+                        kind: tx.tx_type.kind(),
+                        error,
+                    };
+                    self.rejections.push_front(rejection);
+                    self.rejections.truncate(REJECTIONS);
+                    tx
+                }
+                _ => continue,
+            };
+            *touched.entry(tx.account).or_insert(0) += 1;
+            if let Some(to) = tx.tx_type.payee() {
+                *touched.entry(to).or_insert(0) += 1;
+            }
+        }
+        push(&mut self.hot, (since, touched), HOT_WINDOW);
+        self.stats = stats;
+        self.sampled = sampled;
+    }
+    fn draw(&self, frame: &mut Frame) {
+        let [header, body] =
+            Layout::vertical([Constraint::Length(3), Constraint::Fill(1)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(55), Constraint::Percentage(45)])
+                .areas(body);
+        let [handlers, throughput, rejected] = Layout::vertical([
+            Constraint::Fill(1),
+            Constraint::Length(6),
+            Constraint::Length(6),
+        ])
+        .areas(left);
+        let [hot, rejections] = Layout::vertical([
+            Constraint::Length(HOT_ACCOUNTS as u16 + 3),
+            Constraint::Fill(1),
+        ])
+        .areas(right);
+        self.draw_header(frame, header);
+        self.draw_handlers(frame, handlers);
+        let last = |samples: &VecDeque<u64>| samples.back().copied().unwrap_or(0);
+        let title = format!("finished/s: {}", last(&self.throughput));
+        draw_sparkline(frame, throughput, title, &self.throughput, Color::Green);
+        let title = format!("rejected/s: {}", last(&self.rejected));
+        draw_sparkline(frame, rejected, title, &self.rejected, Color::Red);
+        self.draw_hot(frame, hot);
+        self.draw_rejections(frame, rejections);
+    }
+    fn draw_header(&self, frame: &mut Frame, area: Rect) {
+        let stats = &self.stats;
+        let line = format!(
+            "up {}s | {} accounts, {} active | {} applied, {} rejected | latency p50 {:?} p99 {:?}",
+            stats.uptime.as_secs(),
+            stats.accounts,
+            stats.active_accounts,
+            stats.applied,
+            stats.rejected,
+            stats.latency.p50,
+            stats.latency.p99
+        );
+        let block = Block::default().borders(Borders::ALL).title("aptone top");
+        frame.render_widget(Line::from(line).centered(), block.inner(area));
+        frame.render_widget(block, area);
+    }
+    fn draw_handlers(&self, frame: &mut Frame, area: Rect) {
+        let rows = self.stats.handlers.iter().enumerate().map(|(id, handler)| {
+            let per_second = self.rates.get(id).copied().unwrap_or(0.0);
+            let state = match (handler.retired, handler.drained) {
+                (true, _) => "retired",
+                (false, true) => "drained",
+                (false, false) => "",
+            };
+            Row::new(vec![
+                id.to_string(),
+                handler.queue_depth.to_string(),
+                format!("{:.0}", per_second),
+                handler.accounts.to_string(),
+                handler.rejected.to_string(),
+                format!("{:?}", handler.latency.p99),
+                state.to_string(),
+            ])
+        });
+        let widths = [
+            Constraint::Length(7),
+            Constraint::Length(7),
+            Constraint::Length(8),
+            Constraint::Length(9),
+            Constraint::Length(9),
+            Constraint::Length(12),
+            Constraint::Fill(1),
+        ];
+        let header = [
+            "handler", "queue", "tx/s", "accounts", "rejected", "p99", "",
+        ];
+        let table = Table::new(rows, widths)
+            .header(Row::new(header).style(Style::new().add_modifier(Modifier::BOLD)))
+            .block(Block::default().borders(Borders::ALL).title("handlers"));
+        frame.render_widget(table, area);
+    }
+    fn draw_hot(&self, frame: &mut Frame, area: Rect) {
+        let mut counts: HashMap<AccountId, u64> = HashMap::new();
+        for (&account, &count) in self.hot.iter().flat_map(|(_, touched)| touched) {
+            *counts.entry(account).or_insert(0) += count;
+        }
+        let mut hottest: Vec<_> = counts.into_iter().collect();
+        hottest.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let window: Duration = self.hot.iter().map(|&(since, _)| since).sum();
+        let rows = hottest
+            .into_iter()
+            .take(HOT_ACCOUNTS)
+            .map(|(account, count)| Row::new(vec![account.to_string(), count.to_string()]));
+        let widths = [Constraint::Length(10), Constraint::Fill(1)];
+        let title = format!("hottest accounts, last {:.0?}", window);
+        let table = Table::new(rows, widths)
+            .header(Row::new(["account", "txs"]).style(Style::new().add_modifier(Modifier::BOLD)))
+            .block(Block::default().borders(Borders::ALL).title(title));
+        frame.render_widget(table, area);
+    }
+    fn draw_rejections(&self, frame: &mut Frame, area: Rect) {
+        let items = self.rejections.iter().map(|rejection| {
+            ListItem::new(format!(
+                "tx {} on {}, {:?}: {}",
+                rejection.tx_id, rejection.account, rejection.kind, rejection.error
+            ))
+        });
+        let list = List::new(items).style(Style::new().fg(Color::Red)).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("recent rejections"),
+        );
+        frame.render_widget(list, area);
+    }
+}
+
+fn draw_sparkline(
+    frame: &mut Frame,
+    area: Rect,
+    title: String,
+    samples: &VecDeque<u64>,
+    color: Color,
+) {
+    let block = Block::default().borders(Borders::ALL).title(title);
+    // the newest samples, as many as fit
+    let width = block.inner(area).width as usize;
+    let shown: Vec<u64> = samples
+        .iter()
+        .skip(samples.len().saturating_sub(width))
+        .copied()
+        .collect();
+    let sparkline = Sparkline::default()
+        .data(&shown)
+        .style(Style::new().fg(color))
+        .block(block);
+    frame.render_widget(sparkline, area);
+}
+
+fn finished(handler: &HandlerStats) -> u64 {
+    handler.applied + handler.rejected
+}
+
+// Appends `sample`, dropping the oldest past `capacity`.
+fn push<T>(samples: &mut VecDeque<T>, sample: T, capacity: usize) {
+    if samples.len() == capacity {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
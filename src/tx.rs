@@ -0,0 +1,156 @@
+use crate::{AccountId, Currency, Money, TxId};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Tx {
+    pub account: AccountId,
+    pub amount: Money,
+    /// What `amount` is in, and so which of the balances it applies to.
+    pub currency: Currency,
+    pub tx_type: TxType,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TxType {
+    DEPOSIT,
+    WITHDRAW,
+    TRANSFER {
+        to: AccountId,
+    },
+    /// Converts `amount` from the transaction's currency into `to` within the account, at the
+    /// engine's exchange rates.
+    EXCHANGE {
+        to: Currency,
+    },
+    /// Reserves `amount` of the account's available balance under `hold`, leaving its posted
+    /// balance alone.
+    AUTHORIZE {
+        hold: HoldId,
+    },
+    /// Takes what `hold` reserved off the posted balance; `amount` is ignored.
+    CAPTURE {
+        hold: HoldId,
+    },
+    /// Drops `hold`, making what it reserved available again; `amount` is ignored.
+    RELEASE {
+        hold: HoldId,
+    },
+    /// Undoes `original`, a deposit, withdrawal or capture this engine applied to the account,
+    /// unless it was reversed already. The engine fills in `amount` and `currency` from the
+    /// original when the reversal is submitted: `amount` is what the reversal adds to the
+    /// balance, negative when it takes back a deposit.
+    REVERSAL {
+        original: TxId,
+    },
+    /// Charges `amount` to the account as a fee collected in `to`, moving it like a transfer.
+    /// `FeeSchedule` adds these to the transactions it charges.
+    FEE {
+        to: AccountId,
+    },
+}
+
+/// The kinds of transaction, without what they carry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TxKind {
+    Deposit,
+    Withdraw,
+    Transfer,
+    Exchange,
+    Authorize,
+    Capture,
+    Release,
+    Reversal,
+    Fee,
+}
+
+/// Names a hold placed by `Aptone::authorize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HoldId {
+    pub account: AccountId,
+    /// Unique within the engine, and across restarts from a log.
+    pub number: u64,
+}
+
+impl TxType {
+    pub fn kind(self) -> TxKind {
+        match self {
+            TxType::DEPOSIT => TxKind::Deposit,
+            TxType::WITHDRAW => TxKind::Withdraw,
+            TxType::TRANSFER { .. } => TxKind::Transfer,
+            TxType::EXCHANGE { .. } => TxKind::Exchange,
+            TxType::AUTHORIZE { .. } => TxKind::Authorize,
+            TxType::CAPTURE { .. } => TxKind::Capture,
+            TxType::RELEASE { .. } => TxKind::Release,
+            TxType::REVERSAL { .. } => TxKind::Reversal,
+            TxType::FEE { .. } => TxKind::Fee,
+        }
+    }
+    /// The account a transfer or fee moves its amount into.
+    pub(crate) fn payee(self) -> Option<AccountId> {
+        match self {
+            TxType::TRANSFER { to } | TxType::FEE { to } => Some(to),
+            _ => None,
+        }
+    }
+    /// Whether the amount is the submitter's to give, rather than a hold's or the original's.
+    pub(crate) fn has_amount(self) -> bool {
+        !matches!(
+            self,
+            TxType::CAPTURE { .. } | TxType::RELEASE { .. } | TxType::REVERSAL { .. }
+        )
+    }
+}
+
+/// Every account `legs` touch, each once, in the order they first appear.
+pub(crate) fn accounts_of<'a>(legs: impl IntoIterator<Item = &'a Tx>) -> Vec<AccountId> {
+    let mut accounts = Vec::new();
+    for leg in legs {
+        for account in std::iter::once(leg.account).chain(leg.tx_type.payee()) {
+            if !accounts.contains(&account) {
+                accounts.push(account);
+            }
+        }
+    }
+    accounts
+}
+
+/// The account the fees among `legs` are all collected in, unless they have none or anything
+/// but a fee touches it. The handler owning it can credit them while the rest of the batch is
+/// applied elsewhere.
+pub(crate) fn collector_of(legs: &[Tx]) -> Option<AccountId> {
+    let mut collector = None;
+    for leg in legs {
+        if let TxType::FEE { to } = leg.tx_type {
+            if collector.is_some_and(|collector| collector != to) {
+                return None;
+            }
+            collector = Some(to);
+        }
+    }
+    let collector = collector?;
+    let touched = |leg: &Tx| match leg.tx_type {
+        TxType::FEE { .. } => leg.account == collector,
+        tx_type => leg.account == collector || tx_type.payee() == Some(collector),
+    };
+    (!legs.iter().any(touched)).then_some(collector)
+}
+
+impl Tx {
+    /// A transaction in the default currency.
+    pub fn new(account: AccountId, amount: Money, tx_type: TxType) -> Tx {
+        Tx {
+            account,
+            amount,
+            currency: Currency::default(),
+            tx_type,
+        }
+    }
+    pub fn in_currency(mut self, currency: Currency) -> Tx {
+        self.currency = currency;
+        self
+    }
+}
@@ -0,0 +1,355 @@
+//! Append-only transaction log, one accepted transaction, or opened or closed account, per line
+//! after a generation header:
+//!
+//! ```text
+//! GENERATION <n>
+//! OPEN <account> <balance>
+//! CLOSE <account>
+//! DEPOSIT <account> <amount> <currency>
+//! WITHDRAW <account> <amount> <currency>
+//! TRANSFER <account> <to> <amount> <currency>
+//! EXCHANGE <account> <amount> <currency> <to currency>
+//! AUTHORIZE <account> <amount> <currency> <hold>
+//! CAPTURE <account> <hold>
+//! RELEASE <account> <hold>
+//! REVERSAL <account> <original> <amount> <currency>
+//! FEE <account> <to> <amount> <currency>
+//! BATCH <legs>
+//! ```
+//!
+//! A batch is followed by its legs, one transaction per line, and replayed all or nothing. One
+//! cut short by a crash is ignored like a torn line.
+//!
+//! Entries written before currencies existed have none and are in the default currency, and
+//! accounts from before they were opened explicitly open with their first deposit.
+//!
+//! With checkpointing enabled the balances are periodically written to a snapshot next to the
+//! log and the log is truncated, so recovery only replays what came after the snapshot.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::snapshot::{self, Snapshot};
+use crate::{AccountId, Currency, HoldId, Money, ServerData, Tx, TxType};
+
+pub(crate) type Seq = u64;
+
+// room made up front for the legs of a batch, however many its header claims
+pub(crate) const MAX_RESERVED_LEGS: usize = 1024;
+
+/// One line of the log, or a batch with its legs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Tx(Tx),
+    Batch(Vec<Tx>),
+    Open { account: AccountId, balance: Money },
+    Close(AccountId),
+}
+
+pub(crate) struct Wal {
+    path: PathBuf,
+    file: File,
+    generation: u64,
+    next_seq: Seq,
+    checkpoint_interval: Option<u64>,
+    since_checkpoint: u64,
+    next_account: AccountId, // above every account ever opened, so none is opened twice
+    next_hold: u64,          // likewise for holds
+}
+
+impl Wal {
+    /// Opens the log for appending, creating it if needed, and restores the snapshot and logged
+    /// transactions into `data`.
+    pub(crate) fn open(
+        path: &Path,
+        checkpoint_interval: Option<u64>,
+        data: &mut ServerData,
+    ) -> io::Result<Wal> {
+        let snapshot = snapshot::read(&snapshot::path_for(path))?.unwrap_or_default();
+        let (generation, entries, complete) = if path.exists() {
+            read_log(BufReader::new(File::open(path)?))?
+        } else {
+            (0, Vec::new(), 0)
+        };
+        // crashed between writing a snapshot and truncating the log: it's all in the snapshot
+        let stale = generation < snapshot.generation;
+        let entries = if stale { Vec::new() } else { entries };
+
+        for &(account, currency, balance) in &snapshot.balances {
+            data.set_balance(account, currency, balance);
+        }
+        for &(hold, currency, amount) in &snapshot.holds {
+            data.set_hold(hold, currency, amount);
+        }
+        let mut next_account = snapshot.next_account;
+        let mut next_hold = snapshot.next_hold;
+        let mut count_holds = |legs: &[Tx]| {
+            for leg in legs {
+                if let TxType::AUTHORIZE { hold } = leg.tx_type {
+                    next_hold = next_hold.max(hold.number + 1);
+                }
+            }
+        };
+        // transactions rejected the first time around are rejected again, velocity limits aside
+        for tx in &snapshot.pending {
+            count_holds(std::slice::from_ref(tx));
+            let _ = data.apply(tx);
+        }
+        for entry in &entries {
+            match *entry {
+                Entry::Tx(ref tx) => count_holds(std::slice::from_ref(tx)),
+                Entry::Batch(ref legs) => count_holds(legs),
+                Entry::Open { account, .. } => next_account = next_account.max(account + 1),
+                Entry::Close(_) => {}
+            }
+            replay_entry(data, entry);
+        }
+        // in logs from before accounts were opened explicitly
+        if let Some((account, _, _)) = data.balances().max_by_key(|&(account, _, _)| account) {
+            next_account = next_account.max(account + 1);
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        // whatever a crash left of the last entry would run into the next one appended
+        file.set_len(complete)?;
+        let mut wal = Wal {
+            path: path.to_path_buf(),
+            file,
+            generation,
+            next_seq: 0,
+            checkpoint_interval,
+            since_checkpoint: entries.len() as u64,
+            next_account,
+            next_hold,
+        };
+        if stale {
+            wal.generation = snapshot.generation;
+            wal.truncate()?;
+        }
+        Ok(wal)
+    }
+    pub(crate) fn append(&mut self, tx: &Tx) -> io::Result<Seq> {
+        self.write(&encode(tx))?;
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        Ok(seq)
+    }
+    /// Writes a batch in one go. A batch isn't tracked by sequence number, see
+    /// `Aptone::submit_batch`.
+    pub(crate) fn append_batch(&mut self, legs: &[Tx]) -> io::Result<()> {
+        let mut lines = format!("BATCH {}\n", legs.len());
+        for leg in legs {
+            lines.push_str(&encode(leg));
+        }
+        self.write(&lines)
+    }
+    /// Unlike a transaction, an account is opened or closed as soon as it's logged.
+    pub(crate) fn log_open(&mut self, account: AccountId, balance: Money) -> io::Result<()> {
+        self.write(&format!("OPEN {} {}\n", account, balance))
+    }
+    pub(crate) fn log_close(&mut self, account: AccountId) -> io::Result<()> {
+        self.write(&format!("CLOSE {}\n", account))
+    }
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        self.since_checkpoint += 1;
+        Ok(())
+    }
+    /// The id to open the next account with, going by what was recovered.
+    pub(crate) fn next_account(&self) -> AccountId {
+        self.next_account
+    }
+    pub(crate) fn next_hold(&self) -> u64 {
+        self.next_hold
+    }
+    /// Has the OS write out what was appended, rather than hold it in its cache.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+    pub(crate) fn checkpoint_due(&self) -> bool {
+        self.checkpoint_interval
+            .is_some_and(|interval| self.since_checkpoint >= interval)
+    }
+    /// Writes `snapshot`, the balances and holds together with the logged transactions not
+    /// applied to them yet, and truncates the log. They have to come from one consistent view of
+    /// the handlers.
+    pub(crate) fn checkpoint(&mut self, mut snapshot: Snapshot) -> io::Result<()> {
+        snapshot.generation = self.generation + 1;
+        snapshot::write(&snapshot::path_for(&self.path), &snapshot)?;
+
+        self.generation = snapshot.generation;
+        self.truncate()?;
+        self.since_checkpoint = 0;
+        Ok(())
+    }
+    fn truncate(&mut self) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            writeln!(file, "GENERATION {}", self.generation)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Applies `entry` to `data` as recovery does, on the spot and in full.
+pub(crate) fn replay_entry(data: &mut ServerData, entry: &Entry) {
+    match *entry {
+        Entry::Tx(ref tx) => {
+            let _ = data.apply(tx);
+        }
+        Entry::Batch(ref legs) => {
+            let _ = data.apply_batch(legs);
+        }
+        Entry::Open { account, balance } => data.open_account(account, balance),
+        Entry::Close(account) => {
+            data.close_account(account);
+        }
+    }
+}
+
+/// Reads every complete entry of the log at `path`. A torn last line, as left behind by a crash
+/// in the middle of an append, is ignored.
+pub fn read(path: &Path) -> io::Result<Vec<Entry>> {
+    read_from(BufReader::new(File::open(path)?))
+}
+
+/// Like `read`, from whatever the log is held in.
+pub fn read_from(reader: impl BufRead) -> io::Result<Vec<Entry>> {
+    read_log(reader).map(|(_, entries, _)| entries)
+}
+
+// Also gives back the generation, and how many bytes the complete entries take up.
+fn read_log(mut reader: impl BufRead) -> io::Result<(u64, Vec<Entry>, u64)> {
+    let mut generation = 0;
+    let mut entries = Vec::new();
+    let mut complete = 0;
+    let mut line = String::new();
+    while let Some(mut len) = next_line(&mut reader, &mut line)? {
+        if let Some(number) = line.trim_end().strip_prefix("GENERATION ") {
+            generation = number.parse().map_err(|_| invalid(&line))?;
+        } else if let Some(count) = line.trim_end().strip_prefix("BATCH ") {
+            let count: usize = count.parse().map_err(|_| invalid(&line))?;
+            let mut legs = Vec::with_capacity(count.min(MAX_RESERVED_LEGS));
+            while legs.len() < count {
+                match next_line(&mut reader, &mut line)? {
+                    Some(leg_len) => len += leg_len,
+                    None => return Ok((generation, entries, complete)),
+                }
+                legs.push(decode(line.trim_end())?);
+            }
+            entries.push(Entry::Batch(legs));
+        } else {
+            entries.push(decode_entry(line.trim_end())?);
+        }
+        complete += len;
+    }
+    Ok((generation, entries, complete))
+}
+
+// Reads the next line into `line` and gives back its length, unless the log ends before it does.
+pub(crate) fn next_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<Option<u64>> {
+    line.clear();
+    let len = reader.read_line(line)?;
+    Ok((len > 0 && line.ends_with('\n')).then_some(len as u64))
+}
+
+pub(crate) fn encode(tx: &Tx) -> String {
+    match tx.tx_type {
+        TxType::DEPOSIT => format!("DEPOSIT {} {} {}\n", tx.account, tx.amount, tx.currency),
+        TxType::WITHDRAW => format!("WITHDRAW {} {} {}\n", tx.account, tx.amount, tx.currency),
+        TxType::TRANSFER { to } => format!(
+            "TRANSFER {} {} {} {}\n",
+            tx.account, to, tx.amount, tx.currency
+        ),
+        TxType::EXCHANGE { to } => format!(
+            "EXCHANGE {} {} {} {}\n",
+            tx.account, tx.amount, tx.currency, to
+        ),
+        TxType::AUTHORIZE { hold } => format!(
+            "AUTHORIZE {} {} {} {}\n",
+            tx.account, tx.amount, tx.currency, hold.number
+        ),
+        TxType::CAPTURE { hold } => format!("CAPTURE {} {}\n", tx.account, hold.number),
+        TxType::RELEASE { hold } => format!("RELEASE {} {}\n", tx.account, hold.number),
+        TxType::REVERSAL { original } => format!(
+            "REVERSAL {} {} {} {}\n",
+            tx.account, original, tx.amount, tx.currency
+        ),
+        TxType::FEE { to } => format!("FEE {} {} {} {}\n", tx.account, to, tx.amount, tx.currency),
+    }
+}
+
+pub(crate) fn decode_entry(line: &str) -> io::Result<Entry> {
+    let account =
+        |field: &str| -> io::Result<AccountId> { field.parse().map_err(|_| invalid(line)) };
+    match line.split(' ').collect::<Vec<_>>()[..] {
+        ["OPEN", id, balance] => Ok(Entry::Open {
+            account: account(id)?,
+            balance: balance.parse().map_err(|_| invalid(line))?,
+        }),
+        ["CLOSE", id] => Ok(Entry::Close(account(id)?)),
+        _ => decode(line).map(Entry::Tx),
+    }
+}
+
+pub(crate) fn decode(line: &str) -> io::Result<Tx> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    let field = |i: usize| fields.get(i).ok_or_else(|| invalid(line));
+    let number =
+        |i: usize| -> io::Result<AccountId> { field(i)?.parse().map_err(|_| invalid(line)) };
+    let amount = |i: usize| -> io::Result<Money> { field(i)?.parse().map_err(|_| invalid(line)) };
+    let currency =
+        |i: usize| -> io::Result<Currency> { field(i)?.parse().map_err(|_| invalid(line)) };
+    // without one the entry predates currencies
+    let last = |i: usize| match fields.len() {
+        len if len > i => currency(i),
+        _ => Ok(Currency::default()),
+    };
+    let hold = |i: usize| -> io::Result<HoldId> {
+        Ok(HoldId {
+            account: number(1)?,
+            number: field(i)?.parse().map_err(|_| invalid(line))?,
+        })
+    };
+    let tx = match (fields[0], fields.len()) {
+        ("DEPOSIT", 3 | 4) => {
+            Tx::new(number(1)?, amount(2)?, TxType::DEPOSIT).in_currency(last(3)?)
+        }
+        ("WITHDRAW", 3 | 4) => {
+            Tx::new(number(1)?, amount(2)?, TxType::WITHDRAW).in_currency(last(3)?)
+        }
+        ("TRANSFER", 4 | 5) => Tx::new(number(1)?, amount(3)?, TxType::TRANSFER { to: number(2)? })
+            .in_currency(last(4)?),
+        ("EXCHANGE", 5) => Tx::new(
+            number(1)?,
+            amount(2)?,
+            TxType::EXCHANGE { to: currency(4)? },
+        )
+        .in_currency(currency(3)?),
+        ("AUTHORIZE", 5) => Tx::new(number(1)?, amount(2)?, TxType::AUTHORIZE { hold: hold(4)? })
+            .in_currency(currency(3)?),
+        ("CAPTURE", 3) => Tx::new(number(1)?, Money::ZERO, TxType::CAPTURE { hold: hold(2)? }),
+        ("RELEASE", 3) => Tx::new(number(1)?, Money::ZERO, TxType::RELEASE { hold: hold(2)? }),
+        ("REVERSAL", 5) => {
+            let original = field(2)?.parse().map_err(|_| invalid(line))?;
+            Tx::new(number(1)?, amount(3)?, TxType::REVERSAL { original }).in_currency(currency(4)?)
+        }
+        ("FEE", 5) => Tx::new(number(1)?, amount(3)?, TxType::FEE { to: number(2)? })
+            .in_currency(currency(4)?),
+        _ => return Err(invalid(line)),
+    };
+    Ok(tx)
+}
+
+pub(crate) fn invalid(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad log entry: {:?}", line.trim_end()),
+    )
+}
@@ -0,0 +1,83 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::alerts::Alerts;
+use crate::{HandleId, TxId};
+
+// how many times the queues are looked over within the deadline
+const CHECKS_PER_DEADLINE: u32 = 4;
+
+/// A transaction found queued past the deadline.
+pub(crate) struct Overdue {
+    pub(crate) handler: HandleId,
+    pub(crate) tx_id: TxId,
+    pub(crate) waited: Duration,
+}
+
+/// Looks at the transactions `overdue` finds queued past `deadline` a few times within it,
+/// raising `Alert::StuckTx` for each the first time it is seen, then has `reroute`, if given,
+/// hand them on to idle handlers. Keeps a handler wedged on one transaction from sitting on the
+/// rest unnoticed. Stops when dropped.
+pub(crate) struct Watchdog {
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Watchdog {
+    pub(crate) fn start<O, R>(
+        deadline: Duration,
+        alerts: Arc<Alerts>,
+        overdue: O,
+        reroute: Option<R>,
+    ) -> Watchdog
+    where
+        O: Fn() -> Vec<Overdue> + Send + 'static,
+        R: Fn() + Send + 'static,
+    {
+        let interval = (deadline / CHECKS_PER_DEADLINE).max(Duration::from_millis(1));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopped);
+        let thread = thread::spawn(move || {
+            let mut flagged = HashSet::new();
+            while !stop.load(Ordering::SeqCst) {
+                let overdue = overdue();
+                let queued: HashSet<TxId> = overdue.iter().map(|stuck| stuck.tx_id).collect();
+                flagged.retain(|tx_id| queued.contains(tx_id));
+                for Overdue {
+                    handler,
+                    tx_id,
+                    waited,
+                } in overdue
+                {
+                    if flagged.insert(tx_id) {
+                        warn!(tx_id, handler, ?waited, "tx stuck on its queue");
+                        alerts.stuck(tx_id, handler, waited);
+                    }
+                }
+                if let Some(reroute) = &reroute {
+                    reroute();
+                }
+                thread::park_timeout(interval);
+            }
+        });
+        Watchdog {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, error, warn};
+
+use crate::channel::{Receiver, RecvTimeoutError};
+use crate::{Tx, TxEvent, TxId, TxType};
+
+// how often the threads look up from waiting to check whether they should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const TIMEOUT: Duration = Duration::from_secs(5);
+const BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Where a webhook is: only plain `http://` URLs are supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct WebhookUrl {
+    url: String,
+    host: String, // with the port, if one was given
+    path: String,
+}
+
+impl WebhookUrl {
+    pub(crate) fn parse(url: &str) -> Option<WebhookUrl> {
+        let rest = url.strip_prefix("http://")?;
+        let (host, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        (!host.is_empty()).then(|| WebhookUrl {
+            url: url.to_string(),
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+/// POSTs every applied transaction, as JSON, to each webhook until it answers with a 2xx,
+/// backing off between failed tries. With a directory, each webhook's undelivered events are
+/// kept in a file there and delivered after a restart, so a webhook may see an event twice but
+/// never misses one. Stops when dropped.
+pub(crate) struct Outbox {
+    stopped: Arc<AtomicBool>,
+    webhooks: Vec<Arc<Webhook>>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+struct Webhook {
+    url: WebhookUrl,
+    pending: Mutex<Pending>,
+    ready: Condvar, // an event was queued, or the outbox is stopping
+}
+
+struct Pending {
+    events: VecDeque<String>, // oldest first
+    file: Option<OutboxFile>,
+}
+
+// The events of one webhook, one per line, from `offset` on not delivered yet.
+struct OutboxFile {
+    file: File,
+    offset_path: PathBuf,
+    offset: u64,
+}
+
+impl Outbox {
+    pub(crate) fn start(
+        urls: &[String],
+        dir: Option<&Path>,
+        events: Receiver<TxEvent>,
+    ) -> io::Result<Outbox> {
+        let mut webhooks = Vec::with_capacity(urls.len());
+        for url in urls {
+            let Some(url) = WebhookUrl::parse(url) else {
+                error!(url, "skipping a webhook that isn't a plain http:// URL");
+                continue;
+            };
+            let (events, file) = match dir {
+                Some(dir) => {
+                    let (events, file) = OutboxFile::open(dir, &url)?;
+                    (events, Some(file))
+                }
+                None => (VecDeque::new(), None),
+            };
+            webhooks.push(Arc::new(Webhook {
+                url,
+                pending: Mutex::new(Pending { events, file }),
+                ready: Condvar::new(),
+            }));
+        }
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut threads = Vec::with_capacity(webhooks.len() + 1);
+        for webhook in &webhooks {
+            let (webhook, stop) = (Arc::clone(webhook), Arc::clone(&stopped));
+            threads.push(thread::spawn(move || webhook.deliver(&stop)));
+        }
+        let (intake, stop) = (webhooks.clone(), Arc::clone(&stopped));
+        threads.push(thread::spawn(move || {
+            let take = |event| {
+                if let TxEvent::Applied { tx_id, tx } = event {
+                    let event = to_json(tx_id, &tx);
+                    for webhook in &intake {
+                        webhook.push(event.clone());
+                    }
+                }
+            };
+            while !stop.load(Ordering::SeqCst) {
+                match events.recv_timeout(POLL_INTERVAL) {
+                    Ok(event) => take(event),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            // what was applied before the stop still goes in the files
+            events.try_iter().for_each(take);
+        }));
+        Ok(Outbox {
+            stopped,
+            webhooks,
+            threads,
+        })
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        for webhook in &self.webhooks {
+            webhook.ready.notify_all();
+        }
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Webhook {
+    fn push(&self, event: String) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(file) = &mut pending.file {
+            if let Err(err) = writeln!(file.file, "{}", event) {
+                // still delivered, unless the engine stops first
+                warn!(%err, url = self.url.url, "failed to keep webhook event");
+            }
+        }
+        pending.events.push_back(event);
+        self.ready.notify_one();
+    }
+    // Delivers the oldest event over and over until it goes through, then the next one.
+    fn deliver(&self, stop: &AtomicBool) {
+        let mut backoff = BACKOFF;
+        while !stop.load(Ordering::SeqCst) {
+            let pending = self.pending.lock().unwrap();
+            let Some(event) = pending.events.front().cloned() else {
+                drop(self.ready.wait_timeout(pending, POLL_INTERVAL).unwrap());
+                continue;
+            };
+            drop(pending);
+            match post(&self.url, &event) {
+                Ok(()) => {
+                    backoff = BACKOFF;
+                    self.pending.lock().unwrap().delivered(&self.url);
+                }
+                Err(err) => {
+                    debug!(%err, url = self.url.url, ?backoff, "webhook delivery failed");
+                    // woken early only to stop
+                    let pending = self.pending.lock().unwrap();
+                    drop(self.ready.wait_timeout(pending, backoff).unwrap());
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+impl Pending {
+    fn delivered(&mut self, url: &WebhookUrl) {
+        let Some(event) = self.events.pop_front() else {
+            return;
+        };
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        file.offset += event.len() as u64 + 1;
+        // once everything is delivered the file starts over
+        let moved = if self.events.is_empty() {
+            file.file.set_len(0).map(|()| file.offset = 0)
+        } else {
+            Ok(())
+        };
+        let saved = moved.and_then(|()| fs::write(&file.offset_path, file.offset.to_string()));
+        if let Err(err) = saved {
+            warn!(%err, url = url.url, "failed to note a delivered webhook event");
+        }
+    }
+}
+
+impl OutboxFile {
+    // Opens the file of `url` in `dir`, giving back the events in it not delivered yet.
+    fn open(dir: &Path, url: &WebhookUrl) -> io::Result<(VecDeque<String>, OutboxFile)> {
+        fs::create_dir_all(dir)?;
+        // FNV-1a, to name the files after the URL the same way on every build
+        let hash = url
+            .url
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+            });
+        let path = dir.join(format!("webhook-{:016x}.log", hash));
+        let offset_path = path.with_extension("offset");
+        // an unreadable offset delivers everything again
+        let offset = fs::read_to_string(&offset_path)
+            .ok()
+            .and_then(|offset| offset.trim().parse().ok())
+            .unwrap_or(0);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let offset = if offset > contents.len() as u64 {
+            0
+        } else {
+            offset
+        };
+        let events = contents[offset as usize..]
+            .lines()
+            .collect::<io::Result<VecDeque<String>>>()?;
+        let file = OutboxFile {
+            file,
+            offset_path,
+            offset,
+        };
+        Ok((events, file))
+    }
+}
+
+fn post(url: &WebhookUrl, body: &str) -> io::Result<()> {
+    let host = url.host.as_str();
+    let addrs = if host.contains(':') {
+        host.to_socket_addrs()
+    } else {
+        (host, 80).to_socket_addrs()
+    };
+    let addr = addrs?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "webhook host has no address"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        url.path,
+        url.host,
+        body.len(),
+        body
+    )?;
+    let mut status_line = String::new();
+    BufReader::new(&stream).read_line(&mut status_line)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        status => Err(io::Error::other(format!(
+            "webhook answered {}",
+            status.unwrap_or("nothing")
+        ))),
+    }
+}
+
+// Only numbers and currency codes go in, so nothing needs escaping.
+fn to_json(tx_id: TxId, tx: &Tx) -> String {
+    let (kind, extra) = match tx.tx_type {
+        TxType::DEPOSIT => ("deposit", String::new()),
+        TxType::WITHDRAW => ("withdraw", String::new()),
+        TxType::TRANSFER { to } => ("transfer", format!(",\"to\":{}", to)),
+        TxType::EXCHANGE { to } => ("exchange", format!(",\"to\":\"{}\"", to)),
+        TxType::AUTHORIZE { hold } => ("authorize", format!(",\"hold\":{}", hold.number)),
+        TxType::CAPTURE { hold } => ("capture", format!(",\"hold\":{}", hold.number)),
+        TxType::RELEASE { hold } => ("release", format!(",\"hold\":{}", hold.number)),
+        TxType::REVERSAL { original } => ("reversal", format!(",\"original\":{}", original)),
+        TxType::FEE { to } => ("fee", format!(",\"to\":{}", to)),
+    };
+    format!(
+        "{{\"tx_id\":{},\"type\":\"{}\",\"account\":{},\"amount\":\"{}\",\"currency\":\"{}\"{}}}",
+        tx_id, kind, tx.account, tx.amount, tx.currency, extra
+    )
+}
@@ -0,0 +1,168 @@
+use std::fmt;
+#[cfg(feature = "wire")]
+use std::io::{self, Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{AccountId, Tx, TxId};
+
+/// The version of the wire format this build writes, and the only one it reads. Bumped whenever
+/// a `WireMessage` changes in a way older builds would misread.
+pub const WIRE_VERSION: u32 = 1;
+
+/// Opens every message `WireMessage::encode` writes, ahead of its version.
+#[cfg(feature = "wire")]
+pub const WIRE_MAGIC: [u8; 4] = *b"APTW";
+
+// the longest message `WireMessage::read` takes, so a garbled length can't exhaust memory
+#[cfg(feature = "wire")]
+const MAX_FRAME: usize = 16 << 20;
+
+/// What a handler is sent, in a form that can leave the process: for network transports, for
+/// persisting queues and for golden files. Reading one of another `WIRE_VERSION` through serde
+/// fails, rather than guessing at what it meant.
+///
+/// With the `wire` feature, `encode` and `decode` give it a binary form for producers deployed
+/// apart from the engine: `WIRE_MAGIC`, the version as a big-endian `u32`, then the body in
+/// postcard, whose format is stable. Variants and fields are only ever added in a new version,
+/// and a build decodes every version up to its own, so producers can be upgraded before or after
+/// the engine they feed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Unchecked")]
+pub struct WireMessage {
+    version: u32,
+    pub body: WireBody,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WireBody {
+    /// A transaction to apply, with the legs after it if it opens a batch.
+    NewTx { tx_id: TxId, tx: Tx, batch: Vec<Tx> },
+    /// Holds `account` until a transfer debited on another handler credits it.
+    Barrier { account: AccountId },
+    /// Stops the handler once it is through with what was sent before.
+    Terminate,
+}
+
+impl WireMessage {
+    pub fn new(body: WireBody) -> WireMessage {
+        WireMessage {
+            version: WIRE_VERSION,
+            body,
+        }
+    }
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+#[cfg(feature = "wire")]
+impl WireMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = WIRE_MAGIC.to_vec();
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        postcard::to_extend(&self.body, bytes).expect("writing to a Vec can't fail")
+    }
+    pub fn decode(bytes: &[u8]) -> Result<WireMessage, WireError> {
+        let body = bytes.strip_prefix(&WIRE_MAGIC).ok_or(WireError::NotWire)?;
+        let (version, body) = body.split_first_chunk().ok_or(WireError::NotWire)?;
+        let version = u32::from_be_bytes(*version);
+        // the decoders of older versions go here, converting to the current body
+        let body = match version {
+            WIRE_VERSION => postcard::take_from_bytes::<WireBody>(body),
+            _ => return Err(WireError::Version(UnsupportedVersion(version))),
+        };
+        match body {
+            Ok((body, [])) => Ok(WireMessage { version, body }),
+            Ok(_) => Err(WireError::Malformed("trailing bytes".to_string())),
+            Err(err) => Err(WireError::Malformed(err.to_string())),
+        }
+    }
+    /// Writes the message to a stream, after its length as a big-endian `u32`.
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let bytes = self.encode();
+        writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        writer.write_all(&bytes)
+    }
+    /// Reads a message `write` wrote.
+    pub fn read(reader: &mut impl Read) -> io::Result<WireMessage> {
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME {
+            let err = format!("message of {} bytes is over {}", len, MAX_FRAME);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+        }
+        let mut bytes = vec![0; len];
+        reader.read_exact(&mut bytes)?;
+        WireMessage::decode(&bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+impl From<WireBody> for WireMessage {
+    fn from(body: WireBody) -> WireMessage {
+        WireMessage::new(body)
+    }
+}
+
+// A message as read, before its version is checked.
+#[derive(Deserialize)]
+struct Unchecked {
+    version: u32,
+    body: WireBody,
+}
+
+/// Returned for a message of another `WIRE_VERSION`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedVersion(pub u32);
+
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "wire version {} is not supported, only {}",
+            self.0, WIRE_VERSION
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+/// Why `WireMessage::decode` failed.
+#[cfg(feature = "wire")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    /// The bytes don't open with `WIRE_MAGIC` and a version.
+    NotWire,
+    Version(UnsupportedVersion),
+    /// The body isn't one of its version.
+    Malformed(String),
+}
+
+#[cfg(feature = "wire")]
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::NotWire => write!(f, "not a wire message"),
+            WireError::Version(err) => err.fmt(f),
+            WireError::Malformed(reason) => write!(f, "malformed wire message: {}", reason),
+        }
+    }
+}
+
+#[cfg(feature = "wire")]
+impl std::error::Error for WireError {}
+
+impl TryFrom<Unchecked> for WireMessage {
+    type Error = UnsupportedVersion;
+
+    fn try_from(message: Unchecked) -> Result<WireMessage, UnsupportedVersion> {
+        if message.version != WIRE_VERSION {
+            return Err(UnsupportedVersion(message.version));
+        }
+        Ok(WireMessage {
+            version: message.version,
+            body: message.body,
+        })
+    }
+}
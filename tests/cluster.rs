@@ -0,0 +1,287 @@
+//! A cluster has to come out with the same balances on every node however its nodes are stopped
+//! and started again: whoever leads, whatever a leader wrote that never committed, and whether
+//! a node catches up from its log, its snapshot or the leader's.
+
+#![cfg(feature = "cluster")]
+
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use aptone::cluster::{ClusterConfig, ClusterError, ClusterNode, NodeId};
+use aptone::{AccountId, Config, Money, Tx, TxType};
+
+fn money(units: i128) -> Money {
+    Money::from_minor(units * 10i128.pow(Money::SCALE))
+}
+
+// Polls `done` until it holds, failing the test if it doesn't within a while.
+fn eventually(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {what}");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Nodes on loopback, each keeping its log in a directory of its own, stopped and started by
+// position.
+struct Cluster {
+    dir: PathBuf,
+    addrs: Vec<(NodeId, SocketAddr)>,
+    nodes: Vec<Option<ClusterNode>>,
+    snapshot_every: u64,
+}
+
+impl Cluster {
+    fn new(name: &str, size: usize, snapshot_every: u64) -> Cluster {
+        let dir = std::env::temp_dir().join(format!("aptone-cluster-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let addrs = (1..=size as NodeId)
+            .map(|id| {
+                // a port free now, for the node to listen on from its first start on
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                (id, listener.local_addr().unwrap())
+            })
+            .collect();
+        let mut cluster = Cluster {
+            dir,
+            addrs,
+            nodes: (0..size).map(|_| None).collect(),
+            snapshot_every,
+        };
+        for node in 0..size {
+            cluster.start(node);
+        }
+        cluster
+    }
+    fn start(&mut self, node: usize) {
+        let (id, addr) = self.addrs[node];
+        let peers = self.addrs.iter().filter(|&&(peer, _)| peer != id);
+        let mut config = ClusterConfig::new(id, addr, peers.copied().collect());
+        config.dir = Some(self.dir.join(id.to_string()));
+        config.election_timeout = Duration::from_millis(150);
+        config.heartbeat_interval = Duration::from_millis(20);
+        config.commit_timeout = Duration::from_secs(1);
+        config.snapshot_every = self.snapshot_every;
+        self.nodes[node] = Some(ClusterNode::start(config, Config::default()).unwrap());
+    }
+    fn stop(&mut self, node: usize) {
+        self.nodes[node] = None;
+    }
+    fn node(&self, node: usize) -> &ClusterNode {
+        self.nodes[node].as_ref().expect("a running node")
+    }
+    fn running(&self) -> impl Iterator<Item = &ClusterNode> {
+        self.nodes.iter().flatten()
+    }
+    // The position of the node leading once the running nodes all follow it.
+    fn leader(&self) -> usize {
+        let mut leader = None;
+        eventually("a leader", || {
+            leader = self.nodes.iter().position(|node| {
+                node.as_ref().is_some_and(|node| {
+                    let id = node.id();
+                    node.is_leader() && self.running().all(|other| other.leader() == Some(id))
+                })
+            });
+            leader.is_some()
+        });
+        leader.unwrap()
+    }
+    // The balances of `accounts` on every running node, once they all applied as much as the
+    // leader did.
+    fn balances(&self, accounts: &[AccountId]) -> Vec<Vec<Money>> {
+        let leader = self.node(self.leader());
+        // answered once the leader applied all that came before, a new leader's log included
+        leader.open_account(Money::ZERO).unwrap();
+        eventually("the nodes to catch up", || {
+            let applied = leader.applied();
+            self.running().all(|node| node.applied() == applied)
+        });
+        let balances = self.running().map(|node| {
+            let aptone = node.aptone();
+            accounts
+                .iter()
+                .map(|&account| aptone.get_balance(account))
+                .collect()
+        });
+        balances.collect()
+    }
+    // Opens `count` accounts holding 100 each and moves money between them through the leader.
+    fn transfers(&self, accounts: &mut Vec<AccountId>, count: usize) {
+        let leader = self.node(self.leader());
+        while accounts.len() < 4 {
+            accounts.push(leader.open_account(money(100)).unwrap());
+        }
+        for i in 0..count {
+            let (from, to) = (accounts[i % 4], accounts[(i + 1) % 4]);
+            let transfer = Tx::new(from, money(i as i128 % 7 + 1), TxType::TRANSFER { to });
+            leader.submit(transfer).unwrap();
+        }
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        self.nodes.clear();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Every row the same as the first.
+fn identical(balances: &[Vec<Money>]) -> bool {
+    balances.iter().all(|row| *row == balances[0])
+}
+
+#[test]
+fn one_leader_is_elected_and_every_node_applies_the_same() {
+    let cluster = Cluster::new("elected", 3, 10_000);
+    let leader = cluster.leader();
+    let mut accounts = Vec::new();
+    cluster.transfers(&mut accounts, 20);
+    let balances = cluster.balances(&accounts);
+    assert_eq!(balances.len(), 3);
+    assert!(identical(&balances), "{balances:?}");
+    let total: Money = balances[0].iter().copied().sum();
+    assert_eq!(total, money(400));
+    let leaders = cluster.running().filter(|node| node.is_leader()).count();
+    assert_eq!(leaders, 1);
+    let follower = cluster.node((leader + 1) % 3);
+    let deposit = Tx::new(accounts[0], money(1), TxType::DEPOSIT);
+    let leader_id = cluster.node(leader).id();
+    assert_eq!(
+        follower.submit(deposit),
+        Err(ClusterError::NotLeader(Some(leader_id)))
+    );
+}
+
+#[test]
+fn nodes_stopped_and_started_again_come_back_from_their_logs() {
+    let mut cluster = Cluster::new("restarted", 3, 10_000);
+    let mut accounts = Vec::new();
+    cluster.transfers(&mut accounts, 10);
+    let before = cluster.balances(&accounts)[0].clone();
+    // the others carry on without the leader, then it catches up on what it missed
+    let leader = cluster.leader();
+    cluster.stop(leader);
+    assert_ne!(cluster.leader(), leader);
+    cluster.transfers(&mut accounts, 10);
+    cluster.start(leader);
+    let balances = cluster.balances(&accounts);
+    assert_eq!(balances.len(), 3);
+    assert!(identical(&balances), "{balances:?}");
+    assert_ne!(balances[0], before);
+    // all of them at once, each applying its log to an engine started afresh
+    let after = balances[0].clone();
+    for node in 0..3 {
+        cluster.stop(node);
+    }
+    for node in 0..3 {
+        cluster.start(node);
+    }
+    let balances = cluster.balances(&accounts);
+    assert!(identical(&balances), "{balances:?}");
+    assert_eq!(balances[0], after);
+}
+
+#[test]
+fn entries_that_never_committed_are_overwritten() {
+    let mut cluster = Cluster::new("overwritten", 3, 10_000);
+    let mut accounts = Vec::new();
+    cluster.transfers(&mut accounts, 5);
+    let before = cluster.balances(&accounts)[0].clone();
+    // the leader alone takes a deposit it can't commit
+    let old = cluster.leader();
+    let followers: Vec<usize> = (0..3).filter(|&node| node != old).collect();
+    for &node in &followers {
+        cluster.stop(node);
+    }
+    let lost = Tx::new(accounts[0], money(1000), TxType::DEPOSIT);
+    assert_eq!(cluster.node(old).submit(lost), Err(ClusterError::Timeout));
+    // the others elect one of them, whose log has something else there
+    cluster.stop(old);
+    for &node in &followers {
+        cluster.start(node);
+    }
+    let new = cluster.leader();
+    assert_ne!(new, old);
+    let kept = Tx::new(accounts[1], money(5), TxType::DEPOSIT);
+    cluster.node(new).submit(kept).unwrap();
+    // back again, the old leader drops its deposit for the new leader's log
+    cluster.start(old);
+    let balances = cluster.balances(&accounts);
+    assert_eq!(balances.len(), 3);
+    assert!(identical(&balances), "{balances:?}");
+    let mut expected = before;
+    expected[1] += money(5);
+    assert_eq!(balances[0], expected);
+}
+
+#[test]
+fn an_earlier_terms_entry_commits_with_the_new_leaders_first() {
+    let mut cluster = Cluster::new("carried", 3, 10_000);
+    let mut accounts = Vec::new();
+    cluster.transfers(&mut accounts, 5);
+    let before = cluster.balances(&accounts)[0].clone();
+    let old = cluster.leader();
+    let followers: Vec<usize> = (0..3).filter(|&node| node != old).collect();
+    for &node in &followers {
+        cluster.stop(node);
+    }
+    let carried = Tx::new(accounts[2], money(30), TxType::DEPOSIT);
+    assert_eq!(
+        cluster.node(old).submit(carried),
+        Err(ClusterError::Timeout)
+    );
+    // with the deposit only in its log, the old leader is the only one that can be elected
+    // with one follower, and commits it along with the entry it opens its new term with
+    cluster.stop(old);
+    cluster.start(old);
+    cluster.start(followers[0]);
+    assert_eq!(cluster.leader(), old);
+    let mut expected = before;
+    expected[2] += money(30);
+    let balances = cluster.balances(&accounts);
+    assert_eq!(balances.len(), 2);
+    assert!(identical(&balances), "{balances:?}");
+    assert_eq!(balances[0], expected);
+    cluster.start(followers[1]);
+    let balances = cluster.balances(&accounts);
+    assert_eq!(balances.len(), 3);
+    assert!(identical(&balances), "{balances:?}");
+    assert_eq!(balances[0], expected);
+}
+
+#[test]
+fn a_follower_behind_the_snapshot_is_sent_it() {
+    let mut cluster = Cluster::new("snapshot", 3, 8);
+    let mut accounts = Vec::new();
+    cluster.transfers(&mut accounts, 5);
+    let leader = cluster.leader();
+    let behind = (leader + 1) % 3;
+    cluster.stop(behind);
+    // enough for the leader to cut what the follower is missing from its log
+    cluster.transfers(&mut accounts, 40);
+    cluster.start(behind);
+    let balances = cluster.balances(&accounts);
+    assert_eq!(balances.len(), 3);
+    assert!(identical(&balances), "{balances:?}");
+    let total: Money = balances[0].iter().copied().sum();
+    assert_eq!(total, money(400));
+    // every node starts again from its own snapshot and what its log has after it
+    let after = balances[0].clone();
+    for node in 0..3 {
+        cluster.stop(node);
+    }
+    for node in 0..3 {
+        cluster.start(node);
+    }
+    cluster.transfers(&mut accounts, 3);
+    let balances = cluster.balances(&accounts);
+    assert!(identical(&balances), "{balances:?}");
+    assert_ne!(balances[0], after);
+}
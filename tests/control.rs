@@ -0,0 +1,96 @@
+//! The admin socket, end to end: a command in, the engine acted on, the answer out, and a
+//! command it can't carry out answered with `ERR`.
+
+#![cfg(unix)]
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use aptone::{Aptone, Money};
+
+fn money(units: i128) -> Money {
+    Money::from_minor(units * 10i128.pow(Money::SCALE))
+}
+
+// A client of `aptone` served on a socket of its own, once it takes connections.
+struct Client {
+    path: PathBuf,
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    fn serve(name: &str, aptone: Arc<Aptone>) -> Client {
+        let path = std::env::temp_dir().join(format!("aptone-{name}-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let serving = path.clone();
+        thread::spawn(move || aptone::control::serve(aptone, serving));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let writer = loop {
+            match UnixStream::connect(&path) {
+                Ok(stream) => break stream,
+                Err(err) => assert!(Instant::now() < deadline, "failed to connect: {err}"),
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        Client {
+            path,
+            reader: BufReader::new(writer.try_clone().unwrap()),
+            writer,
+        }
+    }
+    // Sends `command` and gives back the lines answering it, the `OK` or `ERR` one last.
+    fn send(&mut self, command: &str) -> Vec<String> {
+        writeln!(self.writer, "{}", command).unwrap();
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_string();
+            let last = line.starts_with("OK") || line.starts_with("ERR");
+            lines.push(line);
+            if last {
+                return lines;
+            }
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[test]
+fn commands_act_on_the_engine_and_errors_answer_err() {
+    let aptone = Arc::new(Aptone::builder().threads(2).build());
+    let mut client = Client::serve("control", Arc::clone(&aptone));
+    let account = aptone.open_account(Money::ZERO).unwrap();
+    aptone.deposit(account, money(1)).unwrap().wait().unwrap();
+    let stats = client.send("stats");
+    assert_eq!(stats.len(), 3, "{stats:?}");
+    let totals = stats.last().unwrap();
+    assert!(
+        totals.starts_with("OK applied=1 rejected=0 accounts=1 "),
+        "{totals}"
+    );
+    assert_eq!(client.send("PAUSE"), ["OK"]);
+    assert!(aptone.is_paused());
+    assert!(client.send("stats").last().unwrap().ends_with(" paused"));
+    assert_eq!(client.send("resume"), ["OK"]);
+    assert!(!aptone.is_paused());
+    assert_eq!(client.send("drain 9"), ["ERR no handler 9"]);
+    let resized = client.send("resize 0");
+    assert!(
+        resized[0].starts_with("ERR the pool holds from 1 to "),
+        "{resized:?}"
+    );
+    assert_eq!(client.send("reboot"), ["ERR unknown command: reboot"]);
+}
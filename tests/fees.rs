@@ -0,0 +1,253 @@
+//! Fees have to be charged with the transactions they are on, all or nothing, and show in the
+//! histories as fees.
+
+use std::thread;
+
+use aptone::{
+    Aptone, EntryKind, Fee, FeeSchedule, HoldId, Money, TestHarness, Tx, TxError, TxId, TxKind,
+    TxType,
+};
+
+fn money(units: i128) -> Money {
+    Money::from_minor(units * 10i128.pow(Money::SCALE))
+}
+
+#[test]
+fn fees_show_as_fee_entries() {
+    // the first account opened is the revenue account
+    let schedule = FeeSchedule::new(0).fee(0, TxKind::Withdraw, Fee::Flat(money(1)));
+    let aptone = Aptone::builder().threads(2).fees(schedule).build();
+    let revenue = aptone.open_account(Money::ZERO).unwrap();
+    let account = aptone.open_account(money(10)).unwrap();
+    let tx_id = aptone.withdraw(account, money(4)).unwrap().tx_id();
+    aptone.flush();
+    assert_eq!(aptone.get_balance(account), money(5));
+    assert_eq!(aptone.get_balance(revenue), money(1));
+    let kinds = |account| -> Vec<(TxId, EntryKind)> {
+        let history = aptone.history(account, 10, 0);
+        history
+            .iter()
+            .map(|entry| (entry.tx_id, entry.kind))
+            .collect()
+    };
+    let fee_out = EntryKind::FeeOut { to: revenue };
+    let expected = vec![(tx_id, EntryKind::Withdraw), (tx_id, fee_out)];
+    assert_eq!(kinds(account), expected);
+    let fee_in = EntryKind::FeeIn { from: account };
+    assert_eq!(kinds(revenue), vec![(tx_id, fee_in)]);
+    assert!(aptone.withdraw(account, money(5)).unwrap().wait().is_err());
+    assert_eq!(aptone.get_balance(account), money(5));
+    assert_eq!(aptone.get_balance(revenue), money(1));
+}
+
+#[test]
+fn fees_are_charged_on_the_handlers_of_the_accounts_paying() {
+    let schedule = FeeSchedule::new(0).fee(0, TxKind::Withdraw, Fee::Flat(money(1)));
+    let builder = Aptone::builder().threads(4).fees(schedule);
+    let harness = TestHarness::with_builder(7, builder);
+    let aptone = harness.aptone();
+    let revenue = aptone.open_account(Money::ZERO).unwrap();
+    let accounts = harness.open_accounts(16, money(10)).unwrap();
+    // queued all at once, so the revenue account is busy throughout
+    let receipts: Vec<_> = accounts
+        .iter()
+        .map(|&account| aptone.withdraw(account, money(2)).unwrap())
+        .collect();
+    harness.run_until_idle();
+    for receipt in receipts {
+        receipt.wait().unwrap();
+    }
+    for &account in &accounts {
+        assert_eq!(aptone.get_balance(account), money(7));
+    }
+    assert_eq!(aptone.get_balance(revenue), money(16));
+    let collected = aptone.history(revenue, 100, 0);
+    assert_eq!(collected.len(), 16);
+    assert!(collected
+        .iter()
+        .all(|entry| matches!(entry.kind, EntryKind::FeeIn { .. })));
+    let handlers = aptone.stats().handlers;
+    let busy = handlers.iter().filter(|handler| handler.applied > 0);
+    assert!(busy.count() > 1);
+    assert!(aptone.audit().is_empty());
+}
+
+#[test]
+fn a_fee_the_revenue_account_cannot_take_fails_the_whole_transaction() {
+    let schedule = FeeSchedule::new(0).fee(0, TxKind::Deposit, Fee::Flat(money(2)));
+    let builder = Aptone::builder().threads(4).fees(schedule);
+    let harness = TestHarness::with_builder(8, builder);
+    let aptone = harness.aptone();
+    let revenue = aptone.open_account(Money::MAX).unwrap();
+    let accounts = harness.open_accounts(8, money(10)).unwrap();
+    // keeps the revenue account busy on its handler, and leaves it one short of overflowing
+    let taken = aptone.withdraw(revenue, Money::from_minor(1)).unwrap();
+    let receipts: Vec<_> = accounts
+        .iter()
+        .map(|&account| aptone.deposit(account, money(5)).unwrap())
+        .collect();
+    harness.run_until_idle();
+    taken.wait().unwrap();
+    for receipt in receipts {
+        assert_eq!(receipt.wait(), Err(TxError::Overflow(revenue)));
+    }
+    for &account in &accounts {
+        assert_eq!(aptone.get_balance(account), money(10));
+        assert!(aptone.history(account, 10, 0).is_empty());
+    }
+    assert_eq!(
+        aptone.get_balance(revenue),
+        Money::MAX - Money::from_minor(1)
+    );
+    assert!(aptone.audit().is_empty());
+}
+
+#[test]
+fn fees_keep_the_total_under_concurrent_transfers() {
+    let schedule = FeeSchedule::new(0)
+        .fee(0, TxKind::Transfer, Fee::Percentage(Money::from_minor(100)))
+        .fee(0, TxKind::Withdraw, Fee::Flat(money(1)));
+    let aptone = Aptone::builder()
+        .threads(4)
+        .record_events()
+        .fees(schedule)
+        .build();
+    let revenue = aptone.open_account(Money::ZERO).unwrap();
+    let accounts: Vec<_> = (0..12)
+        .map(|_| aptone.open_account(money(1000)).unwrap())
+        .collect();
+    thread::scope(|scope| {
+        for worker in 0..4 {
+            let (aptone, accounts) = (&aptone, &accounts);
+            scope.spawn(move || {
+                for i in 0..300 {
+                    let from = accounts[(worker * 3 + i) % accounts.len()];
+                    let to = accounts[(worker * 5 + i * 7 + 1) % accounts.len()];
+                    // rejections for want of funds are fine, the total has to hold regardless
+                    let _ = match i % 3 {
+                        0 => aptone.withdraw(from, money(1)),
+                        _ => aptone.transfer(from, to, money(3)),
+                    };
+                }
+            });
+        }
+    });
+    aptone.flush();
+    let withdrawn: Money = accounts
+        .iter()
+        .flat_map(|&account| aptone.history(account, usize::MAX, 0))
+        .filter(|entry| entry.kind == EntryKind::Withdraw)
+        .map(|entry| entry.amount)
+        .sum();
+    let total: Money = accounts
+        .iter()
+        .chain([&revenue])
+        .map(|&account| aptone.get_balance(account))
+        .sum();
+    assert_eq!(total + withdrawn, money(12_000));
+    let fees: Money = aptone
+        .history(revenue, usize::MAX, 0)
+        .iter()
+        .map(|entry| entry.amount)
+        .sum();
+    assert_eq!(aptone.get_balance(revenue), fees);
+    assert!(aptone.audit().is_empty());
+}
+
+#[test]
+fn flat_fees_are_charged_whatever_the_amount() {
+    let schedule = FeeSchedule::new(0).fee(0, TxKind::Withdraw, Fee::Flat(money(2)));
+    for amount in [Money::from_minor(1), money(5), Money::MAX] {
+        let tx = Tx::new(1, amount, TxType::WITHDRAW);
+        assert_eq!(schedule.fee_for(&tx), money(2));
+    }
+    let deposit = Tx::new(1, money(5), TxType::DEPOSIT);
+    assert_eq!(schedule.fee_for(&deposit), Money::ZERO);
+    // a negative fee would pay the account, so nothing is charged
+    let schedule = schedule.fee(0, TxKind::Deposit, Fee::Flat(money(-1)));
+    assert_eq!(schedule.fee_for(&deposit), Money::ZERO);
+}
+
+#[test]
+fn percentage_fees_round_half_to_even() {
+    let half = Fee::Percentage(Money::from_minor(5000));
+    let schedule = FeeSchedule::new(0).fee(0, TxKind::Transfer, half);
+    let fee = |minor| {
+        let tx = Tx::new(1, Money::from_minor(minor), TxType::TRANSFER { to: 2 });
+        schedule.fee_for(&tx)
+    };
+    // 0.00005 and 0.00015 are halfway, and go to the even neighbour
+    assert_eq!(fee(1), Money::ZERO);
+    assert_eq!(fee(3), Money::from_minor(2));
+    assert_eq!(fee(5), Money::from_minor(2));
+    assert_eq!(fee(7), Money::from_minor(4));
+    assert_eq!(fee(10_000), Money::from_minor(5000));
+    let percent = Fee::Percentage(Money::from_minor(100));
+    let schedule = FeeSchedule::new(0).fee(0, TxKind::Transfer, percent);
+    let tx = Tx::new(1, money(1234), TxType::TRANSFER { to: 2 });
+    assert_eq!(schedule.fee_for(&tx), Money::from_minor(123_400));
+}
+
+#[test]
+fn percentage_fees_too_large_to_work_out_are_the_most_there_is() {
+    let percent = Fee::Percentage(Money::from_minor(100));
+    let schedule = FeeSchedule::new(0).fee(0, TxKind::Deposit, percent);
+    let tx = Tx::new(1, Money::MAX, TxType::DEPOSIT);
+    assert_eq!(schedule.fee_for(&tx), Money::MAX);
+}
+
+#[test]
+fn percentage_fees_skip_transactions_without_an_amount() {
+    let percent = Fee::Percentage(Money::from_minor(100));
+    let hold = HoldId {
+        account: 1,
+        number: 0,
+    };
+    let mut schedule = FeeSchedule::new(0);
+    for kind in [TxKind::Capture, TxKind::Release, TxKind::Reversal] {
+        schedule = schedule.fee(0, kind, percent);
+    }
+    for tx_type in [
+        TxType::CAPTURE { hold },
+        TxType::RELEASE { hold },
+        TxType::REVERSAL { original: 7 },
+    ] {
+        let tx = Tx::new(1, money(100), tx_type);
+        assert_eq!(schedule.fee_for(&tx), Money::ZERO, "{tx_type:?}");
+    }
+    // a flat fee needs no amount
+    let schedule = schedule.fee(0, TxKind::Capture, Fee::Flat(money(1)));
+    let tx = Tx::new(1, money(100), TxType::CAPTURE { hold });
+    assert_eq!(schedule.fee_for(&tx), money(1));
+}
+
+#[test]
+fn fees_are_looked_up_by_the_accounts_tier() {
+    let schedule = FeeSchedule::new(0)
+        .fee(0, TxKind::Withdraw, Fee::Flat(money(3)))
+        .fee(1, TxKind::Withdraw, Fee::Flat(money(1)))
+        .fee(2, TxKind::Deposit, Fee::Flat(money(1)))
+        .tier(2, 1)
+        .tier(3, 2);
+    let withdraw = |account| schedule.fee_for(&Tx::new(account, money(10), TxType::WITHDRAW));
+    // accounts not put in a tier are in tier 0
+    assert_eq!(withdraw(1), money(3));
+    assert_eq!(withdraw(2), money(1));
+    // tier 2 has no fee on withdrawals, and doesn't fall back on tier 0's
+    assert_eq!(withdraw(3), Money::ZERO);
+    let deposit = |account| schedule.fee_for(&Tx::new(account, money(10), TxType::DEPOSIT));
+    assert_eq!(deposit(1), Money::ZERO);
+    assert_eq!(deposit(3), money(1));
+}
+
+#[test]
+fn the_revenue_account_is_never_charged() {
+    let schedule = FeeSchedule::new(4)
+        .fee(0, TxKind::Withdraw, Fee::Flat(money(1)))
+        .fee(1, TxKind::Withdraw, Fee::Flat(money(1)))
+        .tier(4, 1);
+    let tx = Tx::new(4, money(10), TxType::WITHDRAW);
+    assert_eq!(schedule.fee_for(&tx), Money::ZERO);
+    let tx = Tx::new(5, money(10), TxType::WITHDRAW);
+    assert_eq!(schedule.fee_for(&tx), money(1));
+}
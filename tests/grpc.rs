@@ -0,0 +1,71 @@
+//! The gRPC front end, end to end over loopback: a request in, the transaction applied, the
+//! answer out, and a rejection coming back with the status it maps to.
+
+#![cfg(feature = "grpc")]
+
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use aptone::grpc::proto::bank_client::BankClient;
+use aptone::grpc::proto::{AmountRequest, BalanceRequest, OpenAccountRequest};
+use aptone::{Aptone, Money, TxError};
+use tonic::transport::Channel;
+use tonic::Code;
+
+// Serves `aptone` on a free loopback port and connects to it, once it takes connections.
+async fn serve(aptone: Aptone) -> BankClient<Channel> {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    tokio::spawn(aptone::grpc::serve(Arc::new(aptone), addr));
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        match BankClient::connect(format!("http://{}", addr)).await {
+            Ok(client) => return client,
+            Err(err) => assert!(Instant::now() < deadline, "failed to connect: {err}"),
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+fn amount(account: u32, amount: &str) -> AmountRequest {
+    AmountRequest {
+        account,
+        amount: amount.to_string(),
+        idempotency_key: None,
+        currency: None,
+    }
+}
+
+#[test]
+fn a_deposit_goes_through_and_a_rejection_maps_to_its_status() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let mut bank = serve(Aptone::new()).await;
+        let open = OpenAccountRequest {
+            initial_balance: Some("10".to_string()),
+        };
+        let account = bank.open_account(open).await.unwrap().into_inner().account;
+        bank.deposit(amount(account, "5.5")).await.unwrap();
+        let read = BalanceRequest {
+            account,
+            currency: None,
+        };
+        let balance = bank.get_balance(read).await.unwrap().into_inner();
+        assert_eq!(balance.balance, "15.5");
+        let rejected = bank.withdraw(amount(account, "100")).await.unwrap_err();
+        assert_eq!(rejected.code(), Code::FailedPrecondition);
+        let insufficient = TxError::InsufficientFunds {
+            account,
+            balance: "15.5".parse::<Money>().unwrap(),
+            amount: "100".parse::<Money>().unwrap(),
+        };
+        assert_eq!(rejected.message(), insufficient.to_string());
+        let unknown = bank.deposit(amount(account + 1, "1")).await.unwrap_err();
+        assert_eq!(unknown.code(), Code::NotFound);
+        let invalid = bank.deposit(amount(account, "lots")).await.unwrap_err();
+        assert_eq!(invalid.code(), Code::InvalidArgument);
+    });
+}
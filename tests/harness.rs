@@ -0,0 +1,394 @@
+//! The same seed has to give the same run, down to the order the handlers apply transactions in,
+//! and the harness has to put the handlers' work in whatever order a test asks for.
+
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use aptone::{
+    AccountId, Aptone, AuditPolicy, AutoscalePolicy, Clock, Config, Fee, FeeSchedule,
+    InterestPolicy, Money, RateLimit, SettlementPolicy, TestHarness, Tx, TxError, TxEvent, TxKind,
+    TxType,
+};
+
+const ACCOUNTS: usize = 8;
+
+fn money(units: i128) -> Money {
+    Money::from_minor(units * 10i128.pow(Money::SCALE))
+}
+
+// Submits transfers and withdrawals between the accounts drawn from the harness's generator,
+// runs them through and gives back what the engine did, in order.
+fn run(seed: u64) -> Vec<TxEvent> {
+    let mut harness = TestHarness::with_builder(seed, Aptone::builder().threads(4));
+    let events = harness.aptone().subscribe();
+    let accounts = harness.open_accounts(ACCOUNTS, money(10)).unwrap();
+    for _ in 0..200 {
+        let from = accounts[harness.below(ACCOUNTS as u64) as usize];
+        let to = accounts[harness.below(ACCOUNTS as u64) as usize];
+        let amount = money(harness.below(5) as i128 + 1);
+        let tx_type = match harness.below(2) {
+            0 => TxType::TRANSFER { to },
+            _ => TxType::WITHDRAW,
+        };
+        // what isn't accepted shows in neither run
+        let _ = harness.aptone().submit_tx(Tx::new(from, amount, tx_type));
+        if harness.below(10) == 0 {
+            harness.step();
+        }
+    }
+    harness.run_until_idle();
+    events.try_iter().collect()
+}
+
+#[test]
+fn same_seed_same_run() {
+    let first = run(7);
+    assert!(first
+        .iter()
+        .any(|event| matches!(event, TxEvent::Rejected { .. })));
+    assert_eq!(first, run(7));
+}
+
+#[test]
+fn seed_picks_the_interleaving() {
+    let runs: Vec<Vec<TxEvent>> = (1..=8).map(run).collect();
+    assert!(runs.iter().any(|run| run != &runs[0]));
+}
+
+#[test]
+fn stepping_one_handler_leaves_the_others_queued() {
+    let harness = TestHarness::with_builder(1, Aptone::builder().threads(2));
+    let accounts: Vec<AccountId> = harness.open_accounts(ACCOUNTS, money(10)).unwrap();
+    let receipts: Vec<_> = accounts
+        .iter()
+        .map(|&account| harness.aptone().deposit(account, money(1)).unwrap())
+        .collect();
+    let stepped = receipts[0].handle_id();
+    while harness.step_handler(stepped) {}
+    let (on_stepped, others): (Vec<_>, Vec<_>) = receipts
+        .into_iter()
+        .partition(|receipt| receipt.handle_id() == stepped);
+    assert!(!others.is_empty());
+    for receipt in on_stepped {
+        assert_eq!(receipt.try_result(), Some(Ok(())));
+    }
+    for receipt in &others {
+        assert_eq!(receipt.try_result(), None);
+    }
+    harness.run_until_idle();
+    for receipt in others {
+        assert_eq!(receipt.wait(), Ok(()));
+    }
+}
+
+#[test]
+fn a_step_processes_one_message() {
+    for handler in [0, 1] {
+        let harness = TestHarness::with_builder(1, Aptone::builder().threads(2));
+        let accounts = harness.open_accounts(ACCOUNTS, money(10)).unwrap();
+        let receipts: Vec<_> = accounts
+            .iter()
+            .map(|&account| harness.aptone().withdraw(account, money(10)).unwrap())
+            .collect();
+        assert!(harness.step_handler(handler));
+        let finished: Vec<_> = receipts
+            .iter()
+            .filter_map(|receipt| {
+                receipt
+                    .try_result()
+                    .map(|result| (receipt.handle_id(), result))
+            })
+            .collect();
+        assert_eq!(finished, vec![(handler, Ok(()))]);
+    }
+}
+
+#[test]
+fn time_moves_only_when_advanced() {
+    let harness = TestHarness::new(1);
+    let accounts = harness.open_accounts(1, money(10)).unwrap();
+    let due = harness.clock().now() + Duration::from_secs(3600);
+    let started = Instant::now();
+    harness
+        .aptone()
+        .schedule(Tx::new(accounts[0], money(4), TxType::WITHDRAW), due)
+        .unwrap();
+    harness.advance(Duration::from_secs(1800));
+    assert_eq!(harness.aptone().get_balance(accounts[0]), money(10));
+    harness.advance(Duration::from_secs(1800));
+    assert_eq!(harness.aptone().get_balance(accounts[0]), money(6));
+    assert_eq!(harness.clock().elapsed(), Duration::from_secs(3600));
+    assert!(started.elapsed() < Duration::from_secs(60));
+}
+
+#[test]
+fn apply_waits_for_the_result() {
+    let harness = TestHarness::new(3);
+    let accounts = harness.open_accounts(2, money(10)).unwrap();
+    let transfer = Tx::new(accounts[0], money(3), TxType::TRANSFER { to: accounts[1] });
+    assert!(harness.apply(transfer).is_ok());
+    assert_eq!(harness.aptone().get_balance(accounts[1]), money(13));
+    let overdrawn = Tx::new(accounts[0], money(30), TxType::WITHDRAW);
+    assert!(matches!(
+        harness.apply(overdrawn),
+        Err(TxError::InsufficientFunds { .. })
+    ));
+}
+
+#[test]
+fn history_is_stamped_by_the_clock() {
+    let harness = TestHarness::new(5);
+    let accounts = harness.open_accounts(2, money(10)).unwrap();
+    harness.clock().advance(Duration::from_secs(60));
+    let transfer = Tx::new(accounts[0], money(3), TxType::TRANSFER { to: accounts[1] });
+    harness.apply(transfer).unwrap();
+    harness.clock().advance(Duration::from_secs(60));
+    harness
+        .apply(Tx::new(accounts[1], money(1), TxType::WITHDRAW))
+        .unwrap();
+    let times = |account| -> Vec<SystemTime> {
+        let history = harness.aptone().history(account, 10, 0);
+        history.iter().map(|entry| entry.time).collect()
+    };
+    let minutes = |count: u64| UNIX_EPOCH + Duration::from_secs(60 * count);
+    assert_eq!(times(accounts[0]), vec![minutes(1)]);
+    assert_eq!(times(accounts[1]), vec![minutes(1), minutes(2)]);
+}
+
+#[test]
+fn settlements_line_up_with_the_engine_clock() {
+    let builder = Aptone::builder().settlement(SettlementPolicy::daily(Duration::from_secs(3600)));
+    let harness = TestHarness::with_builder(6, builder);
+    let events = harness.aptone().subscribe();
+    harness.open_accounts(1, money(10)).unwrap();
+    let settled = || {
+        let events = events.try_iter();
+        let settlements = events.filter_map(|event| match event {
+            TxEvent::Settled { settlement, .. } => Some(settlement),
+            _ => None,
+        });
+        settlements.collect::<Vec<_>>()
+    };
+    // the virtual clock starts at midnight, an hour before the first settlement
+    harness.advance(Duration::from_secs(59 * 60));
+    assert!(settled().is_empty());
+    harness.advance(Duration::from_secs(60));
+    assert_eq!(settled(), vec![1]);
+    harness.advance(Duration::from_secs(24 * 3600));
+    assert_eq!(settled(), vec![2]);
+}
+
+#[test]
+fn zero_intervals_are_turned_down() {
+    let settlement = SettlementPolicy {
+        at: Duration::ZERO,
+        every: Duration::ZERO,
+    };
+    let config = Config {
+        settlement: Some(settlement),
+        ..Config::default()
+    };
+    let error = Aptone::try_with_config(config).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    let interest = InterestPolicy::new(Money::ZERO, Duration::ZERO);
+    let config = Config {
+        interest: Some(interest),
+        ..Config::default()
+    };
+    let error = Aptone::try_with_config(config).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn configs_the_builder_would_panic_on_are_turned_down() {
+    let configs = [
+        Config {
+            threads: 0,
+            ..Config::default()
+        },
+        Config {
+            threads: 4,
+            max_threads: Some(2),
+            ..Config::default()
+        },
+        Config {
+            rate_limit: Some(RateLimit {
+                per_second: 0,
+                burst: 10,
+            }),
+            ..Config::default()
+        },
+        Config {
+            autoscale: Some(AutoscalePolicy::new(4, 2)),
+            ..Config::default()
+        },
+        Config {
+            audit: Some(AuditPolicy::new(Duration::ZERO)),
+            ..Config::default()
+        },
+        Config {
+            webhooks: vec!["https://example.com".to_string()],
+            ..Config::default()
+        },
+    ];
+    for config in configs {
+        let error = Aptone::try_with_config(config).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
+
+// The balance and number of history entries of each of `accounts`.
+fn ledger(harness: &TestHarness, accounts: &[AccountId]) -> Vec<(Money, usize)> {
+    let aptone = harness.aptone();
+    let ledger = accounts.iter().map(|&account| {
+        let history = aptone.history(account, usize::MAX, 0);
+        (aptone.get_balance(account), history.len())
+    });
+    ledger.collect()
+}
+
+#[test]
+fn a_batch_failing_mid_way_applies_none_of_its_legs() {
+    let builder = Aptone::builder().threads(4).record_events();
+    let harness = TestHarness::with_builder(7, builder);
+    let aptone = harness.aptone();
+    let accounts = harness.open_accounts(5, money(100)).unwrap();
+    // pin the accounts to their handlers, so the batch has to gather them from several
+    let queued: Vec<_> = accounts
+        .iter()
+        .map(|&account| aptone.deposit(account, money(1)).unwrap())
+        .collect();
+    let mut handlers: Vec<_> = queued.iter().map(|receipt| receipt.handle_id()).collect();
+    handlers.sort_unstable();
+    handlers.dedup();
+    assert!(handlers.len() > 1);
+    let legs = vec![
+        Tx::new(accounts[0], money(10), TxType::TRANSFER { to: accounts[1] }),
+        Tx::new(accounts[2], money(5), TxType::DEPOSIT),
+        Tx::new(accounts[3], money(20), TxType::TRANSFER { to: accounts[0] }),
+        Tx::new(accounts[4], money(1000), TxType::WITHDRAW),
+        Tx::new(accounts[1], money(1), TxType::DEPOSIT),
+    ];
+    let batch = aptone.submit_batch(legs.clone()).unwrap();
+    harness.run_until_idle();
+    let failed = TxError::InsufficientFunds {
+        account: accounts[4],
+        balance: money(101),
+        amount: money(1000),
+    };
+    assert_eq!(batch.wait(), Err(failed));
+    for receipt in queued {
+        assert_eq!(receipt.wait(), Ok(()));
+    }
+    // only the deposits queued before it went through
+    assert_eq!(ledger(&harness, &accounts), vec![(money(101), 1); 5]);
+    assert!(aptone.audit().is_empty());
+    // without the failing leg the rest goes through together
+    let legs: Vec<_> = legs
+        .into_iter()
+        .filter(|leg| leg.account != accounts[4])
+        .collect();
+    let batch = aptone.submit_batch(legs).unwrap();
+    harness.run_until_idle();
+    assert_eq!(batch.wait(), Ok(()));
+    let expected = vec![
+        (money(111), 3),
+        (money(112), 3),
+        (money(106), 2),
+        (money(81), 2),
+        (money(101), 1),
+    ];
+    assert_eq!(ledger(&harness, &accounts), expected);
+    assert!(aptone.audit().is_empty());
+}
+
+#[test]
+fn a_batch_failing_mid_way_credits_no_fee_on_another_handler() {
+    let schedule = FeeSchedule::new(0).fee(0, TxKind::Transfer, Fee::Flat(money(1)));
+    let builder = Aptone::builder().threads(4).record_events().fees(schedule);
+    let harness = TestHarness::with_builder(8, builder);
+    let aptone = harness.aptone();
+    let revenue = aptone.open_account(Money::ZERO).unwrap();
+    let accounts = harness.open_accounts(3, money(100)).unwrap();
+    // busy with a deposit, the revenue account stays on its handler and is credited there
+    let busy = aptone.deposit(revenue, money(1)).unwrap();
+    let legs = vec![
+        Tx::new(accounts[0], money(10), TxType::TRANSFER { to: accounts[1] }),
+        Tx::new(accounts[1], money(20), TxType::TRANSFER { to: accounts[2] }),
+        Tx::new(accounts[2], money(1000), TxType::WITHDRAW),
+    ];
+    let batch = aptone.submit_batch(legs.clone()).unwrap();
+    assert_ne!(batch.handle_id(), busy.handle_id());
+    harness.run_until_idle();
+    assert!(matches!(
+        batch.wait(),
+        Err(TxError::InsufficientFunds { .. })
+    ));
+    assert_eq!(busy.wait(), Ok(()));
+    let mut all = vec![revenue];
+    all.extend(&accounts);
+    let untouched = vec![
+        (money(1), 1),
+        (money(100), 0),
+        (money(100), 0),
+        (money(100), 0),
+    ];
+    assert_eq!(ledger(&harness, &all), untouched);
+    assert!(aptone.audit().is_empty());
+    // the same again without the withdrawal, the revenue account busy elsewhere once more
+    let busy = aptone.deposit(revenue, money(1)).unwrap();
+    let batch = aptone.submit_batch(legs[..2].to_vec()).unwrap();
+    assert_ne!(batch.handle_id(), busy.handle_id());
+    harness.run_until_idle();
+    assert_eq!(batch.wait(), Ok(()));
+    let expected = vec![
+        (money(4), 4),
+        (money(89), 2),
+        (money(89), 3),
+        (money(120), 1),
+    ];
+    assert_eq!(ledger(&harness, &all), expected);
+    assert!(aptone.audit().is_empty());
+}
+
+#[test]
+fn a_transfer_turned_away_by_a_dead_handler_leaves_nothing_queued() {
+    let harness = TestHarness::with_builder(2, Aptone::builder().threads(2));
+    let aptone = harness.aptone();
+    let accounts = harness.open_accounts(ACCOUNTS, money(10)).unwrap();
+    // deposits left queued pin every account to its handler
+    let receipts: Vec<_> = accounts
+        .iter()
+        .map(|&account| aptone.deposit(account, money(1)).unwrap())
+        .collect();
+    let on = |handler| {
+        let mut pinned = accounts.iter().zip(&receipts);
+        let (&account, _) = pinned
+            .find(|(_, receipt)| receipt.handle_id() == handler)
+            .unwrap();
+        account
+    };
+    let (from, to) = (on(0), on(1));
+    harness.kill_handler(1);
+    let depths = |harness: &TestHarness| {
+        let health = harness.aptone().health();
+        (health[0].queue_depth, health[1].queue_depth)
+    };
+    let before = (depths(&harness), aptone.get_pending_tx(to));
+    assert_eq!(
+        aptone.transfer(from, to, money(5)).map(|_| ()),
+        Err(TxError::HandlerUnavailable(1))
+    );
+    assert_eq!((depths(&harness), aptone.get_pending_tx(to)), before);
+    // what went to the live handler goes through, after which it has nothing left to do
+    harness.run_until_idle();
+    aptone.flush();
+    for (account, receipt) in accounts.iter().zip(receipts) {
+        if receipt.handle_id() == 0 {
+            assert_eq!(receipt.wait(), Ok(()), "deposit to {account}");
+        }
+    }
+    assert_eq!(depths(&harness).0, 0);
+    assert_eq!(aptone.get_pending_tx(from), 0);
+    assert_eq!(aptone.close_account(from).unwrap()[0].1, money(11));
+}
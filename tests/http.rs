@@ -0,0 +1,95 @@
+//! The REST front end, end to end over loopback: a request in, the transaction applied, the
+//! answer out, and a rejection coming back with the status it maps to.
+
+#![cfg(feature = "http")]
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use aptone::{AccountId, Aptone, Money, TxError};
+use serde_json::{json, Value};
+
+fn money(units: i128) -> Money {
+    Money::from_minor(units * 10i128.pow(Money::SCALE))
+}
+
+// Serves `aptone` on a free loopback port, once it takes connections.
+fn serve(aptone: Aptone) -> SocketAddr {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(aptone::http::serve(Arc::new(aptone), addr))
+    });
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(addr).is_err() {
+        assert!(
+            Instant::now() < deadline,
+            "timed out waiting for the server"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    addr
+}
+
+// Sends one request, with a JSON body if given, and gives back the status and JSON answer.
+fn request(addr: SocketAddr, method: &str, path: &str, body: Option<Value>) -> (u16, Value) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let body = body.map_or(String::new(), |body| body.to_string());
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[test]
+fn a_deposit_goes_through_and_a_rejection_maps_to_its_status() {
+    let addr = serve(Aptone::new());
+    let (status, opened) = request(
+        addr,
+        "POST",
+        "/accounts",
+        Some(json!({ "initial_balance": "10" })),
+    );
+    assert_eq!(status, 200);
+    let account = opened["account"].as_u64().unwrap() as AccountId;
+    let deposit = format!("/accounts/{}/deposit", account);
+    let (status, _) = request(addr, "POST", &deposit, Some(json!({ "amount": "5.5" })));
+    assert_eq!(status, 200);
+    let balance = format!("/accounts/{}/balance", account);
+    let (status, read) = request(addr, "GET", &balance, None);
+    assert_eq!(status, 200);
+    let expected: Money = "15.5".parse().unwrap();
+    assert_eq!(read["balance"], json!(expected));
+    let withdraw = format!("/accounts/{}/withdraw", account);
+    let (status, rejected) = request(addr, "POST", &withdraw, Some(json!({ "amount": 100 })));
+    assert_eq!(status, 422);
+    let insufficient = TxError::InsufficientFunds {
+        account,
+        balance: expected,
+        amount: money(100),
+    };
+    assert_eq!(rejected, json!({ "error": insufficient.to_string() }));
+    let unknown = format!("/accounts/{}/deposit", account + 1);
+    let (status, rejected) = request(addr, "POST", &unknown, Some(json!({ "amount": 1 })));
+    assert_eq!(status, 404);
+    let error = TxError::UnknownAccount(account + 1).to_string();
+    assert_eq!(rejected, json!({ "error": error }));
+}
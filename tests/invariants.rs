@@ -0,0 +1,105 @@
+//! Random deposits and withdrawals, submitted from several threads at once, have to leave every
+//! account with exactly what its successful transactions add up to, and nothing pending.
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use aptone::{AccountId, Aptone, BackpressurePolicy, Money, TxError, TxType};
+use proptest::prelude::*;
+
+const ACCOUNTS: AccountId = 4;
+
+#[derive(Debug, Clone, Copy)]
+struct Op {
+    account: AccountId,
+    amount: Money,
+    deposit: bool,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    // down to a ten thousandth, the finest amount there is
+    (0..ACCOUNTS, 1..1_000_000i128, any::<bool>()).prop_map(|(account, amount, deposit)| Op {
+        account,
+        amount: Money::from_minor(amount),
+        deposit,
+    })
+}
+
+#[derive(Default)]
+struct Totals {
+    deposited: Money,
+    withdrawn: Money,
+}
+
+// Submits `ops` in order and waits for all of them, adding up what went through per account.
+fn submit(aptone: &Aptone, ops: &[Op]) -> HashMap<AccountId, Totals> {
+    let mut receipts = Vec::with_capacity(ops.len());
+    for &op in ops {
+        let tx_type = if op.deposit {
+            TxType::DEPOSIT
+        } else {
+            TxType::WITHDRAW
+        };
+        match aptone.handle_tx(op.account, op.amount, tx_type) {
+            Ok(receipt) => receipts.push((op, receipt)),
+            Err(err) => panic!("{:?} not accepted: {}", op, err),
+        }
+    }
+    let mut totals: HashMap<AccountId, Totals> = HashMap::new();
+    for (op, receipt) in receipts {
+        match receipt.wait() {
+            Ok(()) => {
+                let account = totals.entry(op.account).or_default();
+                if op.deposit {
+                    account.deposited += op.amount;
+                } else {
+                    account.withdrawn += op.amount;
+                }
+            }
+            Err(TxError::InsufficientFunds { .. }) if !op.deposit => {}
+            Err(err) => panic!("{:?} failed: {}", op, err),
+        }
+    }
+    totals
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn balances_add_up(
+        handlers in 1..4usize,
+        capacity in 1..8usize,
+        submitters in prop::collection::vec(prop::collection::vec(op(), 0..40), 1..4),
+    ) {
+        let aptone = Aptone::builder()
+            .threads(handlers)
+            .channel_capacity(capacity)
+            .backpressure(BackpressurePolicy::Block)
+            .build();
+        for _ in 0..ACCOUNTS {
+            aptone.open_account(Money::ZERO).unwrap();
+        }
+
+        let totals: Vec<_> = thread::scope(|scope| {
+            let threads: Vec<_> = submitters
+                .iter()
+                .map(|ops| scope.spawn(|| submit(&aptone, ops)))
+                .collect();
+            threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+        });
+
+        for account in 0..ACCOUNTS {
+            let (deposited, withdrawn) = totals
+                .iter()
+                .filter_map(|totals| totals.get(&account))
+                .fold((Money::ZERO, Money::ZERO), |(deposited, withdrawn), totals| {
+                    (deposited + totals.deposited, withdrawn + totals.withdrawn)
+                });
+            prop_assert_eq!(aptone.get_balance(account), deposited - withdrawn);
+            prop_assert_eq!(aptone.get_pending_tx(account), 0);
+        }
+        prop_assert!(aptone.shutdown(Duration::from_secs(10)).is_ok());
+    }
+}
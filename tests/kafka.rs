@@ -0,0 +1,210 @@
+//! A Kafka source has to commit a partition's offsets only as far as every message up to them
+//! was applied or rejected for good, so that what failed for now is read again on the next run,
+//! and whatever was read again after going through is skipped as a duplicate.
+
+#![cfg(feature = "kafka")]
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use aptone::kafka::{KafkaBatch, KafkaError, KafkaMessage, KafkaReport, KafkaSource, Partitions};
+use aptone::{AccountId, Aptone, Money, TxError, VirtualClock};
+
+const TOPIC: &str = "transactions";
+
+fn money(units: i128) -> Money {
+    Money::from_minor(units * 10i128.pow(Money::SCALE))
+}
+
+// A topic kept in memory, each message at the offset of its place in its partition, with the
+// offset the group committed last in each partition.
+struct Topic {
+    partitions: Vec<Vec<String>>,
+    committed: Vec<Option<i64>>,
+}
+
+impl Topic {
+    fn new(partitions: Vec<Vec<String>>) -> Topic {
+        let committed = vec![None; partitions.len()];
+        Topic {
+            partitions,
+            committed,
+        }
+    }
+    // A member of the group, reading on from the committed offsets.
+    fn reader<'a>(&'a mut self, stop: &'a AtomicBool) -> Reader<'a> {
+        let next = self
+            .committed
+            .iter()
+            .map(|&offset| offset.map_or(0, |o| o + 1));
+        let next = next.collect();
+        let consumed = self.committed.clone();
+        Reader {
+            topic: self,
+            next,
+            consumed,
+            stop,
+        }
+    }
+}
+
+// Hands out what came in since the last poll, and stops the run once there is nothing new.
+struct Reader<'a> {
+    topic: &'a mut Topic,
+    next: Vec<i64>,
+    consumed: Vec<Option<i64>>,
+    stop: &'a AtomicBool,
+}
+
+impl Partitions for Reader<'_> {
+    fn poll(&mut self) -> Result<Vec<KafkaBatch>, KafkaError> {
+        let mut batches = Vec::new();
+        for (partition, messages) in self.topic.partitions.iter().enumerate() {
+            let next = &mut self.next[partition];
+            let messages: Vec<_> = (*next..messages.len() as i64)
+                .map(|offset| KafkaMessage {
+                    offset,
+                    value: messages[offset as usize].as_bytes().to_vec(),
+                })
+                .collect();
+            if let Some(last) = messages.last() {
+                *next = last.offset + 1;
+                batches.push(KafkaBatch {
+                    topic: TOPIC.to_string(),
+                    partition: partition as i32,
+                    messages,
+                });
+            }
+        }
+        if batches.is_empty() {
+            self.stop.store(true, Ordering::SeqCst);
+        }
+        Ok(batches)
+    }
+    fn consume(&mut self, topic: &str, partition: i32, offset: i64) -> Result<(), KafkaError> {
+        assert_eq!(topic, TOPIC);
+        self.consumed[partition as usize] = Some(offset);
+        Ok(())
+    }
+    fn commit(&mut self) -> Result<(), KafkaError> {
+        self.topic.committed = self.consumed.clone();
+        Ok(())
+    }
+}
+
+// Reads `topic` into `aptone` until there is nothing new.
+fn run(topic: &mut Topic, aptone: &Aptone) -> Result<KafkaReport, KafkaError> {
+    let stop = AtomicBool::new(false);
+    KafkaSource::with_consumer(topic.reader(&stop)).run(aptone, &stop)
+}
+
+fn deposit(account: AccountId, units: i128) -> String {
+    format!("deposit,{},{}", account, units)
+}
+
+#[test]
+fn offsets_stop_short_of_a_message_turned_away_for_now() {
+    let clock = VirtualClock::new();
+    let aptone = Aptone::builder()
+        .clock(clock.clone())
+        .rate_limit(1, 1)
+        .build();
+    let (a, b) = (
+        aptone.open_account(Money::ZERO).unwrap(),
+        aptone.open_account(Money::ZERO).unwrap(),
+    );
+    let mut topic = Topic::new(vec![
+        vec![deposit(a, 5), "not a transaction".to_string()],
+        // `b`'s second deposit is over its rate limit, the first time round
+        vec![deposit(b, 7), deposit(b, 1), deposit(a, 3)],
+    ]);
+    match run(&mut topic, &aptone) {
+        Err(KafkaError::Submit {
+            partition: 1,
+            offset: 1,
+            error: TxError::RateLimited(account),
+            ..
+        }) => assert_eq!(account, b),
+        other => panic!("expected the second deposit to b turned away, got {other:?}"),
+    }
+    // the partition before the failure is committed as well as the one it stopped
+    assert_eq!(topic.committed, vec![Some(1), Some(0)]);
+    assert_eq!(aptone.get_balance(a), money(5));
+    assert_eq!(aptone.get_balance(b), money(7));
+    // with the tokens back, the next run carries on from the failed message
+    clock.advance(Duration::from_secs(1));
+    let report = run(&mut topic, &aptone).unwrap();
+    let expected = KafkaReport {
+        messages: 2,
+        applied: 2,
+        ..KafkaReport::default()
+    };
+    assert_eq!(report, expected);
+    assert_eq!(topic.committed, vec![Some(1), Some(2)]);
+    assert_eq!(aptone.get_balance(a), money(8));
+    assert_eq!(aptone.get_balance(b), money(8));
+    // read again from the start, nothing is applied twice
+    clock.advance(Duration::from_secs(1));
+    topic.committed = vec![None, None];
+    let report = run(&mut topic, &aptone).unwrap();
+    let expected = KafkaReport {
+        messages: 5,
+        duplicates: 4,
+        malformed: 1,
+        ..KafkaReport::default()
+    };
+    assert_eq!(report, expected);
+    assert_eq!(aptone.get_balance(a), money(8));
+    assert_eq!(aptone.get_balance(b), money(8));
+}
+
+#[test]
+fn rejections_for_good_are_committed() {
+    let aptone = Aptone::new();
+    let account = aptone.open_account(money(10)).unwrap();
+    let mut topic = Topic::new(vec![vec![
+        format!("withdraw,{},50", account),
+        deposit(account, 1),
+    ]]);
+    let report = run(&mut topic, &aptone).unwrap();
+    let expected = KafkaReport {
+        messages: 2,
+        applied: 1,
+        rejected: 1,
+        ..KafkaReport::default()
+    };
+    assert_eq!(report, expected);
+    assert_eq!(topic.committed, vec![Some(1)]);
+    assert_eq!(aptone.get_balance(account), money(11));
+}
+
+#[cfg(feature = "chaos")]
+#[test]
+fn a_receipt_failing_for_now_leaves_its_offset_uncommitted() {
+    use aptone::Faults;
+
+    let faults = Faults {
+        seed: 1,
+        drop_probability: 1.0,
+        ..Faults::default()
+    };
+    let lossy = Aptone::builder().faults(faults).build();
+    let account = lossy.open_account(Money::ZERO).unwrap();
+    let mut topic = Topic::new(vec![vec![deposit(account, 5), deposit(account, 6)]]);
+    match run(&mut topic, &lossy) {
+        Err(KafkaError::Submit {
+            offset: 0,
+            error: TxError::HandlerUnavailable(_),
+            ..
+        }) => {}
+        other => panic!("expected the first deposit to be lost, got {other:?}"),
+    }
+    assert_eq!(topic.committed, vec![None]);
+    // another engine picks up both deposits where the lossy one left off
+    let aptone = Aptone::new();
+    assert_eq!(aptone.open_account(Money::ZERO), Ok(account));
+    let report = run(&mut topic, &aptone).unwrap();
+    assert_eq!(report.applied, 2);
+    assert_eq!(topic.committed, vec![Some(1)]);
+    assert_eq!(aptone.get_balance(account), money(11));
+}
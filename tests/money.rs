@@ -0,0 +1,37 @@
+//! The operators on `Money` saturate at its bounds, where the checked forms fail.
+
+use aptone::Money;
+
+#[test]
+fn operators_saturate() {
+    let one = Money::from_minor(1);
+    assert_eq!(Money::MAX + one, Money::MAX);
+    assert_eq!(Money::MIN - one, Money::MIN);
+    assert_eq!(-Money::MIN, Money::MAX);
+    let mut total = Money::MAX;
+    total += Money::MAX;
+    assert_eq!(total, Money::MAX);
+    total -= Money::MAX;
+    assert_eq!(total, Money::ZERO);
+    assert_eq!(
+        [Money::MAX, one, -one].into_iter().sum::<Money>(),
+        Money::MAX - one
+    );
+}
+
+#[test]
+fn checked_forms_fail_at_the_bounds() {
+    let one = Money::from_minor(1);
+    assert_eq!(Money::MAX.checked_add(one), None);
+    assert_eq!(Money::MIN.checked_sub(one), None);
+    assert_eq!((Money::MAX - one).checked_add(one), Some(Money::MAX));
+}
+
+#[test]
+fn rounding_saturates_at_the_bounds() {
+    assert_eq!(Money::MAX.round_dp(0), Money::MAX);
+    assert_eq!(Money::MIN.round_dp(0), Money::MIN);
+    // rounded down, which fits
+    let hundredths = Money::MAX.minor() / 100 * 100;
+    assert_eq!(Money::MAX.round_dp(2), Money::from_minor(hundredths));
+}
@@ -0,0 +1,154 @@
+//! The NATS front end has to answer every request with how its transaction went, and a request
+//! sent again under the same idempotency key with the first one's transaction rather than a
+//! second one. Served here to a NATS server faked on loopback, speaking just enough of the
+//! protocol for one subscriber.
+
+#![cfg(feature = "nats")]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use aptone::{Aptone, Money, TxError};
+use serde_json::{json, Value};
+
+fn money(units: i128) -> Money {
+    Money::from_minor(units * 10i128.pow(Money::SCALE))
+}
+
+// The server end of the engine's connection, sending it requests as though from a client.
+struct FakeNats {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    sid: String,
+    requests: u64,
+}
+
+impl FakeNats {
+    // Serves `aptone` to a fake server, once it has subscribed.
+    fn serve(aptone: Aptone) -> FakeNats {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let url = format!("nats://{}", addr);
+            runtime.block_on(aptone::nats::serve(Arc::new(aptone), &url))
+        });
+        let (mut writer, _) = listener.accept().unwrap();
+        writer
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let info = json!({
+            "server_id": "fake",
+            "server_name": "fake",
+            "version": "2.10.0",
+            "go": "go1.22",
+            "host": addr.ip().to_string(),
+            "port": addr.port(),
+            "headers": true,
+            "max_payload": 1 << 20,
+            "proto": 1,
+        });
+        write!(writer, "INFO {}\r\n", info).unwrap();
+        let mut nats = FakeNats {
+            reader: BufReader::new(writer.try_clone().unwrap()),
+            writer,
+            sid: String::new(),
+            requests: 0,
+        };
+        while nats.sid.is_empty() {
+            nats.command();
+        }
+        nats
+    }
+    // Reads one command off the connection, answering pings and noting subscriptions, and gives
+    // back the subject and payload of a message published.
+    fn command(&mut self) -> Option<(String, Vec<u8>)> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["PING"] => self.writer.write_all(b"PONG\r\n").unwrap(),
+            ["SUB", .., sid] => self.sid = sid.to_string(),
+            ["PUB", subject, .., length] => {
+                let mut payload = vec![0; length.parse::<usize>().unwrap() + 2];
+                self.reader.read_exact(&mut payload).unwrap();
+                payload.truncate(payload.len() - 2);
+                return Some((subject.to_string(), payload));
+            }
+            _ => {}
+        }
+        None
+    }
+    // Sends `body` on `subject`, under `key` if there is one, and gives back the reply.
+    fn request(&mut self, subject: &str, key: Option<&str>, body: Value) -> Value {
+        self.requests += 1;
+        let inbox = format!("_INBOX.{}", self.requests);
+        let body = body.to_string();
+        match key {
+            Some(key) => {
+                let headers = format!("NATS/1.0\r\nIdempotency-Key: {}\r\n\r\n", key);
+                write!(
+                    self.writer,
+                    "HMSG {} {} {} {} {}\r\n{}{}\r\n",
+                    subject,
+                    self.sid,
+                    inbox,
+                    headers.len(),
+                    headers.len() + body.len(),
+                    headers,
+                    body
+                )
+            }
+            None => write!(
+                self.writer,
+                "MSG {} {} {} {}\r\n{}\r\n",
+                subject,
+                self.sid,
+                inbox,
+                body.len(),
+                body
+            ),
+        }
+        .unwrap();
+        loop {
+            if let Some((subject, payload)) = self.command() {
+                assert_eq!(subject, inbox);
+                return serde_json::from_slice(&payload).unwrap();
+            }
+        }
+    }
+}
+
+#[test]
+fn requests_are_answered_and_retries_deduplicated() {
+    let aptone = Aptone::new();
+    let account = aptone.open_account(money(10)).unwrap();
+    let mut nats = FakeNats::serve(aptone);
+    let deposit = json!({ "account": account, "amount": money(5) });
+    let reply = nats.request("aptone.tx.deposit", Some("first"), deposit.clone());
+    assert_eq!(reply["account"], json!(account));
+    assert_eq!(reply["balance"], json!(money(15)));
+    let tx_id = reply["tx_id"].as_u64().unwrap();
+    // the same deposit sent again, as after a lost reply
+    let reply = nats.request("aptone.tx.deposit", Some("first"), deposit.clone());
+    let duplicate = TxError::Duplicate(tx_id).to_string();
+    assert_eq!(reply, json!({ "error": duplicate }));
+    let reply = nats.request("aptone.tx.deposit", None, deposit);
+    assert_eq!(reply["balance"], json!(money(20)));
+    let withdraw = json!({ "account": account, "amount": money(50) });
+    let reply = nats.request("aptone.tx.withdraw", None, withdraw);
+    let insufficient = TxError::InsufficientFunds {
+        account,
+        balance: money(20),
+        amount: money(50),
+    };
+    assert_eq!(reply, json!({ "error": insufficient.to_string() }));
+    let reply = nats.request("aptone.tx.transfer", None, json!({}));
+    assert_eq!(
+        reply,
+        json!({ "error": "no transactions on aptone.tx.transfer" })
+    );
+}
@@ -0,0 +1,153 @@
+//! Paging through a history query has to give every matching entry once, in order, across
+//! accounts, however the filters and the page size cut it.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aptone::{
+    AccountId, Aptone, HistoryPage, HistoryQuery, Money, TestHarness, Tx, TxKind, TxType,
+};
+
+fn money(units: i128) -> Money {
+    Money::from_minor(units * 10i128.pow(Money::SCALE))
+}
+
+fn minutes(count: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(60 * count)
+}
+
+// Three accounts taking deposits in turn, then a transfer from the last to the first and a
+// withdrawal, a minute apart. Their histories come to:
+//   0: deposits of 5, 50 and 500, and 60 in
+//   1: deposits of 7 and 70
+//   2: deposits of 9 and 90, 60 out and 30 withdrawn
+fn histories() -> (TestHarness, Vec<AccountId>) {
+    let harness = TestHarness::new(13);
+    let accounts = harness.open_accounts(3, Money::ZERO).unwrap();
+    let deposits = [(0, 5), (0, 50), (1, 7), (2, 9), (0, 500), (1, 70), (2, 90)];
+    for (account, amount) in deposits {
+        let deposit = Tx::new(accounts[account], money(amount), TxType::DEPOSIT);
+        harness.apply(deposit).unwrap();
+        harness.clock().advance(Duration::from_secs(60));
+    }
+    let to = accounts[0];
+    let transfer = Tx::new(accounts[2], money(60), TxType::TRANSFER { to });
+    harness.apply(transfer).unwrap();
+    harness.clock().advance(Duration::from_secs(60));
+    let withdraw = Tx::new(accounts[2], money(30), TxType::WITHDRAW);
+    harness.apply(withdraw).unwrap();
+    (harness, accounts)
+}
+
+// Every page of `query`, following `next` from the first.
+fn pages(query: &HistoryQuery) -> Vec<HistoryPage> {
+    let mut pages = vec![query.fetch()];
+    while let Some(next) = pages.last().unwrap().next {
+        pages.push(query.clone().offset(next).fetch());
+    }
+    pages
+}
+
+// The account and amount of each entry on each page.
+fn amounts(pages: &[HistoryPage], accounts: &[AccountId]) -> Vec<Vec<(usize, i128)>> {
+    let index = |account| accounts.iter().position(|&a| a == account).unwrap();
+    let units = |amount: Money| amount.minor() / money(1).minor();
+    pages
+        .iter()
+        .map(|page| {
+            let entries = page.entries.iter();
+            entries
+                .map(|(account, entry)| (index(*account), units(entry.amount)))
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn pages_carry_on_into_the_next_account() {
+    let (harness, accounts) = histories();
+    let query = harness.aptone().history_query().limit(3);
+    let pages = pages(&query);
+    let expected = vec![
+        vec![(0, 5), (0, 50), (0, 500)],
+        vec![(0, 60), (1, 7), (1, 70)],
+        vec![(2, 9), (2, 90), (2, 60)],
+        vec![(2, 30)],
+    ];
+    assert_eq!(amounts(&pages, &accounts), expected);
+    let offsets: Vec<_> = pages.iter().map(|page| page.next).collect();
+    assert_eq!(offsets, vec![Some(3), Some(6), Some(9), None]);
+    // the pages put together are the whole history in one
+    let everything = harness.aptone().history_query().fetch();
+    let paged: Vec<_> = pages.into_iter().flat_map(|page| page.entries).collect();
+    assert_eq!(paged, everything.entries);
+    assert_eq!(everything.next, None);
+}
+
+#[test]
+fn a_last_page_filled_exactly_has_no_next() {
+    let (harness, accounts) = histories();
+    let query = harness.aptone().history_query().limit(5);
+    let pages = pages(&query);
+    let expected = vec![
+        vec![(0, 5), (0, 50), (0, 500), (0, 60), (1, 7)],
+        vec![(1, 70), (2, 9), (2, 90), (2, 60), (2, 30)],
+    ];
+    assert_eq!(amounts(&pages, &accounts), expected);
+    assert_eq!(pages[1].next, None);
+    // past the end there is nothing, and nothing after
+    let past = query.offset(10).fetch();
+    assert_eq!(past, HistoryPage::default());
+    let account = harness.aptone().history_query().account(accounts[1]);
+    assert_eq!(account.offset(2).fetch(), HistoryPage::default());
+}
+
+#[test]
+fn filters_are_applied_before_the_limit() {
+    let (harness, accounts) = histories();
+    let aptone = harness.aptone();
+    let large = aptone
+        .history_query()
+        .tx_type(TxKind::Deposit)
+        .min_amount(money(50))
+        .limit(2);
+    let expected = vec![vec![(0, 50), (0, 500)], vec![(1, 70), (2, 90)]];
+    assert_eq!(amounts(&pages(&large), &accounts), expected);
+    // deposits from minute 1 up to, but not including, minute 6
+    let between = aptone
+        .history_query()
+        .tx_type(TxKind::Deposit)
+        .between(minutes(1), minutes(6))
+        .limit(2);
+    let paged = pages(&between);
+    let expected = vec![vec![(0, 50), (0, 500)], vec![(1, 7), (1, 70)], vec![(2, 9)]];
+    assert_eq!(amounts(&paged, &accounts), expected);
+    assert_eq!(paged[1].next, Some(4));
+    // both sides of the transfer, one to a page
+    let transfers = aptone.history_query().tx_type(TxKind::Transfer).limit(1);
+    let expected = vec![vec![(0, 60)], vec![(2, 60)]];
+    assert_eq!(amounts(&pages(&transfers), &accounts), expected);
+    let out = transfers.account(accounts[2]).fetch();
+    assert_eq!(amounts(&[out], &accounts), vec![vec![(2, 60)]]);
+}
+
+#[test]
+#[should_panic(expected = "at least one entry")]
+fn an_empty_page_is_turned_down() {
+    let (harness, _) = histories();
+    let _ = harness.aptone().history_query().limit(0);
+}
+
+#[test]
+fn accounts_are_searched_from_the_first_id_the_engine_hands_out() {
+    let builder = Aptone::builder().account_ids(1_000_000..2_000_000);
+    let harness = TestHarness::with_builder(17, builder);
+    let accounts = harness.open_accounts(2, Money::ZERO).unwrap();
+    assert_eq!(accounts, vec![1_000_000, 1_000_001]);
+    for (account, amount) in [(0, 3), (1, 4), (0, 5)] {
+        let deposit = Tx::new(accounts[account], money(amount), TxType::DEPOSIT);
+        harness.apply(deposit).unwrap();
+    }
+    let query = harness.aptone().history_query().limit(2);
+    let expected = vec![vec![(0, 3), (0, 5)], vec![(1, 4)]];
+    assert_eq!(amounts(&pages(&query), &accounts), expected);
+}
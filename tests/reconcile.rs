@@ -0,0 +1,72 @@
+//! Reconciling has to find every account where the engine and an external ledger part ways, and
+//! the adjustments it gives have to bring the engine back in line.
+
+use aptone::{AccountId, Aptone, Mismatch, Money, Tx, TxType};
+
+fn money(units: i128) -> Money {
+    Money::from_minor(units * 10i128.pow(Money::SCALE))
+}
+
+#[test]
+fn a_divergence_is_found_and_adjusted_away() {
+    let aptone = Aptone::new();
+    let accounts: Vec<AccountId> = [10, 20, 30, 40]
+        .into_iter()
+        .map(|units| aptone.open_account(money(units)).unwrap())
+        .collect();
+    let (a, b, c, d) = (accounts[0], accounts[1], accounts[2], accounts[3]);
+    let mut external: Vec<(AccountId, Money)> = accounts
+        .iter()
+        .map(|&account| (account, aptone.get_balance(account)))
+        .collect();
+    assert!(aptone.reconcile(external.clone()).is_reconciled());
+    // a withdrawal the external ledger never heard of, a deposit only it has, an account it
+    // lost and one the engine never opened
+    let withdraw = Tx::new(b, money(5), TxType::WITHDRAW);
+    aptone.submit_tx(withdraw).unwrap().wait().unwrap();
+    external[2].1 += money(3);
+    external.retain(|&(account, _)| account != d);
+    let unknown = d + 100;
+    external.push((unknown, money(7)));
+    let reconciliation = aptone.reconcile(external.clone());
+    assert_eq!(reconciliation.matched, 1);
+    let mismatch = |account, engine, external| Mismatch {
+        account,
+        engine,
+        external,
+    };
+    let expected = vec![
+        mismatch(b, Some(money(15)), Some(money(20))),
+        mismatch(c, Some(money(30)), Some(money(33))),
+        mismatch(d, Some(money(40)), None),
+        mismatch(unknown, None, Some(money(7))),
+    ];
+    assert_eq!(reconciliation.mismatches, expected);
+    let diffs: Vec<Money> = expected.iter().map(Mismatch::diff).collect();
+    assert_eq!(diffs, vec![money(5), money(3), money(-40), money(7)]);
+    assert!(reconciliation
+        .to_string()
+        .starts_with("1 matched, 4 mismatched\n"));
+    // the engine can't adjust an account it doesn't have
+    let adjustments = reconciliation.adjustments();
+    let expected = vec![
+        Tx::new(b, money(5), TxType::DEPOSIT),
+        Tx::new(c, money(3), TxType::DEPOSIT),
+        Tx::new(d, money(40), TxType::WITHDRAW),
+    ];
+    assert_eq!(adjustments, expected);
+    for adjustment in adjustments {
+        aptone.submit_tx(adjustment).unwrap().wait().unwrap();
+    }
+    // what's left is which side has which account
+    let reconciliation = aptone.reconcile(external);
+    assert_eq!(reconciliation.matched, 3);
+    let accounts: Vec<AccountId> = reconciliation
+        .mismatches
+        .iter()
+        .map(|mismatch| mismatch.account)
+        .collect();
+    assert_eq!(accounts, vec![d, unknown]);
+    assert_eq!(aptone.get_balance(a), money(10));
+    assert!(reconciliation.adjustments().is_empty());
+}
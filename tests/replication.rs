@@ -0,0 +1,81 @@
+//! A follower has to come out with its primary's balances whatever it missed: everything the
+//! primary applied before it connected, and whatever was applied while the primary wasn't
+//! serving it, once it reconnects.
+
+#![cfg(feature = "replication")]
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use aptone::replication::{Follower, Primary};
+use aptone::{AccountId, Aptone, Config, Money, Tx, TxType};
+
+fn money(units: i128) -> Money {
+    Money::from_minor(units * 10i128.pow(Money::SCALE))
+}
+
+// Polls `done` until it holds, failing the test if it doesn't within a while.
+fn eventually(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {what}");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Moves money around `accounts`, and now and then tries to take out more than there is.
+fn transfers(aptone: &Aptone, accounts: &[AccountId], count: usize) {
+    for i in 0..count {
+        let (from, to) = (accounts[i % 4], accounts[(i + 1) % 4]);
+        let amount = if i % 100 == 0 {
+            1000
+        } else {
+            i as i128 % 7 + 1
+        };
+        let transfer = Tx::new(from, money(amount), TxType::TRANSFER { to });
+        aptone.submit_tx(transfer).unwrap();
+    }
+    aptone.flush();
+}
+
+// Waits for `follower` to apply all `aptone` did, then checks it has the same balances.
+fn caught_up(follower: &Follower, aptone: &Aptone, accounts: &[AccountId]) {
+    let events = aptone.ledger_events(0).len() as u64;
+    eventually("the follower to catch up", || follower.position() == events);
+    for &account in accounts {
+        assert_eq!(follower.get_balance(account), aptone.get_balance(account));
+    }
+}
+
+#[test]
+fn a_follower_catches_up_on_what_it_missed() {
+    let aptone = Arc::new(Aptone::builder().record_events().build());
+    let accounts: Vec<AccountId> = (0..4)
+        .map(|_| aptone.open_account(money(100)).unwrap())
+        .collect();
+    let primary = Primary::start(Arc::clone(&aptone), "127.0.0.1:0").unwrap();
+    let addr = primary.local_addr();
+    // more than one frame's worth before it connects
+    transfers(&aptone, &accounts, 5000);
+    let follower = Follower::connect(addr).unwrap();
+    caught_up(&follower, &aptone, &accounts);
+    // applied while the primary isn't serving
+    drop(primary);
+    eventually("the follower to notice", || !follower.is_connected());
+    transfers(&aptone, &accounts, 500);
+    let _primary = Primary::start(Arc::clone(&aptone), addr).unwrap();
+    caught_up(&follower, &aptone, &accounts);
+    let total: Money = accounts
+        .iter()
+        .map(|&account| follower.get_balance(account))
+        .sum();
+    assert_eq!(total, money(400));
+    // taking over, it opens accounts after the primary's
+    let promoted = follower.promote(Config::default()).unwrap();
+    for &account in &accounts {
+        assert_eq!(promoted.get_balance(account), aptone.get_balance(account));
+    }
+    let opened = promoted.open_account(Money::ZERO).unwrap();
+    assert!(accounts.iter().all(|&account| account < opened));
+}
@@ -0,0 +1,106 @@
+//! A saga has to leave the balances as it found them once a step is rejected, undoing every step
+//! before it in reverse order, and say so when one of them can't be undone.
+
+use aptone::{AccountId, Aptone, Money, Saga, SagaOutcome, SagaStep, Tx, TxError, TxType};
+
+fn money(units: i128) -> Money {
+    Money::from_minor(units * 10i128.pow(Money::SCALE))
+}
+
+fn transfer(from: AccountId, units: i128, to: AccountId) -> Tx {
+    Tx::new(from, money(units), TxType::TRANSFER { to })
+}
+
+fn balances(aptone: &Aptone, accounts: &[AccountId]) -> Vec<Money> {
+    let balances = accounts.iter().map(|&account| aptone.get_balance(account));
+    balances.collect()
+}
+
+#[test]
+fn every_step_is_applied_in_order() {
+    let aptone = Aptone::new();
+    let (a, b) = (
+        aptone.open_account(money(50)).unwrap(),
+        aptone.open_account(Money::ZERO).unwrap(),
+    );
+    // the second step only goes through once the first was applied
+    let report = Saga::new()
+        .step(transfer(a, 30, b))
+        .step(Tx::new(b, money(30), TxType::WITHDRAW))
+        .run(&aptone);
+    assert_eq!(report.outcome, SagaOutcome::Completed);
+    assert_eq!(report.steps.len(), 2);
+    assert!(report.steps.iter().all(|step| step.result.is_ok()));
+    assert!(report.steps.iter().all(|step| step.compensation.is_none()));
+    assert_eq!(balances(&aptone, &[a, b]), vec![money(20), Money::ZERO]);
+}
+
+#[test]
+fn a_rejected_step_undoes_the_ones_before_it() {
+    let aptone = Aptone::new();
+    let accounts: Vec<AccountId> = [50, 10, 20]
+        .into_iter()
+        .map(|units| aptone.open_account(money(units)).unwrap())
+        .collect();
+    let (a, b, c) = (accounts[0], accounts[1], accounts[2]);
+    let report = Saga::new()
+        .step(transfer(a, 30, b))
+        .step(Tx::new(c, money(10), TxType::WITHDRAW))
+        .step(transfer(b, 500, c))
+        .step(Tx::new(a, money(1), TxType::DEPOSIT))
+        .run(&aptone);
+    let error = TxError::InsufficientFunds {
+        account: b,
+        balance: money(40),
+        amount: money(500),
+    };
+    assert_eq!(report.outcome, SagaOutcome::Compensated { step: 2, error });
+    // the last step never ran
+    assert_eq!(report.steps.len(), 3);
+    let undone = |step: &SagaStep| matches!(step.compensation, Some((_, Ok(_))));
+    assert!(report.steps[..2].iter().all(undone));
+    let (transfer_back, _) = report.steps[0].compensation.as_ref().unwrap();
+    assert_eq!(*transfer_back, transfer(b, 30, a));
+    let (reversal, _) = report.steps[1].compensation.as_ref().unwrap();
+    let withdrawn = *report.steps[1].result.as_ref().unwrap();
+    assert_eq!(
+        reversal.tx_type,
+        TxType::REVERSAL {
+            original: withdrawn
+        }
+    );
+    assert!(report.steps[2].compensation.is_none());
+    assert_eq!(
+        balances(&aptone, &accounts),
+        vec![money(50), money(10), money(20)]
+    );
+}
+
+#[test]
+fn a_rejected_compensation_leaves_the_saga_stuck() {
+    let aptone = Aptone::new();
+    let (a, b) = (
+        aptone.open_account(money(50)).unwrap(),
+        aptone.open_account(Money::ZERO).unwrap(),
+    );
+    let report = Saga::new()
+        .step(transfer(a, 20, b))
+        // undone by more than there is
+        .step_with(
+            Tx::new(b, money(5), TxType::DEPOSIT),
+            Tx::new(b, money(100), TxType::WITHDRAW),
+        )
+        .step(Tx::new(b, money(1000), TxType::WITHDRAW))
+        .run(&aptone);
+    assert!(
+        matches!(report.outcome, SagaOutcome::Stuck { step: 2, .. }),
+        "{:?}",
+        report.outcome
+    );
+    let (_, undone) = report.steps[1].compensation.as_ref().unwrap();
+    assert!(undone.is_err());
+    // the steps before the stuck one are still undone
+    let (_, undone) = report.steps[0].compensation.as_ref().unwrap();
+    assert!(undone.is_ok());
+    assert_eq!(balances(&aptone, &[a, b]), vec![money(50), money(5)]);
+}
@@ -0,0 +1,179 @@
+//! Statements have to open and close on the balances the account had at the ends of their
+//! period, worked back from its history, and list exactly the entries in between.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aptone::{AccountId, Currency, EntryKind, Money, Statement, TestHarness, Tx, TxId, TxType};
+
+fn money(units: i128) -> Money {
+    Money::from_minor(units * 10i128.pow(Money::SCALE))
+}
+
+fn minutes(count: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(60 * count)
+}
+
+// An account taking 100 at minute 0, then a deposit, a withdrawal, a captured hold and a
+// released one, a reversal of the withdrawal and a transfer out, about a minute apart, with the
+// ids of what the tests look for.
+struct Account {
+    harness: TestHarness,
+    account: AccountId,
+    withdraw: TxId,
+    capture: TxId,
+    reversal: TxId,
+}
+
+fn account() -> Account {
+    let harness = TestHarness::new(11);
+    let accounts = harness.open_accounts(2, Money::ZERO).unwrap();
+    let (account, payee) = (accounts[0], accounts[1]);
+    let aptone = harness.aptone();
+    harness
+        .apply(Tx::new(account, money(100), TxType::DEPOSIT))
+        .unwrap();
+    harness.clock().advance(Duration::from_secs(60));
+    harness
+        .apply(Tx::new(account, money(50), TxType::DEPOSIT))
+        .unwrap();
+    harness.clock().advance(Duration::from_secs(60));
+    let withdraw = harness
+        .apply(Tx::new(account, money(20), TxType::WITHDRAW))
+        .unwrap();
+    harness.clock().advance(Duration::from_secs(60));
+    let (captured, _) = aptone.authorize(account, money(30)).unwrap();
+    let (released, _) = aptone.authorize(account, money(5)).unwrap();
+    harness.run_until_idle();
+    harness.clock().advance(Duration::from_secs(30));
+    aptone.release(released).unwrap();
+    harness.run_until_idle();
+    harness.clock().advance(Duration::from_secs(30));
+    let capture = aptone.capture(captured).unwrap().tx_id();
+    harness.run_until_idle();
+    harness.clock().advance(Duration::from_secs(60));
+    let reversal = aptone.reverse(account, withdraw).unwrap().tx_id();
+    harness.run_until_idle();
+    harness.clock().advance(Duration::from_secs(60));
+    let transfer = Tx::new(account, money(10), TxType::TRANSFER { to: payee });
+    harness.apply(transfer).unwrap();
+    Account {
+        harness,
+        account,
+        withdraw,
+        capture,
+        reversal,
+    }
+}
+
+fn statement(account: &Account, from: SystemTime, to: SystemTime) -> Statement {
+    let aptone = account.harness.aptone();
+    aptone.statement(account.account, from, to).unwrap()
+}
+
+fn usd(balance: i128) -> Vec<(Currency, Money)> {
+    vec![(Currency::USD, money(balance))]
+}
+
+#[test]
+fn balances_are_worked_back_to_the_ends_of_the_period() {
+    let account = account();
+    assert_eq!(
+        account.harness.aptone().get_balance(account.account),
+        money(110)
+    );
+    // from minute 2 up to, but not including, minute 5
+    let first = statement(&account, minutes(2), minutes(5));
+    assert_eq!(first.opening, usd(150));
+    assert_eq!(first.closing, usd(100));
+    let ids: Vec<TxId> = first.entries.iter().map(|entry| entry.tx_id).collect();
+    assert_eq!(ids, vec![account.withdraw, account.capture]);
+    // the next period opens where that one closed
+    let next = statement(&account, minutes(5), minutes(7));
+    assert_eq!(next.opening, first.closing);
+    assert_eq!(next.closing, usd(110));
+    assert_eq!(next.entries.len(), 2);
+}
+
+#[test]
+fn periods_without_entries_keep_the_balance() {
+    let account = account();
+    let before = statement(&account, UNIX_EPOCH, minutes(0));
+    assert_eq!((before.opening, before.closing), (usd(0), usd(0)));
+    assert!(before.entries.is_empty());
+    let after = statement(&account, minutes(10), minutes(20));
+    assert_eq!((after.opening, after.closing), (usd(110), usd(110)));
+    assert!(after.entries.is_empty());
+    let everything = statement(&account, UNIX_EPOCH, minutes(20));
+    assert_eq!((everything.opening, everything.closing), (usd(0), usd(110)));
+    assert_eq!(everything.entries.len(), 6);
+}
+
+#[test]
+fn holds_show_once_captured_and_reversals_as_what_they_add() {
+    let account = account();
+    let statement = statement(&account, minutes(3), minutes(6));
+    let entries: Vec<_> = statement
+        .entries
+        .iter()
+        .map(|entry| (entry.time, entry.kind, entry.amount, entry.balance))
+        .collect();
+    let hold = match statement.entries[0].kind {
+        EntryKind::Capture { hold } => hold,
+        kind => panic!("expected the capture first, got {kind:?}"),
+    };
+    // authorizing and releasing leave the posted balance alone, so only the capture shows
+    let reversal = EntryKind::Reversal {
+        original: account.withdraw,
+    };
+    let expected = vec![
+        (
+            minutes(4),
+            EntryKind::Capture { hold },
+            money(30),
+            money(100),
+        ),
+        (minutes(5), reversal, money(20), money(120)),
+    ];
+    assert_eq!(entries, expected);
+    assert_eq!(statement.entries[1].tx_id, account.reversal);
+    assert_eq!(statement.opening, usd(130));
+    assert_eq!(statement.closing, usd(120));
+}
+
+#[test]
+fn csv_has_the_opening_entries_and_closing_in_rows() {
+    let account = account();
+    let statement = statement(&account, minutes(2), minutes(5));
+    let hold = match statement.entries[1].kind {
+        EntryKind::Capture { hold } => hold,
+        kind => panic!("expected a capture, got {kind:?}"),
+    };
+    let mut csv = Vec::new();
+    statement.write_csv(&mut csv).unwrap();
+    let expected = format!(
+        "tx_id,time,kind,reference,currency,amount,balance\n\
+         ,120000,opening,,USD,,150\n\
+         {},120000,withdraw,,USD,20,130\n\
+         {},240000,capture,{},USD,30,100\n\
+         ,300000,closing,,USD,,100\n",
+        account.withdraw, account.capture, hold.number
+    );
+    assert_eq!(String::from_utf8(csv).unwrap(), expected);
+}
+
+#[cfg(feature = "export")]
+#[test]
+fn json_gives_the_statement_back() {
+    let account = account();
+    let statement = statement(&account, minutes(2), minutes(5));
+    let mut json = Vec::new();
+    statement.write_json(&mut json).unwrap();
+    let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(value["account"], account.account);
+    assert_eq!(value["opening"], serde_json::json!([["USD", "150"]]));
+    assert_eq!(value["closing"], serde_json::json!([["USD", "100"]]));
+    assert_eq!(value["entries"][0]["kind"], "Withdraw");
+    assert_eq!(value["entries"][0]["amount"], "20");
+    let parsed: Statement = serde_json::from_value(value).unwrap();
+    assert_eq!(parsed, statement);
+}
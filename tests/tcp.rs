@@ -0,0 +1,65 @@
+//! The line protocol, end to end over loopback: a request in, the transaction applied, the
+//! answer out, and a rejection coming back as an `ERR` line.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use aptone::{Aptone, Money, TxError};
+
+// A client of `aptone` served on a free loopback port, once it takes connections.
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    fn serve(aptone: Aptone) -> Client {
+        let addr: SocketAddr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        thread::spawn(move || aptone::tcp::serve(Arc::new(aptone), addr));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let writer = loop {
+            match TcpStream::connect(addr) {
+                Ok(stream) => break stream,
+                Err(err) => assert!(Instant::now() < deadline, "failed to connect: {err}"),
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        Client {
+            reader: BufReader::new(writer.try_clone().unwrap()),
+            writer,
+        }
+    }
+    // Sends `request` and gives back the line answering it.
+    fn send(&mut self, request: &str) -> String {
+        writeln!(self.writer, "{}", request).unwrap();
+        let mut answer = String::new();
+        self.reader.read_line(&mut answer).unwrap();
+        answer.trim_end().to_string()
+    }
+}
+
+#[test]
+fn a_deposit_goes_through_and_a_rejection_answers_err() {
+    let mut client = Client::serve(Aptone::new());
+    let opened = client.send("OPEN 10");
+    let account = opened.strip_prefix("OK ").unwrap();
+    assert_eq!(client.send(&format!("deposit {} 5.5", account)), "OK 15.5");
+    assert_eq!(client.send(&format!("BALANCE {}", account)), "OK 15.5");
+    let insufficient = TxError::InsufficientFunds {
+        account: account.parse().unwrap(),
+        balance: "15.5".parse::<Money>().unwrap(),
+        amount: "100".parse::<Money>().unwrap(),
+    };
+    assert_eq!(
+        client.send(&format!("WITHDRAW {} 100", account)),
+        format!("ERR {}", insufficient)
+    );
+    assert_eq!(client.send("DEPOSIT x 1"), "ERR invalid account: x");
+    assert_eq!(client.send("LEND 1 2"), "ERR unknown command: LEND");
+}
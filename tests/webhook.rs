@@ -0,0 +1,145 @@
+//! A webhook has to see every applied transaction, in the order they were applied: each one is
+//! tried again until the webhook takes it, before the next, and one the engine stopped before
+//! delivering is delivered once it starts again from the same outbox.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use aptone::{AccountId, Aptone, Money};
+
+fn money(units: i128) -> Money {
+    Money::from_minor(units * 10i128.pow(Money::SCALE))
+}
+
+// A webhook on loopback answering each request with the status `answer` gives for its number,
+// counting from 0, and handing on the body of every request it got. Runs until the test ends.
+fn webhook(answer: impl Fn(usize) -> u16 + Send + 'static) -> (String, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/events", listener.local_addr().unwrap());
+    let (bodies, received) = mpsc::channel();
+    thread::spawn(move || {
+        for (request, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("Content-Length: ") {
+                    length = value.parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let status = answer(request);
+            let response = format!(
+                "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+            if bodies.send(String::from_utf8(body).unwrap()).is_err() {
+                return;
+            }
+        }
+    });
+    (url, received)
+}
+
+// The next `count` bodies the webhook got.
+fn next(received: &Receiver<String>, count: usize) -> Vec<String> {
+    let next = (0..count).map(|_| received.recv_timeout(Duration::from_secs(10)));
+    next.collect::<Result<_, _>>()
+        .expect("timed out waiting for the webhook")
+}
+
+// Whether `body` is the event of a deposit of `units` to `account`.
+fn is_deposit(body: &str, account: AccountId, units: i128) -> bool {
+    let fields = [
+        "\"type\":\"deposit\"".to_string(),
+        format!("\"account\":{}", account),
+        format!("\"amount\":\"{}\"", money(units)),
+    ];
+    fields.iter().all(|field| body.contains(field.as_str()))
+}
+
+fn outbox_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("aptone-webhook-{name}-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn start(url: &str, dir: &Path) -> Aptone {
+    Aptone::builder().webhook(url).outbox_dir(dir).build()
+}
+
+#[test]
+fn a_failed_delivery_is_tried_again_before_the_next_event() {
+    let (url, received) = webhook(|request| if request < 2 { 500 } else { 200 });
+    let aptone = Aptone::builder().webhook(url).build();
+    let (a, b) = (
+        aptone.open_account(Money::ZERO).unwrap(),
+        aptone.open_account(Money::ZERO).unwrap(),
+    );
+    aptone.deposit(a, money(5)).unwrap().wait().unwrap();
+    aptone.deposit(b, money(7)).unwrap().wait().unwrap();
+    let bodies = next(&received, 4);
+    assert!(
+        bodies[..3].iter().all(|body| is_deposit(body, a, 5)),
+        "{bodies:?}"
+    );
+    assert!(is_deposit(&bodies[3], b, 7), "{bodies:?}");
+    assert_eq!(
+        received.recv_timeout(Duration::from_millis(300)),
+        Err(RecvTimeoutError::Timeout)
+    );
+}
+
+#[test]
+fn events_left_undelivered_are_delivered_after_a_restart() {
+    let dir = outbox_dir("restart");
+    let up = Arc::new(AtomicBool::new(false));
+    let answering = Arc::clone(&up);
+    let (url, received) = webhook(move |_| {
+        if answering.load(Ordering::SeqCst) {
+            200
+        } else {
+            503
+        }
+    });
+    let aptone = start(&url, &dir);
+    let (a, b) = (
+        aptone.open_account(Money::ZERO).unwrap(),
+        aptone.open_account(Money::ZERO).unwrap(),
+    );
+    aptone.deposit(a, money(5)).unwrap().wait().unwrap();
+    aptone.deposit(b, money(7)).unwrap().wait().unwrap();
+    // turned away at least once, then stopped
+    assert!(is_deposit(&next(&received, 1)[0], a, 5));
+    drop(aptone);
+    received.try_iter().for_each(drop);
+    up.store(true, Ordering::SeqCst);
+    let aptone = start(&url, &dir);
+    let bodies = next(&received, 2);
+    assert!(is_deposit(&bodies[0], a, 5), "{bodies:?}");
+    assert!(is_deposit(&bodies[1], b, 7), "{bodies:?}");
+    // nothing delivered is delivered again
+    drop(aptone);
+    let _aptone = start(&url, &dir);
+    assert_eq!(
+        received.recv_timeout(Duration::from_millis(300)),
+        Err(RecvTimeoutError::Timeout)
+    );
+    let _ = fs::remove_dir_all(&dir);
+}